@@ -0,0 +1,285 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! What the auth middleware reads from the database on every request.
+//!
+//! Users, sessions, signing keys and revoked tokens are cached for a short
+//! while, and read through a small pool of connections opened once, so
+//! verifying a token does not open the database. Connections handed out by
+//! the cache tell it about the changes made through them, dropping the
+//! entries they make stale.
+
+use super::auth_middleware::now;
+use super::users_db::{ ReadFilter, Session, SigningKey, User, UsersDb };
+
+use iron::Request;
+use iron::typemap::Key;
+use rusqlite;
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+use std::sync::{ Arc, Mutex, RwLock };
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+/// Seconds cached entries are used for before being read again.
+const CACHE_TTL: i64 = 60;
+
+/// Connections kept open for the middleware once released.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// Seconds between updates of the last time a session was seen.
+const SESSION_TOUCH_INTERVAL: i64 = 60;
+
+struct Entry<T> {
+    value: T,
+    cached_at: i64
+}
+
+impl<T: Clone> Entry<T> {
+    fn new(value: T) -> Entry<T> {
+        Entry {
+            value: value,
+            cached_at: now()
+        }
+    }
+
+    fn fresh(&self) -> Option<T> {
+        if now() - self.cached_at < CACHE_TTL {
+            Some(self.value.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Default)]
+struct Caches {
+    /// Bumped by every invalidation, so what was read from the database
+    /// before it is not cached after it.
+    generation: u64,
+    users: HashMap<String, Entry<User>>,
+    sessions: HashMap<String, Entry<Session>>,
+    signing_keys: Option<Entry<Arc<Vec<SigningKey>>>>,
+    revoked_tokens: Option<Entry<Arc<HashSet<String>>>>
+}
+
+/// Cache of the auth data of a database, shared by the auth middlewares,
+/// routers and connections of a `UsersManager`.
+#[derive(Clone)]
+pub struct AuthCache {
+    db_file: String,
+    caches: Arc<RwLock<Caches>>,
+    connections: Arc<Mutex<Vec<UsersDb>>>,
+    schema_created: Arc<AtomicBool>
+}
+
+impl fmt::Debug for AuthCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AuthCache {{ db_file: {:?} }}", self.db_file)
+    }
+}
+
+impl Key for AuthCache {
+    type Value = AuthCache;
+}
+
+impl AuthCache {
+    pub fn new(db_file: &str) -> AuthCache {
+        AuthCache {
+            db_file: db_file.to_owned(),
+            caches: Arc::new(RwLock::new(Caches::default())),
+            connections: Arc::new(Mutex::new(Vec::new())),
+            schema_created: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// The database file the cache is for.
+    pub fn db_file(&self) -> &str {
+        &self.db_file
+    }
+
+    /// The cache the auth middleware `req` went through shares for
+    /// `db_file`, or a new one if there is none.
+    pub fn from_request(req: &Request, db_file: &str) -> AuthCache {
+        match req.extensions.get::<AuthCache>() {
+            Some(cache) if cache.db_file == db_file => cache.clone(),
+            _ => AuthCache::new(db_file)
+        }
+    }
+
+    /// Opens a connection to the database whose changes invalidate the
+    /// cache. The tables are only created by the first one.
+    pub fn connect(&self) -> UsersDb {
+        self.open().invalidating(self.clone())
+    }
+
+    fn open(&self) -> UsersDb {
+        if self.schema_created.load(Ordering::SeqCst) {
+            return UsersDb::open(&self.db_file);
+        }
+        let db = UsersDb::new(&self.db_file);
+        self.schema_created.store(true, Ordering::SeqCst);
+        db
+    }
+
+    /// Runs `f` with a pooled connection to the database. Changes made
+    /// through it do not invalidate the cache.
+    pub fn with_db<T, F>(&self, f: F) -> T where F: FnOnce(&UsersDb) -> T {
+        let pooled = self.connections.lock().unwrap().pop();
+        let db = match pooled {
+            Some(db) => db,
+            None => self.open()
+        };
+        let result = f(&db);
+        let mut connections = self.connections.lock().unwrap();
+        if connections.len() < MAX_IDLE_CONNECTIONS {
+            connections.push(db);
+        }
+        result
+    }
+
+    /// The value `lookup` finds in the cache, or else the one `read` gets
+    /// from the database. The lock is not held while reading, so `store`
+    /// only caches the value if nothing was invalidated meanwhile.
+    fn get<T, L, R, S>(&self, lookup: L, read: R, store: S)
+        -> rusqlite::Result<T>
+        where T: Clone,
+              L: FnOnce(&Caches) -> Option<T>,
+              R: FnOnce(&UsersDb) -> rusqlite::Result<T>,
+              S: FnOnce(&mut Caches, T) {
+        let generation = {
+            let caches = self.caches.read().unwrap();
+            if let Some(value) = lookup(&*caches) {
+                return Ok(value);
+            }
+            caches.generation
+        };
+        let value = try!(self.with_db(read));
+        let mut caches = self.caches.write().unwrap();
+        if caches.generation == generation {
+            store(&mut *caches, value.clone());
+        }
+        Ok(value)
+    }
+
+    /// The user identified by `id`, if any.
+    pub fn user(&self, id: &str) -> rusqlite::Result<Option<User>> {
+        self.get(
+            |caches| caches.users.get(id).and_then(Entry::fresh).map(Some),
+            |db| db.read(ReadFilter::Id(id.to_owned()))
+                   .map(|users| users.into_iter().next()),
+            |caches, user| if let Some(user) = user {
+                caches.users.insert(id.to_owned(), Entry::new(user));
+            })
+    }
+
+    /// The open session identified by `id`, if any.
+    pub fn session(&self, id: &str) -> rusqlite::Result<Option<Session>> {
+        self.get(
+            |caches| caches.sessions.get(id).and_then(Entry::fresh).map(Some),
+            |db| db.read_session(id),
+            |caches, session| if let Some(session) = session {
+                caches.sessions.insert(id.to_owned(), Entry::new(session));
+            })
+    }
+
+    fn signing_keys(&self) -> rusqlite::Result<Arc<Vec<SigningKey>>> {
+        self.get(
+            |caches| caches.signing_keys.as_ref().and_then(Entry::fresh),
+            |db| db.read_signing_keys().map(Arc::new),
+            |caches, keys| caches.signing_keys = Some(Entry::new(keys)))
+    }
+
+    /// The signing key identified by `kid`, if any. The keys are read
+    /// again if it is not cached, as it may have been created since.
+    pub fn signing_key(&self, kid: &str)
+        -> rusqlite::Result<Option<SigningKey>> {
+        let find = |keys: Arc<Vec<SigningKey>>| {
+            keys.iter().find(|key| key.kid == kid).cloned()
+        };
+        if let Some(key) = find(try!(self.signing_keys())) {
+            return Ok(Some(key));
+        }
+        self.invalidate_signing_keys();
+        Ok(find(try!(self.signing_keys())))
+    }
+
+    /// Whether the session token identified by `jti` has been revoked.
+    pub fn is_token_revoked(&self, jti: &str) -> rusqlite::Result<bool> {
+        let revoked = try!(self.get(
+            |caches| caches.revoked_tokens.as_ref().and_then(Entry::fresh),
+            |db| db.read_revoked_tokens()
+                   .map(|tokens| Arc::new(tokens.into_iter().collect())),
+            |caches, revoked| caches.revoked_tokens = Some(Entry::new(revoked))
+        ));
+        Ok(revoked.contains(jti))
+    }
+
+    /// Records that the session identified by `id` was seen at `now`. To
+    /// spare a write on every request, it is only stored if the session
+    /// was last seen more than `SESSION_TOUCH_INTERVAL` seconds before.
+    pub fn touch_session(&self, id: &str, now: i64) -> rusqlite::Result<()> {
+        if let Some(entry) = self.caches.read().unwrap().sessions.get(id) {
+            if now - entry.value.last_seen < SESSION_TOUCH_INTERVAL {
+                return Ok(());
+            }
+        }
+        try!(self.with_db(|db| db.touch_session(id, now)));
+        if let Some(entry) = self.caches.write().unwrap().sessions.get_mut(id) {
+            entry.value.last_seen = now;
+        }
+        Ok(())
+    }
+
+    fn invalidate<F>(&self, f: F) where F: FnOnce(&mut Caches) {
+        let mut caches = self.caches.write().unwrap();
+        caches.generation += 1;
+        f(&mut *caches);
+    }
+
+    pub fn invalidate_all(&self) {
+        self.invalidate(|caches| {
+            let generation = caches.generation;
+            *caches = Caches::default();
+            caches.generation = generation;
+        });
+    }
+
+    pub fn invalidate_user(&self, id: &str) {
+        self.invalidate(|caches| { caches.users.remove(id); });
+    }
+
+    pub fn invalidate_session(&self, id: &str) {
+        self.invalidate(|caches| { caches.sessions.remove(id); });
+    }
+
+    pub fn invalidate_user_sessions(&self, user_id: &str) {
+        self.invalidate(|caches| {
+            let ids: Vec<String> = caches.sessions.iter()
+                .filter(|&(_, entry)| entry.value.user_id == user_id)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in ids {
+                caches.sessions.remove(&id);
+            }
+        });
+    }
+
+    pub fn invalidate_sessions(&self) {
+        self.invalidate(|caches| caches.sessions.clear());
+    }
+
+    pub fn invalidate_signing_keys(&self) {
+        self.invalidate(|caches| caches.signing_keys = None);
+    }
+
+    pub fn invalidate_revoked_tokens(&self) {
+        self.invalidate(|caches| caches.revoked_tokens = None);
+    }
+}
+
+/// A connection to the database of `db_path` for the handler of `req`,
+/// through which changes are seen right away by the auth middleware.
+pub fn users_db(req: &Request, db_path: &str) -> UsersDb {
+    AuthCache::from_request(req, db_path).connect()
+}
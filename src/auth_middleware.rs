@@ -13,47 +13,332 @@
 //! in the body of the response. This token must be sent with any further request to
 //! keep track of the session.
 
-use super::users_db::{ReadFilter, User, UsersDb};
+use super::auth_cache::AuthCache;
+use super::users_db::{AccessToken, KeyPurpose, SigningAlgorithm, SigningKey, User,
+                      UsersDb};
 use super::errors::*;
+use super::jws::{ self, JoseHeader, Jws };
+use super::route_matcher::RouteMatcher;
 
-use crypto::sha2::Sha256;
 use iron::{AroundMiddleware, Handler, headers, status};
 use iron::method::Method;
 use iron::prelude::*;
-use jwt::{self, Error, Header, Token};
+use iron::typemap::Key;
+use crypto::util::fixed_time_eq;
+use rusqlite;
+use std::i64;
+use std::str;
 use std::sync::{ Arc, RwLock };
+use std::time::{ SystemTime, UNIX_EPOCH };
 use urlencoded::UrlEncodedQuery;
+use uuid::Uuid;
+
+/// Scope granted to every user, allowing to read users information.
+pub static SCOPE_USERS_READ: &'static str = "users:read";
+/// Scope granted to admin users, allowing to manage other users.
+pub static SCOPE_USERS_ADMIN: &'static str = "users:admin";
+/// Scope allowing service clients to introspect tokens. It is not granted
+/// by default, but through the permissions of a role.
+pub static SCOPE_TOKENS_INTROSPECT: &'static str = "tokens:introspect";
+
+/// Prefix of personal access tokens, telling them apart from session
+/// tokens.
+pub static ACCESS_TOKEN_PREFIX: &'static str = "pat_";
+
+/// Cookie carrying the session token, when cookie sessions are enabled.
+pub static SESSION_COOKIE: &'static str = "session_token";
+/// Cookie carrying the CSRF token of a cookie session. Unlike the session
+/// cookie it is readable by scripts, which must send it back in the
+/// `CSRF_HEADER` header with any state-changing request.
+pub static CSRF_COOKIE: &'static str = "csrf_token";
+pub static CSRF_HEADER: &'static str = "X-CSRF-Token";
+/// Cookie carrying the refresh token of a cookie session. It is only sent
+/// to the refresh endpoint.
+pub static REFRESH_COOKIE: &'static str = "refresh_token";
+
+/// `Set-Cookie` header values for a cookie session. The refresh token
+/// cookie is restricted to `refresh_path` and lasts as long as the refresh
+/// token. A `max_age` of zero removes the cookies.
+pub fn session_cookies(session_token: &str, refresh_token: &str,
+                       csrf_token: &str, max_age: i64, refresh_path: &str,
+                       secure: bool) -> Vec<Vec<u8>> {
+    let secure = if secure { "; Secure" } else { "" };
+    let attributes = format!("Max-Age={}; Path=/; SameSite=Strict{}",
+                             max_age, secure);
+    let refresh_max_age = if max_age > 0 { SessionToken::REFRESH_TTL } else { 0 };
+    vec![
+        format!("{}={}; {}; HttpOnly", SESSION_COOKIE, session_token,
+                attributes).into_bytes(),
+        format!("{}={}; {}", CSRF_COOKIE, csrf_token, attributes).into_bytes(),
+        format!("{}={}; Max-Age={}; Path={}; SameSite=Strict{}; HttpOnly",
+                REFRESH_COOKIE, refresh_token, refresh_max_age, refresh_path,
+                secure).into_bytes()
+    ]
+}
+
+/// Value of the cookie `name` sent with the request, if any.
+pub fn get_cookie(req: &Request, name: &str) -> Option<String> {
+    let lines = match req.headers.get_raw("Cookie") {
+        Some(lines) => lines,
+        None => return None
+    };
+    for line in lines {
+        let line = match str::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => continue
+        };
+        for pair in line.split(';') {
+            let mut parts = pair.splitn(2, '=');
+            if parts.next().map(|part| part.trim()) == Some(name) {
+                if let Some(value) = parts.next() {
+                    return Some(value.trim().to_owned());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether a request with the given method may change the server state.
+fn is_state_changing(method: &Method) -> bool {
+    match *method {
+        Method::Get | Method::Head | Method::Options => false,
+        _ => true
+    }
+}
+
+/// Current time as seconds since the Unix epoch, the unit used by the
+/// `iat`, `exp` and `nbf` claims.
+pub fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(_) => 0
+    }
+}
 
 /// Structure representing [JWT claims section](https://jwt.io/introduction/).
 ///
 /// Claims made by the authentication protocol includes `id` and `email` with
-/// database unique id and email respectively.
-#[derive(Default, RustcDecodable, RustcEncodable)]
+/// database unique id and email respectively, the registered `iat`
+/// (issued at), `exp` (expiration time) and `nbf` (not before) claims
+/// bounding the validity of the token, a unique `jti` (JWT ID) that
+/// allows revoking single tokens, the `sid` of the server side session
+/// the token belongs to and the `scopes` granted to the token bearer.
+/// Scopes include the permissions of the user's roles at the time the
+/// token was issued. Tokens issued to OAuth clients carry the `client_id`
+/// of the client as well.
+#[derive(Clone, Debug, Default, RustcDecodable, RustcEncodable)]
 pub struct SessionClaims{
     pub id: String,
-    pub email: String
+    pub email: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub nbf: i64,
+    pub jti: String,
+    pub sid: String,
+    pub scopes: Vec<String>,
+    pub client_id: Option<String>
+}
+
+impl SessionClaims {
+    /// Claims for `user` within the session `session_id`, valid from now
+    /// and for the following `ttl` seconds, with the default scopes of
+    /// the user.
+    pub fn new(user: &User, session_id: &str, ttl: i64) -> SessionClaims {
+        let now = now();
+        SessionClaims {
+            id: user.id.to_owned(),
+            email: user.email.to_owned(),
+            iat: now,
+            exp: now + ttl,
+            nbf: now,
+            jti: Uuid::new_v4().simple().to_string(),
+            sid: session_id.to_owned(),
+            scopes: SessionClaims::default_scopes(user),
+            client_id: None
+        }
+    }
+
+    /// Scopes granted to `user` on login: every user can read users
+    /// information and admins can manage them as well.
+    pub fn default_scopes(user: &User) -> Vec<String> {
+        let mut scopes = vec![SCOPE_USERS_READ.to_owned()];
+        if user.is_admin {
+            scopes.push(SCOPE_USERS_ADMIN.to_owned());
+        }
+        scopes
+    }
+
+    /// Scopes currently held by `user`: the default ones plus the
+    /// permissions granted by the roles assigned to the user.
+    pub fn granted_scopes(user: &User, db: &UsersDb)
+        -> rusqlite::Result<Vec<String>> {
+        let mut scopes = SessionClaims::default_scopes(user);
+        for permission in try!(db.read_user_permissions(&user.id)) {
+            if !scopes.contains(&permission) {
+                scopes.push(permission);
+            }
+        }
+        Ok(scopes)
+    }
+
+    /// Whether the claims grant all the given scopes.
+    pub fn has_scopes(&self, scopes: &[String]) -> bool {
+        scopes.iter().all(|scope| self.scopes.contains(scope))
+    }
+}
+
+/// The verified caller of an authenticated endpoint.
+///
+/// The auth middleware inserts it into the request extensions once the
+/// session token is verified, so handlers can find out who is calling
+/// without parsing the token again:
+///
+/// ```ignore
+/// let caller = req.extensions.get::<AuthenticatedUser>().unwrap();
+/// println!("{} is calling", caller.user.email);
+/// ```
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub claims: SessionClaims,
+    pub user: User,
+    pub kind: TokenKind
+}
+
+impl Key for AuthenticatedUser {
+    type Value = AuthenticatedUser;
+}
+
+impl AuthenticatedUser {
+    /// Whether the user is calling with a session token of its own, rather
+    /// than with a personal access token or a token issued to an OAuth
+    /// client.
+    pub fn is_first_party(&self) -> bool {
+        match self.kind {
+            TokenKind::Session { .. } => self.claims.client_id.is_none(),
+            TokenKind::AccessToken { .. } => false
+        }
+    }
+}
+
+/// The kind of a verified token.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    /// A session token, signed by the server key `kid`.
+    Session { kid: String, algorithm: SigningAlgorithm },
+    /// The personal access token identified by `id`.
+    AccessToken { id: String, name: String }
+}
+
+/// A token that passed verification, with the user it was issued to and
+/// how it was issued.
+///
+/// Personal access tokens get claims too, with their id as `jti`, an empty
+/// `sid` and, for tokens that do not expire, the largest possible `exp`.
+#[derive(Clone, Debug)]
+pub struct VerifiedSession {
+    pub claims: SessionClaims,
+    pub user: User,
+    pub kind: TokenKind
+}
+
+/// Reasons why a session token can be rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenError {
+    /// The token cannot be parsed or lacks the `kid` header.
+    Malformed,
+    /// The token is not signed by a current server key or its signature
+    /// does not match, or the access token does not exist.
+    BadSignature,
+    /// The user the token was issued to does not exist.
+    UnknownUser,
+    /// The user the token was issued to is not active.
+    Inactive,
+    /// The `exp` claim is in the past.
+    Expired,
+    /// The `nbf` claim is in the future.
+    NotYetValid,
+    /// The token has been explicitly revoked, i.e. on logout.
+    Revoked,
+    /// The session the token belongs to does not exist anymore.
+    UnknownSession,
+    /// The token could not be checked against the database.
+    Internal
 }
 
 /// Factory to create a session token `String` for a user in the database.
 pub struct SessionToken;
 
 impl SessionToken {
-    pub fn from_user(user: &User) -> Result<String, Error> {
-        let jwt_header: jwt::Header = Default::default();
-        let claims = SessionClaims {
-            id: user.id.to_owned(),
-            email: user.email.to_owned()
+    /// Session tokens lifetime, in seconds, if no other is specified.
+    pub const DEFAULT_TTL: i64 = 24 * 60 * 60;
+
+    /// Lifetime, in seconds, of the refresh tokens issued together with
+    /// session tokens.
+    pub const REFRESH_TTL: i64 = 30 * 24 * 60 * 60;
+
+    /// Lifetime, in seconds, of the single-use activation tokens sent with
+    /// user invitations.
+    pub const ACTIVATION_TTL: i64 = 24 * 60 * 60;
+
+    /// Lifetime, in seconds, of the single-use tokens of password reset
+    /// links.
+    pub const PASSWORD_RESET_TTL: i64 = 60 * 60;
+
+    /// Minimum time, in seconds, between two password reset links sent to
+    /// the same email address.
+    pub const PASSWORD_RESET_INTERVAL: i64 = 60;
+
+    /// Grace period, in seconds, during which tokens signed with a
+    /// rotated key are still accepted if no other is specified. It matches
+    /// the default session tokens lifetime so no session is cut short.
+    pub const KEY_GRACE_PERIOD: i64 = 24 * 60 * 60;
+
+    pub fn from_user(user: &User, session_id: &str, key: &SigningKey)
+        -> Result<String, ()> {
+        SessionToken::from_user_with_ttl(user, session_id,
+                                         SessionToken::DEFAULT_TTL, key)
+    }
+
+    /// Create a session token for `user` within the session `session_id`
+    /// that expires in `ttl` seconds, signed with `key`.
+    pub fn from_user_with_ttl(user: &User, session_id: &str, ttl: i64,
+                              key: &SigningKey) -> Result<String, ()> {
+        SessionToken::from_claims(user, SessionClaims::new(user, session_id,
+                                                           ttl), key)
+    }
+
+    /// Sign the given claims, i.e. with custom scopes, for `user` with the
+    /// server key `key`, whose id goes in the `kid` header and whose
+    /// algorithm goes in the `alg` header.
+    pub fn from_claims(user: &User, claims: SessionClaims, key: &SigningKey)
+        -> Result<String, ()> {
+        let header = JoseHeader::new(key.algorithm.as_str(), &key.kid);
+        let signed = match key.algorithm {
+            SigningAlgorithm::HS256 => {
+                jws::encode(&header, &claims,
+                            &SessionToken::signing_secret(user, key))
+            },
+            SigningAlgorithm::EdDSA => match key.ed25519_keypair() {
+                Some((secret_key, _)) => {
+                    jws::encode(&header, &claims, &secret_key)
+                },
+                None => return Err(())
+            }
         };
-        let token = jwt::Token::new(jwt_header, claims);
-        token.signed(
-            user.secret.to_owned().as_bytes(),
-            Sha256::new()
-        )
+        signed.map_err(|_| ())
+    }
+
+    /// The HMAC secret for `HS256` tokens of `user` signed with `key`. It
+    /// mixes the server key with the user secret, so both rotating the
+    /// server keys and changing the user secret invalidate tokens.
+    fn signing_secret(user: &User, key: &SigningKey) -> Vec<u8> {
+        format!("{}.{}", key.secret, user.secret).into_bytes()
     }
 
-    pub fn from_string(token_str: &str)
-        -> Result<Token<Header, SessionClaims>, Error> {
-        Token::<Header, SessionClaims>::parse(token_str)
+    /// Parse a session token without verifying it.
+    pub fn from_string(token_str: &str) -> Result<Jws<SessionClaims>, ()> {
+        jws::decode::<SessionClaims>(token_str)
     }
 }
 
@@ -62,89 +347,121 @@ impl SessionToken {
 /// When initializing [`AuthMiddleware`](./struct.AuthMiddleware.html) you need to
 /// pass some routes to be authenticated, these are instances of `AuthEndpoint`.
 ///
-/// `AuthEndpoints` take a vector of methods and a string representing the path
-/// of the endpoint to be authenticated. This path can contain wildcard
+/// `AuthEndpoints` take a vector of methods, a string representing the path
+/// of the endpoint to be authenticated and a vector of the scopes a session
+/// token needs to access the endpoint. This path can contain wildcard
 /// parts. For example:
 ///
-/// `AuthEndpoint`(vec![`Method::Get`, `Method::Post`], "/a/path/:foo/bar/:baz")
+/// `AuthEndpoint`(vec![`Method::Get`, `Method::Post`], "/a/path/:foo/bar/:baz",
+///                vec![])
 ///
 /// would match with a GET or POST request to /a/path/whatever/bar/whatever
-#[derive(Debug, Clone)]
-pub struct AuthEndpoint(pub Vec<Method>, pub String);
-
-impl PartialEq for AuthEndpoint {
-    fn eq(&self, other: &AuthEndpoint) -> bool {
-        let AuthEndpoint(ref self_method, ref self_path) = *self;
-        let AuthEndpoint(ref other_method, ref other_path) = *other;
-
-        let self_path: Vec<&str> = if self_path.starts_with('/') {
-            self_path[1..].split('/').collect()
-        } else {
-            self_path[0..].split('/').collect()
-        };
-        let other_path: Vec<&str> = if other_path.starts_with('/') {
-            other_path[1..].split('/').collect()
-        } else {
-            other_path[0..].split('/').collect()
-        };
-
-        if self_path.len() != other_path.len() {
-            return false;
-        }
-
-        let mut contains_method = false;
-        for (_, method) in self_method.iter().enumerate() {
-            if other_method.contains(method) {
-                contains_method = true;
-            }
-        }
-
-        if !contains_method {
-            return false;
-        }
-
-        for (i, path) in self_path.iter().enumerate() {
-            if &other_path[i] != path && !other_path[i].starts_with(':') {
-                return false;
-            }
-        }
-        true
-    }
-}
+/// with any valid session token, while
+///
+/// `AuthEndpoint`(vec![`Method::Delete`], "/users/:id",
+///                vec![`SCOPE_USERS_ADMIN`.to_owned()])
+///
+/// would only allow DELETE requests with a token granting the `users:admin`
+/// scope. Host applications can require their own permissions, like
+/// `lights:control`, which users get through the roles assigned to them.
+/// Besides being granted by the token, required scopes must still be held
+/// by the user when the request is made, so taking a role away from a user
+/// takes effect immediately.
+///
+/// A trailing `*` makes the endpoint match any path starting with the
+/// previous segments, so
+///
+/// `AuthEndpoint`(vec![`Method::Get`], "/services/*", vec![])
+///
+/// would match GET requests to /services and to any path below it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthEndpoint(pub Vec<Method>, pub String, pub Vec<String>);
 
 struct AuthHandler<H: Handler> {
     handler: H,
-    auth_db_file: String,
-    auth_endpoints: Arc<RwLock<Vec<AuthEndpoint>>>
+    cache: AuthCache,
+    auth_endpoints: Arc<RwLock<RouteMatcher>>
 }
 
 impl<H: Handler> Handler for AuthHandler<H> {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        {
-            let endpoint = AuthEndpoint(vec![req.method.clone()],
-                                        req.url.path.join("/"));
+        // Let the handlers share the cache, so what they change is seen
+        // by the next requests.
+        req.extensions.insert::<AuthCache>(self.cache.clone());
 
-            let guard = self.auth_endpoints.read().unwrap();
+        // Gather the scopes required by all the matching endpoints.
+        let scopes = self.auth_endpoints.read().unwrap()
+            .find(&req.method, &req.url.path.join("/"));
 
-            // If this is not an authenticated endpoint, just proceed with the
-            // original request.
-            if !guard.contains(&endpoint) {
-                return self.handler.handle(req);
-            }
-        }
+        // If this is not an authenticated endpoint, just proceed with the
+        // original request.
+        let scopes = match scopes {
+            Some(scopes) => scopes,
+            None => return self.handler.handle(req)
+        };
 
         // Otherwise, we need to verify the authorization token that can
-        // come within the Authorization header or as a query parameter.
-        match AuthMiddleware::get_session_token(req) {
-            Some(token) => {
-                if let Err(_) = AuthMiddleware::verify(&token,
-                                                       &self.auth_db_file) {
-                    return EndpointError::with(status::Unauthorized, 401, None)
+        // come within the Authorization header, as a query parameter or in
+        // the session cookie.
+        let caller = match AuthMiddleware::find_session_token(req) {
+            Some((token, from_cookie)) => {
+                // Browsers send cookies with requests started by other
+                // sites, so requests changing anything must prove they know
+                // the CSRF token, which other sites cannot read.
+                if from_cookie && is_state_changing(&req.method) &&
+                   !AuthMiddleware::has_csrf_token(req) {
+                    return EndpointError::with(status::Forbidden, 114,
+                        Some("Missing or invalid CSRF token".to_owned()));
+                }
+
+                match AuthMiddleware::verify_cached(&token, &self.cache) {
+                    Ok(session) => AuthenticatedUser {
+                        claims: session.claims,
+                        user: session.user,
+                        kind: session.kind
+                    },
+                    Err(error) => return from_token_error(error)
                 }
             },
-            None => return EndpointError::with(status::Unauthorized, 401, None)
+            None => return missing_token_error()
         };
 
+        if !caller.claims.has_scopes(&scopes) {
+            return insufficient_scope_error(&scopes);
+        }
+
+        // Roles may have changed since the token was issued, so check the
+        // user still holds the required permissions.
+        if !scopes.is_empty() {
+            let granted = self.cache.with_db(|db| {
+                SessionClaims::granted_scopes(&caller.user, db)
+            }).unwrap_or(vec![]);
+            if !scopes.iter().all(|scope| granted.contains(scope)) {
+                return insufficient_scope_error(&scopes);
+            }
+        }
+
+        match caller.kind {
+            TokenKind::Session { .. } => {
+                if let Err(error) = self.cache.touch_session(&caller.claims.sid,
+                                                             now()) {
+                    println!("Could not update session {}: {:?}",
+                             caller.claims.sid, error);
+                }
+            },
+            TokenKind::AccessToken { ref id, .. } => {
+                if let Err(error) = self.cache.with_db(|db| {
+                    db.touch_access_token(id, now())
+                }) {
+                    println!("Could not update access token {}: {:?}",
+                             id, error);
+                }
+            }
+        }
+
+        // Let the handlers know who is calling.
+        req.extensions.insert::<AuthenticatedUser>(caller);
+
         self.handler.handle(req)
     }
 }
@@ -174,11 +491,12 @@ impl<H: Handler> Handler for AuthHandler<H> {
 /// ```
 #[derive(Debug, Clone)]
 pub struct AuthMiddleware {
-    /// `Arc<RwLock<Vec<AuthEndpoint>>>` containing the set of endpoints to
-    /// be authenticated. This vector can be dynamically modified even after
-    /// it has been given to an Iron chain.
-    pub auth_endpoints: Arc<RwLock<Vec<AuthEndpoint>>>,
+    /// The set of endpoints to be authenticated, compiled for matching.
+    /// It can be dynamically modified even after the middleware has been
+    /// given to an Iron chain.
+    auth_endpoints: Arc<RwLock<RouteMatcher>>,
     pub auth_db_file: String,
+    cache: AuthCache
 }
 
 impl AroundMiddleware for AuthMiddleware {
@@ -186,87 +504,244 @@ impl AroundMiddleware for AuthMiddleware {
         Box::new(AuthHandler {
             handler: handler,
             auth_endpoints: self.auth_endpoints.clone(),
-            auth_db_file: self.auth_db_file.clone(),
+            cache: self.cache.clone()
         }) as Box<Handler>
     }
 }
 
 impl AuthMiddleware {
     pub fn new(auth_endpoints: Vec<AuthEndpoint>, auth_db_file: String) -> AuthMiddleware {
+        let cache = AuthCache::new(&auth_db_file);
+        AuthMiddleware::with_cache(auth_endpoints, cache)
+    }
+
+    /// A middleware sharing `cache` with the routers and connections of
+    /// its `UsersManager`.
+    pub fn with_cache(auth_endpoints: Vec<AuthEndpoint>, cache: AuthCache)
+        -> AuthMiddleware {
         AuthMiddleware{
-            auth_endpoints: Arc::new(RwLock::new(auth_endpoints)),
-            auth_db_file: auth_db_file
+            auth_endpoints: Arc::new(RwLock::new(
+                RouteMatcher::new(auth_endpoints)
+            )),
+            auth_db_file: cache.db_file().to_owned(),
+            cache: cache
         }
     }
 
     /// Allow the addition of authenticated endpoints.
     pub fn add_auth_endpoints(&mut self, endpoints: Vec<AuthEndpoint>) {
         let mut guard = self.auth_endpoints.write().unwrap();
-        for (_, endpoint) in endpoints.iter().enumerate() {
-            // If the endpoint is already stored as an authenticated endpoint
-            // we just bail out.
-            if guard.contains(endpoint) {
-                continue;
-            }
-            guard.push((*endpoint).clone());
+        for endpoint in endpoints {
+            // Endpoints already stored as authenticated are skipped.
+            guard.add(endpoint);
         }
     }
 
-    pub fn verify(token: &str, auth_db_file: &str) -> Result<(), ()> {
+    /// Allow the removal of authenticated endpoints. Only endpoints equal
+    /// to the given ones, including their methods and scopes, are removed.
+    pub fn remove_auth_endpoints(&mut self, endpoints: Vec<AuthEndpoint>) {
+        self.auth_endpoints.write().unwrap().remove(&endpoints);
+    }
+
+    /// Replace all the authenticated endpoints at once, so no request is
+    /// handled with only some of them.
+    pub fn replace_auth_endpoints(&mut self, endpoints: Vec<AuthEndpoint>) {
+        *self.auth_endpoints.write().unwrap() = RouteMatcher::new(endpoints);
+    }
+
+    /// The authenticated endpoints, in the order they were added.
+    pub fn auth_endpoints(&self) -> Vec<AuthEndpoint> {
+        self.auth_endpoints.read().unwrap().endpoints().to_vec()
+    }
+
+    /// Verify a session token, returning the verified session if the token
+    /// is valid, its user active and its session still open.
+    ///
+    /// This is what the middleware does on authenticated endpoints, and
+    /// can be used to authenticate non HTTP channels, like WebSockets.
+    /// It opens the database every time, `UsersManager::verify_token`
+    /// should be preferred to verify many tokens.
+    pub fn verify(token: &str, auth_db_file: &str)
+        -> Result<VerifiedSession, TokenError> {
+        AuthMiddleware::verify_cached(token, &AuthCache::new(auth_db_file))
+    }
+
+    /// Verify a session token reading the users, sessions, signing keys
+    /// and revoked tokens through `cache`.
+    pub fn verify_cached(token: &str, cache: &AuthCache)
+        -> Result<VerifiedSession, TokenError> {
+        if token.starts_with(ACCESS_TOKEN_PREFIX) {
+            return AuthMiddleware::verify_access_token(token, cache);
+        }
+
         let token = match SessionToken::from_string(token) {
             Ok(token) => token,
-            Err(_) => return Err(())
+            Err(_) => return Err(TokenError::Malformed)
         };
 
-        let id = token.claims.id.clone();
-
-        // To verify the token we need to get the secret associated to
-        // user id contained in the token claim.
-        let db = UsersDb::new(auth_db_file);
-        match db.read(ReadFilter::Id(id)) {
-            Ok(users) => {
-                if users.len() != 1 {
-                    return Err(());
-                }
-                if !token.verify(users[0].secret.to_owned().as_bytes(),
-                                 Sha256::new()) {
-                    return Err(());
-                }
+        // The token must be signed by a server key that is not retired yet.
+        let key = match token.header.kid {
+            Some(ref kid) => match cache.signing_key(kid) {
+                Ok(Some(key)) => key,
+                Ok(None) => return Err(TokenError::BadSignature),
+                Err(_) => return Err(TokenError::Internal)
             },
-            Err(_) => {
-                return Err(());
+            None => return Err(TokenError::Malformed)
+        };
+        // Do not let the token choose how it is verified, nor pass an ID
+        // token off as a session token.
+        if key.is_retired() || key.purpose != KeyPurpose::Session ||
+           token.header.alg != key.algorithm.as_str() {
+            return Err(TokenError::BadSignature);
+        }
+
+        // To verify `HS256` tokens we also need the secret associated to
+        // the user id contained in the token claim.
+        let user = match cache.user(&token.claims.id) {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(TokenError::UnknownUser),
+            Err(_) => return Err(TokenError::Internal)
+        };
+        let verified = match key.algorithm {
+            SigningAlgorithm::HS256 => token.verify(
+                &SessionToken::signing_secret(&user, &key)
+            ),
+            SigningAlgorithm::EdDSA => match key.ed25519_keypair() {
+                Some((_, public_key)) => token.verify(&public_key),
+                None => false
+            }
+        };
+        if !verified {
+            return Err(TokenError::BadSignature);
+        }
+
+        // Only check the validity period once we know the claims can be
+        // trusted.
+        let now = now();
+        if token.claims.exp <= now {
+            return Err(TokenError::Expired);
+        }
+        if token.claims.nbf > now {
+            return Err(TokenError::NotYetValid);
+        }
+
+        // Deactivated users cannot login, so they cannot keep using the
+        // tokens they got before either.
+        if !user.is_active {
+            return Err(TokenError::Inactive);
+        }
+
+        match cache.is_token_revoked(&token.claims.jti) {
+            Ok(false) => {},
+            Ok(true) => return Err(TokenError::Revoked),
+            Err(_) => return Err(TokenError::Internal)
+        };
+
+        match cache.session(&token.claims.sid) {
+            Ok(Some(ref session)) if session.user_id == token.claims.id => {},
+            Ok(_) => return Err(TokenError::UnknownSession),
+            Err(_) => return Err(TokenError::Internal)
+        };
+
+        Ok(VerifiedSession {
+            claims: token.claims,
+            user: user,
+            kind: TokenKind::Session {
+                kid: key.kid,
+                algorithm: key.algorithm
             }
+        })
+    }
+
+    /// Verify a personal access token. Access tokens are not bound to the
+    /// user secret nor to a session, so they survive password changes and
+    /// logouts until they expire or are revoked.
+    fn verify_access_token(token: &str, cache: &AuthCache)
+        -> Result<VerifiedSession, TokenError> {
+        let access_token = match cache.with_db(|db| db.read_access_token(token)) {
+            Ok(Some(access_token)) => access_token,
+            Ok(None) => return Err(TokenError::BadSignature),
+            Err(_) => return Err(TokenError::Internal)
         };
+        if let Some(expires_at) = access_token.expires_at {
+            if expires_at <= now() {
+                return Err(TokenError::Expired);
+            }
+        }
 
-        Ok(())
+        let user = match cache.user(&access_token.user_id) {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(TokenError::UnknownUser),
+            Err(_) => return Err(TokenError::Internal)
+        };
+        if !user.is_active {
+            return Err(TokenError::Inactive);
+        }
+
+        let AccessToken { id, name, scopes, created_at, expires_at, .. } =
+            access_token;
+        Ok(VerifiedSession {
+            claims: SessionClaims {
+                id: user.id.clone(),
+                email: user.email.clone(),
+                iat: created_at,
+                exp: expires_at.unwrap_or(i64::MAX),
+                nbf: created_at,
+                jti: id.clone(),
+                sid: String::new(),
+                scopes: scopes,
+                client_id: None
+            },
+            user: user,
+            kind: TokenKind::AccessToken {
+                id: id,
+                name: name
+            }
+        })
     }
 
-    /// Extract the session token string from the Authorization header or
-    /// the url query parameters.
+    /// Extract the session token string from the Authorization header,
+    /// the url query parameters or the session cookie.
     pub fn get_session_token(req: &mut Request) -> Option<String> {
-        match req.headers.clone()
-                 .get::<headers::Authorization<headers::Bearer>>() {
-            Some(&headers::Authorization(headers::Bearer { ref token })) => {
-                Some(token.clone())
-            },
-            _ => {
-                match req.get_ref::<UrlEncodedQuery>() {
-                    Ok(ref params) => {
-                        match params.get("auth") {
-                            Some(token) => {
-                                Some(token[0].clone())
-                            },
-                            _ => None
-                        }
-                    },
-                    _ => None
-                }
+        AuthMiddleware::find_session_token(req).map(|(token, _)| token)
+    }
+
+    /// Like `get_session_token`, also telling whether the token comes from
+    /// the session cookie.
+    fn find_session_token(req: &mut Request) -> Option<(String, bool)> {
+        if let Some(&headers::Authorization(headers::Bearer { ref token })) =
+            req.headers.get::<headers::Authorization<headers::Bearer>>() {
+            return Some((token.clone(), false));
+        }
+        if let Ok(ref params) = req.get_ref::<UrlEncodedQuery>() {
+            if let Some(token) = params.get("auth") {
+                return Some((token[0].clone(), false));
             }
         }
+        get_cookie(req, SESSION_COOKIE).map(|token| (token, true))
+    }
+
+    /// Whether the request carries the CSRF token of its cookie session
+    /// both in the CSRF cookie and in the CSRF header.
+    pub fn has_csrf_token(req: &Request) -> bool {
+        let cookie = match get_cookie(req, CSRF_COOKIE) {
+            Some(ref cookie) if !cookie.is_empty() => cookie.clone(),
+            _ => return false
+        };
+        match req.headers.get_raw(CSRF_HEADER) {
+            Some(lines) if lines.len() == 1 => {
+                fixed_time_eq(cookie.as_bytes(), &lines[0])
+            },
+            _ => false
+        }
     }
 
     /// Extract the user id from the Authorization header or the url query
     /// parametes.
+    ///
+    /// The token signature is not verified. Handlers behind authenticated
+    /// endpoints should rely on the `AuthenticatedUser` request extension
+    /// instead.
     pub fn get_user_id(req: &mut Request) -> Option<String> {
         match AuthMiddleware::get_session_token(req) {
             Some(token) => {
@@ -297,28 +772,90 @@ describe! auth_middleware_tests {
         fn not_implemented(_: &mut Request) -> IronResult<Response> {
             Ok(Response::with(Status::NotImplemented))
         }
+        fn whoami(req: &mut Request) -> IronResult<Response> {
+            match req.extensions.get::<AuthenticatedUser>() {
+                Some(caller) => Ok(Response::with(
+                    (Status::Ok, format!("{} {}", caller.user.email,
+                                         caller.claims.sid))
+                )),
+                None => Ok(Response::with(Status::InternalServerError))
+            }
+        }
 
         let mut router = Router::new();
         router.get("/authenticated", not_implemented);
         router.get("/authenticated/:foo/bar/:baz", not_implemented);
         router.delete("/authenticated", not_implemented);
         router.get("/not_authenticated", not_implemented);
+        router.get("/scoped", not_implemented);
+        router.get("/lights", not_implemented);
+        router.get("/whoami", whoami);
 
         let mut chain = Chain::new(router);
         let mut middleware = manager.get_middleware(
             vec![AuthEndpoint(vec![Method::Get, Method::Delete],
-                              "/authenticated".to_owned())]
+                              "/authenticated".to_owned(), vec![])]
         );
 
         chain.around(middleware.clone());
 
         middleware.add_auth_endpoints(vec![
              AuthEndpoint(vec![Method::Get],
-                          "/authenticated/:foo/bar/:baz".to_owned())
+                          "/authenticated/:foo/bar/:baz".to_owned(), vec![]),
+             AuthEndpoint(vec![Method::Get], "/scoped".to_owned(),
+                          vec![SCOPE_USERS_ADMIN.to_owned()]),
+             AuthEndpoint(vec![Method::Get], "/lights".to_owned(),
+                          vec!["lights:control".to_owned()]),
+             AuthEndpoint(vec![Method::Get], "/whoami".to_owned(), vec![])
         ]);
     }
 
     describe! not_authenticated_requests {
+        it "should list the authenticated endpoints" {
+            let endpoints = middleware.auth_endpoints();
+            assert_eq!(endpoints.len(), 5);
+            assert_eq!(endpoints[0],
+                       AuthEndpoint(vec![Method::Get, Method::Delete],
+                                    "/authenticated".to_owned(), vec![]));
+        }
+
+        it "should stop authenticating removed endpoints" {
+            middleware.remove_auth_endpoints(vec![
+                AuthEndpoint(vec![Method::Get, Method::Delete],
+                             "/authenticated".to_owned(), vec![])
+            ]);
+            assert_eq!(middleware.auth_endpoints().len(), 4);
+            match request::get("http://localhost:3000/authenticated",
+                               Headers::new(), &chain) {
+                Ok(res) => {
+                    assert_eq!(res.status.unwrap(), Status::NotImplemented)
+                },
+                Err(_) => assert!(false)
+            };
+        }
+
+        it "should authenticate replaced endpoints only" {
+            middleware.replace_auth_endpoints(vec![
+                AuthEndpoint(vec![Method::Get], "/not_authenticated".to_owned(),
+                             vec![])
+            ]);
+            assert_eq!(middleware.auth_endpoints().len(), 1);
+            match request::get("http://localhost:3000/authenticated",
+                               Headers::new(), &chain) {
+                Ok(res) => {
+                    assert_eq!(res.status.unwrap(), Status::NotImplemented)
+                },
+                Err(_) => assert!(false)
+            };
+            match request::get("http://localhost:3000/not_authenticated",
+                               Headers::new(), &chain) {
+                Ok(_) => assert!(false),
+                Err(err) => {
+                    assert_eq!(err.response.status.unwrap(), Status::Unauthorized)
+                }
+            };
+        }
+
         it "should allow request to not authenticated endpoint" {
             match request::get("http://localhost:3000/not_authenticated",
                                Headers::new(), &chain) {
@@ -352,13 +889,59 @@ describe! auth_middleware_tests {
                 }
             };
         }
+
+        it "should challenge requests lacking a token with errno 115" {
+            use errors::ErrorBody;
+            use iron_test::response::extract_body_to_string;
+            use rustc_serialize::json;
+
+            match request::get("http://localhost:3000/authenticated",
+                               Headers::new(), &chain) {
+                Ok(_) => assert!(false),
+                Err(err) => {
+                    let response = err.response;
+                    let challenge = response.headers
+                        .get_raw("WWW-Authenticate").unwrap()[0].clone();
+                    assert_eq!(String::from_utf8(challenge).unwrap(),
+                               "Bearer realm=\"foxbox_users\"");
+                    let body = extract_body_to_string(response);
+                    let json: ErrorBody = json::decode(&body).unwrap();
+                    assert_eq!(json.errno, 115);
+                }
+            };
+        }
+
+        it "should challenge requests with an invalid token with errno 116" {
+            use errors::ErrorBody;
+            use iron::headers::{ Authorization, Bearer };
+            use iron_test::response::extract_body_to_string;
+            use rustc_serialize::json;
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer { token: "foo.bar.baz".to_owned() }));
+            match request::get("http://localhost:3000/authenticated",
+                               headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(err) => {
+                    let response = err.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let challenge = response.headers
+                        .get_raw("WWW-Authenticate").unwrap()[0].clone();
+                    assert_eq!(String::from_utf8(challenge).unwrap(),
+                               "Bearer realm=\"foxbox_users\", \
+                                error=\"invalid_token\", \
+                                error_description=\"Invalid token\"");
+                    let body = extract_body_to_string(response);
+                    let json: ErrorBody = json::decode(&body).unwrap();
+                    assert_eq!(json.errno, 116);
+                }
+            };
+        }
     }
 
     describe! authenticated_requests {
         before_each {
-            use crypto::sha2::Sha256;
-            use jwt;
-            use users_db::remove_test_db;
+            use users_db::{ remove_test_db, Session };
             use UserBuilder;
 
             let db = manager.get_db();
@@ -368,19 +951,16 @@ describe! auth_middleware_tests {
                 .password(String::from("password"))
                 .email(String::from("username@example.com"))
                 .secret(String::from("secret"))
+                .active(true)
                 .finalize().unwrap();
             db.create(&user).ok();
 
-            let jwt_header: jwt::Header = Default::default();
-            let claims = SessionClaims {
-                id: user.id.to_owned(),
-                email: user.email.to_owned()
-            };
-            let token = jwt::Token::new(jwt_header, claims);
-            let signed = token.signed(
-                user.secret.to_owned().as_bytes(),
-                Sha256::new()
-            ).ok().unwrap();
+            let session = Session::new(&user.id, "", "");
+            db.create_session(&session).unwrap();
+            let key = db.current_signing_key().unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let signed = SessionToken::from_claims(&user, claims, &key).unwrap();
         }
 
         after_each {
@@ -394,6 +974,24 @@ describe! auth_middleware_tests {
                 headers.set(Authorization(Bearer { token: signed.to_owned() }));
             }
 
+            it "should reject request lacking the endpoint scopes with 403" {
+                match request::get("http://localhost:3000/scoped",
+                                   headers.clone(), &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        let response = err.response;
+                        assert_eq!(response.status.unwrap(), Status::Forbidden);
+                        let challenge = response.headers
+                            .get_raw("WWW-Authenticate").unwrap()[0].clone();
+                        assert_eq!(String::from_utf8(challenge).unwrap(),
+                                   "Bearer realm=\"foxbox_users\", \
+                                    error=\"insufficient_scope\", \
+                                    error_description=\"Insufficient scope\", \
+                                    scope=\"users:admin\"");
+                    }
+                };
+            }
+
             it "should allow request to authenticated endpoint" {
                 match request::get("http://localhost:3000/authenticated",
                                    headers.clone(), &chain) {
@@ -417,6 +1015,417 @@ describe! auth_middleware_tests {
                     Err(_) => assert!(false)
                 };
             }
+
+            it "should let handlers know the authenticated user" {
+                use iron_test::response::extract_body_to_string;
+
+                match request::get("http://localhost:3000/whoami",
+                                   headers, &chain) {
+                    Ok(res) => {
+                        assert_eq!(res.status.unwrap(), Status::Ok);
+                        assert_eq!(extract_body_to_string(res),
+                                   format!("username@example.com {}",
+                                           session.id));
+                    },
+                    Err(_) => assert!(false)
+                };
+            }
+        }
+
+        describe! verify {
+            it "should return the verified session" {
+                let verified = AuthMiddleware::verify(&signed,
+                                                      &get_db_environment())
+                    .unwrap();
+                assert_eq!(verified.claims.sid, session.id);
+                assert_eq!(verified.user, user);
+                assert_eq!(verified.kind, TokenKind::Session {
+                    kid: key.kid.clone(),
+                    algorithm: key.algorithm
+                });
+            }
+
+            it "should tell malformed tokens and bad signatures apart" {
+                let db_file = get_db_environment();
+                assert_eq!(AuthMiddleware::verify("foo", &db_file).unwrap_err(),
+                           TokenError::Malformed);
+
+                let parts: Vec<&str> = signed.split('.').collect();
+                let forged = format!("{}.{}.{}{}", parts[0], parts[1],
+                    if parts[2].starts_with('A') { 'B' } else { 'A' },
+                    &parts[2][1..]);
+                assert_eq!(AuthMiddleware::verify(&forged, &db_file)
+                               .unwrap_err(),
+                           TokenError::BadSignature);
+            }
+
+            it "should keep users cached until they change" {
+                use rusqlite::Connection;
+
+                let db_file = get_db_environment();
+                assert!(manager.verify_token(&signed).is_ok());
+
+                // Changes that do not go through the manager go unnoticed...
+                Connection::open(&db_file).unwrap()
+                    .execute("UPDATE users SET is_active=0 WHERE id=$1",
+                             &[&user.id]).unwrap();
+                assert!(manager.verify_token(&signed).is_ok());
+
+                // ...but the ones that do are seen right away.
+                let mut inactive = user.clone();
+                inactive.is_active = false;
+                manager.get_db().update(&inactive).unwrap();
+                assert_eq!(manager.verify_token(&signed).unwrap_err(),
+                           TokenError::Inactive);
+            }
+
+            it "should see closed sessions and revoked tokens right away" {
+                let token = SessionToken::from_string(&signed).unwrap();
+                assert!(manager.verify_token(&signed).is_ok());
+                manager.get_db().revoke_token(&token.claims.jti,
+                                              token.claims.exp).unwrap();
+                assert_eq!(manager.verify_token(&signed).unwrap_err(),
+                           TokenError::Revoked);
+
+                let db = manager.get_db();
+                let other = Session::new(&user.id, "", "");
+                db.create_session(&other).unwrap();
+                let other_signed = SessionToken::from_user(&user, &other.id,
+                                                           &key).unwrap();
+                assert!(manager.verify_token(&other_signed).is_ok());
+                db.delete_session(&other.id).unwrap();
+                assert_eq!(manager.verify_token(&other_signed).unwrap_err(),
+                           TokenError::UnknownSession);
+            }
+
+            bench "verify with the cache" (bencher) {
+                bencher.iter(|| manager.verify_token(&signed));
+            }
+
+            bench "verify opening the database" (bencher) {
+                let db_file = get_db_environment();
+                bencher.iter(|| AuthMiddleware::verify(&signed, &db_file));
+            }
+
+            it "should reject tokens of deleted users" {
+                db.delete(&user.id).unwrap();
+                assert_eq!(AuthMiddleware::verify(&signed,
+                                                  &get_db_environment())
+                               .unwrap_err(),
+                           TokenError::UnknownUser);
+            }
+
+            it "should reject tokens of deactivated users with errno 113" {
+                use iron::headers::{ Authorization, Bearer };
+                use errors::ErrorBody;
+                use iron_test::response::extract_body_to_string;
+                use rustc_serialize::json;
+
+                let mut inactive = user.clone();
+                inactive.is_active = false;
+                db.update(&inactive).unwrap();
+                assert_eq!(AuthMiddleware::verify(&signed,
+                                                  &get_db_environment())
+                               .unwrap_err(),
+                           TokenError::Inactive);
+
+                let mut headers = Headers::new();
+                headers.set(Authorization(Bearer { token: signed.to_owned() }));
+                match request::get("http://localhost:3000/authenticated",
+                                   headers, &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        assert_eq!(err.response.status.unwrap(),
+                                   Status::Unauthorized);
+                        let body: ErrorBody = json::decode(
+                            &extract_body_to_string(err.response)
+                        ).unwrap();
+                        assert_eq!(body.errno, 113);
+                    }
+                };
+            }
+        }
+
+        describe! with_role_permissions {
+            before_each {
+                use iron::headers::{ Authorization, Bearer };
+                use users_db::Role;
+
+                db.create_role(&Role {
+                    name: "household".to_owned(),
+                    permissions: vec!["lights:control".to_owned()]
+                }).unwrap();
+                db.add_user_role(&user.id, "household").unwrap();
+
+                let claims = SessionClaims {
+                    scopes: SessionClaims::granted_scopes(&user, &db).unwrap(),
+                    .. SessionClaims::new(&user, &session.id,
+                                          SessionToken::DEFAULT_TTL)
+                };
+                let signed = SessionToken::from_claims(&user, claims, &key)
+                                 .unwrap();
+                let mut headers = Headers::new();
+                headers.set(Authorization(Bearer { token: signed }));
+            }
+
+            it "should allow request requiring a permission of the user role" {
+                match request::get("http://localhost:3000/lights",
+                                   headers, &chain) {
+                    Ok(res) => {
+                        assert_eq!(res.status.unwrap(), Status::NotImplemented)
+                    },
+                    Err(_) => assert!(false)
+                };
+            }
+
+            it "should reject request once the role is taken away" {
+                db.remove_user_role(&user.id, "household").unwrap();
+                match request::get("http://localhost:3000/lights",
+                                   headers, &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        assert_eq!(err.response.status.unwrap(), Status::Forbidden)
+                    }
+                };
+            }
+        }
+
+        describe! with_rotated_keys {
+            before_each {
+                use iron::headers::{ Authorization, Bearer };
+
+                let mut headers = Headers::new();
+                headers.set(Authorization(Bearer { token: signed.to_owned() }));
+            }
+
+            it "should accept tokens of a rotated key during the grace period" {
+                db.rotate_signing_keys(60, SigningAlgorithm::HS256).unwrap();
+                match request::get("http://localhost:3000/authenticated",
+                                   headers, &chain) {
+                    Ok(res) => {
+                        assert_eq!(res.status.unwrap(), Status::NotImplemented)
+                    },
+                    Err(_) => assert!(false)
+                };
+            }
+
+            it "should accept EdDSA signed tokens" {
+                let key = db.rotate_signing_keys(60, SigningAlgorithm::EdDSA)
+                            .unwrap();
+                let claims = SessionClaims::new(&user, &session.id,
+                                                SessionToken::DEFAULT_TTL);
+                let signed = SessionToken::from_claims(&user, claims, &key)
+                                 .unwrap();
+                let mut headers = Headers::new();
+                headers.set(Authorization(Bearer { token: signed }));
+                match request::get("http://localhost:3000/authenticated",
+                                   headers, &chain) {
+                    Ok(res) => {
+                        assert_eq!(res.status.unwrap(), Status::NotImplemented)
+                    },
+                    Err(_) => assert!(false)
+                };
+            }
+
+            it "should reject tokens signed with an ID token key" {
+                let key = db.current_id_token_key().unwrap();
+                let claims = SessionClaims::new(&user, &session.id,
+                                                SessionToken::DEFAULT_TTL);
+                let signed = SessionToken::from_claims(&user, claims, &key)
+                                 .unwrap();
+                let mut headers = Headers::new();
+                headers.set(Authorization(Bearer { token: signed }));
+                match request::get("http://localhost:3000/authenticated",
+                                   headers, &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        assert_eq!(err.response.status.unwrap(), Status::Unauthorized)
+                    }
+                };
+            }
+
+            it "should reject tokens of a retired key" {
+                db.rotate_signing_keys(60, SigningAlgorithm::HS256).unwrap();
+                db.retire_signing_key(&key.kid, 0).unwrap();
+                match request::get("http://localhost:3000/authenticated",
+                                   headers, &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        assert_eq!(err.response.status.unwrap(), Status::Unauthorized)
+                    }
+                };
+            }
+
+            it "should reject tokens without kid header" {
+                use crypto::sha2::Sha256;
+                use jwt;
+
+                let claims = SessionClaims::new(&user, &session.id,
+                                                SessionToken::DEFAULT_TTL);
+                let token = jwt::Token::new(Default::default(), claims);
+                let signed = token.signed(
+                    user.secret.to_owned().as_bytes(),
+                    Sha256::new()
+                ).ok().unwrap();
+                let mut headers = Headers::new();
+                headers.set(Authorization(Bearer { token: signed }));
+                match request::get("http://localhost:3000/authenticated",
+                                   headers, &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        assert_eq!(err.response.status.unwrap(), Status::Unauthorized)
+                    }
+                };
+            }
+        }
+
+        describe! with_access_token {
+            before_each {
+                use iron::headers::{ Authorization, Bearer };
+                use users_db::AccessToken;
+
+                let access_token = AccessToken::new(&user.id, "script",
+                                                    vec![], None);
+                let token = format!("{}secret", ACCESS_TOKEN_PREFIX);
+                db.create_access_token(&token, &access_token).unwrap();
+
+                let mut headers = Headers::new();
+                headers.set(Authorization(Bearer { token: token.clone() }));
+            }
+
+            it "should accept access tokens as Bearer tokens" {
+                match request::get("http://localhost:3000/authenticated",
+                                   headers.clone(), &chain) {
+                    Ok(res) => {
+                        assert_eq!(res.status.unwrap(), Status::NotImplemented)
+                    },
+                    Err(_) => assert!(false)
+                };
+                let tokens = db.read_access_tokens(&user.id).unwrap();
+                assert!(tokens[0].last_used.is_some());
+
+                let verified = AuthMiddleware::verify(&token,
+                                                      &get_db_environment())
+                    .unwrap();
+                assert_eq!(verified.user.id, user.id);
+                assert_eq!(verified.kind, TokenKind::AccessToken {
+                    id: access_token.id.clone(),
+                    name: "script".to_owned()
+                });
+            }
+
+            it "should keep access tokens valid when the user secret changes" {
+                let mut updated = user.clone();
+                updated.secret = "new secret".to_owned();
+                db.update(&updated).unwrap();
+                assert!(AuthMiddleware::verify(&token, &get_db_environment())
+                            .is_ok());
+            }
+
+            it "should only grant the scopes of the access token" {
+                match request::get("http://localhost:3000/scoped",
+                                   headers, &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        assert_eq!(err.response.status.unwrap(), Status::Forbidden)
+                    }
+                };
+            }
+
+            it "should reject expired and revoked access tokens" {
+                let expired = AccessToken::new(&user.id, "expired", vec![],
+                                               Some(now() - 1));
+                let expired_token = format!("{}expired", ACCESS_TOKEN_PREFIX);
+                db.create_access_token(&expired_token, &expired).unwrap();
+                assert_eq!(AuthMiddleware::verify(&expired_token,
+                                                  &get_db_environment())
+                               .unwrap_err(),
+                           TokenError::Expired);
+
+                db.delete_access_token(&user.id, &access_token.id).unwrap();
+                assert_eq!(AuthMiddleware::verify(&token,
+                                                  &get_db_environment())
+                               .unwrap_err(),
+                           TokenError::BadSignature);
+            }
+        }
+
+        describe! with_closed_session {
+            before_each {
+                use errors::ErrorBody;
+                use iron::headers::{ Authorization, Bearer };
+                use iron_test::response::extract_body_to_string;
+                use rustc_serialize::json;
+
+                let mut headers = Headers::new();
+                headers.set(Authorization(Bearer { token: signed.to_owned() }));
+                db.delete_session(&session.id).unwrap();
+            }
+
+            it "should reject the token with errno 109" {
+                match request::get("http://localhost:3000/authenticated",
+                                   headers, &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        let response = err.response;
+                        assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                        let body = extract_body_to_string(response);
+                        let json: ErrorBody = json::decode(&body).unwrap();
+                        assert_eq!(json.errno, 109);
+                    }
+                };
+            }
+        }
+
+        describe! with_expired_or_early_token {
+            before_each {
+                use errors::ErrorBody;
+                use iron::headers::{ Authorization, Bearer };
+                use iron_test::response::extract_body_to_string;
+                use rustc_serialize::json;
+
+                let sign = |claims: SessionClaims| -> Headers {
+                    let signed = SessionToken::from_claims(&user, claims, &key)
+                                     .unwrap();
+                    let mut headers = Headers::new();
+                    headers.set(Authorization(Bearer { token: signed }));
+                    headers
+                };
+            }
+
+            it "should reject an expired token with errno 105" {
+                let mut claims = SessionClaims::new(&user, &session.id, 60);
+                claims.iat -= 120;
+                claims.nbf -= 120;
+                claims.exp -= 120;
+                match request::get("http://localhost:3000/authenticated",
+                                   sign(claims), &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        let response = err.response;
+                        assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                        let body = extract_body_to_string(response);
+                        let json: ErrorBody = json::decode(&body).unwrap();
+                        assert_eq!(json.errno, 105);
+                    }
+                };
+            }
+
+            it "should reject a token used before its nbf claim with errno 106" {
+                let mut claims = SessionClaims::new(&user, &session.id, 60);
+                claims.nbf += 30;
+                match request::get("http://localhost:3000/authenticated",
+                                   sign(claims), &chain) {
+                    Ok(_) => assert!(false),
+                    Err(err) => {
+                        let response = err.response;
+                        assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                        let body = extract_body_to_string(response);
+                        let json: ErrorBody = json::decode(&body).unwrap();
+                        assert_eq!(json.errno, 106);
+                    }
+                };
+            }
         }
 
         describe! with_auth_query_parameter {
@@ -2,7 +2,9 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use super::auth_middleware::TokenError;
 use super::users_db::UserBuilderError;
+use super::webauthn::WebAuthnError;
 
 use iron::status;
 use iron::prelude::*;
@@ -35,11 +37,74 @@ pub struct ErrorBody {
     pub message: Option<String>
 }
 
+/// Realm of the `WWW-Authenticate` challenges sent to clients failing to
+/// authenticate.
+pub static BEARER_REALM: &'static str = "foxbox_users";
+
+/// Reason for a bearer token challenge, as defined by RFC 6750.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BearerError {
+    /// The token is malformed, expired, revoked or not valid for any other
+    /// reason.
+    InvalidToken,
+    /// The token does not grant the given scopes.
+    InsufficientScope(Vec<String>)
+}
+
+/// Build a `WWW-Authenticate` challenge. Requests lacking a token get a
+/// challenge without error code, as recommended by the RFC.
+fn bearer_challenge(error: Option<&BearerError>, description: Option<&String>)
+    -> String {
+    let mut challenge = format!("Bearer realm=\"{}\"", BEARER_REALM);
+    let error = match error {
+        Some(error) => error,
+        None => return challenge
+    };
+    match *error {
+        BearerError::InvalidToken => {
+            challenge.push_str(", error=\"invalid_token\"");
+        },
+        BearerError::InsufficientScope(_) => {
+            challenge.push_str(", error=\"insufficient_scope\"");
+        }
+    }
+    if let Some(description) = description {
+        // Quotes and backslashes are not allowed in error descriptions.
+        let description: String = description.chars()
+            .filter(|c| *c >= ' ' && *c <= '~' && *c != '"' && *c != '\\')
+            .collect();
+        challenge.push_str(&format!(", error_description=\"{}\"",
+                                    description));
+    }
+    if let BearerError::InsufficientScope(ref scopes) = *error {
+        challenge.push_str(&format!(", scope=\"{}\"", scopes.join(" ")));
+    }
+    challenge
+}
+
 pub struct EndpointError;
 
 impl EndpointError {
     pub fn with(status: status::Status, errno: u16, message: Option<String>)
         -> IronResult<Response> {
+        Err(EndpointError::error(status, errno, message))
+    }
+
+    /// Like `with`, also challenging the client to authenticate with a
+    /// bearer token through a `WWW-Authenticate` header.
+    pub fn with_challenge(status: status::Status, errno: u16,
+                          message: Option<String>,
+                          error: Option<BearerError>)
+        -> IronResult<Response> {
+        let challenge = bearer_challenge(error.as_ref(), message.as_ref());
+        let mut error = EndpointError::error(status, errno, message);
+        error.response.headers.set_raw("WWW-Authenticate",
+                                       vec![challenge.into_bytes()]);
+        Err(error)
+    }
+
+    fn error(status: status::Status, errno: u16, message: Option<String>)
+        -> IronError {
         let error = status.canonical_reason().unwrap().to_owned();
         let body = ErrorBody {
             code: status.to_u16(),
@@ -48,13 +113,33 @@ impl EndpointError {
             message: message
         };
 
-        Err(
-            IronError::new(StringError(error),
-            (status, json::encode(&body).unwrap()))
-        )
+        IronError::new(StringError(error),
+                       (status, json::encode(&body).unwrap()))
     }
 }
 
+/// Error body of the OAuth 2.0 token endpoint, as defined by RFC 6749,
+/// which OAuth client libraries expect instead of `ErrorBody`.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct OAuthErrorBody {
+    pub error: String,
+    pub error_description: Option<String>
+}
+
+/// Error of the OAuth 2.0 token endpoint, like `invalid_grant`.
+pub fn oauth_error(status: status::Status, error: &str, description: &str)
+    -> IronResult<Response> {
+    let body = OAuthErrorBody {
+        error: error.to_owned(),
+        error_description: Some(description.to_owned())
+    };
+    let mut error = IronError::new(StringError(error.to_owned()),
+                                   (status, json::encode(&body).unwrap()));
+    error.response.headers.set_raw("Cache-Control",
+                                   vec![b"no-store".to_vec()]);
+    Err(error)
+}
+
 pub fn from_decoder_error(error: json::DecoderError) -> IronResult<Response> {
     match error {
         json::DecoderError::MissingFieldError(field) => {
@@ -80,6 +165,12 @@ pub fn from_sqlite_error(error: rusqlite_error) -> IronResult<Response> {
                 "UNIQUE constraint failed: users.username" =>
                     EndpointError::with(status::Conflict, 409,
                                         Some("User already exists".to_owned())),
+                "UNIQUE constraint failed: roles.name" =>
+                    EndpointError::with(status::Conflict, 409,
+                                        Some("Role already exists".to_owned())),
+                "UNIQUE constraint failed: webauthn_credentials.id" =>
+                    EndpointError::with(status::Conflict, 409,
+                        Some("Credential already registered".to_owned())),
                 _ => EndpointError::with(
                     status::InternalServerError, 501,
                     Some("Database error".to_owned())
@@ -112,3 +203,64 @@ pub fn from_user_builder_error(error: UserBuilderError)
     };
     EndpointError::with(status::BadRequest, errno, message)
 }
+
+pub fn from_token_error(error: TokenError) -> IronResult<Response> {
+    let (errno, message) = match error {
+        TokenError::Malformed |
+        TokenError::BadSignature |
+        TokenError::UnknownUser => {
+            (116, Some("Invalid token".to_owned()))
+        },
+        TokenError::Inactive => {
+            (113, Some("Inactive user".to_owned()))
+        },
+        TokenError::Expired => {
+            (105, Some("Expired token".to_owned()))
+        },
+        TokenError::NotYetValid => {
+            (106, Some("Token not valid yet".to_owned()))
+        },
+        TokenError::Revoked => {
+            (108, Some("Revoked token".to_owned()))
+        },
+        TokenError::UnknownSession => {
+            (109, Some("Session closed".to_owned()))
+        },
+        TokenError::Internal => {
+            return EndpointError::with(status::InternalServerError, 501,
+                                       None);
+        }
+    };
+    EndpointError::with_challenge(status::Unauthorized, errno, message,
+                                  Some(BearerError::InvalidToken))
+}
+
+pub fn from_webauthn_error(error: WebAuthnError) -> IronResult<Response> {
+    let message = match error {
+        WebAuthnError::Malformed => "Malformed WebAuthn response",
+        WebAuthnError::InvalidClientData =>
+            "Invalid type, challenge or origin in the client data",
+        WebAuthnError::InvalidRpId => "Invalid relying party",
+        WebAuthnError::UserNotPresent => "The user is not present",
+        WebAuthnError::UserNotVerified => "The user is not verified",
+        WebAuthnError::UnsupportedAlgorithm =>
+            "Only Ed25519 credentials are supported",
+        WebAuthnError::InvalidSignature => "Invalid signature"
+    };
+    EndpointError::with(status::BadRequest, 122, Some(message.to_owned()))
+}
+
+/// Error for requests to authenticated endpoints lacking a token.
+pub fn missing_token_error() -> IronResult<Response> {
+    EndpointError::with_challenge(status::Unauthorized, 115,
+                                  Some("Missing token".to_owned()), None)
+}
+
+/// Error for tokens not granting the scopes required by an endpoint. A
+/// valid token without the required scopes is not a matter of
+/// authentication, so the status is 403 instead of 401.
+pub fn insufficient_scope_error(scopes: &[String]) -> IronResult<Response> {
+    EndpointError::with_challenge(status::Forbidden, 110,
+        Some("Insufficient scope".to_owned()),
+        Some(BearerError::InsufficientScope(scopes.to_vec())))
+}
@@ -40,7 +40,23 @@ impl InvitationMiddleware {
         self.invitation_url_prepath = Some(invitation_url_prepath.to_owned());
     }
 
+    /// Whether the email server and the URL prepath are set up.
+    pub fn is_setup(&self) -> bool {
+        self.email_server.is_some() && self.invitation_url_prepath.is_some()
+    }
+
     pub fn send(&self, user_email: &str, activation_url: &str) {
+        self.post("/v1/invitation", user_email, activation_url);
+    }
+
+    /// Send the single-use link to reset the password of the user to
+    /// `user_email`. `reset_url` is appended to the URL prepath, as
+    /// activation URLs are.
+    pub fn send_password_reset(&self, user_email: &str, reset_url: &str) {
+        self.post("/v1/password_reset", user_email, reset_url);
+    }
+
+    fn post(&self, path: &str, user_email: &str, url: &str) {
         let invitation_url_prepath = match self.invitation_url_prepath {
             Some(ref prepath) => prepath,
             None => {
@@ -59,17 +75,17 @@ impl InvitationMiddleware {
 
         let body = match json::encode(&InvitationRequest {
             email: user_email.to_owned(),
-            url: format!("{}{}", invitation_url_prepath, activation_url)
+            url: format!("{}{}", invitation_url_prepath, url)
         }) {
             Ok(body) => body,
             Err(_) => {
-                println!("Could not send invitation email.");
+                println!("Could not send email.");
                 return;
             }
         };
 
         let client = Client::new();
-        let endpoint = format!("{}/v1/invitation", email_server);
+        let endpoint = format!("{}{}", email_server, path);
         let mut headers = Headers::new();
         headers.set(Connection::close());
         let res = client.post(&endpoint)
@@ -78,7 +94,7 @@ impl InvitationMiddleware {
               .send();
 
         if let Err(_) = res {
-            println!("Unable to send invitation to {}", user_email);
+            println!("Unable to send email to {}", user_email);
         }
     }
 }
@@ -86,8 +102,14 @@ impl InvitationMiddleware {
 impl AfterMiddleware for InvitationMiddleware {
     fn after(&self, req: &mut Request, mut res: Response)
         -> IronResult<Response> {
-        if req.method != Method::Post ||
-           req.url.path != vec![self.version.clone(), "users".to_owned()] {
+        // Invitations are sent on user creation (POST /users) and when
+        // an admin asks to resend them (POST /users/:id/invitation).
+        let path = &req.url.path;
+        let is_invitation = path.len() >= 2 &&
+            path[0] == self.version && path[1] == "users" &&
+            (path.len() == 2 ||
+             (path.len() == 4 && path[3] == "invitation"));
+        if req.method != Method::Post || !is_invitation {
             return Ok(res);
         }
 
@@ -0,0 +1,166 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Compact [JWS](https://tools.ietf.org/html/rfc7515) serialization of
+//! session tokens.
+//!
+//! Tokens can be signed with HMAC SHA-256 (`HS256`) or with Ed25519
+//! (`EdDSA`, as defined by [RFC 8037](https://tools.ietf.org/html/rfc8037)).
+//! `EdDSA` tokens can be verified by anyone knowing the public key of the
+//! signing key, which is published as a JWK. `RS256` and `ES256` are not
+//! supported.
+
+use crypto::ed25519;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rustc_serialize::{ Decodable, Encodable };
+use rustc_serialize::base64::{ FromBase64, ToBase64, URL_SAFE };
+use rustc_serialize::json;
+
+pub static ALG_HS256: &'static str = "HS256";
+pub static ALG_EDDSA: &'static str = "EdDSA";
+
+/// JOSE header of a token.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct JoseHeader {
+    pub typ: Option<String>,
+    pub kid: Option<String>,
+    pub alg: String
+}
+
+impl JoseHeader {
+    pub fn new(alg: &str, kid: &str) -> JoseHeader {
+        JoseHeader {
+            typ: Some("JWT".to_owned()),
+            kid: Some(kid.to_owned()),
+            alg: alg.to_owned()
+        }
+    }
+}
+
+/// A decoded token whose signature is not verified yet.
+#[derive(Debug)]
+pub struct Jws<C> {
+    pub header: JoseHeader,
+    pub claims: C,
+    signing_input: String,
+    signature: Vec<u8>
+}
+
+fn hs256(data: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result().code().to_vec()
+}
+
+/// Serialize and sign `claims`. `key` is the HMAC key for `HS256` and the
+/// 64 bytes Ed25519 secret key for `EdDSA`.
+pub fn encode<C: Encodable>(header: &JoseHeader, claims: &C, key: &[u8])
+    -> Result<String, json::EncoderError> {
+    let header_json = try!(json::encode(header));
+    let claims_json = try!(json::encode(claims));
+    let signing_input = format!("{}.{}",
+                                header_json.as_bytes().to_base64(URL_SAFE),
+                                claims_json.as_bytes().to_base64(URL_SAFE));
+    let signature = if header.alg == ALG_EDDSA {
+        ed25519::signature(signing_input.as_bytes(), key).to_vec()
+    } else {
+        hs256(signing_input.as_bytes(), key)
+    };
+    Ok(format!("{}.{}", signing_input, signature.to_base64(URL_SAFE)))
+}
+
+/// Parse a token without verifying its signature.
+pub fn decode<C: Decodable>(token: &str) -> Result<Jws<C>, ()> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(());
+    }
+
+    let decode_part = |part: &str| -> Result<String, ()> {
+        let bytes = try!(part.from_base64().map_err(|_| ()));
+        String::from_utf8(bytes).map_err(|_| ())
+    };
+    let header: JoseHeader = try!(
+        json::decode(&try!(decode_part(parts[0]))).map_err(|_| ())
+    );
+    let claims: C = try!(
+        json::decode(&try!(decode_part(parts[1]))).map_err(|_| ())
+    );
+    let signature = try!(parts[2].from_base64().map_err(|_| ()));
+
+    Ok(Jws {
+        header: header,
+        claims: claims,
+        signing_input: format!("{}.{}", parts[0], parts[1]),
+        signature: signature
+    })
+}
+
+impl<C> Jws<C> {
+    /// Verify the signature of the token. `key` is the HMAC key for
+    /// `HS256` and the 32 bytes Ed25519 public key for `EdDSA`.
+    pub fn verify(&self, key: &[u8]) -> bool {
+        if self.header.alg == ALG_EDDSA {
+            self.signature.len() == 64 && key.len() == 32 &&
+            ed25519::verify(self.signing_input.as_bytes(), key,
+                            &self.signature)
+        } else if self.header.alg == ALG_HS256 {
+            fixed_time_eq(&hs256(self.signing_input.as_bytes(), key),
+                          &self.signature)
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+describe! jws_tests {
+    before_each {
+        use crypto::ed25519;
+        use rustc_serialize::base64::{ ToBase64, URL_SAFE };
+
+        #[derive(Debug, PartialEq, RustcDecodable, RustcEncodable)]
+        struct Claims {
+            sub: String
+        }
+        let claims = Claims { sub: "1".to_owned() };
+    }
+
+    it "should sign and verify HS256 tokens" {
+        let header = JoseHeader::new(ALG_HS256, "kid");
+        let token = encode(&header, &claims, b"secret").unwrap();
+
+        let jws = decode::<Claims>(&token).unwrap();
+        assert_eq!(jws.header, header);
+        assert_eq!(jws.claims, claims);
+        assert!(jws.verify(b"secret"));
+        assert!(!jws.verify(b"other secret"));
+    }
+
+    it "should sign and verify EdDSA tokens" {
+        let (secret_key, public_key) = ed25519::keypair(&[7; 32]);
+        let (_, other_public_key) = ed25519::keypair(&[8; 32]);
+        let header = JoseHeader::new(ALG_EDDSA, "kid");
+        let token = encode(&header, &claims, &secret_key).unwrap();
+
+        let jws = decode::<Claims>(&token).unwrap();
+        assert_eq!(jws.claims, claims);
+        assert!(jws.verify(&public_key));
+        assert!(!jws.verify(&other_public_key));
+    }
+
+    it "should reject tampered tokens" {
+        let header = JoseHeader::new(ALG_HS256, "kid");
+        let token = encode(&header, &claims, b"secret").unwrap();
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = format!("{}.{}.{}", parts[0],
+                             "{\"sub\":\"2\"}".as_bytes().to_base64(URL_SAFE),
+                             parts[2]);
+        assert!(!decode::<Claims>(&forged).unwrap().verify(b"secret"));
+        assert!(decode::<Claims>("not a token").is_err());
+    }
+}
@@ -5,11 +5,14 @@
 #![feature(associated_consts, plugin)]
 
 #![cfg_attr(test, feature(const_fn))] // Dependency of stainless
+#![cfg_attr(test, feature(test))]     // Benchmarks
 #![cfg_attr(test, plugin(stainless))] // Test runner
 #![cfg_attr(test, plugin(clippy))]    // Linter
 
 #[cfg(test)]
 extern crate iron_test;
+#[cfg(test)]
+extern crate test;
 #[macro_use]
 #[cfg(test)]
 extern crate url;
@@ -21,31 +24,61 @@ extern crate iron_cors;
 extern crate jwt;
 extern crate libc;
 extern crate pwhash;
+extern crate ring;
 extern crate router;
 extern crate rustc_serialize;
 extern crate rusqlite;
 extern crate unicase;
+extern crate untrusted;
 extern crate urlencoded;
 extern crate uuid;
 
+mod auth_cache;
 mod auth_middleware;
 mod errors;
 mod invitation_middleware;
+mod jws;
+mod oauth_router;
+mod route_matcher;
+#[cfg(test)]
+mod test_helpers;
+mod totp;
+mod two_factor_router;
 mod users_db;
 mod users_router;
+mod webauthn;
+mod webauthn_router;
 
+pub use users_db::AccessToken as AccessToken;
 pub use users_db::UsersDb as UsersDb;
+pub use users_db::OAuthClient as OAuthClient;
 pub use users_db::UserBuilder as UserBuilder;
 pub use users_db::UserBuilderError as UserBuilderError;
 pub use users_db::ReadFilter as ReadFilter;
+pub use users_db::RefreshToken as RefreshToken;
+pub use users_db::Role as Role;
+pub use users_db::Session as Session;
+pub use users_db::SigningAlgorithm as SigningAlgorithm;
+pub use users_db::SigningKey as SigningKey;
 pub use users_db::User as User;
 pub use users_router::UsersRouter as UsersRouter;
+pub use auth_middleware::ACCESS_TOKEN_PREFIX as ACCESS_TOKEN_PREFIX;
 pub use auth_middleware::AuthMiddleware as AuthMiddleware;
 pub use auth_middleware::AuthEndpoint as AuthEndpoint;
+pub use auth_middleware::AuthenticatedUser as AuthenticatedUser;
+pub use auth_middleware::CSRF_COOKIE as CSRF_COOKIE;
+pub use auth_middleware::CSRF_HEADER as CSRF_HEADER;
+pub use auth_middleware::SCOPE_TOKENS_INTROSPECT as SCOPE_TOKENS_INTROSPECT;
+pub use auth_middleware::SCOPE_USERS_ADMIN as SCOPE_USERS_ADMIN;
+pub use auth_middleware::SCOPE_USERS_READ as SCOPE_USERS_READ;
+pub use auth_middleware::SESSION_COOKIE as SESSION_COOKIE;
+pub use auth_middleware::SessionClaims as SessionClaims;
 pub use auth_middleware::SessionToken as SessionToken;
+pub use auth_middleware::TokenError as TokenError;
+pub use auth_middleware::TokenKind as TokenKind;
+pub use auth_middleware::VerifiedSession as VerifiedSession;
 
 pub struct UsersManager {
-    db_file_path: String,
     router: UsersRouter
 }
 
@@ -54,14 +87,13 @@ impl UsersManager {
     /// The database will be stored at `db_file_path`.
     pub fn new(db_file_path: &str)-> Self {
         UsersManager {
-            db_file_path: String::from(db_file_path),
             router: UsersRouter::new(db_file_path)
         }
     }
 
     /// Get a new database connection.
     pub fn get_db(&self) -> UsersDb {
-        UsersDb::new(&self.db_file_path)
+        self.router.auth_cache().connect()
     }
 
     /// Get Iron chain containing the Users HTTP API routes.
@@ -73,6 +105,7 @@ impl UsersManager {
     /// the url of the email server that should be an instance of
     /// https://github.com/fxbox/users-email-server
     /// and the URL prepath to be appended to the user activation endpoints.
+    /// The email server also sends the password reset links.
     pub fn setup_invitation_middleware(&mut self,
                                        email_server: &str,
                                        invitation_url_prepath: &str) {
@@ -80,12 +113,62 @@ impl UsersManager {
                                                 invitation_url_prepath);
     }
 
+    /// Let other local web apps sign users in with OpenID Connect.
+    /// `issuer` is the URL the HTTP API is reached at, including the API
+    /// version, like `https://box.local/v1`, and `authorization_endpoint`
+    /// the URL of the page of the host application asking users for
+    /// consent. It must be called before `get_router_chain`.
+    pub fn setup_openid_provider(&mut self, issuer: &str,
+                                 authorization_endpoint: &str) {
+        self.router.setup_openid_provider(issuer, authorization_endpoint);
+    }
+
+    /// Let devices without a browser or keyboard, like speakers, sign in
+    /// with the OAuth 2.0 device authorization grant. `verification_uri`
+    /// is the URL of the page of the host application where users enter
+    /// the code shown by the device. It must be called before
+    /// `get_router_chain`.
+    pub fn setup_device_flow(&mut self, verification_uri: &str) {
+        self.router.setup_device_flow(verification_uri);
+    }
+
+    /// Let users register WebAuthn credentials, like the passkeys of their
+    /// phones, to log in without a password or as a second factor.
+    /// `rp_id` is the domain the host application is reached at, like
+    /// `box.local`, `rp_name` the name shown by authenticators and `origin`
+    /// the origin of the web pages running the ceremonies, like
+    /// `https://box.local`. P-256 and Ed25519 credentials are supported. It
+    /// must be called before `get_router_chain`.
+    pub fn setup_webauthn(&mut self, rp_id: &str, rp_name: &str,
+                          origin: &str) {
+        self.router.setup_webauthn(rp_id, rp_name, origin);
+    }
+
+    /// Set the lifetime, in seconds, of the session tokens issued by the
+    /// HTTP API. It must be called before `get_router_chain`.
+    /// Defaults to `SessionToken::DEFAULT_TTL`.
+    pub fn set_session_ttl(&mut self, session_ttl: i64) {
+        self.router.set_session_ttl(session_ttl);
+    }
+
+    /// Let the HTTP API hand session and refresh tokens in HttpOnly cookies
+    /// instead of response bodies, for browser clients. Cookies are only
+    /// sent over HTTPS if `secure` is true. It must be called before
+    /// `get_router_chain`.
+    pub fn enable_cookie_sessions(&mut self, secure: bool) {
+        self.router.enable_cookie_sessions(secure);
+    }
+
     pub fn get_middleware(&self, auth_endpoints: Vec<AuthEndpoint>)
                           -> AuthMiddleware {
-        AuthMiddleware::new(auth_endpoints, self.db_file_path.to_owned())
+        AuthMiddleware::with_cache(auth_endpoints, self.router.auth_cache())
     }
 
-    pub fn verify_token(&self, token: &str) -> Result<(), ()> {
-        AuthMiddleware::verify(token, &self.db_file_path)
+    /// Verify a session token outside of the HTTP API, telling why the
+    /// token is rejected if it is not valid. Users, sessions and keys are
+    /// cached like in the middleware.
+    pub fn verify_token(&self, token: &str)
+        -> Result<VerifiedSession, TokenError> {
+        AuthMiddleware::verify_cached(token, &self.router.auth_cache())
     }
 }
@@ -0,0 +1,1896 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! OAuth 2.0 authorization server.
+//!
+//! Lets third-party applications get access to users accounts without
+//! asking for their password, through the authorization code grant with
+//! [PKCE](https://tools.ietf.org/html/rfc7636) and refresh tokens.
+//!
+//! The consent step is left to the host application: it shows the user
+//! the authorization request described by GET /oauth/authorize and posts
+//! the user decision to POST /oauth/authorize, which tells where to send
+//! the user back to. Access tokens are regular session tokens, restricted
+//! to the scopes the user approved, within a session opened for the
+//! client, so users can sign applications out like any other device.
+//!
+//! Once set up with `UsersRouter::setup_openid_provider`, the server is an
+//! OpenID Connect provider as well: clients asking for the `openid` scope
+//! also get an ID token, signed with an `EdDSA` key published in the JWK
+//! set, and can read the user claims from GET /userinfo. The `profile`
+//! scope releases the `name` claim and the `email` scope the `email` and
+//! `email_verified` ones.
+//!
+//! Devices without a browser or a keyboard, like speakers or displays, can
+//! use the device authorization grant of
+//! [RFC 8628](https://tools.ietf.org/html/rfc8628) instead, once set up
+//! with `UsersRouter::setup_device_flow`: the device shows a user code,
+//! which the user approves from another device with POST /device/verify,
+//! while the device polls POST /oauth/token for its tokens.
+
+use super::auth_cache::users_db;
+use super::auth_middleware::{ AuthenticatedUser, SessionClaims, SessionToken,
+                              SCOPE_USERS_READ, now };
+use super::errors::*;
+use super::jws::{ self, JoseHeader };
+use super::users_db::{ AuthorizationCode, DeviceAuthorization, OAuthClient,
+                       OAuthRefreshToken, ReadFilter, Session, User, UsersDb };
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use iron::headers::{ Authorization, Basic };
+use iron::prelude::*;
+use iron::status;
+use router::Router;
+use rustc_serialize::base64::{ ToBase64, URL_SAFE };
+use rustc_serialize::json::{ self, Json };
+use urlencoded::{ QueryMap, UrlEncodedBody, UrlEncodedQuery };
+use uuid::Uuid;
+
+use std::collections::{ BTreeMap, HashMap };
+use std::io::Read;
+
+/// Lifetime of authorization codes, in seconds.
+static AUTHORIZATION_CODE_TTL: i64 = 60;
+
+/// Lifetime of device authorization requests, in seconds.
+static DEVICE_CODE_TTL: i64 = 600;
+
+/// Minimum time, in seconds, devices wait between polling requests.
+static DEVICE_POLLING_INTERVAL: i64 = 5;
+
+/// Characters of user codes: consonants only, so codes are easy to type
+/// and do not spell words.
+static USER_CODE_CHARS: &'static [u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+/// Scope asking for an ID token.
+static SCOPE_OPENID: &'static str = "openid";
+static SCOPE_PROFILE: &'static str = "profile";
+static SCOPE_EMAIL: &'static str = "email";
+
+/// OpenID Connect provider settings.
+#[derive(Clone, Debug)]
+pub struct OpenIdOptions {
+    /// Issuer identifier: the URL the HTTP API is reached at, including
+    /// the API version, like `https://box.local/v1`.
+    pub issuer: String,
+    /// URL of the page of the host application asking users for consent,
+    /// which relies on GET and POST /oauth/authorize.
+    pub authorization_endpoint: String
+}
+
+/// Body response for POST /oauth/clients. The secret of confidential
+/// clients is only returned on registration.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct RegisterClientResponse {
+    client: OAuthClient,
+    client_secret: Option<String>
+}
+
+/// Body response for GET /oauth/clients
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct GetClientsResponse {
+    clients: Vec<OAuthClient>
+}
+
+/// Body response for GET /oauth/authorize, with what the user is asked to
+/// consent to.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct AuthorizationRequestResponse {
+    client_id: String,
+    client_name: String,
+    redirect_uri: String,
+    scopes: Vec<String>,
+    state: Option<String>
+}
+
+/// Body response for POST /oauth/authorize
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct AuthorizeResponse {
+    redirect_to: String
+}
+
+/// Body response for POST /device/code, as defined by RFC 8628.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: i64,
+    interval: i64
+}
+
+/// Body response for GET /device/verify, with what the user is asked to
+/// consent to.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct DeviceAuthorizationResponse {
+    client_id: String,
+    client_name: String,
+    user_code: String,
+    scopes: Vec<String>
+}
+
+/// A validated authorization request.
+struct AuthorizationRequest {
+    client: OAuthClient,
+    redirect_uri: String,
+    scopes: Vec<String>,
+    state: Option<String>,
+    code_challenge: String,
+    nonce: Option<String>
+}
+
+/// The first value of the parameter `name`, if any.
+pub fn first(params: &QueryMap, name: &str) -> Option<String> {
+    params.get(name).and_then(|values| values.first().cloned())
+}
+
+fn split_scopes(scopes: &str) -> Vec<String> {
+    scopes.split_whitespace().map(|scope| scope.to_owned()).collect()
+}
+
+/// Whether `scope` is one of the OpenID Connect scopes, which are not
+/// permissions of the user.
+fn is_openid_scope(scope: &str) -> bool {
+    scope == SCOPE_OPENID || scope == SCOPE_PROFILE || scope == SCOPE_EMAIL
+}
+
+/// Check that `scopes` can be granted by `user`. OpenID Connect scopes are
+/// only accepted if `openid` is true.
+fn check_scopes(scopes: &[String], user: &User, db: &UsersDb, openid: bool)
+    -> Result<(), IronResult<Response>> {
+    let granted = match SessionClaims::granted_scopes(user, db) {
+        Ok(granted) => granted,
+        Err(error) => {
+            println!("{:?}", error);
+            return Err(from_sqlite_error(error));
+        }
+    };
+    if !scopes.iter().all(|scope| {
+        granted.contains(scope) || (openid && is_openid_scope(scope))
+    }) {
+        return Err(EndpointError::with(status::Forbidden, 110,
+            Some("Insufficient scope".to_owned())));
+    }
+    Ok(())
+}
+
+/// The OpenID Connect claims about `user` released for `scopes`.
+fn user_claims(user: &User, scopes: &[String], db: &UsersDb)
+    -> Result<BTreeMap<String, Json>, IronResult<Response>> {
+    let mut claims = BTreeMap::new();
+    claims.insert("sub".to_owned(), Json::String(user.id.clone()));
+    if scopes.iter().any(|scope| scope == SCOPE_PROFILE) {
+        claims.insert("name".to_owned(), Json::String(user.name.clone()));
+    }
+    if scopes.iter().any(|scope| scope == SCOPE_EMAIL) {
+        let verified = match db.is_email_verified(user) {
+            Ok(verified) => verified,
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+        claims.insert("email".to_owned(), Json::String(user.email.clone()));
+        claims.insert("email_verified".to_owned(), Json::Boolean(verified));
+    }
+    Ok(claims)
+}
+
+/// A new random token, for codes, secrets and refresh tokens.
+pub fn random_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// A new random user code of 8 characters, as recommended by RFC 8628.
+fn random_user_code() -> String {
+    let mut code = String::new();
+    while code.len() < 8 {
+        let mut hasher = Sha256::new();
+        hasher.input_str(&random_token());
+        let mut digest = [0; 32];
+        hasher.result(&mut digest);
+        // Bytes from 240 up are dropped so all the characters are equally
+        // likely.
+        for byte in digest.iter().filter(|byte| **byte < 240) {
+            if code.len() < 8 {
+                code.push(USER_CODE_CHARS[(*byte % 20) as usize] as char);
+            }
+        }
+    }
+    code
+}
+
+/// A user code as typed by the user, without separators nor lowercase.
+fn normalize_user_code(user_code: &str) -> String {
+    user_code.chars().filter(|c| c.is_alphanumeric())
+             .flat_map(|c| c.to_uppercase()).collect()
+}
+
+/// A user code as shown to the user, like `BCDF-GHJK`.
+fn format_user_code(user_code: &str) -> String {
+    if user_code.len() != 8 {
+        return user_code.to_owned();
+    }
+    format!("{}-{}", &user_code[..4], &user_code[4..])
+}
+
+/// The PKCE S256 challenge of a code verifier.
+fn pkce_challenge(verifier: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(verifier);
+    let mut digest = [0; 32];
+    hasher.result(&mut digest);
+    digest.to_base64(URL_SAFE)
+}
+
+/// `value` with everything but the unreserved characters of RFC 3986
+/// percent-encoded.
+pub fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' |
+            b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    encoded
+}
+
+/// `redirect_uri` with the given query parameters appended.
+fn redirect_to(redirect_uri: &str, params: &[(&str, &str)]) -> String {
+    let mut url = redirect_uri.to_owned();
+    let mut separator = if url.contains('?') { '&' } else { '?' };
+    for &(name, value) in params {
+        url.push(separator);
+        url.push_str(name);
+        url.push('=');
+        url.push_str(&percent_encode(value));
+        separator = '&';
+    }
+    url
+}
+
+/// Whether `uri` can be registered as a redirect URI: an absolute URI
+/// without fragment.
+fn is_valid_redirect_uri(uri: &str) -> bool {
+    match uri.find("://") {
+        Some(index) => index > 0 && uri.len() > index + 3 &&
+                       !uri.contains('#') &&
+                       !uri.contains(|c: char| c.is_whitespace()),
+        None => false
+    }
+}
+
+fn invalid_grant(description: &str) -> IronResult<Response> {
+    oauth_error(status::BadRequest, "invalid_grant", description)
+}
+
+fn invalid_request(description: &str) -> IronResult<Response> {
+    oauth_error(status::BadRequest, "invalid_request", description)
+}
+
+impl AuthorizationRequest {
+    /// Validate the authorization request in the query of `req`, made by
+    /// `caller`. Requests from unknown clients or with unregistered
+    /// redirect URIs are never sent back to the client. OpenID Connect
+    /// scopes are only accepted if `openid` is true.
+    fn from_request(req: &mut Request, db: &UsersDb, caller: &User,
+                    openid: bool)
+        -> Result<AuthorizationRequest, IronResult<Response>> {
+        let params = req.get::<UrlEncodedQuery>().unwrap_or(HashMap::new());
+
+        let client_id = first(&params, "client_id").unwrap_or(String::new());
+        let client = match db.read_oauth_client(&client_id) {
+            Ok(Some(client)) => client,
+            Ok(None) => return Err(EndpointError::with(
+                status::BadRequest, 117, Some("Unknown client".to_owned())
+            )),
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+
+        // The redirect URI can only be omitted by clients registering one.
+        let redirect_uri = match first(&params, "redirect_uri") {
+            Some(redirect_uri) => redirect_uri,
+            None if client.redirect_uris.len() == 1 => {
+                client.redirect_uris[0].clone()
+            },
+            None => String::new()
+        };
+        if !client.redirect_uris.contains(&redirect_uri) {
+            return Err(EndpointError::with(status::BadRequest, 117,
+                Some("Invalid redirect URI".to_owned())));
+        }
+
+        if first(&params, "response_type") != Some("code".to_owned()) {
+            return Err(EndpointError::with(status::BadRequest, 118,
+                Some("Unsupported response type".to_owned())));
+        }
+
+        let code_challenge = first(&params, "code_challenge")
+            .unwrap_or(String::new());
+        if code_challenge.len() != 43 ||
+           first(&params, "code_challenge_method") != Some("S256".to_owned()) {
+            return Err(EndpointError::with(status::BadRequest, 118,
+                Some("A S256 PKCE code challenge is required".to_owned())));
+        }
+
+        let mut scopes = split_scopes(&first(&params, "scope")
+                                      .unwrap_or(String::new()));
+        if scopes.is_empty() {
+            scopes.push(SCOPE_USERS_READ.to_owned());
+        }
+        try!(check_scopes(&scopes, caller, db, openid));
+
+        Ok(AuthorizationRequest {
+            client: client,
+            redirect_uri: redirect_uri,
+            scopes: scopes,
+            state: first(&params, "state"),
+            code_challenge: code_challenge,
+            nonce: first(&params, "nonce")
+        })
+    }
+}
+
+/// Manages the OAuth 2.0 REST operations. The routes are installed by
+/// `UsersRouter`.
+pub struct OAuthRouter;
+
+impl OAuthRouter {
+    /// POST /oauth/clients handler.
+    /// Register a new OAuth client. Confidential clients get a secret.
+    pub fn register_client(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct RegisterClientBody {
+            name: String,
+            redirect_uris: Vec<String>,
+            confidential: Option<bool>
+        }
+
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let body: RegisterClientBody = match json::decode(&payload) {
+            Ok(body) => body,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_decoder_error(error);
+            }
+        };
+
+        let name = body.name.trim().to_owned();
+        if name.is_empty() {
+            return EndpointError::with(status::BadRequest, 100,
+                                       Some("Invalid name".to_owned()));
+        }
+        if body.redirect_uris.is_empty() ||
+           !body.redirect_uris.iter().all(|uri| is_valid_redirect_uri(uri)) {
+            return EndpointError::with(status::BadRequest, 117,
+                Some("Invalid redirect URI".to_owned()));
+        }
+
+        let confidential = body.confidential.unwrap_or(false);
+        let client = OAuthClient::new(&name, body.redirect_uris, confidential);
+        let client_secret = if confidential {
+            Some(random_token())
+        } else {
+            None
+        };
+
+        let db = users_db(req, db_path);
+        if let Err(error) = db.create_oauth_client(
+            &client, client_secret.as_ref().map(|secret| secret.as_str())) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        let body = match json::encode(&RegisterClientResponse {
+            client: client,
+            client_secret: client_secret
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Created, body)))
+    }
+
+    /// GET /oauth/clients handler.
+    /// Get the list of registered OAuth clients, without their secrets.
+    pub fn get_clients(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let db = users_db(req, db_path);
+        match db.read_oauth_clients() {
+            Ok(clients) => {
+                let body = match json::encode(&GetClientsResponse {
+                    clients: clients
+                }) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Ok, body)))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// DELETE /oauth/clients/:client_id handler.
+    /// Remove an OAuth client, revoking all the tokens it was issued.
+    pub fn delete_client(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let client_id = req.extensions.get::<Router>().unwrap()
+            .find("client_id").unwrap_or("").to_owned();
+
+        let db = users_db(req, db_path);
+        match db.delete_oauth_client(&client_id) {
+            Ok(0) => EndpointError::with(status::NotFound, 404,
+                Some("Client not found".to_owned())),
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// The user making the request, who must be signed in with a session
+    /// of its own, so clients cannot approve their own requests.
+    fn get_resource_owner(req: &Request) -> Result<User, IronResult<Response>> {
+        match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) if caller.is_first_party() => Ok(caller.user.clone()),
+            Some(_) => Err(EndpointError::with(status::Forbidden, 403,
+                Some("A session of the user is required".to_owned()))),
+            None => Err(EndpointError::with(status::Unauthorized, 401, None))
+        }
+    }
+
+    /// GET /oauth/authorize handler.
+    /// Validate an authorization request and describe it, so the host
+    /// application can ask the user for consent.
+    pub fn get_authorization_request(req: &mut Request, db_path: &str,
+                                     openid: &Option<OpenIdOptions>)
+        -> IronResult<Response> {
+        let user = match OAuthRouter::get_resource_owner(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+        let db = users_db(req, db_path);
+        let request = match AuthorizationRequest::from_request(
+            req, &db, &user, openid.is_some()) {
+            Ok(request) => request,
+            Err(response) => return response
+        };
+
+        let body = match json::encode(&AuthorizationRequestResponse {
+            client_id: request.client.id,
+            client_name: request.client.name,
+            redirect_uri: request.redirect_uri,
+            scopes: request.scopes,
+            state: request.state
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Ok, body)))
+    }
+
+    /// POST /oauth/authorize handler.
+    /// Record the decision of the user on an authorization request and
+    /// tell where to send the user back to: with a single-use
+    /// authorization code if the request is approved, or with an
+    /// `access_denied` error otherwise.
+    pub fn authorize(req: &mut Request, db_path: &str,
+                     openid: &Option<OpenIdOptions>)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct AuthorizeBody {
+            approved: bool
+        }
+
+        let user = match OAuthRouter::get_resource_owner(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+        let db = users_db(req, db_path);
+        let request = match AuthorizationRequest::from_request(
+            req, &db, &user, openid.is_some()) {
+            Ok(request) => request,
+            Err(response) => return response
+        };
+
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let body: AuthorizeBody = match json::decode(&payload) {
+            Ok(body) => body,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_decoder_error(error);
+            }
+        };
+
+        let code = random_token();
+        let mut params = vec![];
+        if body.approved {
+            if let Err(error) = db.create_authorization_code(
+                &code, &AuthorizationCode {
+                    client_id: request.client.id.clone(),
+                    user_id: user.id.clone(),
+                    redirect_uri: request.redirect_uri.clone(),
+                    scopes: request.scopes.clone(),
+                    code_challenge: request.code_challenge.clone(),
+                    nonce: request.nonce.clone(),
+                    expires_at: now() + AUTHORIZATION_CODE_TTL
+                }) {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+            params.push(("code", code.as_str()));
+        } else {
+            params.push(("error", "access_denied"));
+        }
+        if let Some(ref state) = request.state {
+            params.push(("state", state.as_str()));
+        }
+
+        let body = match json::encode(&AuthorizeResponse {
+            redirect_to: redirect_to(&request.redirect_uri, &params)
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Ok, body)))
+    }
+
+    /// POST /oauth/token handler.
+    /// Issue tokens to a client in exchange for an authorization code or a
+    /// refresh token. Parameters are form encoded and errors follow
+    /// RFC 6749 rather than the errors of the rest of the API, as expected
+    /// by OAuth client libraries.
+    pub fn token(req: &mut Request, db_path: &str, session_ttl: i64,
+                 openid: &Option<OpenIdOptions>) -> IronResult<Response> {
+        let params = match req.get::<UrlEncodedBody>() {
+            Ok(params) => params,
+            Err(_) => return invalid_request("Missing parameters")
+        };
+
+        let db = users_db(req, db_path);
+        let client = match OAuthRouter::authenticate_client(req, &params,
+                                                            &db) {
+            Ok(client) => client,
+            Err(response) => return response
+        };
+
+        let grant_type = first(&params, "grant_type").unwrap_or(String::new());
+        match grant_type.as_ref() {
+            "authorization_code" => {
+                OAuthRouter::exchange_code(req, &params, &client, &db,
+                                           session_ttl, openid.as_ref())
+            },
+            "refresh_token" => {
+                OAuthRouter::refresh(&params, &client, &db, session_ttl,
+                                     openid.as_ref())
+            },
+            "urn:ietf:params:oauth:grant-type:device_code" => {
+                OAuthRouter::poll_device_code(req, &params, &client, &db,
+                                              session_ttl, openid.as_ref())
+            },
+            "" => invalid_request("Missing grant_type"),
+            _ => oauth_error(status::BadRequest, "unsupported_grant_type",
+                             "Unsupported grant type")
+        }
+    }
+
+    /// The client making a token request. Confidential clients must
+    /// authenticate with their secret, with HTTP Basic authentication or
+    /// the `client_secret` parameter.
+    fn authenticate_client(req: &Request, params: &QueryMap, db: &UsersDb)
+        -> Result<OAuthClient, IronResult<Response>> {
+        let (client_id, secret) = match req.headers
+                                           .get::<Authorization<Basic>>() {
+            Some(&Authorization(Basic { ref username, ref password })) => {
+                (Some(username.clone()), password.clone())
+            },
+            None => (first(params, "client_id"),
+                     first(params, "client_secret"))
+        };
+        let client_id = match client_id {
+            Some(client_id) => client_id,
+            None => return Err(oauth_error(status::Unauthorized,
+                                           "invalid_client",
+                                           "Missing client_id"))
+        };
+
+        let client = match db.read_oauth_client(&client_id) {
+            Ok(Some(client)) => client,
+            Ok(None) => return Err(oauth_error(status::Unauthorized,
+                                               "invalid_client",
+                                               "Unknown client")),
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+        if client.confidential {
+            let authenticated = match secret {
+                Some(ref secret) => {
+                    match db.check_oauth_client_secret(&client.id, secret) {
+                        Ok(authenticated) => authenticated,
+                        Err(error) => {
+                            println!("{:?}", error);
+                            return Err(from_sqlite_error(error));
+                        }
+                    }
+                },
+                None => false
+            };
+            if !authenticated {
+                return Err(oauth_error(status::Unauthorized, "invalid_client",
+                                       "Invalid client credentials"));
+            }
+        }
+        Ok(client)
+    }
+
+    /// Exchange an authorization code for a first pair of tokens, opening
+    /// a new session for the client.
+    fn exchange_code(req: &Request, params: &QueryMap, client: &OAuthClient,
+                     db: &UsersDb, session_ttl: i64,
+                     openid: Option<&OpenIdOptions>) -> IronResult<Response> {
+        let code = match first(params, "code") {
+            Some(code) => code,
+            None => return invalid_request("Missing code")
+        };
+        let authorization = match db.take_authorization_code(&code) {
+            Ok(Some(authorization)) => authorization,
+            Ok(None) => return invalid_grant("Invalid authorization code"),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        let verifier = first(params, "code_verifier").unwrap_or(String::new());
+        let redirect_uri = first(params, "redirect_uri")
+            .unwrap_or(authorization.redirect_uri.clone());
+        if authorization.client_id != client.id ||
+           authorization.expires_at <= now() ||
+           redirect_uri != authorization.redirect_uri ||
+           !fixed_time_eq(pkce_challenge(&verifier).as_bytes(),
+                          authorization.code_challenge.as_bytes()) {
+            return invalid_grant("Invalid authorization code");
+        }
+
+        let user = match OAuthRouter::get_active_user(&authorization.user_id,
+                                                      db) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+
+        let session = match OAuthRouter::new_session(req, &user, client,
+                                                     db) {
+            Ok(session) => session,
+            Err(response) => return response
+        };
+        OAuthRouter::issue_tokens(&user, client, &session.id,
+                                  authorization.scopes, db, session_ttl,
+                                  openid, authorization.nonce)
+    }
+
+    /// Open a new session of `user` for `client`, named after the client.
+    fn new_session(req: &Request, user: &User, client: &OAuthClient,
+                   db: &UsersDb) -> Result<Session, IronResult<Response>> {
+        let session = Session::new(&user.id, &client.name,
+                                   &req.remote_addr.ip().to_string());
+        match db.create_session(&session) {
+            Ok(_) => Ok(session),
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+
+    /// Trade a refresh token for a new pair of tokens within the same
+    /// session. Refresh tokens can only be used once. Clients can ask for
+    /// a subset of the scopes they were granted with the `scope`
+    /// parameter, which the new tokens are restricted to.
+    fn refresh(params: &QueryMap, client: &OAuthClient, db: &UsersDb,
+               session_ttl: i64, openid: Option<&OpenIdOptions>)
+        -> IronResult<Response> {
+        let token = match first(params, "refresh_token") {
+            Some(token) => token,
+            None => return invalid_request("Missing refresh_token")
+        };
+        let refresh_token = match db.read_oauth_refresh_token(&token) {
+            Ok(Some(ref refresh_token))
+                if refresh_token.client_id == client.id => {
+                refresh_token.clone()
+            },
+            Ok(_) => return invalid_grant("Invalid refresh token"),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        // The token is only used by the request that deletes it, so it
+        // cannot be traded twice by concurrent requests.
+        match db.delete_oauth_refresh_token(&token) {
+            Ok(1) => {},
+            Ok(_) => return invalid_grant("Invalid refresh token"),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+        if refresh_token.expires_at <= now() {
+            return invalid_grant("Invalid refresh token");
+        }
+        match db.read_session(&refresh_token.session_id) {
+            Ok(Some(_)) => {},
+            Ok(None) => return invalid_grant("Session closed"),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+
+        let scopes = match first(params, "scope") {
+            Some(scope) => {
+                let scopes = split_scopes(&scope);
+                if !scopes.iter().all(|scope| {
+                    refresh_token.scopes.contains(scope)
+                }) {
+                    return oauth_error(status::BadRequest, "invalid_scope",
+                                       "Scope not granted to the client");
+                }
+                scopes
+            },
+            None => refresh_token.scopes.clone()
+        };
+
+        let user = match OAuthRouter::get_active_user(&refresh_token.user_id,
+                                                      db) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+        if let Err(error) = db.touch_session(&refresh_token.session_id,
+                                             now()) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+        OAuthRouter::issue_tokens(&user, client, &refresh_token.session_id,
+                                  scopes, db, session_ttl, openid, None)
+    }
+
+    /// Answer a device polling for the tokens of its authorization
+    /// request. Tokens are issued once the user approves the request, and
+    /// devices polling too often are asked to slow down.
+    fn poll_device_code(req: &Request, params: &QueryMap,
+                        client: &OAuthClient, db: &UsersDb, session_ttl: i64,
+                        openid: Option<&OpenIdOptions>)
+        -> IronResult<Response> {
+        let device_code = match first(params, "device_code") {
+            Some(device_code) => device_code,
+            None => return invalid_request("Missing device_code")
+        };
+        let authorization = match db.read_device_authorization(&device_code) {
+            Ok(Some(ref authorization))
+                if authorization.client_id == client.id => {
+                authorization.clone()
+            },
+            Ok(_) => return invalid_grant("Invalid device code"),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        let now = now();
+        if authorization.approved.is_none() && authorization.expires_at > now {
+            let too_early = match authorization.last_polled_at {
+                Some(last_polled_at) => {
+                    now - last_polled_at < DEVICE_POLLING_INTERVAL
+                },
+                None => false
+            };
+            if let Err(error) = db.touch_device_authorization(&device_code,
+                                                              now) {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+            if too_early {
+                return oauth_error(status::BadRequest, "slow_down",
+                                   "Polling too often");
+            }
+            return oauth_error(status::BadRequest, "authorization_pending",
+                               "The user has not approved the request yet");
+        }
+
+        // The request is over, whatever the outcome. Only the poll that
+        // deletes it gets the outcome, so concurrent polls cannot both
+        // get tokens.
+        match db.delete_device_authorization(&device_code) {
+            Ok(1) => {},
+            Ok(_) => return invalid_grant("Invalid device code"),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+        if authorization.expires_at <= now {
+            return oauth_error(status::BadRequest, "expired_token",
+                               "Expired device code");
+        }
+        let user_id = match (authorization.approved, authorization.user_id) {
+            (Some(true), Some(user_id)) => user_id,
+            _ => return oauth_error(status::BadRequest, "access_denied",
+                                    "The user denied the request")
+        };
+
+        let user = match OAuthRouter::get_active_user(&user_id, db) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+        let session = match OAuthRouter::new_session(req, &user, client, db) {
+            Ok(session) => session,
+            Err(response) => return response
+        };
+        OAuthRouter::issue_tokens(&user, client, &session.id,
+                                  authorization.scopes, db, session_ttl,
+                                  openid, None)
+    }
+
+    fn get_active_user(user_id: &str, db: &UsersDb)
+        -> Result<User, IronResult<Response>> {
+        match db.read(ReadFilter::Id(user_id.to_owned())) {
+            Ok(mut users) => {
+                if users.len() != 1 || !users[0].is_active {
+                    return Err(invalid_grant("Inactive user"));
+                }
+                Ok(users.remove(0))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+
+    /// Respond with a new access token and refresh token for `client`
+    /// within `session_id`, along with an ID token if the `openid` scope
+    /// was approved. Access tokens only grant the approved scopes the user
+    /// still holds.
+    fn issue_tokens(user: &User, client: &OAuthClient, session_id: &str,
+                    scopes: Vec<String>, db: &UsersDb, session_ttl: i64,
+                    openid: Option<&OpenIdOptions>, nonce: Option<String>)
+        -> IronResult<Response> {
+        let granted = match SessionClaims::granted_scopes(user, db) {
+            Ok(granted) => granted,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        let claims = SessionClaims {
+            scopes: scopes.iter().filter(|scope| {
+                granted.contains(scope) || is_openid_scope(scope)
+            }).cloned().collect(),
+            client_id: Some(client.id.clone()),
+            .. SessionClaims::new(user, session_id, session_ttl)
+        };
+        let scope = claims.scopes.join(" ");
+        let id_token = match openid {
+            Some(openid) if claims.scopes.iter().any(|scope| {
+                scope == SCOPE_OPENID
+            }) => {
+                match OAuthRouter::id_token(user, client, &claims.scopes,
+                                            nonce, &openid.issuer, db,
+                                            session_ttl) {
+                    Ok(id_token) => Some(id_token),
+                    Err(response) => return response
+                }
+            },
+            _ => None
+        };
+        let key = match db.current_signing_key() {
+            Ok(key) => key,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        let access_token = match SessionToken::from_claims(user, claims,
+                                                           &key) {
+            Ok(token) => token,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+
+        let refresh_token = random_token();
+        if let Err(error) = db.create_oauth_refresh_token(
+            &refresh_token, &OAuthRefreshToken {
+                client_id: client.id.clone(),
+                user_id: user.id.clone(),
+                session_id: session_id.to_owned(),
+                scopes: scopes,
+                expires_at: now() + SessionToken::REFRESH_TTL
+            }) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        // Token response as defined by RFC 6749 and OpenID Connect.
+        let mut body = BTreeMap::new();
+        body.insert("access_token".to_owned(), Json::String(access_token));
+        body.insert("token_type".to_owned(), Json::String("Bearer".to_owned()));
+        body.insert("expires_in".to_owned(), Json::I64(session_ttl));
+        body.insert("refresh_token".to_owned(), Json::String(refresh_token));
+        body.insert("scope".to_owned(), Json::String(scope));
+        if let Some(id_token) = id_token {
+            body.insert("id_token".to_owned(), Json::String(id_token));
+        }
+        let mut response = Response::with((status::Ok,
+                                           Json::Object(body).to_string()));
+        response.headers.set_raw("Cache-Control", vec![b"no-store".to_vec()]);
+        response.headers.set_raw("Pragma", vec![b"no-cache".to_vec()]);
+        Ok(response)
+    }
+
+    /// POST /device/code handler.
+    /// Start a device authorization request for a device that cannot
+    /// show a web page, as defined by RFC 8628. The user approves it from
+    /// another device by entering the returned user code at
+    /// `verification_uri`, while the device polls POST /oauth/token.
+    pub fn device_code(req: &mut Request, db_path: &str,
+                       verification_uri: &str) -> IronResult<Response> {
+        let params = match req.get::<UrlEncodedBody>() {
+            Ok(params) => params,
+            Err(_) => return invalid_request("Missing parameters")
+        };
+
+        let db = users_db(req, db_path);
+        let client = match OAuthRouter::authenticate_client(req, &params,
+                                                            &db) {
+            Ok(client) => client,
+            Err(response) => return response
+        };
+
+        let mut scopes = split_scopes(&first(&params, "scope")
+                                      .unwrap_or(String::new()));
+        if scopes.is_empty() {
+            scopes.push(SCOPE_USERS_READ.to_owned());
+        }
+
+        let device_code = random_token();
+        let user_code = random_user_code();
+        if let Err(error) = db.create_device_authorization(
+            &device_code, &DeviceAuthorization {
+                user_code: user_code.clone(),
+                client_id: client.id.clone(),
+                scopes: scopes,
+                user_id: None,
+                approved: None,
+                last_polled_at: None,
+                expires_at: now() + DEVICE_CODE_TTL
+            }) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        let user_code = format_user_code(&user_code);
+        let body = match json::encode(&DeviceCodeResponse {
+            device_code: device_code,
+            verification_uri_complete: redirect_to(
+                verification_uri, &[("user_code", user_code.as_str())]
+            ),
+            user_code: user_code,
+            verification_uri: verification_uri.to_owned(),
+            expires_in: DEVICE_CODE_TTL,
+            interval: DEVICE_POLLING_INTERVAL
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        let mut response = Response::with((status::Ok, body));
+        response.headers.set_raw("Cache-Control", vec![b"no-store".to_vec()]);
+        Ok(response)
+    }
+
+    /// The pending device authorization request with `user_code`, as
+    /// typed by the user, and the client that made it.
+    fn get_pending_device_authorization(user_code: &str, db: &UsersDb)
+        -> Result<(DeviceAuthorization, OAuthClient), IronResult<Response>> {
+        let invalid = || EndpointError::with(status::BadRequest, 119,
+            Some("Invalid or expired user code".to_owned()));
+        let authorization = match db.read_device_authorization_by_user_code(
+            &normalize_user_code(user_code)) {
+            Ok(Some(ref authorization))
+                if authorization.approved.is_none() &&
+                   authorization.expires_at > now() => authorization.clone(),
+            Ok(_) => return Err(invalid()),
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+        match db.read_oauth_client(&authorization.client_id) {
+            Ok(Some(client)) => Ok((authorization, client)),
+            Ok(None) => Err(invalid()),
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+
+    /// GET /device/verify handler.
+    /// Describe the pending device authorization request with the
+    /// `user_code` query parameter, so the host application can ask the
+    /// user for consent.
+    pub fn get_device_authorization(req: &mut Request, db_path: &str,
+                                    openid: &Option<OpenIdOptions>)
+        -> IronResult<Response> {
+        let user = match OAuthRouter::get_resource_owner(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+        let params = req.get::<UrlEncodedQuery>().unwrap_or(HashMap::new());
+        let user_code = first(&params, "user_code").unwrap_or(String::new());
+
+        let db = users_db(req, db_path);
+        let (authorization, client) =
+            match OAuthRouter::get_pending_device_authorization(&user_code,
+                                                                &db) {
+            Ok(pending) => pending,
+            Err(response) => return response
+        };
+        if let Err(response) = check_scopes(&authorization.scopes, &user, &db,
+                                            openid.is_some()) {
+            return response;
+        }
+
+        let body = match json::encode(&DeviceAuthorizationResponse {
+            client_id: client.id,
+            client_name: client.name,
+            user_code: format_user_code(&authorization.user_code),
+            scopes: authorization.scopes
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Ok, body)))
+    }
+
+    /// POST /device/verify handler.
+    /// Record the decision of the user on the pending device authorization
+    /// request with the given user code. The device gets its tokens on its
+    /// next polling request if the user approves it.
+    pub fn verify_device(req: &mut Request, db_path: &str,
+                         openid: &Option<OpenIdOptions>)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct VerifyDeviceBody {
+            user_code: String,
+            approved: bool
+        }
+
+        let user = match OAuthRouter::get_resource_owner(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let body: VerifyDeviceBody = match json::decode(&payload) {
+            Ok(body) => body,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_decoder_error(error);
+            }
+        };
+
+        let db = users_db(req, db_path);
+        let (authorization, _) =
+            match OAuthRouter::get_pending_device_authorization(
+                &body.user_code, &db) {
+            Ok(pending) => pending,
+            Err(response) => return response
+        };
+        if body.approved {
+            if let Err(response) = check_scopes(&authorization.scopes, &user,
+                                                &db, openid.is_some()) {
+                return response;
+            }
+        }
+
+        match db.decide_device_authorization(&authorization.user_code,
+                                             &user.id, body.approved) {
+            // Decided by someone else meanwhile.
+            Ok(0) => EndpointError::with(status::BadRequest, 119,
+                Some("Invalid or expired user code".to_owned())),
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// An ID token telling `client` who `user` is, valid for `ttl`
+    /// seconds. It is signed with the current `EdDSA` key, so clients can
+    /// check it with the JWK set.
+    fn id_token(user: &User, client: &OAuthClient, scopes: &[String],
+                nonce: Option<String>, issuer: &str, db: &UsersDb, ttl: i64)
+        -> Result<String, IronResult<Response>> {
+        let mut claims = try!(user_claims(user, scopes, db));
+        let iat = now();
+        claims.insert("iss".to_owned(), Json::String(issuer.to_owned()));
+        claims.insert("aud".to_owned(), Json::String(client.id.clone()));
+        claims.insert("iat".to_owned(), Json::I64(iat));
+        claims.insert("exp".to_owned(), Json::I64(iat + ttl));
+        if let Some(nonce) = nonce {
+            claims.insert("nonce".to_owned(), Json::String(nonce));
+        }
+
+        let key = match db.current_id_token_key() {
+            Ok(key) => key,
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+        let header = JoseHeader::new(key.algorithm.as_str(), &key.kid);
+        match key.ed25519_keypair() {
+            Some((secret_key, _)) => {
+                jws::encode(&header, &Json::Object(claims), &secret_key)
+                    .map_err(|_| EndpointError::with(
+                        status::InternalServerError, 501, None
+                    ))
+            },
+            None => Err(EndpointError::with(status::InternalServerError,
+                                            501, None))
+        }
+    }
+
+    /// GET and POST /userinfo handler.
+    /// Get the claims about the user an access token with the `openid`
+    /// scope was issued for.
+    pub fn userinfo(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let caller = match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) => caller.clone(),
+            None => return missing_token_error()
+        };
+        let openid = vec![SCOPE_OPENID.to_owned()];
+        if !caller.claims.has_scopes(&openid) {
+            return insufficient_scope_error(&openid);
+        }
+
+        let db = users_db(req, db_path);
+        match user_claims(&caller.user, &caller.claims.scopes, &db) {
+            Ok(claims) => Ok(Response::with((status::Ok,
+                                             Json::Object(claims).to_string()))),
+            Err(response) => response
+        }
+    }
+
+    /// GET /.well-known/openid-configuration handler.
+    /// Describe the OpenID Connect provider, as defined by OpenID Connect
+    /// Discovery, so clients can be set up from the issuer URL alone.
+    pub fn openid_configuration(_: &mut Request, openid: &OpenIdOptions,
+                                device_flow: bool) -> IronResult<Response> {
+        fn strings(values: &[&str]) -> Json {
+            Json::Array(values.iter()
+                              .map(|value| Json::String((*value).to_owned()))
+                              .collect())
+        }
+
+        let issuer = &openid.issuer;
+        let mut body = BTreeMap::new();
+        body.insert("issuer".to_owned(), Json::String(issuer.clone()));
+        body.insert("authorization_endpoint".to_owned(),
+                    Json::String(openid.authorization_endpoint.clone()));
+        body.insert("token_endpoint".to_owned(),
+                    Json::String(format!("{}/oauth/token", issuer)));
+        body.insert("userinfo_endpoint".to_owned(),
+                    Json::String(format!("{}/userinfo", issuer)));
+        body.insert("jwks_uri".to_owned(),
+                    Json::String(format!("{}/.well-known/jwks.json", issuer)));
+        body.insert("scopes_supported".to_owned(),
+                    strings(&[SCOPE_OPENID, SCOPE_PROFILE, SCOPE_EMAIL]));
+        body.insert("response_types_supported".to_owned(), strings(&["code"]));
+        let mut grant_types = vec!["authorization_code", "refresh_token"];
+        if device_flow {
+            body.insert("device_authorization_endpoint".to_owned(),
+                        Json::String(format!("{}/device/code", issuer)));
+            grant_types.push("urn:ietf:params:oauth:grant-type:device_code");
+        }
+        body.insert("grant_types_supported".to_owned(), strings(&grant_types));
+        body.insert("subject_types_supported".to_owned(),
+                    strings(&["public"]));
+        body.insert("id_token_signing_alg_values_supported".to_owned(),
+                    strings(&[jws::ALG_EDDSA]));
+        body.insert("token_endpoint_auth_methods_supported".to_owned(),
+                    strings(&["none", "client_secret_basic",
+                              "client_secret_post"]));
+        body.insert("code_challenge_methods_supported".to_owned(),
+                    strings(&["S256"]));
+        body.insert("claims_supported".to_owned(),
+                    strings(&["sub", "iss", "aud", "iat", "exp", "nonce",
+                              "name", "email", "email_verified"]));
+        Ok(Response::with((status::Ok, Json::Object(body).to_string())))
+    }
+}
+
+#[cfg(test)]
+describe! oauth_router_tests {
+    before_each {
+        #[allow(unused_imports)]
+        use super::{ AuthorizationRequestResponse, AuthorizeResponse,
+                     DeviceAuthorizationResponse, DeviceCodeResponse,
+                     GetClientsResponse, RegisterClientResponse };
+
+        use auth_middleware::SCOPE_USERS_ADMIN;
+        #[allow(unused_imports)]
+        use errors::{ ErrorBody, OAuthErrorBody };
+        use iron::Headers;
+        #[allow(unused_imports)]
+        use iron::headers::{ Authorization, Basic, Bearer };
+        use iron::status::Status;
+        use iron_test::request;
+        #[allow(unused_imports)]
+        use iron_test::response::extract_body_to_string;
+        #[allow(unused_imports)]
+        use jws;
+        #[allow(unused_imports)]
+        use rustc_serialize::json::{ self, Json };
+        use test_helpers::{ bearer, create_user, extract_body_to, login };
+        use users_db::{ OAuthClient, get_db_environment, remove_test_db };
+        use users_router::endpoint;
+        use UsersManager;
+
+        /// Body response for POST /oauth/token.
+        #[allow(dead_code)]
+        #[derive(Debug, RustcDecodable)]
+        struct TokenResponse {
+            access_token: String,
+            token_type: String,
+            expires_in: i64,
+            refresh_token: String,
+            scope: String,
+            id_token: Option<String>
+        }
+
+        let mut manager = UsersManager::new(&get_db_environment());
+        manager.setup_openid_provider("https://box.local/v1/",
+                                      "https://box.local/consent");
+        manager.setup_device_flow("https://box.local/device");
+        let chain = manager.get_router_chain();
+
+        let usersDb = manager.get_db();
+        usersDb.clear().ok();
+        create_user(&usersDb, "admin", true);
+        let user = create_user(&usersDb, "username", false);
+        let headers = bearer(&login(&chain, "username@example.com"));
+
+        let client = OAuthClient::new("dashboard",
+                                      vec!["http://dashboard/cb".to_owned()],
+                                      false);
+        usersDb.create_oauth_client(&client, None).unwrap();
+
+        // Test vector of RFC 7636, appendix B.
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = "E9Melhoa2OwvFrEMTJguCQaoeuaO8dxAxnBhuWBR9M";
+
+        let authorize_endpoint = &format!(
+            "http://localhost:3000{}?response_type=code&client_id={}\
+             &scope=users%3Aread&state=xyz&code_challenge={}\
+             &code_challenge_method=S256",
+            endpoint("/oauth/authorize"), client.id, challenge);
+        let token_endpoint = &format!("http://localhost:3000{}",
+                                      endpoint("/oauth/token"));
+
+        // Approve the authorization request and return the code the client
+        // is sent back with.
+        let authorize = |url: &str, headers: Headers| -> String {
+            let response = request::post(url, headers,
+                                         "{\"approved\": true}",
+                                         &chain).unwrap();
+            let body = extract_body_to::<AuthorizeResponse>(response)
+                       .unwrap();
+            let query = body.redirect_to.split('?').nth(1).unwrap()
+                            .to_owned();
+            query.split('&').find(|param| param.starts_with("code="))
+                 .unwrap()[5..].to_owned()
+        };
+    }
+
+    it "should register clients returning their secret only once" {
+        let admin = bearer(&login(&chain, "admin@example.com"));
+        let clients_endpoint = &format!("http://localhost:3000{}",
+                                        endpoint("/oauth/clients"));
+        match request::post(clients_endpoint, admin.clone(),
+                            "{\"name\": \"media server\",
+                              \"redirect_uris\": [\"http://media/cb\"],
+                              \"confidential\": true}", &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Created);
+                let body = extract_body_to::<RegisterClientResponse>
+                           (response).unwrap();
+                assert!(body.client.confidential);
+                assert!(body.client_secret.is_some());
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+
+        let response = request::get(clients_endpoint, admin, &chain).unwrap();
+        let body = extract_body_to_string(response);
+        let clients: GetClientsResponse = json::decode(&body).unwrap();
+        assert_eq!(clients.clients.len(), 2);
+        assert!(!body.contains("secret"));
+
+        // Only admins can register clients.
+        match request::post(clients_endpoint, headers,
+                            "{\"name\": \"media server\",
+                              \"redirect_uris\": [\"http://media/cb\"]}",
+                            &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                assert_eq!(error.response.status.unwrap(), Status::Forbidden);
+            }
+        };
+    }
+
+    it "should describe authorization requests to ask for consent" {
+        match request::get(authorize_endpoint, headers, &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+                let body = extract_body_to::<AuthorizationRequestResponse>
+                           (response).unwrap();
+                assert_eq!(body.client_name, "dashboard");
+                assert_eq!(body.redirect_uri, "http://dashboard/cb");
+                assert_eq!(body.scopes, vec!["users:read".to_owned()]);
+                assert_eq!(body.state, Some("xyz".to_owned()));
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+    }
+
+    it "should reject unknown clients, redirect URIs and missing PKCE" {
+        let requests = vec![
+            (format!("{}?response_type=code&client_id=unknown\
+                      &code_challenge={}&code_challenge_method=S256",
+                     endpoint("/oauth/authorize"), challenge), 117),
+            (format!("{}&redirect_uri=http%3A%2F%2Fevil%2Fcb",
+                     authorize_endpoint), 117),
+            (format!("{}?response_type=code&client_id={}",
+                     endpoint("/oauth/authorize"), client.id), 118)
+        ];
+        for (url, errno) in requests {
+            let url = if url.starts_with("http") {
+                url
+            } else {
+                format!("http://localhost:3000{}", url)
+            };
+            match request::get(&url, headers.clone(), &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::BadRequest);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, errno);
+                }
+            };
+        }
+    }
+
+    it "should return 403 Forbidden for scopes not granted to the user" {
+        let url = authorize_endpoint.replace("users%3Aread",
+                                             SCOPE_USERS_ADMIN);
+        match request::get(&url, headers, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::Forbidden);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 110);
+            }
+        };
+    }
+
+    it "should send the user back with an error when denied" {
+        match request::post(authorize_endpoint, headers,
+                            "{\"approved\": false}", &chain) {
+            Ok(response) => {
+                let body = extract_body_to::<AuthorizeResponse>(response)
+                           .unwrap();
+                assert_eq!(body.redirect_to,
+                           "http://dashboard/cb?error=access_denied&state=xyz");
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+    }
+
+    it "should issue scoped tokens for an authorization code" {
+        let code = authorize(authorize_endpoint, headers);
+        let token = match request::post(token_endpoint, Headers::new(),
+            &format!("grant_type=authorization_code&code={}\
+                      &redirect_uri=http%3A%2F%2Fdashboard%2Fcb\
+                      &client_id={}&code_verifier={}",
+                     code, client.id, verifier), &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+                extract_body_to::<TokenResponse>(response).unwrap()
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                panic!()
+            }
+        };
+        assert_eq!(token.token_type, "Bearer");
+        assert_eq!(token.scope, "users:read");
+        assert!(token.id_token.is_none());
+
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer {
+            token: token.access_token.clone()
+        }));
+        let user_endpoint = &format!("http://localhost:3000{}",
+            endpoint(&format!("/users/{}", user.id)));
+        match request::get(user_endpoint, headers.clone(), &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+
+        // Clients cannot approve authorization requests on their own.
+        match request::post(authorize_endpoint, headers,
+                            "{\"approved\": true}", &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                assert_eq!(error.response.status.unwrap(), Status::Forbidden);
+            }
+        };
+
+        // The client shows up as a session of the user.
+        let sessions = usersDb.read_sessions(&user.id).unwrap();
+        assert!(sessions.iter().any(|session| {
+            session.user_agent == "dashboard"
+        }));
+    }
+
+    it "should reject reused codes and wrong code verifiers" {
+        let code = authorize(authorize_endpoint, headers.clone());
+        let exchange = |code: &str, verifier: &str| {
+            request::post(token_endpoint, Headers::new(),
+                &format!("grant_type=authorization_code&code={}\
+                          &client_id={}&code_verifier={}",
+                         code, client.id, verifier), &chain)
+        };
+
+        match exchange(&code, "wrong-verifier-wrong-verifier-wrong-verifier") {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<OAuthErrorBody>(response)
+                           .unwrap();
+                assert_eq!(json.error, "invalid_grant");
+            }
+        };
+        // Failed attempts use the code up as well.
+        match exchange(&code, verifier) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                assert_eq!(error.response.status.unwrap(), Status::BadRequest);
+            }
+        };
+
+        let code = authorize(authorize_endpoint, headers);
+        assert!(exchange(&code, verifier).is_ok());
+        assert!(exchange(&code, verifier).is_err());
+    }
+
+    it "should rotate refresh tokens" {
+        let code = authorize(authorize_endpoint, headers);
+        let response = request::post(token_endpoint, Headers::new(),
+            &format!("grant_type=authorization_code&code={}\
+                      &client_id={}&code_verifier={}",
+                     code, client.id, verifier), &chain).unwrap();
+        let token = extract_body_to::<TokenResponse>(response).unwrap();
+
+        let refresh = format!("grant_type=refresh_token&refresh_token={}\
+                               &client_id={}", token.refresh_token,
+                              client.id);
+        match request::post(token_endpoint, Headers::new(), &refresh,
+                            &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+                let refreshed = extract_body_to::<TokenResponse>(response)
+                                .unwrap();
+                assert!(refreshed.refresh_token != token.refresh_token);
+                assert_eq!(refreshed.scope, "users:read");
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+        match request::post(token_endpoint, Headers::new(), &refresh,
+                            &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<OAuthErrorBody>(response)
+                           .unwrap();
+                assert_eq!(json.error, "invalid_grant");
+            }
+        };
+    }
+
+    it "should authenticate confidential clients" {
+        let confidential = OAuthClient::new("media server",
+                                            vec!["http://media/cb".to_owned()],
+                                            true);
+        usersDb.create_oauth_client(&confidential, Some("secret")).unwrap();
+        let body = format!("grant_type=authorization_code&code=unknown\
+                            &client_id={}&code_verifier={}",
+                           confidential.id, verifier);
+        match request::post(token_endpoint, Headers::new(), &body, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                let json = extract_body_to::<OAuthErrorBody>(response)
+                           .unwrap();
+                assert_eq!(json.error, "invalid_client");
+            }
+        };
+
+        let mut basic = Headers::new();
+        basic.set(Authorization(Basic {
+            username: confidential.id.clone(),
+            password: Some("secret".to_owned())
+        }));
+        match request::post(token_endpoint, basic, &body, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                // The client is authenticated, but the code is not valid.
+                let json = extract_body_to::<OAuthErrorBody>(error.response)
+                           .unwrap();
+                assert_eq!(json.error, "invalid_grant");
+            }
+        };
+    }
+
+    it "should issue ID tokens for the openid scope" {
+        usersDb.set_email_verified(&user.id, &user.email).unwrap();
+        let url = authorize_endpoint.replace(
+            "users%3Aread", "openid%20profile%20email&nonce=n-0S6_WzA2Mj");
+        let code = authorize(&url, headers);
+        let response = request::post(token_endpoint, Headers::new(),
+            &format!("grant_type=authorization_code&code={}\
+                      &client_id={}&code_verifier={}",
+                     code, client.id, verifier), &chain).unwrap();
+        let token = extract_body_to::<TokenResponse>(response).unwrap();
+        assert_eq!(token.scope, "openid profile email");
+
+        // The ID token is signed with a key of the JWK set.
+        let id_token = jws::decode::<Json>(&token.id_token.unwrap()).unwrap();
+        let key = usersDb.current_id_token_key().unwrap();
+        let (_, public_key) = key.ed25519_keypair().unwrap();
+        assert_eq!(id_token.header.kid, Some(key.kid));
+        assert!(id_token.verify(&public_key));
+        let claims = id_token.claims;
+        assert_eq!(claims.find("iss").unwrap().as_string(),
+                   Some("https://box.local/v1"));
+        assert_eq!(claims.find("aud").unwrap().as_string(),
+                   Some(client.id.as_str()));
+        assert_eq!(claims.find("sub").unwrap().as_string(),
+                   Some(user.id.as_str()));
+        assert_eq!(claims.find("nonce").unwrap().as_string(),
+                   Some("n-0S6_WzA2Mj"));
+        assert_eq!(claims.find("name").unwrap().as_string(), Some("username"));
+        assert_eq!(claims.find("email").unwrap().as_string(),
+                   Some("username@example.com"));
+        assert_eq!(claims.find("email_verified").unwrap().as_boolean(),
+                   Some(true));
+
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: token.access_token }));
+        let userinfo_endpoint = &format!("http://localhost:3000{}",
+                                         endpoint("/userinfo"));
+        match request::get(userinfo_endpoint, headers, &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+                let userinfo = Json::from_str(&extract_body_to_string(response))
+                               .unwrap();
+                assert_eq!(userinfo.find("sub").unwrap().as_string(),
+                           Some(user.id.as_str()));
+                assert_eq!(userinfo.find("email").unwrap().as_string(),
+                           Some("username@example.com"));
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+    }
+
+    it "should only release the user claims for the openid scope" {
+        let url = authorize_endpoint.replace("users%3Aread", "openid");
+        let code = authorize(&url, headers.clone());
+        let response = request::post(token_endpoint, Headers::new(),
+            &format!("grant_type=authorization_code&code={}\
+                      &client_id={}&code_verifier={}",
+                     code, client.id, verifier), &chain).unwrap();
+        let token = extract_body_to::<TokenResponse>(response).unwrap();
+        let id_token = jws::decode::<Json>(&token.id_token.unwrap()).unwrap();
+        assert!(id_token.claims.find("sub").is_some());
+        assert!(id_token.claims.find("email").is_none());
+        assert!(id_token.claims.find("name").is_none());
+
+        // Tokens without the openid scope cannot read the user claims.
+        let userinfo_endpoint = &format!("http://localhost:3000{}",
+                                         endpoint("/userinfo"));
+        match request::get(userinfo_endpoint, headers, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::Forbidden);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 110);
+            }
+        };
+    }
+
+    it "should describe the OpenID provider" {
+        let url = &format!("http://localhost:3000{}",
+                           endpoint("/.well-known/openid-configuration"));
+        match request::get(url, Headers::new(), &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+                let body = Json::from_str(&extract_body_to_string(response))
+                           .unwrap();
+                assert_eq!(body.find("issuer").unwrap().as_string(),
+                           Some("https://box.local/v1"));
+                assert_eq!(body.find("authorization_endpoint").unwrap()
+                               .as_string(),
+                           Some("https://box.local/consent"));
+                assert_eq!(body.find("token_endpoint").unwrap().as_string(),
+                           Some("https://box.local/v1/oauth/token"));
+                assert_eq!(body.find("jwks_uri").unwrap().as_string(),
+                           Some("https://box.local/v1/.well-known/jwks.json"));
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+    }
+
+    it "should not accept the openid scope unless set up" {
+        let chain = UsersManager::new(&get_db_environment())
+                    .get_router_chain();
+        let url = authorize_endpoint.replace("users%3Aread", "openid");
+        match request::get(&url, headers, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::Forbidden);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 110);
+            }
+        };
+        let url = &format!("http://localhost:3000{}",
+                           endpoint("/.well-known/openid-configuration"));
+        assert!(request::get(url, Headers::new(), &chain).is_err());
+    }
+
+    it "should issue tokens to devices once the user approves them" {
+        let device_endpoint = &format!("http://localhost:3000{}",
+                                       endpoint("/device/code"));
+        let device = match request::post(device_endpoint, Headers::new(),
+            &format!("client_id={}&scope=users%3Aread", client.id), &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+                extract_body_to::<DeviceCodeResponse>(response).unwrap()
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                panic!()
+            }
+        };
+        assert_eq!(device.user_code.len(), 9);
+        assert_eq!(device.verification_uri, "https://box.local/device");
+        assert_eq!(device.verification_uri_complete,
+                   format!("https://box.local/device?user_code={}",
+                           device.user_code));
+
+        let poll = || request::post(token_endpoint, Headers::new(),
+            &format!("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type\
+                      %3Adevice_code&device_code={}&client_id={}",
+                     device.device_code, client.id), &chain);
+        for expected in vec!["authorization_pending", "slow_down"] {
+            match poll() {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let json = extract_body_to::<OAuthErrorBody>
+                               (error.response).unwrap();
+                    assert_eq!(json.error, expected);
+                }
+            };
+        }
+
+        // Users can type the code without the separator, in lowercase.
+        let typed = device.user_code.replace("-", "").to_lowercase();
+        let verify_endpoint = &format!("http://localhost:3000{}",
+                                       endpoint("/device/verify"));
+        match request::get(&format!("{}?user_code={}", verify_endpoint, typed),
+                           headers.clone(), &chain) {
+            Ok(response) => {
+                let body = extract_body_to::<DeviceAuthorizationResponse>
+                           (response).unwrap();
+                assert_eq!(body.client_name, "dashboard");
+                assert_eq!(body.user_code, device.user_code);
+                assert_eq!(body.scopes, vec!["users:read".to_owned()]);
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+        match request::post(verify_endpoint, headers,
+                            &format!("{{\"user_code\": \"{}\",
+                                       \"approved\": true}}", typed),
+                            &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::NoContent);
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+
+        match poll() {
+            Ok(response) => {
+                let token = extract_body_to::<TokenResponse>(response)
+                            .unwrap();
+                assert_eq!(token.scope, "users:read");
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+        let sessions = usersDb.read_sessions(&user.id).unwrap();
+        assert!(sessions.iter().any(|session| {
+            session.user_agent == "dashboard"
+        }));
+
+        // Device codes are single-use: polling again after the approval
+        // gets no other tokens.
+        match poll() {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let json = extract_body_to::<OAuthErrorBody>(error.response)
+                           .unwrap();
+                assert_eq!(json.error, "invalid_grant");
+            }
+        };
+    }
+
+    it "should tell devices when the user denies them" {
+        let device_endpoint = &format!("http://localhost:3000{}",
+                                       endpoint("/device/code"));
+        let response = request::post(device_endpoint, Headers::new(),
+            &format!("client_id={}", client.id), &chain).unwrap();
+        let device = extract_body_to::<DeviceCodeResponse>(response).unwrap();
+
+        let verify_endpoint = &format!("http://localhost:3000{}",
+                                       endpoint("/device/verify"));
+        let verify = format!("{{\"user_code\": \"{}\",
+                               \"approved\": false}}", device.user_code);
+        assert!(request::post(verify_endpoint, headers.clone(), &verify,
+                              &chain).is_ok());
+        // The decision is final.
+        match request::post(verify_endpoint, headers, &verify, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 119);
+            }
+        };
+
+        match request::post(token_endpoint, Headers::new(),
+            &format!("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type\
+                      %3Adevice_code&device_code={}&client_id={}",
+                     device.device_code, client.id), &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let json = extract_body_to::<OAuthErrorBody>(error.response)
+                           .unwrap();
+                assert_eq!(json.error, "access_denied");
+            }
+        };
+    }
+
+    it "should reject unknown user codes" {
+        let url = &format!("http://localhost:3000{}?user_code=BCDF-GHJK",
+                           endpoint("/device/verify"));
+        match request::get(url, headers, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 119);
+            }
+        };
+    }
+
+    after_each {
+        remove_test_db();
+    }
+}
@@ -0,0 +1,240 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Matching of request paths against the authenticated endpoints.
+//!
+//! Endpoints are compiled into a trie of path segments, so finding the
+//! endpoints matching a request does not depend on how many endpoints are
+//! registered but on the length of the request path.
+
+use super::auth_middleware::AuthEndpoint;
+
+use iron::method::Method;
+use std::collections::HashMap;
+
+/// Scopes required by the endpoints ending at a node, by method.
+type Entries = HashMap<Method, Vec<String>>;
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    /// Children for literal segments.
+    segments: HashMap<String, Node>,
+    /// Child for `:param` segments, matching any segment.
+    param: Option<Box<Node>>,
+    /// Endpoints whose path ends at this node.
+    entries: Entries,
+    /// Endpoints whose path ends at this node followed by a `*`, matching
+    /// this node and any path below it.
+    prefix_entries: Entries
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.split('/').filter(|segment| !segment.is_empty()).collect()
+}
+
+fn merge(scopes: &mut Option<Vec<String>>, required: Option<&Vec<String>>) {
+    if let Some(required) = required {
+        let mut all = scopes.take().unwrap_or(vec![]);
+        for scope in required {
+            if !all.contains(scope) {
+                all.push(scope.clone());
+            }
+        }
+        *scopes = Some(all);
+    }
+}
+
+/// Compiled set of authenticated endpoints.
+#[derive(Clone, Debug, Default)]
+pub struct RouteMatcher {
+    root: Node,
+    endpoints: Vec<AuthEndpoint>
+}
+
+impl RouteMatcher {
+    pub fn new(endpoints: Vec<AuthEndpoint>) -> RouteMatcher {
+        let mut matcher = RouteMatcher::default();
+        for endpoint in endpoints {
+            matcher.add(endpoint);
+        }
+        matcher
+    }
+
+    /// The endpoints the matcher was built from, in registration order.
+    pub fn endpoints(&self) -> &[AuthEndpoint] {
+        &self.endpoints
+    }
+
+    /// Add an endpoint, unless it is already registered.
+    pub fn add(&mut self, endpoint: AuthEndpoint) {
+        if self.endpoints.contains(&endpoint) {
+            return;
+        }
+
+        {
+            let AuthEndpoint(ref methods, ref path, ref scopes) = endpoint;
+            let mut segments = segments(path);
+            let prefix = segments.last() == Some(&"*");
+            if prefix {
+                segments.pop();
+            }
+
+            let mut node = &mut self.root;
+            for segment in segments {
+                let current = node;
+                node = if segment.starts_with(':') {
+                    if current.param.is_none() {
+                        current.param = Some(Box::new(Node::default()));
+                    }
+                    &mut **current.param.as_mut().unwrap()
+                } else {
+                    current.segments.entry(segment.to_owned())
+                                    .or_insert_with(Node::default)
+                };
+            }
+
+            let entries = if prefix {
+                &mut node.prefix_entries
+            } else {
+                &mut node.entries
+            };
+            for method in methods {
+                let required = entries.entry(method.clone())
+                                      .or_insert_with(Vec::new);
+                for scope in scopes {
+                    if !required.contains(scope) {
+                        required.push(scope.clone());
+                    }
+                }
+            }
+        }
+
+        self.endpoints.push(endpoint);
+    }
+
+    /// Remove the given endpoints. The trie is compiled again from the
+    /// remaining endpoints.
+    pub fn remove(&mut self, endpoints: &[AuthEndpoint]) {
+        let remaining = self.endpoints.iter()
+            .filter(|endpoint| !endpoints.contains(endpoint))
+            .cloned().collect();
+        *self = RouteMatcher::new(remaining);
+    }
+
+    /// The scopes required by all the endpoints matching a `method` request
+    /// to `path`, or `None` if no endpoint matches it.
+    pub fn find(&self, method: &Method, path: &str) -> Option<Vec<String>> {
+        let mut scopes = None;
+        RouteMatcher::collect(&self.root, method, &segments(path),
+                              &mut scopes);
+        scopes
+    }
+
+    fn collect(node: &Node, method: &Method, path: &[&str],
+               scopes: &mut Option<Vec<String>>) {
+        merge(scopes, node.prefix_entries.get(method));
+        if path.is_empty() {
+            merge(scopes, node.entries.get(method));
+            return;
+        }
+        if let Some(child) = node.segments.get(path[0]) {
+            RouteMatcher::collect(child, method, &path[1..], scopes);
+        }
+        if let Some(ref child) = node.param {
+            RouteMatcher::collect(child, method, &path[1..], scopes);
+        }
+    }
+}
+
+#[cfg(test)]
+describe! route_matcher_tests {
+    before_each {
+        use auth_middleware::AuthEndpoint;
+        use iron::method::Method;
+
+        let matcher = RouteMatcher::new(vec![
+            AuthEndpoint(vec![Method::Get, Method::Delete],
+                         "/users".to_owned(), vec![]),
+            AuthEndpoint(vec![Method::Get], "/users/:id".to_owned(),
+                         vec!["users:read".to_owned()]),
+            AuthEndpoint(vec![Method::Put], "/users/:id".to_owned(),
+                         vec!["users:admin".to_owned()]),
+            AuthEndpoint(vec![Method::Get], "/users/:id/roles/:role".to_owned(),
+                         vec![]),
+            AuthEndpoint(vec![Method::Get], "/users/me".to_owned(),
+                         vec!["profile".to_owned()]),
+            AuthEndpoint(vec![Method::Get, Method::Put],
+                         "/services/*".to_owned(), vec![]),
+            AuthEndpoint(vec![Method::Put], "/services/lights/*".to_owned(),
+                         vec!["lights:control".to_owned()])
+        ]);
+    }
+
+    it "should match literal paths" {
+        assert_eq!(matcher.find(&Method::Get, "/users"), Some(vec![]));
+        assert_eq!(matcher.find(&Method::Delete, "users"), Some(vec![]));
+        assert_eq!(matcher.find(&Method::Get, "/users/"), Some(vec![]));
+        assert_eq!(matcher.find(&Method::Get, "/"), None);
+        assert_eq!(matcher.find(&Method::Get, "/userss"), None);
+    }
+
+    it "should match param segments" {
+        assert_eq!(matcher.find(&Method::Get, "/users/1"),
+                   Some(vec!["users:read".to_owned()]));
+        assert_eq!(matcher.find(&Method::Get, "/users/1/roles/admin"),
+                   Some(vec![]));
+        assert_eq!(matcher.find(&Method::Get, "/users/1/roles"), None);
+        assert_eq!(matcher.find(&Method::Get, "/users/1/roles/admin/foo"),
+                   None);
+    }
+
+    it "should gather the scopes of all the matching endpoints" {
+        let scopes = matcher.find(&Method::Get, "/users/me").unwrap();
+        assert_eq!(scopes.len(), 2);
+        assert!(scopes.contains(&"users:read".to_owned()));
+        assert!(scopes.contains(&"profile".to_owned()));
+    }
+
+    it "should match per method" {
+        assert_eq!(matcher.find(&Method::Put, "/users/1"),
+                   Some(vec!["users:admin".to_owned()]));
+        assert_eq!(matcher.find(&Method::Post, "/users/1"), None);
+        assert_eq!(matcher.find(&Method::Put, "/users"), None);
+    }
+
+    it "should match prefixes with a trailing wildcard" {
+        assert_eq!(matcher.find(&Method::Get, "/services"), Some(vec![]));
+        assert_eq!(matcher.find(&Method::Get, "/services/lights/1/state"),
+                   Some(vec![]));
+        assert_eq!(matcher.find(&Method::Put, "/services/lights/1/state"),
+                   Some(vec!["lights:control".to_owned()]));
+        assert_eq!(matcher.find(&Method::Put, "/services/doors/1"),
+                   Some(vec![]));
+        assert_eq!(matcher.find(&Method::Post, "/services/lights/1"), None);
+    }
+
+    it "should stop matching removed endpoints" {
+        let mut matcher = matcher.clone();
+        matcher.remove(&[
+            AuthEndpoint(vec![Method::Get], "/users/:id".to_owned(),
+                         vec!["users:read".to_owned()]),
+            AuthEndpoint(vec![Method::Put], "/services/lights/*".to_owned(),
+                         vec!["lights:control".to_owned()])
+        ]);
+        assert_eq!(matcher.endpoints().len(), 5);
+        assert_eq!(matcher.find(&Method::Get, "/users/1"), None);
+        assert_eq!(matcher.find(&Method::Get, "/users/me"),
+                   Some(vec!["profile".to_owned()]));
+        assert_eq!(matcher.find(&Method::Put, "/services/lights/1"),
+                   Some(vec![]));
+    }
+
+    it "should ignore duplicated endpoints" {
+        let mut matcher = matcher.clone();
+        let count = matcher.endpoints().len();
+        matcher.add(AuthEndpoint(vec![Method::Get], "/users/me".to_owned(),
+                                 vec!["profile".to_owned()]));
+        assert_eq!(matcher.endpoints().len(), count);
+    }
+}
@@ -0,0 +1,63 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers shared by the tests of the routers.
+
+use iron::Headers;
+use iron::headers::{ Authorization, Basic, Bearer };
+use iron::middleware::Chain;
+use iron::prelude::Response;
+use iron_test::request;
+use iron_test::response::extract_body_to_string;
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{ self, DecodeResult, Json };
+use users_db::{ User, UserBuilder, UsersDb };
+use users_router::endpoint;
+
+/// Password of the users created by `create_user`.
+pub static PASSWORD: &'static str = "password";
+
+pub fn extract_body_to<T: Decodable>(response: Response) -> DecodeResult<T> {
+    json::decode(&extract_body_to_string(response))
+}
+
+/// URL of the HTTP API endpoint at `path`.
+pub fn url(path: &str) -> String {
+    format!("http://localhost:3000{}", endpoint(path))
+}
+
+/// Create an active user named `name`, with the email address
+/// `name@example.com` and `PASSWORD`.
+pub fn create_user(db: &UsersDb, name: &str, admin: bool) -> User {
+    db.create(&UserBuilder::new(None)
+              .name(name.to_owned())
+              .password(PASSWORD.to_owned())
+              .email(format!("{}@example.com", name))
+              .admin(admin)
+              .active(true)
+              .finalize().unwrap()).unwrap()
+}
+
+/// Log the user with `email` in with `PASSWORD`, returning the body of the
+/// response.
+pub fn login(chain: &Chain, email: &str) -> Json {
+    let mut headers = Headers::new();
+    headers.set(Authorization(Basic {
+        username: email.to_owned(),
+        password: Some(PASSWORD.to_owned())
+    }));
+    let response = request::post(&url("/login"), headers, "", chain).unwrap();
+    Json::from_str(&extract_body_to_string(response)).unwrap()
+}
+
+/// Headers authenticating requests with the session token of a login
+/// response body.
+pub fn bearer(body: &Json) -> Headers {
+    let mut headers = Headers::new();
+    headers.set(Authorization(Bearer {
+        token: body.find("session_token").unwrap().as_string().unwrap()
+                   .to_owned()
+    }));
+    headers
+}
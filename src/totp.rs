@@ -0,0 +1,146 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Time-based one-time passwords, as defined by
+//! [RFC 6238](https://tools.ietf.org/html/rfc6238).
+//!
+//! Codes use the parameters every authenticator app supports: HMAC-SHA1,
+//! 6 digits and 30 seconds time steps.
+
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use uuid::Uuid;
+
+/// Duration of a time step, in seconds.
+pub static PERIOD: i64 = 30;
+
+/// Number of digits of the codes.
+pub static DIGITS: u32 = 6;
+
+static BASE32_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A new random secret of 160 bits, the size recommended by RFC 4226.
+pub fn generate_secret() -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input_str(&format!("{}{}", Uuid::new_v4().simple(),
+                              Uuid::new_v4().simple()));
+    let mut digest = [0; 32];
+    hasher.result(&mut digest);
+    digest[..20].to_vec()
+}
+
+/// Unpadded base 32 encoding of `data`, as defined by RFC 4648, which is
+/// how authenticator apps expect secrets.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = ((buffer << 8) | *byte as u32) & 0xffff;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize]
+                         as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize]
+                     as char);
+    }
+    encoded
+}
+
+/// The HOTP value of `secret` for `counter`, as defined by RFC 4226.
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut message = [0; 8];
+    for (i, byte) in message.iter_mut().enumerate() {
+        *byte = (counter >> (56 - 8 * i)) as u8;
+    }
+    let mut hmac = Hmac::new(Sha1::new(), secret);
+    hmac.input(&message);
+    let result = hmac.result();
+    let digest = result.code();
+
+    // Dynamic truncation.
+    let offset = (digest[19] & 0xf) as usize;
+    let binary = ((digest[offset] as u32 & 0x7f) << 24) |
+                 ((digest[offset + 1] as u32) << 16) |
+                 ((digest[offset + 2] as u32) << 8) |
+                 (digest[offset + 3] as u32);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// The code of `secret` for the time step `step`.
+pub fn code(secret: &[u8], step: i64) -> String {
+    format!("{:01$}", hotp(secret, step as u64), DIGITS as usize)
+}
+
+/// The time step of `time`, in seconds since the epoch.
+pub fn time_step(time: i64) -> i64 {
+    time / PERIOD
+}
+
+/// The time step `code` was generated for with `secret`, if it is valid at
+/// `time`. Codes of the previous and next steps are accepted too, to make
+/// up for the drift of the clock of the user device.
+pub fn verify(secret: &[u8], code: &str, time: i64) -> Option<i64> {
+    let code = code.trim();
+    let step = time_step(time);
+    for candidate in &[step - 1, step, step + 1] {
+        if *candidate >= 0 &&
+           fixed_time_eq(self::code(secret, *candidate).as_bytes(),
+                         code.as_bytes()) {
+            return Some(*candidate);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+describe! totp_tests {
+    before_each {
+        // Secret of the test vectors of RFC 4226 and RFC 6238.
+        let secret = b"12345678901234567890";
+    }
+
+    it "should compute the HOTP values of RFC 4226" {
+        let values = [755224, 287082, 359152, 969429, 338314, 254676,
+                      287922, 162583, 399871, 520489];
+        for (counter, value) in values.iter().enumerate() {
+            assert_eq!(hotp(secret, counter as u64), *value);
+        }
+    }
+
+    it "should compute the TOTP values of RFC 6238" {
+        // The RFC uses 8 digits codes, whose last 6 digits are ours.
+        assert_eq!(code(secret, time_step(59)), "287082");
+        assert_eq!(code(secret, time_step(1111111109)), "081804");
+        assert_eq!(code(secret, time_step(1234567890)), "005924");
+        assert_eq!(code(secret, time_step(2000000000)), "279037");
+    }
+
+    it "should accept codes of the adjacent time steps only" {
+        let time = 1234567890;
+        let step = time_step(time);
+        assert_eq!(verify(secret, "005924", time), Some(step));
+        assert_eq!(verify(secret, &code(secret, step - 1), time),
+                   Some(step - 1));
+        assert_eq!(verify(secret, &code(secret, step + 1), time),
+                   Some(step + 1));
+        assert_eq!(verify(secret, &code(secret, step - 2), time), None);
+        assert_eq!(verify(secret, "", time), None);
+    }
+
+    it "should encode secrets in base 32" {
+        assert_eq!(base32_encode(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(generate_secret().len(), 20);
+    }
+}
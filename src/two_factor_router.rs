@@ -0,0 +1,979 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Two-factor authentication.
+//!
+//! Users enroll an authenticator app with POST /users/:id/2fa/totp and
+//! enable it by confirming a first code. From then on, POST /login answers
+//! their password with a short-lived challenge instead of a session token,
+//! and the login is completed by POST /login/2fa with a code of the app.
+//!
+//! WebAuthn credentials, managed by `WebAuthnRouter`, are second factors
+//! too when the host application sets up a relying party.
+//!
+//! Enabling a second factor also gives users a set of single-use recovery
+//! codes, which complete the login in place of a code of the app, so they
+//! are not locked out when they lose their phone.
+
+use super::auth_cache::users_db;
+use super::auth_middleware::{ AuthenticatedUser, SCOPE_USERS_ADMIN, now };
+use super::errors::*;
+use super::oauth_router::{ first, percent_encode, random_token };
+use super::totp;
+use super::users_db::{ LoginChallenge, ReadFilter, TotpSecret, User,
+                       UsersDb };
+use super::webauthn::RelyingParty;
+use super::webauthn_router::{ AssertionBody, WebAuthnRouter };
+
+use iron::prelude::*;
+use iron::status;
+use router::Router;
+use urlencoded::UrlEncodedQuery;
+use rustc_serialize::hex::{ FromHex, ToHex };
+use rustc_serialize::json::{ self, Json };
+
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// Lifetime of login challenges, in seconds.
+static LOGIN_CHALLENGE_TTL: i64 = 300;
+
+/// Number of failed attempts after which a login challenge is dropped.
+static MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+/// Issuer shown by authenticator apps along with the account.
+static TOTP_ISSUER: &'static str = "FoxBox";
+
+/// Seconds after logging in during which users can remove their second
+/// factors without giving a one-time code.
+static RECENT_LOGIN: i64 = 300;
+
+/// Number of recovery codes given to users.
+static RECOVERY_CODES: usize = 10;
+
+/// Second factor method of authenticator apps.
+static METHOD_TOTP: &'static str = "totp";
+
+/// Second factor method of WebAuthn credentials.
+static METHOD_WEBAUTHN: &'static str = "webauthn";
+
+/// Second factor method of recovery codes.
+static METHOD_RECOVERY_CODE: &'static str = "recovery_code";
+
+/// Body response for POST /users/:id/2fa/totp
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct EnrollTotpResponse {
+    secret: String,
+    otpauth_uri: String
+}
+
+/// Body response for POST /users/:id/2fa/totp/confirm and
+/// POST /users/:id/2fa/recovery_codes
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>
+}
+
+/// A new random recovery code of 80 bits, like `q3fa-7xkc-m2wd-p5ne`.
+/// That is too many to guess, even with the hashes of the codes.
+fn recovery_code() -> String {
+    let random = totp::base32_encode(&totp::generate_secret()[..10])
+        .to_lowercase();
+    format!("{}-{}-{}-{}", &random[..4], &random[4..8], &random[8..12],
+            &random[12..16])
+}
+
+/// A recovery code as typed by the user, without separators nor
+/// uppercase.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase()).collect()
+}
+
+/// Manages the second factors of users. The routes are installed by
+/// `UsersRouter`, which relies on it to log users in.
+pub struct TwoFactorRouter;
+
+impl TwoFactorRouter {
+    /// The user whose second factors are managed by the request, who must
+    /// be the caller, signed in with a session of their own.
+    pub fn get_enrolling_user(req: &Request)
+        -> Result<User, IronResult<Response>> {
+        let user_id = req.extensions.get::<Router>().unwrap()
+            .find("id").unwrap_or("").to_owned();
+        match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) if caller.user.id == user_id &&
+                            caller.is_first_party() => Ok(caller.user.clone()),
+            Some(_) => Err(EndpointError::with(status::Forbidden, 403, None)),
+            None => Err(EndpointError::with(status::Unauthorized, 401, None))
+        }
+    }
+
+    /// Check that the caller can remove a second factor of the user whose
+    /// id is in the route, and return that id. It takes a session of their
+    /// own: admins need the `users:admin` scope, while owners must have
+    /// logged in recently or give a current code of their authenticator
+    /// app as the `code` query parameter, so a stolen session is not
+    /// enough to strip an account of its second factors.
+    pub fn authorize_removal(req: &mut Request, db: &UsersDb)
+        -> Result<String, IronResult<Response>> {
+        let user_id = req.extensions.get::<Router>().unwrap()
+            .find("id").unwrap_or("").to_owned();
+        let code = req.get::<UrlEncodedQuery>().ok()
+            .and_then(|params| first(&params, "code"));
+        let session_id = match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) if !caller.is_first_party() => {
+                return Err(EndpointError::with(status::Forbidden, 403, None));
+            },
+            Some(caller) if caller.user.id == user_id => {
+                caller.claims.sid.clone()
+            },
+            Some(caller) if caller.user.is_admin &&
+                            caller.claims.has_scopes(
+                                &[SCOPE_USERS_ADMIN.to_owned()]) => {
+                return Ok(user_id);
+            },
+            Some(_) => {
+                return Err(EndpointError::with(status::Forbidden, 403, None));
+            },
+            None => {
+                return Err(EndpointError::with(status::Unauthorized, 401,
+                                               None));
+            }
+        };
+
+        match db.read_session(&session_id) {
+            Ok(Some(ref session))
+                if now() - session.created_at < RECENT_LOGIN => {
+                return Ok(user_id);
+            },
+            Ok(_) => {},
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        }
+        let code = match code {
+            Some(code) => code,
+            None => return Err(EndpointError::with(status::Forbidden, 125,
+                Some("Log in again or give a one-time code".to_owned())))
+        };
+        let invalid_code = || EndpointError::with(status::Unauthorized, 120,
+            Some("Invalid one-time code".to_owned()));
+        match db.read_totp_secret(&user_id) {
+            Ok(Some(ref secret)) if secret.confirmed => {
+                match TwoFactorRouter::check_totp(secret, &code, db) {
+                    Ok(true) => Ok(user_id),
+                    Ok(false) => Err(invalid_code()),
+                    Err(response) => Err(response)
+                }
+            },
+            Ok(_) => Err(invalid_code()),
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+
+    /// Verify `code` against `secret`, recording its time step so it
+    /// cannot be used again.
+    fn check_totp(secret: &TotpSecret, code: &str, db: &UsersDb)
+        -> Result<bool, IronResult<Response>> {
+        let key = match secret.secret.from_hex() {
+            Ok(key) => key,
+            Err(_) => return Err(EndpointError::with(
+                status::InternalServerError, 501, None
+            ))
+        };
+        match totp::verify(&key, code, now()) {
+            Some(step) => match db.use_totp_step(&secret.user_id, step) {
+                Ok(updated) => Ok(updated == 1),
+                Err(error) => {
+                    println!("{:?}", error);
+                    Err(from_sqlite_error(error))
+                }
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// POST /users/:id/2fa/totp handler.
+    /// Generate a new TOTP secret for the user and return it as an
+    /// `otpauth` URI, to be scanned by an authenticator app. It is enabled
+    /// once confirmed with POST /users/:id/2fa/totp/confirm.
+    pub fn enroll_totp(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user = match TwoFactorRouter::get_enrolling_user(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+
+        let db = users_db(req, db_path);
+        match db.read_totp_secret(&user.id) {
+            Ok(Some(ref secret)) if secret.confirmed => {
+                return EndpointError::with(status::Conflict, 409,
+                    Some("TOTP is already enabled".to_owned()));
+            },
+            Ok(_) => {},
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+
+        let secret = totp::generate_secret();
+        if let Err(error) = db.create_totp_secret(&user.id, &secret.to_hex()) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        let secret = totp::base32_encode(&secret);
+        let issuer = percent_encode(TOTP_ISSUER);
+        let otpauth_uri = format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1\
+             &digits={}&period={}",
+            issuer, percent_encode(&user.email), secret, issuer,
+            totp::DIGITS, totp::PERIOD
+        );
+        let body = match json::encode(&EnrollTotpResponse {
+            secret: secret,
+            otpauth_uri: otpauth_uri
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Created, body)))
+    }
+
+    /// POST /users/:id/2fa/totp/confirm handler.
+    /// Enable the TOTP secret of the user with a first code, which proves
+    /// that the authenticator app is set up.
+    pub fn confirm_totp(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct ConfirmTotpBody {
+            code: String
+        }
+
+        let user = match TwoFactorRouter::get_enrolling_user(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let body: ConfirmTotpBody = match json::decode(&payload) {
+            Ok(body) => body,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_decoder_error(error);
+            }
+        };
+
+        let db = users_db(req, db_path);
+        let secret = match db.read_totp_secret(&user.id) {
+            Ok(Some(ref secret)) if secret.confirmed => {
+                return EndpointError::with(status::Conflict, 409,
+                    Some("TOTP is already enabled".to_owned()));
+            },
+            Ok(Some(secret)) => secret,
+            Ok(None) => return EndpointError::with(status::NotFound, 404,
+                Some("TOTP is not enrolled".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        match TwoFactorRouter::check_totp(&secret, &body.code, &db) {
+            Ok(true) => TwoFactorRouter::new_recovery_codes(&user, &db),
+            Ok(false) => EndpointError::with(status::BadRequest, 120,
+                Some("Invalid one-time code".to_owned())),
+            Err(response) => response
+        }
+    }
+
+    /// POST /users/:id/2fa/recovery_codes handler.
+    /// Replace the recovery codes of a user who enabled a second factor,
+    /// for instance once most of them are used.
+    pub fn regenerate_recovery_codes(req: &mut Request, db_path: &str,
+                                     rp: Option<&RelyingParty>)
+        -> IronResult<Response> {
+        let user = match TwoFactorRouter::get_enrolling_user(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+
+        let db = users_db(req, db_path);
+        match TwoFactorRouter::second_factors(&user.id, &db, rp) {
+            Ok(ref methods) if methods.is_empty() => {
+                EndpointError::with(status::NotFound, 404,
+                    Some("No second factor is enabled".to_owned()))
+            },
+            Ok(_) => TwoFactorRouter::new_recovery_codes(&user, &db),
+            Err(response) => response
+        }
+    }
+
+    /// Give `user` a new set of recovery codes, replacing the previous
+    /// ones. This is the only time they are shown: only a hash of them is
+    /// stored.
+    fn new_recovery_codes(user: &User, db: &UsersDb)
+        -> IronResult<Response> {
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| recovery_code()).collect();
+        let normalized: Vec<String> = codes.iter()
+            .map(|code| normalize_recovery_code(code)).collect();
+        if let Err(error) = db.replace_recovery_codes(&user.id, &normalized) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        let body = match json::encode(&RecoveryCodesResponse {
+            recovery_codes: codes
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Created, body)))
+    }
+
+    /// DELETE /users/:id/2fa/totp handler.
+    /// Disable the TOTP second factor of a user. Admins can disable it for
+    /// users who lost their authenticator app. Recovery codes are dropped
+    /// along with the last second factor.
+    pub fn disable_totp(req: &mut Request, db_path: &str,
+                        rp: Option<&RelyingParty>) -> IronResult<Response> {
+        let db = users_db(req, db_path);
+        let user_id = match TwoFactorRouter::authorize_removal(req, &db) {
+            Ok(user_id) => user_id,
+            Err(response) => return response
+        };
+        match db.delete_totp_secret(&user_id) {
+            Ok(0) => return EndpointError::with(status::NotFound, 404,
+                Some("TOTP is not enrolled".to_owned())),
+            Ok(_) => {},
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+        if let Err(response) =
+            TwoFactorRouter::drop_unused_recovery_codes(&user_id, &db, rp) {
+            return response;
+        }
+        Ok(Response::with(status::NoContent))
+    }
+
+    /// Remove the recovery codes of the user identified by `user_id` if
+    /// they have no second factor left.
+    pub fn drop_unused_recovery_codes(user_id: &str, db: &UsersDb,
+                                      rp: Option<&RelyingParty>)
+        -> Result<(), IronResult<Response>> {
+        let methods = try!(TwoFactorRouter::second_factors(user_id, db, rp));
+        if !methods.is_empty() {
+            return Ok(());
+        }
+        match db.delete_recovery_codes(user_id) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+
+    /// The second factor methods enabled by the user identified by
+    /// `user_id`, one of which must be completed to log in. WebAuthn
+    /// credentials only count if a relying party is set up, and recovery
+    /// codes are only offered along with another method.
+    pub fn second_factors(user_id: &str, db: &UsersDb,
+                          rp: Option<&RelyingParty>)
+        -> Result<Vec<String>, IronResult<Response>> {
+        let mut methods = vec![];
+        match db.read_totp_secret(user_id) {
+            Ok(Some(ref secret)) if secret.confirmed => {
+                methods.push(METHOD_TOTP.to_owned());
+            },
+            Ok(_) => {},
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        }
+        if rp.is_some() {
+            match db.read_webauthn_credentials(user_id) {
+                Ok(ref credentials) if credentials.is_empty() => {},
+                Ok(_) => methods.push(METHOD_WEBAUTHN.to_owned()),
+                Err(error) => {
+                    println!("{:?}", error);
+                    return Err(from_sqlite_error(error));
+                }
+            }
+        }
+        if methods.is_empty() {
+            return Ok(methods);
+        }
+        match db.count_recovery_codes(user_id) {
+            Ok(0) => {},
+            Ok(_) => methods.push(METHOD_RECOVERY_CODE.to_owned()),
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        }
+        Ok(methods)
+    }
+
+    /// Answer the password of `user` with a challenge, to be completed
+    /// with POST /login/2fa and one of the second factor `methods`. The
+    /// challenge is also the one of the WebAuthn assertion, if offered.
+    pub fn challenge(user: &User, methods: Vec<String>, db: &UsersDb,
+                     rp: Option<&RelyingParty>) -> IronResult<Response> {
+        let challenge = random_token();
+        if let Err(error) = db.create_login_challenge(
+            &challenge, &LoginChallenge {
+                user_id: user.id.clone(),
+                attempts: 0,
+                expires_at: now() + LOGIN_CHALLENGE_TTL
+            }) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        let mut body = BTreeMap::new();
+        match rp {
+            Some(rp) if methods.iter().any(|m| m == METHOD_WEBAUTHN) => {
+                let credentials = match db.read_webauthn_credentials(&user.id) {
+                    Ok(credentials) => credentials,
+                    Err(error) => {
+                        println!("{:?}", error);
+                        return from_sqlite_error(error);
+                    }
+                };
+                // The password was checked already, so the authenticator
+                // does not need to verify the user.
+                body.insert("webauthn".to_owned(),
+                            WebAuthnRouter::request_options(
+                                &challenge, rp, &credentials, "discouraged"));
+            },
+            _ => {}
+        }
+        body.insert("challenge".to_owned(), Json::String(challenge));
+        body.insert("methods".to_owned(), Json::Array(
+            methods.into_iter().map(Json::String).collect()));
+        body.insert("expires_in".to_owned(), Json::I64(LOGIN_CHALLENGE_TTL));
+        Ok(Response::with((status::Ok, Json::Object(body).to_string())))
+    }
+
+    /// Complete the login challenge in the body of `req` with the second
+    /// factor sent along, either a TOTP code, a WebAuthn assertion or a
+    /// recovery code, returning the user logging in. Challenges can only
+    /// be completed once and are dropped after too many failed attempts.
+    pub fn complete_login(req: &mut Request, db: &UsersDb,
+                          rp: Option<&RelyingParty>)
+        -> Result<User, IronResult<Response>> {
+        #[derive(RustcDecodable, Debug)]
+        struct CompleteLoginBody {
+            challenge: String,
+            code: Option<String>,
+            webauthn: Option<AssertionBody>,
+            recovery_code: Option<String>
+        }
+
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let body: CompleteLoginBody = match json::decode(&payload) {
+            Ok(body) => body,
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_decoder_error(error));
+            }
+        };
+
+        let login = match db.read_login_challenge(&body.challenge) {
+            Ok(Some(ref login))
+                if login.expires_at > now() &&
+                   login.attempts < MAX_CHALLENGE_ATTEMPTS => login.clone(),
+            Ok(_) => return Err(EndpointError::with(status::Unauthorized,
+                121, Some("Invalid or expired login challenge".to_owned()))),
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+
+        let user = match db.read(ReadFilter::Id(login.user_id)) {
+            Ok(mut users) => {
+                if users.len() != 1 || !users[0].is_active {
+                    return Err(EndpointError::with(status::Unauthorized, 401,
+                                                   None));
+                }
+                users.remove(0)
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+
+        let secret = match db.read_totp_secret(&user.id) {
+            Ok(secret) => secret,
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+        let verified = match (secret, body.code, body.recovery_code) {
+            (Some(ref secret), Some(ref code), _) if secret.confirmed => {
+                try!(TwoFactorRouter::check_totp(secret, code, db))
+            },
+            (_, None, _) if body.webauthn.is_some() => match rp {
+                Some(rp) => try!(WebAuthnRouter::check_assertion(
+                    body.webauthn.as_ref().unwrap(), &body.challenge,
+                    Some(&user.id), false, rp, db)).is_some(),
+                None => false
+            },
+            (_, None, Some(ref code)) => {
+                match db.use_recovery_code(&user.id,
+                                           &normalize_recovery_code(code)) {
+                    Ok(used) => used == 1,
+                    Err(error) => {
+                        println!("{:?}", error);
+                        return Err(from_sqlite_error(error));
+                    }
+                }
+            },
+            _ => false
+        };
+        if !verified {
+            if let Err(error) = db.fail_login_challenge(&body.challenge) {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+            if body.webauthn.is_some() {
+                return Err(EndpointError::with(status::Unauthorized, 122,
+                    Some("Invalid WebAuthn assertion".to_owned())));
+            }
+            return Err(EndpointError::with(status::Unauthorized, 120,
+                Some("Invalid one-time code".to_owned())));
+        }
+
+        // Only the request that deletes the challenge logs in, so it cannot
+        // be completed twice by concurrent requests with different factors.
+        match db.delete_login_challenge(&body.challenge) {
+            Ok(1) => Ok(user),
+            Ok(_) => Err(EndpointError::with(status::Unauthorized, 121,
+                Some("Invalid or expired login challenge".to_owned()))),
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+describe! two_factor_router_tests {
+    before_each {
+        use super::{ EnrollTotpResponse, RecoveryCodesResponse };
+
+        use auth_middleware::now;
+        use errors::ErrorBody;
+        #[allow(unused_imports)]
+        use iron::Headers;
+        #[allow(unused_imports)]
+        use iron::headers::{ Authorization, Basic };
+        use iron::status::Status;
+        use iron_test::request;
+        #[allow(unused_imports)]
+        use iron_test::response::extract_body_to_string;
+        use rustc_serialize::hex::FromHex;
+        #[allow(unused_imports)]
+        use rustc_serialize::json::{ self, Json };
+        use test_helpers::{ bearer, create_user, extract_body_to, login };
+        use totp;
+        use users_db::{ get_db_environment, remove_test_db };
+        use users_router::endpoint;
+        use UsersManager;
+
+        #[derive(Debug, RustcDecodable)]
+        struct ChallengeResponse {
+            challenge: String,
+            methods: Vec<String>,
+            expires_in: i64
+        }
+
+        let manager = UsersManager::new(&get_db_environment());
+        let chain = manager.get_router_chain();
+
+        let usersDb = manager.get_db();
+        usersDb.clear().ok();
+        create_user(&usersDb, "admin", true);
+        let user = create_user(&usersDb, "username", false);
+
+        let login_endpoint = &format!("http://localhost:3000{}",
+                                      endpoint("/login"));
+        let login_2fa_endpoint = &format!("http://localhost:3000{}",
+                                          endpoint("/login/2fa"));
+        let totp_endpoint = &format!("http://localhost:3000{}",
+            endpoint(&format!("/users/{}/2fa/totp", user.id)));
+
+        let headers = bearer(&login(&chain, "username@example.com"));
+
+        // Enroll and confirm TOTP for the user, returning the secret and
+        // the recovery codes.
+        let enable_totp = || -> (Vec<u8>, Vec<String>) {
+            request::post(totp_endpoint, headers.clone(), "", &chain)
+                .unwrap();
+            let secret = usersDb.read_totp_secret(&user.id).unwrap().unwrap()
+                                .secret.from_hex().unwrap();
+            let code = totp::code(&secret, totp::time_step(now()));
+            let response = request::post(
+                &format!("{}/confirm", totp_endpoint), headers.clone(),
+                &format!("{{\"code\": \"{}\"}}", code), &chain).unwrap();
+            (secret, extract_body_to::<RecoveryCodesResponse>(response)
+                     .unwrap().recovery_codes)
+        };
+        // A code not used yet.
+        let next_code = |secret: &[u8]| -> String {
+            totp::code(secret, totp::time_step(now()) + 1)
+        };
+    }
+
+    it "should enroll TOTP and enable it with a first code" {
+        use super::normalize_recovery_code;
+
+        let enrollment = match request::post(totp_endpoint, headers.clone(),
+                                             "", &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Created);
+                extract_body_to::<EnrollTotpResponse>(response).unwrap()
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                panic!()
+            }
+        };
+        assert_eq!(enrollment.secret.len(), 32);
+        assert_eq!(enrollment.otpauth_uri, format!(
+            "otpauth://totp/FoxBox:username%40example.com?secret={}\
+             &issuer=FoxBox&algorithm=SHA1&digits=6&period=30",
+            enrollment.secret));
+
+        // Logging in does not require TOTP until it is confirmed.
+        assert!(login(&chain, "username@example.com")
+                    .find("session_token").is_some());
+
+        let confirm_endpoint = &format!("{}/confirm", totp_endpoint);
+        match request::post(confirm_endpoint, headers.clone(),
+                            "{\"code\": \"000000\"}", &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 120);
+            }
+        };
+
+        let secret = usersDb.read_totp_secret(&user.id).unwrap().unwrap()
+                            .secret.from_hex().unwrap();
+        match request::post(confirm_endpoint, headers.clone(),
+                            &format!("{{\"code\": \"{}\"}}",
+                                     next_code(&secret)), &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Created);
+                let codes = extract_body_to::<RecoveryCodesResponse>(response)
+                            .unwrap().recovery_codes;
+                assert_eq!(codes.len(), 10);
+                // 16 base 32 characters, 80 bits each.
+                assert!(codes.iter().all(|code| {
+                    normalize_recovery_code(code).len() == 16
+                }));
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+
+        // Enabled secrets cannot be replaced.
+        match request::post(totp_endpoint, headers, "", &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                assert_eq!(error.response.status.unwrap(), Status::Conflict);
+            }
+        };
+    }
+
+    it "should only let users enroll themselves" {
+        let admin_headers = bearer(&login(&chain, "admin@example.com"));
+        match request::post(totp_endpoint, admin_headers, "", &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                assert_eq!(error.response.status.unwrap(), Status::Forbidden);
+            }
+        };
+    }
+
+    it "should ask for a TOTP code to complete the login" {
+        let (secret, _) = enable_totp();
+
+        let challenge = match request::post(login_endpoint, {
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: "username@example.com".to_owned(),
+                password: Some("password".to_owned())
+            }));
+            headers
+        }, "", &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+                let body = extract_body_to_string(response);
+                assert!(!body.contains("session_token"));
+                json::decode::<ChallengeResponse>(&body).unwrap()
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                panic!()
+            }
+        };
+        assert_eq!(challenge.methods, vec!["totp".to_owned(),
+                                          "recovery_code".to_owned()]);
+
+        match request::post(login_2fa_endpoint, Headers::new(),
+                            &format!("{{\"challenge\": \"{}\",
+                                       \"code\": \"000000\"}}",
+                                     challenge.challenge), &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 120);
+            }
+        };
+
+        let code = next_code(&secret);
+        let complete = format!("{{\"challenge\": \"{}\", \"code\": \"{}\"}}",
+                               challenge.challenge, code);
+        match request::post(login_2fa_endpoint, Headers::new(), &complete,
+                            &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Ok);
+                let body = Json::from_str(&extract_body_to_string(response))
+                           .unwrap();
+                assert!(body.find("session_token").is_some());
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+
+        // Neither challenges nor codes can be used twice.
+        match request::post(login_2fa_endpoint, Headers::new(), &complete,
+                            &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let json = extract_body_to::<ErrorBody>(error.response)
+                           .unwrap();
+                assert_eq!(json.errno, 121);
+            }
+        };
+        let challenge = login(&chain, "username@example.com");
+        let challenge = challenge.find("challenge").unwrap().as_string()
+                                 .unwrap();
+        match request::post(login_2fa_endpoint, Headers::new(),
+                            &format!("{{\"challenge\": \"{}\",
+                                       \"code\": \"{}\"}}", challenge, code),
+                            &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let json = extract_body_to::<ErrorBody>(error.response)
+                           .unwrap();
+                assert_eq!(json.errno, 120);
+            }
+        };
+    }
+
+    it "should drop login challenges after too many failed attempts" {
+        let (secret, _) = enable_totp();
+        let challenge = login(&chain, "username@example.com");
+        let challenge = challenge.find("challenge").unwrap().as_string()
+                                 .unwrap();
+        let attempt = |code: &str| {
+            request::post(login_2fa_endpoint, Headers::new(),
+                          &format!("{{\"challenge\": \"{}\",
+                                     \"code\": \"{}\"}}", challenge, code),
+                          &chain)
+        };
+        for _ in 0..5 {
+            assert!(attempt("000000").is_err());
+        }
+        match attempt(&next_code(&secret)) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let json = extract_body_to::<ErrorBody>(error.response)
+                           .unwrap();
+                assert_eq!(json.errno, 121);
+            }
+        };
+    }
+
+    it "should let admins disable TOTP" {
+        enable_totp();
+        let admin_headers = bearer(&login(&chain, "admin@example.com"));
+        match request::delete(totp_endpoint, admin_headers, &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::NoContent);
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+        assert!(login(&chain, "username@example.com")
+                    .find("session_token").is_some());
+        assert_eq!(usersDb.count_recovery_codes(&user.id).unwrap(), 0);
+    }
+
+    it "should only let admins with the users:admin scope disable TOTP" {
+        use auth_middleware::{ SessionClaims, SessionToken };
+        use iron::headers::Bearer;
+        use users_db::{ ReadFilter, Session };
+
+        enable_totp();
+        let admin = usersDb.read(ReadFilter::Email(
+            "admin@example.com".to_owned())).unwrap().remove(0);
+        let session = Session::new(&admin.id, "", "");
+        usersDb.create_session(&session).unwrap();
+        let mut claims = SessionClaims::new(&admin, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+        claims.scopes = vec![];
+        let key = usersDb.current_signing_key().unwrap();
+        let mut admin_headers = Headers::new();
+        admin_headers.set(Authorization(Bearer {
+            token: SessionToken::from_claims(&admin, claims, &key).unwrap()
+        }));
+        match request::delete(totp_endpoint, admin_headers, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                assert_eq!(error.response.status.unwrap(), Status::Forbidden);
+            }
+        };
+        assert!(usersDb.read_totp_secret(&user.id).unwrap().is_some());
+    }
+
+    it "should ask owners for a code once their login is not recent" {
+        use auth_middleware::{ SessionClaims, SessionToken };
+        use iron::headers::Bearer;
+        use users_db::Session;
+
+        let (secret, _) = enable_totp();
+        let mut session = Session::new(&user.id, "", "");
+        session.created_at = now() - 3600;
+        usersDb.create_session(&session).unwrap();
+        let claims = SessionClaims::new(&user, &session.id,
+                                        SessionToken::DEFAULT_TTL);
+        let key = usersDb.current_signing_key().unwrap();
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer {
+            token: SessionToken::from_claims(&user, claims, &key).unwrap()
+        }));
+
+        match request::delete(totp_endpoint, headers.clone(), &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::Forbidden);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 125);
+            }
+        };
+        let with_code = |code: &str| format!("{}?code={}", totp_endpoint, code);
+        match request::delete(&with_code("000000"), headers.clone(), &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 120);
+            }
+        };
+        match request::delete(&with_code(&next_code(&secret)), headers,
+                              &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::NoContent);
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+    }
+
+    it "should accept each recovery code once" {
+        let (_, codes) = enable_totp();
+        let recover = |code: &str| {
+            let challenge = login(&chain, "username@example.com");
+            let challenge = challenge.find("challenge").unwrap().as_string()
+                                     .unwrap().to_owned();
+            request::post(login_2fa_endpoint, Headers::new(),
+                          &format!("{{\"challenge\": \"{}\",
+                                     \"recovery_code\": \"{}\"}}",
+                                   challenge, code),
+                          &chain)
+        };
+
+        // Codes are accepted whatever the case and separators.
+        match recover(&codes[0].to_uppercase().replace("-", " ")) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Created);
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+        match recover(&codes[0]) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let json = extract_body_to::<ErrorBody>(error.response)
+                           .unwrap();
+                assert_eq!(json.errno, 120);
+            }
+        };
+        assert_eq!(usersDb.count_recovery_codes(&user.id).unwrap(), 9);
+
+        // Regenerating the codes invalidates the previous ones.
+        let regenerate_endpoint = &format!("http://localhost:3000{}",
+            endpoint(&format!("/users/{}/2fa/recovery_codes", user.id)));
+        let new_codes = match request::post(regenerate_endpoint,
+                                            headers.clone(), "", &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Created);
+                extract_body_to::<RecoveryCodesResponse>(response).unwrap()
+                    .recovery_codes
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                panic!()
+            }
+        };
+        assert_eq!(new_codes.len(), 10);
+        assert!(recover(&codes[1]).is_err());
+        assert!(recover(&new_codes[1]).is_ok());
+    }
+
+    after_each {
+        remove_test_db();
+    }
+}
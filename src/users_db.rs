@@ -33,9 +33,16 @@
 //! can inspect `UserWithError#error` attribute to see what failed during initialization.
 //!
 
+use super::auth_cache::AuthCache;
+use super::auth_middleware::now;
+
+use crypto::digest::Digest;
+use crypto::ed25519;
+use crypto::sha2::Sha256;
 use pwhash::bcrypt;
 use libc::c_int;
 use rusqlite::{ self, Connection };
+use rustc_serialize::hex::FromHex;
 use uuid::Uuid;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, RustcDecodable, RustcEncodable)]
@@ -250,6 +257,356 @@ impl UserBuilder {
     }
 }
 
+/// A refresh token as stored in the database. The token itself is never
+/// stored, only its SHA-256 hash.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RefreshToken {
+    pub user_id: String,
+    pub session_id: String,
+    pub expires_at: i64
+}
+
+/// A pending invitation, allowing its user to activate the account once.
+/// As with refresh tokens, only the hash of the activation token is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invitation {
+    pub user_id: String,
+    pub expires_at: i64
+}
+
+/// A pending password reset, allowing its user to set a new password once.
+/// Only the hash of the reset token is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordReset {
+    pub user_id: String,
+    pub expires_at: i64
+}
+
+/// Algorithms session tokens can be signed with. `EdDSA` is the only
+/// asymmetric one: RSA (`RS256`) and ECDSA (`ES256`) keys are not supported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SigningAlgorithm {
+    /// HMAC SHA-256, keyed with the server key and the user secret.
+    HS256,
+    /// Ed25519 signatures, verifiable with the published public key.
+    EdDSA
+}
+
+impl SigningAlgorithm {
+    /// The JOSE `alg` name of the algorithm.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SigningAlgorithm::HS256 => "HS256",
+            SigningAlgorithm::EdDSA => "EdDSA"
+        }
+    }
+
+    pub fn from_str(alg: &str) -> Option<SigningAlgorithm> {
+        match alg {
+            "HS256" => Some(SigningAlgorithm::HS256),
+            "EdDSA" => Some(SigningAlgorithm::EdDSA),
+            _ => None
+        }
+    }
+}
+
+/// What a signing key signs. Session tokens are never accepted when
+/// signed with a key of another purpose.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyPurpose {
+    /// Session tokens, with the key rotated through `POST /keys`.
+    Session,
+    /// OpenID Connect ID tokens.
+    IdToken
+}
+
+impl KeyPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            KeyPurpose::Session => "session",
+            KeyPurpose::IdToken => "id_token"
+        }
+    }
+
+    pub fn from_str(purpose: &str) -> Option<KeyPurpose> {
+        match purpose {
+            "session" => Some(KeyPurpose::Session),
+            "id_token" => Some(KeyPurpose::IdToken),
+            _ => None
+        }
+    }
+}
+
+/// A server key used to sign tokens, which carry its `kid` in their
+/// header. Keys are valid until their `retire_at` time, if any.
+///
+/// For `EdDSA` keys the secret is the hex encoded Ed25519 seed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: SigningAlgorithm,
+    pub purpose: KeyPurpose,
+    pub secret: String,
+    pub created_at: i64,
+    pub retire_at: Option<i64>
+}
+
+impl SigningKey {
+    /// A new random `HS256` key.
+    pub fn new() -> SigningKey {
+        SigningKey::with_algorithm(SigningAlgorithm::HS256)
+    }
+
+    /// A new random `EdDSA` key to sign ID tokens with.
+    pub fn for_id_tokens() -> SigningKey {
+        SigningKey {
+            purpose: KeyPurpose::IdToken,
+            ..SigningKey::with_algorithm(SigningAlgorithm::EdDSA)
+        }
+    }
+
+    /// A new random session key for the given algorithm.
+    pub fn with_algorithm(algorithm: SigningAlgorithm) -> SigningKey {
+        let secret = match algorithm {
+            SigningAlgorithm::HS256 => Uuid::new_v4().simple().to_string(),
+            SigningAlgorithm::EdDSA => {
+                // 32 bytes seed.
+                hash_token(&format!("{}{}", Uuid::new_v4().simple(),
+                                    Uuid::new_v4().simple()))
+            }
+        };
+        SigningKey {
+            kid: Uuid::new_v4().simple().to_string(),
+            algorithm: algorithm,
+            purpose: KeyPurpose::Session,
+            secret: secret,
+            created_at: now(),
+            retire_at: None
+        }
+    }
+
+    /// The Ed25519 secret and public keys of an `EdDSA` key.
+    pub fn ed25519_keypair(&self) -> Option<([u8; 64], [u8; 32])> {
+        if self.algorithm != SigningAlgorithm::EdDSA {
+            return None;
+        }
+        match self.secret.from_hex() {
+            Ok(ref seed) if seed.len() == 32 => Some(ed25519::keypair(seed)),
+            _ => None
+        }
+    }
+
+    /// Whether tokens signed with this key are still accepted.
+    pub fn is_retired(&self) -> bool {
+        match self.retire_at {
+            Some(retire_at) => retire_at <= now(),
+            None => false
+        }
+    }
+}
+
+/// A server side session. Every session token belongs to a session, so
+/// removing the session signs the device that owns it out.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub user_agent: String,
+    pub remote_addr: String
+}
+
+impl Session {
+    /// A new session for `user_id` starting now.
+    pub fn new(user_id: &str, user_agent: &str, remote_addr: &str) -> Session {
+        let now = now();
+        Session {
+            id: Uuid::new_v4().simple().to_string(),
+            user_id: user_id.to_owned(),
+            created_at: now,
+            last_seen: now,
+            user_agent: user_agent.to_owned(),
+            remote_addr: remote_addr.to_owned()
+        }
+    }
+}
+
+/// A named set of permissions that can be assigned to users. Permissions
+/// are free form strings defined by the host application, like
+/// `lights:control`.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Role {
+    pub name: String,
+    pub permissions: Vec<String>
+}
+
+/// A long lived personal access token, which users create for scripts and
+/// other services to access their account. Only a hash of the token itself
+/// is stored, so it is not part of this struct.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct AccessToken {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: i64,
+    /// Tokens without expiration are valid until revoked.
+    pub expires_at: Option<i64>,
+    pub last_used: Option<i64>
+}
+
+impl AccessToken {
+    /// A new access token for `user_id` created now.
+    pub fn new(user_id: &str, name: &str, scopes: Vec<String>,
+               expires_at: Option<i64>) -> AccessToken {
+        AccessToken {
+            id: Uuid::new_v4().simple().to_string(),
+            user_id: user_id.to_owned(),
+            name: name.to_owned(),
+            scopes: scopes,
+            created_at: now(),
+            expires_at: expires_at,
+            last_used: None
+        }
+    }
+}
+
+/// A third-party application registered to get access to users accounts
+/// through OAuth 2.0. Confidential clients authenticate with a secret, of
+/// which only a hash is stored, so it is not part of this struct. Public
+/// clients, like single-page or native applications, have none.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct OAuthClient {
+    pub id: String,
+    pub name: String,
+    /// Exact URIs users can be sent back to once they approve or deny an
+    /// authorization request.
+    pub redirect_uris: Vec<String>,
+    pub confidential: bool,
+    pub created_at: i64
+}
+
+impl OAuthClient {
+    /// A new client registered now.
+    pub fn new(name: &str, redirect_uris: Vec<String>, confidential: bool)
+        -> OAuthClient {
+        OAuthClient {
+            id: Uuid::new_v4().simple().to_string(),
+            name: name.to_owned(),
+            redirect_uris: redirect_uris,
+            confidential: confidential,
+            created_at: now()
+        }
+    }
+}
+
+/// A single-use OAuth 2.0 authorization code, issued once a user approves
+/// an authorization request. Only a hash of the code is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthorizationCode {
+    pub client_id: String,
+    pub user_id: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    /// PKCE S256 challenge the code verifier must match.
+    pub code_challenge: String,
+    /// OpenID Connect nonce, echoed in the ID token.
+    pub nonce: Option<String>,
+    pub expires_at: i64
+}
+
+/// A refresh token issued to an OAuth 2.0 client. Unlike the refresh tokens
+/// of the users' own sessions, it keeps the scopes the user approved. Only
+/// a hash of the token is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OAuthRefreshToken {
+    pub client_id: String,
+    pub user_id: String,
+    pub session_id: String,
+    pub scopes: Vec<String>,
+    pub expires_at: i64
+}
+
+/// A device authorization request, as defined by RFC 8628, waiting for a
+/// user to approve it with its user code while the device polls for
+/// tokens. Only a hash of the device code is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceAuthorization {
+    /// Code the user types in, without separators.
+    pub user_code: String,
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    /// The user who approved or denied the request.
+    pub user_id: Option<String>,
+    /// Decision of the user, `None` while the request is pending.
+    pub approved: Option<bool>,
+    /// Last time the device polled for tokens.
+    pub last_polled_at: Option<i64>,
+    pub expires_at: i64
+}
+
+/// The TOTP second factor of a user. It is only enabled once confirmed
+/// with a first code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TotpSecret {
+    pub user_id: String,
+    /// Hex encoded secret shared with the authenticator app of the user.
+    pub secret: String,
+    pub confirmed: bool,
+    /// Time step of the last code used, which cannot be used again.
+    pub last_step: Option<i64>
+}
+
+/// A login waiting for the second factor of the user. Only a hash of the
+/// challenge is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoginChallenge {
+    pub user_id: String,
+    /// Number of failed attempts to complete the login.
+    pub attempts: i64,
+    pub expires_at: i64
+}
+
+/// A WebAuthn credential of a user, like a passkey of their phone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebAuthnCredential {
+    /// Base64url encoded credential id, chosen by the authenticator.
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    /// Hex encoded public key: an uncompressed P-256 point or an Ed25519
+    /// key.
+    pub public_key: String,
+    /// Signature counter of the authenticator, which must increase with
+    /// each assertion unless the authenticator does not count them.
+    pub sign_count: i64,
+    pub created_at: i64,
+    pub last_used: Option<i64>
+}
+
+/// A pending WebAuthn ceremony. Only a hash of its challenge is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebAuthnChallenge {
+    /// The user registering a credential, `None` for passwordless logins,
+    /// where the user is only known from the credential.
+    pub user_id: Option<String>,
+    /// Client data type of the ceremony: `webauthn.create` or
+    /// `webauthn.get`.
+    pub kind: String,
+    pub expires_at: i64
+}
+
+fn split_scopes(scopes: String) -> Vec<String> {
+    scopes.split_whitespace().map(|scope| scope.to_owned()).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
 pub enum ReadFilter {
     All,
     Id(String),
@@ -265,7 +622,10 @@ pub struct UsersDb {
     // rusqlite::Connection already implements the Drop trait for the
     // inner connection so we don't need to manually close it. It will
     // be closed when the UsersDb instances go out of scope.
-    connection: Connection
+    connection: Connection,
+    // The cache of the auth middleware, told about the changes made
+    // through this connection that make its entries stale.
+    cache: Option<AuthCache>
 }
 
 #[cfg(test)]
@@ -284,7 +644,35 @@ impl UsersDb {
     /// When the database instance exits the scope where it was created, it is
     /// automatically closed.
     pub fn new(path: &str) -> UsersDb {
-        let connection = Connection::open(path).unwrap();
+        let db = UsersDb::open(path);
+        db.create_tables();
+        db
+    }
+
+    /// Opens the database without creating its tables, which must exist
+    /// already.
+    pub fn open(path: &str) -> UsersDb {
+        UsersDb {
+            connection: Connection::open(path).unwrap(),
+            cache: None
+        }
+    }
+
+    /// Tells `cache` about the changes made through this connection that
+    /// make its entries stale.
+    pub fn invalidating(mut self, cache: AuthCache) -> UsersDb {
+        self.cache = Some(cache);
+        self
+    }
+
+    fn invalidate<F>(&self, f: F) where F: FnOnce(&AuthCache) {
+        if let Some(ref cache) = self.cache {
+            f(cache);
+        }
+    }
+
+    fn create_tables(&self) {
+        let connection = &self.connection;
         connection.execute("CREATE TABLE IF NOT EXISTS users (
                 id          TEXT PRIMARY KEY,
                 name        TEXT,
@@ -294,10 +682,140 @@ impl UsersDb {
                 is_admin    BOOL NOT NULL DEFAULT 0,
                 is_active   BOOL NOT NULL DEFAULT 0
             )", &[]).unwrap();
-
-        UsersDb {
-            connection: connection
-        }
+        connection.execute("CREATE TABLE IF NOT EXISTS refresh_tokens (
+                token       TEXT PRIMARY KEY,
+                user_id     TEXT NOT NULL,
+                session_id  TEXT NOT NULL,
+                expires_at  INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS revoked_tokens (
+                jti         TEXT PRIMARY KEY,
+                expires_at  INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS sessions (
+                id          TEXT PRIMARY KEY,
+                user_id     TEXT NOT NULL,
+                created_at  INTEGER NOT NULL,
+                last_seen   INTEGER NOT NULL,
+                user_agent  TEXT,
+                remote_addr TEXT
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS invitations (
+                token       TEXT PRIMARY KEY,
+                user_id     TEXT NOT NULL,
+                expires_at  INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS password_resets (
+                token       TEXT PRIMARY KEY,
+                user_id     TEXT NOT NULL,
+                expires_at  INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS password_reset_requests (
+                email        TEXT PRIMARY KEY,
+                requested_at INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS signing_keys (
+                kid         TEXT PRIMARY KEY,
+                algorithm   TEXT NOT NULL,
+                purpose     TEXT NOT NULL DEFAULT 'session',
+                secret      TEXT NOT NULL,
+                created_at  INTEGER NOT NULL,
+                retire_at   INTEGER
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS roles (
+                name        TEXT PRIMARY KEY
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS role_permissions (
+                role        TEXT NOT NULL,
+                permission  TEXT NOT NULL,
+                PRIMARY KEY (role, permission)
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS user_roles (
+                user_id     TEXT NOT NULL,
+                role        TEXT NOT NULL,
+                PRIMARY KEY (user_id, role)
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS access_tokens (
+                id          TEXT PRIMARY KEY,
+                token       TEXT NOT NULL UNIQUE,
+                user_id     TEXT NOT NULL,
+                name        TEXT NOT NULL,
+                scopes      TEXT NOT NULL,
+                created_at  INTEGER NOT NULL,
+                expires_at  INTEGER,
+                last_used   INTEGER
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS oauth_clients (
+                id            TEXT PRIMARY KEY,
+                secret        TEXT,
+                name          TEXT NOT NULL,
+                redirect_uris TEXT NOT NULL,
+                created_at    INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS oauth_codes (
+                code           TEXT PRIMARY KEY,
+                client_id      TEXT NOT NULL,
+                user_id        TEXT NOT NULL,
+                redirect_uri   TEXT NOT NULL,
+                scopes         TEXT NOT NULL,
+                code_challenge TEXT NOT NULL,
+                nonce          TEXT,
+                expires_at     INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS oauth_refresh_tokens (
+                token       TEXT PRIMARY KEY,
+                client_id   TEXT NOT NULL,
+                user_id     TEXT NOT NULL,
+                session_id  TEXT NOT NULL,
+                scopes      TEXT NOT NULL,
+                expires_at  INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS device_codes (
+                device_code    TEXT PRIMARY KEY,
+                user_code      TEXT NOT NULL UNIQUE,
+                client_id      TEXT NOT NULL,
+                scopes         TEXT NOT NULL,
+                user_id        TEXT,
+                approved       BOOL,
+                last_polled_at INTEGER,
+                expires_at     INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS totp_secrets (
+                user_id     TEXT PRIMARY KEY,
+                secret      TEXT NOT NULL,
+                confirmed   BOOL NOT NULL DEFAULT 0,
+                last_step   INTEGER
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS login_challenges (
+                challenge   TEXT PRIMARY KEY,
+                user_id     TEXT NOT NULL,
+                attempts    INTEGER NOT NULL DEFAULT 0,
+                expires_at  INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS recovery_codes (
+                code        TEXT NOT NULL,
+                user_id     TEXT NOT NULL,
+                PRIMARY KEY (user_id, code)
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS webauthn_credentials (
+                id          TEXT PRIMARY KEY,
+                user_id     TEXT NOT NULL,
+                name        TEXT NOT NULL,
+                public_key  TEXT NOT NULL,
+                sign_count  INTEGER NOT NULL,
+                created_at  INTEGER NOT NULL,
+                last_used   INTEGER
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS webauthn_challenges (
+                challenge   TEXT PRIMARY KEY,
+                user_id     TEXT,
+                kind        TEXT NOT NULL,
+                expires_at  INTEGER NOT NULL
+            )", &[]).unwrap();
+        connection.execute("CREATE TABLE IF NOT EXISTS verified_emails (
+                user_id     TEXT PRIMARY KEY,
+                email       TEXT NOT NULL
+            )", &[]).unwrap();
     }
 
     /// Empties the complete database.
@@ -315,11 +833,34 @@ impl UsersDb {
     /// assert!(users.is_empty());
     /// ```
     pub fn clear(&self) -> rusqlite::Result<()> {
-        self.connection.execute_batch(
+        let result = self.connection.execute_batch(
             "DELETE FROM users;
+             DELETE FROM refresh_tokens;
+             DELETE FROM revoked_tokens;
+             DELETE FROM sessions;
+             DELETE FROM invitations;
+             DELETE FROM password_resets;
+             DELETE FROM password_reset_requests;
+             DELETE FROM signing_keys;
+             DELETE FROM roles;
+             DELETE FROM role_permissions;
+             DELETE FROM user_roles;
+             DELETE FROM access_tokens;
+             DELETE FROM oauth_clients;
+             DELETE FROM oauth_codes;
+             DELETE FROM oauth_refresh_tokens;
+             DELETE FROM device_codes;
+             DELETE FROM totp_secrets;
+             DELETE FROM login_challenges;
+             DELETE FROM recovery_codes;
+             DELETE FROM webauthn_credentials;
+             DELETE FROM webauthn_challenges;
+             DELETE FROM verified_emails;
              DELETE FROM SQLITE_SEQUENCE WHERE name='users';
              VACUUM;"
-        )
+        );
+        self.invalidate(|cache| cache.invalidate_all());
+        result
     }
 
     /// Creates a new user.
@@ -438,16 +979,1278 @@ impl UsersDb {
 
     /// Replaces a pre-existent user, identified by its database id.
     pub fn update(&self, user: &User) -> rusqlite::Result<c_int> {
-        self.connection.execute("UPDATE users
+        let result = self.connection.execute("UPDATE users
             SET name=$1, email=$2, password=$3, secret=$4, is_admin=$5, is_active=$6
             WHERE id=$7",
             &[&user.name, &user.email, &user.password, &user.secret,
-              &user.is_admin, &user.is_active, &user.id])
+              &user.is_admin, &user.is_active, &user.id]);
+        self.invalidate(|cache| cache.invalidate_user(&user.id));
+        result
     }
 
     /// Removes a user identified by its id.
     pub fn delete(&self, id: &str) -> rusqlite::Result<c_int> {
-        self.connection.execute("DELETE FROM users WHERE id=$1", &[&id])
+        try!(self.connection.execute(
+            "DELETE FROM refresh_tokens WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM sessions WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM user_roles WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM access_tokens WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM oauth_codes WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM oauth_refresh_tokens WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM device_codes WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM totp_secrets WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM login_challenges WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM recovery_codes WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM webauthn_credentials WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM webauthn_challenges WHERE user_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM verified_emails WHERE user_id=$1", &[&id]
+        ));
+        try!(self.delete_invitations(id));
+        try!(self.delete_password_resets(id));
+        let result = self.connection.execute("DELETE FROM users WHERE id=$1",
+                                             &[&id]);
+        self.invalidate(|cache| {
+            cache.invalidate_user(id);
+            cache.invalidate_user_sessions(id);
+        });
+        result
+    }
+
+    /// Stores a new refresh token for the given user and session, valid
+    /// until `expires_at` (in seconds since the epoch).
+    pub fn create_refresh_token(&self, token: &str, user_id: &str,
+                                session_id: &str, expires_at: i64)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("INSERT INTO refresh_tokens
+            (token, user_id, session_id, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&hash_token(token), &user_id, &session_id, &expires_at])
+    }
+
+    /// Retrieves the refresh token matching `token`, if any.
+    pub fn read_refresh_token(&self, token: &str)
+        -> rusqlite::Result<Option<RefreshToken>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT user_id, session_id, expires_at FROM refresh_tokens
+             WHERE token=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&hash_token(token)]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(RefreshToken {
+                    user_id: row.get(0),
+                    session_id: row.get(1),
+                    expires_at: row.get(2)
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Revokes a single refresh token.
+    pub fn delete_refresh_token(&self, token: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("DELETE FROM refresh_tokens WHERE token=$1",
+                                &[&hash_token(token)])
+    }
+
+    /// Revokes a single refresh token, if it belongs to the user identified
+    /// by `user_id`.
+    pub fn delete_user_refresh_token(&self, token: &str, user_id: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM refresh_tokens WHERE token=$1 AND user_id=$2",
+            &[&hash_token(token), &user_id])
+    }
+
+    /// Stores a new invitation for the given user, valid until `expires_at`.
+    /// Previous invitations of the user are invalidated, as well as any
+    /// expired invitation.
+    pub fn create_invitation(&self, token: &str, user_id: &str,
+                             expires_at: i64) -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM invitations WHERE user_id=$1 OR expires_at<=$2",
+            &[&user_id, &now()]
+        ));
+        self.connection.execute("INSERT INTO invitations
+            (token, user_id, expires_at) VALUES ($1, $2, $3)",
+            &[&hash_token(token), &user_id, &expires_at])
+    }
+
+    /// Retrieves the invitation matching the activation token `token`, if
+    /// any.
+    pub fn read_invitation(&self, token: &str)
+        -> rusqlite::Result<Option<Invitation>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT user_id, expires_at FROM invitations WHERE token=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&hash_token(token)]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(Invitation {
+                    user_id: row.get(0),
+                    expires_at: row.get(1)
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Consumes the invitation matching the activation token `token` if it
+    /// has not expired at `now`. Of concurrent requests using the same
+    /// token, only the one that deletes the invitation gets it.
+    pub fn take_invitation(&self, token: &str, now: i64)
+        -> rusqlite::Result<Option<Invitation>> {
+        let invitation = match try!(self.read_invitation(token)) {
+            Some(invitation) => invitation,
+            None => return Ok(None)
+        };
+        match try!(self.connection.execute(
+            "DELETE FROM invitations WHERE token=$1 AND expires_at>$2",
+            &[&hash_token(token), &now]
+        )) {
+            1 => Ok(Some(invitation)),
+            _ => Ok(None)
+        }
+    }
+
+    /// Invalidates all the invitations of a user.
+    pub fn delete_invitations(&self, user_id: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("DELETE FROM invitations WHERE user_id=$1",
+                                &[&user_id])
+    }
+
+    /// Stores a new password reset for the given user, valid until
+    /// `expires_at`. Previous password resets of the user are invalidated,
+    /// as well as any expired one.
+    pub fn create_password_reset(&self, token: &str, user_id: &str,
+                                 expires_at: i64) -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM password_resets WHERE user_id=$1 OR expires_at<=$2",
+            &[&user_id, &now()]
+        ));
+        self.connection.execute("INSERT INTO password_resets
+            (token, user_id, expires_at) VALUES ($1, $2, $3)",
+            &[&hash_token(token), &user_id, &expires_at])
+    }
+
+    /// Retrieves the password reset matching the reset token `token`, if
+    /// any.
+    pub fn read_password_reset(&self, token: &str)
+        -> rusqlite::Result<Option<PasswordReset>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT user_id, expires_at FROM password_resets WHERE token=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&hash_token(token)]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(PasswordReset {
+                    user_id: row.get(0),
+                    expires_at: row.get(1)
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Consumes the password reset matching the reset token `token` if it
+    /// has not expired at `now`, returning the id of its user. The row is
+    /// deleted before anything else, so a token is only ever taken once.
+    pub fn take_password_reset(&self, token: &str, now: i64)
+        -> rusqlite::Result<Option<String>> {
+        let user_id = match try!(self.read_password_reset(token)) {
+            Some(reset) => reset.user_id,
+            None => return Ok(None)
+        };
+        match try!(self.connection.execute(
+            "DELETE FROM password_resets WHERE token=$1 AND expires_at>$2",
+            &[&hash_token(token), &now]
+        )) {
+            1 => Ok(Some(user_id)),
+            _ => Ok(None)
+        }
+    }
+
+    /// Records a password reset request for `email` at `now`, unless one
+    /// was recorded less than `interval` seconds before, and returns
+    /// whether it was. Only a hash of the address is stored.
+    pub fn record_password_reset_request(&self, email: &str, now: i64,
+                                         interval: i64)
+        -> rusqlite::Result<bool> {
+        try!(self.connection.execute(
+            "DELETE FROM password_reset_requests WHERE requested_at<=$1",
+            &[&(now - interval)]
+        ));
+        let recorded = try!(self.connection.execute(
+            "INSERT OR IGNORE INTO password_reset_requests
+             (email, requested_at) VALUES ($1, $2)",
+            &[&hash_token(&email.to_lowercase()), &now]
+        ));
+        Ok(recorded == 1)
+    }
+
+    /// Invalidates all the password resets of a user.
+    pub fn delete_password_resets(&self, user_id: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM password_resets WHERE user_id=$1", &[&user_id]
+        )
+    }
+
+    /// Revokes the session token identified by `jti` until `expires_at`,
+    /// its own expiration time. Entries for tokens that are already
+    /// expired are removed on the way, as they are rejected anyway.
+    pub fn revoke_token(&self, jti: &str, expires_at: i64)
+        -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM revoked_tokens WHERE expires_at<=$1", &[&now()]
+        ));
+        let result = self.connection.execute("INSERT OR REPLACE INTO revoked_tokens
+            (jti, expires_at) VALUES ($1, $2)", &[&jti, &expires_at]);
+        self.invalidate(|cache| cache.invalidate_revoked_tokens());
+        result
+    }
+
+    /// Retrieves the ids of the revoked session tokens that are not expired
+    /// yet.
+    pub fn read_revoked_tokens(&self) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT jti FROM revoked_tokens WHERE expires_at>$1"
+        ));
+        let rows = try!(stmt.query(&[&now()]));
+        let mut tokens = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            tokens.push(row.get(0));
+        }
+        Ok(tokens)
+    }
+
+    /// Opens a new session.
+    pub fn create_session(&self, session: &Session)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("INSERT INTO sessions
+            (id, user_id, created_at, last_seen, user_agent, remote_addr)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            &[&session.id, &session.user_id, &session.created_at,
+              &session.last_seen, &session.user_agent, &session.remote_addr])
+    }
+
+    /// Retrieves the session identified by `id`, if any.
+    pub fn read_session(&self, id: &str) -> rusqlite::Result<Option<Session>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT * FROM sessions WHERE id=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&id]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(UsersDb::session_from_row(&row)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Retrieves all the open sessions of a user, oldest first.
+    pub fn read_sessions(&self, user_id: &str)
+        -> rusqlite::Result<Vec<Session>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT * FROM sessions WHERE user_id=$1 ORDER BY created_at"
+        ));
+        let rows = try!(stmt.query(&[&user_id]));
+        let mut sessions = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            sessions.push(UsersDb::session_from_row(&row));
+        }
+        Ok(sessions)
+    }
+
+    fn session_from_row(row: &rusqlite::Row) -> Session {
+        Session {
+            id: row.get(0),
+            user_id: row.get(1),
+            created_at: row.get(2),
+            last_seen: row.get(3),
+            user_agent: row.get(4),
+            remote_addr: row.get(5)
+        }
+    }
+
+    /// Updates the last time the session was seen.
+    pub fn touch_session(&self, id: &str, last_seen: i64)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("UPDATE sessions SET last_seen=$1 WHERE id=$2",
+                                &[&last_seen, &id])
+    }
+
+    /// Closes a session, revoking its refresh tokens. Session tokens issued
+    /// for the session are rejected from now on.
+    pub fn delete_session(&self, id: &str) -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM refresh_tokens WHERE session_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM oauth_refresh_tokens WHERE session_id=$1", &[&id]
+        ));
+        let result = self.connection.execute("DELETE FROM sessions WHERE id=$1",
+                                             &[&id]);
+        self.invalidate(|cache| cache.invalidate_session(id));
+        result
+    }
+
+    /// Closes all the sessions of a user, revoking their refresh tokens.
+    pub fn delete_sessions(&self, user_id: &str) -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM refresh_tokens WHERE user_id=$1", &[&user_id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM oauth_refresh_tokens WHERE user_id=$1", &[&user_id]
+        ));
+        let result = self.connection.execute(
+            "DELETE FROM sessions WHERE user_id=$1", &[&user_id]
+        );
+        self.invalidate(|cache| cache.invalidate_user_sessions(user_id));
+        result
+    }
+
+    /// Stores a new signing key.
+    pub fn create_signing_key(&self, key: &SigningKey)
+        -> rusqlite::Result<c_int> {
+        let result = self.connection.execute("INSERT INTO signing_keys
+            (kid, algorithm, purpose, secret, created_at, retire_at)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+            &[&key.kid, &key.algorithm.as_str(), &key.purpose.as_str(),
+              &key.secret, &key.created_at, &key.retire_at]);
+        self.invalidate(|cache| cache.invalidate_signing_keys());
+        result
+    }
+
+    /// Retrieves all the signing keys, newest first, including the retired
+    /// ones that were not pruned yet.
+    pub fn read_signing_keys(&self) -> rusqlite::Result<Vec<SigningKey>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT kid, algorithm, purpose, secret, created_at, retire_at
+             FROM signing_keys ORDER BY created_at DESC, rowid DESC"
+        ));
+        let rows = try!(stmt.query(&[]));
+        let mut keys = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            keys.push(UsersDb::signing_key_from_row(&row));
+        }
+        Ok(keys)
+    }
+
+    /// Retrieves the signing key identified by `kid`, if any.
+    pub fn read_signing_key(&self, kid: &str)
+        -> rusqlite::Result<Option<SigningKey>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT kid, algorithm, purpose, secret, created_at, retire_at
+             FROM signing_keys WHERE kid=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&kid]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(UsersDb::signing_key_from_row(&row)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn signing_key_from_row(row: &rusqlite::Row) -> SigningKey {
+        let algorithm: String = row.get(1);
+        let purpose: String = row.get(2);
+        SigningKey {
+            kid: row.get(0),
+            algorithm: SigningAlgorithm::from_str(&algorithm)
+                .unwrap_or(SigningAlgorithm::HS256),
+            purpose: KeyPurpose::from_str(&purpose)
+                .unwrap_or(KeyPurpose::Session),
+            secret: row.get(3),
+            created_at: row.get(4),
+            retire_at: row.get(5)
+        }
+    }
+
+    /// Retrieves the key new session tokens are signed with: the newest
+    /// session key that is not scheduled for retirement. A new `HS256` key
+    /// is created if there is none.
+    pub fn current_signing_key(&self) -> rusqlite::Result<SigningKey> {
+        let current = try!(self.read_signing_keys()).into_iter()
+            .find(|key| key.retire_at.is_none() &&
+                        key.purpose == KeyPurpose::Session);
+        match current {
+            Some(key) => Ok(key),
+            None => {
+                let key = SigningKey::new();
+                try!(self.create_signing_key(&key));
+                Ok(key)
+            }
+        }
+    }
+
+    /// Retrieves the key ID tokens are signed with: the newest ID token key
+    /// that is not scheduled for retirement, whose public key relying
+    /// parties get from the JWK set. A new one is created if there is none,
+    /// without changing the key session tokens are signed with.
+    pub fn current_id_token_key(&self) -> rusqlite::Result<SigningKey> {
+        let current = try!(self.read_signing_keys()).into_iter()
+            .find(|key| key.retire_at.is_none() &&
+                        key.purpose == KeyPurpose::IdToken);
+        match current {
+            Some(key) => Ok(key),
+            None => {
+                let key = SigningKey::for_id_tokens();
+                try!(self.create_signing_key(&key));
+                Ok(key)
+            }
+        }
+    }
+
+    /// Creates a new session key for `algorithm` and schedules the
+    /// retirement of the previous ones in `grace_period` seconds, so the
+    /// tokens they signed keep working meanwhile. Already retired session
+    /// keys are removed. ID token keys are left alone.
+    pub fn rotate_signing_keys(&self, grace_period: i64,
+                               algorithm: SigningAlgorithm)
+        -> rusqlite::Result<SigningKey> {
+        let now = now();
+        let session = KeyPurpose::Session.as_str();
+        try!(self.connection.execute(
+            "DELETE FROM signing_keys WHERE retire_at<=$1 AND purpose=$2",
+            &[&now, &session]
+        ));
+        try!(self.connection.execute(
+            "UPDATE signing_keys SET retire_at=$1
+             WHERE (retire_at IS NULL OR retire_at>$1) AND purpose=$2",
+            &[&(now + grace_period), &session]
+        ));
+        let key = SigningKey::with_algorithm(algorithm);
+        try!(self.create_signing_key(&key));
+        Ok(key)
+    }
+
+    /// Retires the session key identified by `kid` at `retire_at`. ID
+    /// token keys cannot be retired this way.
+    pub fn retire_signing_key(&self, kid: &str, retire_at: i64)
+        -> rusqlite::Result<c_int> {
+        let result = self.connection.execute(
+            "UPDATE signing_keys SET retire_at=$1 WHERE kid=$2 AND purpose=$3",
+            &[&retire_at, &kid, &KeyPurpose::Session.as_str()]);
+        self.invalidate(|cache| cache.invalidate_signing_keys());
+        result
+    }
+
+    /// Creates a new role with its permissions.
+    pub fn create_role(&self, role: &Role) -> rusqlite::Result<c_int> {
+        let result = try!(self.connection.execute(
+            "INSERT INTO roles (name) VALUES ($1)", &[&role.name]
+        ));
+        try!(self.set_role_permissions(&role.name, &role.permissions));
+        Ok(result)
+    }
+
+    /// Retrieves all the roles with their permissions.
+    pub fn read_roles(&self) -> rusqlite::Result<Vec<Role>> {
+        let mut stmt = try!(
+            self.connection.prepare("SELECT name FROM roles ORDER BY name")
+        );
+        let rows = try!(stmt.query(&[]));
+        let mut names: Vec<String> = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            names.push(row.get(0));
+        }
+
+        let mut roles = Vec::new();
+        for name in names {
+            let permissions = try!(self.read_role_permissions(&name));
+            roles.push(Role {
+                name: name,
+                permissions: permissions
+            });
+        }
+        Ok(roles)
+    }
+
+    /// Retrieves the role named `name`, if any.
+    pub fn read_role(&self, name: &str) -> rusqlite::Result<Option<Role>> {
+        let exists = {
+            let mut stmt = try!(
+                self.connection.prepare("SELECT name FROM roles WHERE name=$1")
+            );
+            let mut rows = try!(stmt.query(&[&name]));
+            rows.next().is_some()
+        };
+        if !exists {
+            return Ok(None);
+        }
+        let permissions = try!(self.read_role_permissions(name));
+        Ok(Some(Role {
+            name: name.to_owned(),
+            permissions: permissions
+        }))
+    }
+
+    fn read_role_permissions(&self, name: &str)
+        -> rusqlite::Result<Vec<String>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT permission FROM role_permissions WHERE role=$1
+             ORDER BY permission"
+        ));
+        let rows = try!(stmt.query(&[&name]));
+        let mut permissions = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            permissions.push(row.get(0));
+        }
+        Ok(permissions)
+    }
+
+    /// Replaces the permissions of a pre-existent role.
+    pub fn update_role(&self, role: &Role) -> rusqlite::Result<c_int> {
+        self.set_role_permissions(&role.name, &role.permissions)
+    }
+
+    fn set_role_permissions(&self, name: &str, permissions: &[String])
+        -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM role_permissions WHERE role=$1", &[&name]
+        ));
+        for permission in permissions {
+            try!(self.connection.execute(
+                "INSERT OR IGNORE INTO role_permissions (role, permission)
+                 VALUES ($1, $2)", &[&name, permission]
+            ));
+        }
+        Ok(permissions.len() as c_int)
+    }
+
+    /// Removes a role, taking it away from the users it was assigned to.
+    pub fn delete_role(&self, name: &str) -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM role_permissions WHERE role=$1", &[&name]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM user_roles WHERE role=$1", &[&name]
+        ));
+        self.connection.execute("DELETE FROM roles WHERE name=$1", &[&name])
+    }
+
+    /// Assigns the role named `role` to a user.
+    pub fn add_user_role(&self, user_id: &str, role: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("INSERT OR IGNORE INTO user_roles
+            (user_id, role) VALUES ($1, $2)", &[&user_id, &role])
+    }
+
+    /// Takes the role named `role` away from a user.
+    pub fn remove_user_role(&self, user_id: &str, role: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM user_roles WHERE user_id=$1 AND role=$2",
+            &[&user_id, &role])
+    }
+
+    /// Retrieves the names of the roles assigned to a user.
+    pub fn read_user_roles(&self, user_id: &str)
+        -> rusqlite::Result<Vec<String>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT role FROM user_roles WHERE user_id=$1 ORDER BY role"
+        ));
+        let rows = try!(stmt.query(&[&user_id]));
+        let mut roles = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            roles.push(row.get(0));
+        }
+        Ok(roles)
+    }
+
+    /// Retrieves the permissions a user holds through its roles.
+    pub fn read_user_permissions(&self, user_id: &str)
+        -> rusqlite::Result<Vec<String>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT DISTINCT role_permissions.permission
+             FROM role_permissions JOIN user_roles
+             ON role_permissions.role = user_roles.role
+             WHERE user_roles.user_id=$1
+             ORDER BY role_permissions.permission"
+        ));
+        let rows = try!(stmt.query(&[&user_id]));
+        let mut permissions = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            permissions.push(row.get(0));
+        }
+        Ok(permissions)
+    }
+
+    /// Stores a new access token. `token` is the secret the user gets,
+    /// only its hash is stored.
+    pub fn create_access_token(&self, token: &str, access_token: &AccessToken)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("INSERT INTO access_tokens
+            (id, token, user_id, name, scopes, created_at, expires_at,
+             last_used) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[&access_token.id, &hash_token(token), &access_token.user_id,
+              &access_token.name, &access_token.scopes.join(" "),
+              &access_token.created_at, &access_token.expires_at,
+              &access_token.last_used])
+    }
+
+    /// Retrieves the access token matching the secret `token`, if any.
+    pub fn read_access_token(&self, token: &str)
+        -> rusqlite::Result<Option<AccessToken>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT id, user_id, name, scopes, created_at, expires_at,
+             last_used FROM access_tokens WHERE token=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&hash_token(token)]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(UsersDb::access_token_from_row(&row)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Retrieves all the access tokens of a user, oldest first.
+    pub fn read_access_tokens(&self, user_id: &str)
+        -> rusqlite::Result<Vec<AccessToken>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT id, user_id, name, scopes, created_at, expires_at,
+             last_used FROM access_tokens WHERE user_id=$1
+             ORDER BY created_at, rowid"
+        ));
+        let rows = try!(stmt.query(&[&user_id]));
+        let mut access_tokens = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            access_tokens.push(UsersDb::access_token_from_row(&row));
+        }
+        Ok(access_tokens)
+    }
+
+    fn access_token_from_row(row: &rusqlite::Row) -> AccessToken {
+        let scopes: String = row.get(3);
+        AccessToken {
+            id: row.get(0),
+            user_id: row.get(1),
+            name: row.get(2),
+            scopes: scopes.split_whitespace().map(|scope| scope.to_owned())
+                          .collect(),
+            created_at: row.get(4),
+            expires_at: row.get(5),
+            last_used: row.get(6)
+        }
+    }
+
+    /// Updates the last time the access token identified by `id` was used.
+    pub fn touch_access_token(&self, id: &str, last_used: i64)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "UPDATE access_tokens SET last_used=$1 WHERE id=$2",
+            &[&last_used, &id]
+        )
+    }
+
+    /// Revokes the access token identified by `id` of the given user.
+    pub fn delete_access_token(&self, user_id: &str, id: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM access_tokens WHERE id=$1 AND user_id=$2",
+            &[&id, &user_id]
+        )
+    }
+
+    /// Registers a new OAuth client. `secret` is the secret confidential
+    /// clients get, only its hash is stored.
+    pub fn create_oauth_client(&self, client: &OAuthClient,
+                               secret: Option<&str>)
+        -> rusqlite::Result<c_int> {
+        let secret = secret.map(hash_token);
+        self.connection.execute("INSERT INTO oauth_clients
+            (id, secret, name, redirect_uris, created_at)
+             VALUES ($1, $2, $3, $4, $5)",
+            &[&client.id, &secret, &client.name,
+              &client.redirect_uris.join(" "), &client.created_at])
+    }
+
+    /// Retrieves the OAuth client identified by `id`, if any.
+    pub fn read_oauth_client(&self, id: &str)
+        -> rusqlite::Result<Option<OAuthClient>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT id, secret, name, redirect_uris, created_at
+             FROM oauth_clients WHERE id=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&id]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(UsersDb::oauth_client_from_row(&row)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Retrieves all the registered OAuth clients, oldest first.
+    pub fn read_oauth_clients(&self) -> rusqlite::Result<Vec<OAuthClient>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT id, secret, name, redirect_uris, created_at
+             FROM oauth_clients ORDER BY created_at, rowid"
+        ));
+        let rows = try!(stmt.query(&[]));
+        let mut clients = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            clients.push(UsersDb::oauth_client_from_row(&row));
+        }
+        Ok(clients)
+    }
+
+    fn oauth_client_from_row(row: &rusqlite::Row) -> OAuthClient {
+        let secret: Option<String> = row.get(1);
+        OAuthClient {
+            id: row.get(0),
+            name: row.get(2),
+            redirect_uris: split_scopes(row.get(3)),
+            confidential: secret.is_some(),
+            created_at: row.get(4)
+        }
+    }
+
+    /// Whether `secret` is the secret of the confidential OAuth client
+    /// identified by `id`.
+    pub fn check_oauth_client_secret(&self, id: &str, secret: &str)
+        -> rusqlite::Result<bool> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT id FROM oauth_clients WHERE id=$1 AND secret=$2"
+        ));
+        let mut rows = try!(stmt.query(&[&id, &hash_token(secret)]));
+        Ok(rows.next().is_some())
+    }
+
+    /// Removes an OAuth client, along with its pending authorization codes
+    /// and the sessions opened for it, which revokes the tokens it was
+    /// issued.
+    pub fn delete_oauth_client(&self, id: &str) -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM sessions WHERE id IN (SELECT session_id FROM
+             oauth_refresh_tokens WHERE client_id=$1)", &[&id]
+        ));
+        self.invalidate(|cache| cache.invalidate_sessions());
+        try!(self.connection.execute(
+            "DELETE FROM oauth_refresh_tokens WHERE client_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM oauth_codes WHERE client_id=$1", &[&id]
+        ));
+        try!(self.connection.execute(
+            "DELETE FROM device_codes WHERE client_id=$1", &[&id]
+        ));
+        self.connection.execute("DELETE FROM oauth_clients WHERE id=$1",
+                                &[&id])
+    }
+
+    /// Stores a new authorization code. Expired codes are removed as well.
+    pub fn create_authorization_code(&self, code: &str,
+                                     authorization: &AuthorizationCode)
+        -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM oauth_codes WHERE expires_at<=$1", &[&now()]
+        ));
+        self.connection.execute("INSERT INTO oauth_codes
+            (code, client_id, user_id, redirect_uri, scopes, code_challenge,
+             nonce, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[&hash_token(code), &authorization.client_id,
+              &authorization.user_id, &authorization.redirect_uri,
+              &authorization.scopes.join(" "), &authorization.code_challenge,
+              &authorization.nonce, &authorization.expires_at])
+    }
+
+    /// Retrieves and removes the authorization code matching `code`, if
+    /// any, so it cannot be used twice.
+    pub fn take_authorization_code(&self, code: &str)
+        -> rusqlite::Result<Option<AuthorizationCode>> {
+        let authorization = {
+            let mut stmt = try!(self.connection.prepare(
+                "SELECT client_id, user_id, redirect_uri, scopes,
+                 code_challenge, nonce, expires_at FROM oauth_codes
+                 WHERE code=$1"
+            ));
+            let mut rows = try!(stmt.query(&[&hash_token(code)]));
+            match rows.next() {
+                Some(result_row) => {
+                    let row = try!(result_row);
+                    AuthorizationCode {
+                        client_id: row.get(0),
+                        user_id: row.get(1),
+                        redirect_uri: row.get(2),
+                        scopes: split_scopes(row.get(3)),
+                        code_challenge: row.get(4),
+                        nonce: row.get(5),
+                        expires_at: row.get(6)
+                    }
+                },
+                None => return Ok(None)
+            }
+        };
+        // Of concurrent exchanges of the same code, only the one that
+        // actually deletes it gets the authorization.
+        match try!(self.connection.execute(
+            "DELETE FROM oauth_codes WHERE code=$1", &[&hash_token(code)]
+        )) {
+            1 => Ok(Some(authorization)),
+            _ => Ok(None)
+        }
+    }
+
+    /// Stores a new refresh token issued to an OAuth client.
+    pub fn create_oauth_refresh_token(&self, token: &str,
+                                      refresh_token: &OAuthRefreshToken)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("INSERT INTO oauth_refresh_tokens
+            (token, client_id, user_id, session_id, scopes, expires_at)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            &[&hash_token(token), &refresh_token.client_id,
+              &refresh_token.user_id, &refresh_token.session_id,
+              &refresh_token.scopes.join(" "), &refresh_token.expires_at])
+    }
+
+    /// Retrieves the OAuth refresh token matching `token`, if any.
+    pub fn read_oauth_refresh_token(&self, token: &str)
+        -> rusqlite::Result<Option<OAuthRefreshToken>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT client_id, user_id, session_id, scopes, expires_at
+             FROM oauth_refresh_tokens WHERE token=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&hash_token(token)]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(OAuthRefreshToken {
+                    client_id: row.get(0),
+                    user_id: row.get(1),
+                    session_id: row.get(2),
+                    scopes: split_scopes(row.get(3)),
+                    expires_at: row.get(4)
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Revokes a single OAuth refresh token.
+    pub fn delete_oauth_refresh_token(&self, token: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM oauth_refresh_tokens WHERE token=$1",
+            &[&hash_token(token)]
+        )
+    }
+
+    /// Stores a new device authorization request identified by
+    /// `device_code`. Expired requests are removed as well.
+    pub fn create_device_authorization(&self, device_code: &str,
+                                       authorization: &DeviceAuthorization)
+        -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM device_codes WHERE expires_at<=$1", &[&now()]
+        ));
+        self.connection.execute("INSERT INTO device_codes
+            (device_code, user_code, client_id, scopes, user_id, approved,
+             last_polled_at, expires_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[&hash_token(device_code), &authorization.user_code,
+              &authorization.client_id, &authorization.scopes.join(" "),
+              &authorization.user_id, &authorization.approved,
+              &authorization.last_polled_at, &authorization.expires_at])
+    }
+
+    fn read_device_authorization_where(&self, condition: &str, value: &str)
+        -> rusqlite::Result<Option<DeviceAuthorization>> {
+        let mut stmt = try!(self.connection.prepare(&format!(
+            "SELECT user_code, client_id, scopes, user_id, approved,
+             last_polled_at, expires_at FROM device_codes WHERE {}=$1",
+            condition
+        )));
+        let mut rows = try!(stmt.query(&[&value]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(DeviceAuthorization {
+                    user_code: row.get(0),
+                    client_id: row.get(1),
+                    scopes: split_scopes(row.get(2)),
+                    user_id: row.get(3),
+                    approved: row.get(4),
+                    last_polled_at: row.get(5),
+                    expires_at: row.get(6)
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Retrieves the device authorization request identified by
+    /// `device_code`, if any.
+    pub fn read_device_authorization(&self, device_code: &str)
+        -> rusqlite::Result<Option<DeviceAuthorization>> {
+        self.read_device_authorization_where("device_code",
+                                             &hash_token(device_code))
+    }
+
+    /// Retrieves the device authorization request with the given user
+    /// code, if any.
+    pub fn read_device_authorization_by_user_code(&self, user_code: &str)
+        -> rusqlite::Result<Option<DeviceAuthorization>> {
+        self.read_device_authorization_where("user_code", user_code)
+    }
+
+    /// Records the decision of the user identified by `user_id` on the
+    /// pending device authorization request with the given user code.
+    /// Requests that were already decided are left untouched.
+    pub fn decide_device_authorization(&self, user_code: &str, user_id: &str,
+                                       approved: bool)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("UPDATE device_codes
+            SET user_id=$1, approved=$2
+            WHERE user_code=$3 AND approved IS NULL",
+            &[&user_id, &approved, &user_code])
+    }
+
+    /// Records that the device polled for tokens at `polled_at`.
+    pub fn touch_device_authorization(&self, device_code: &str,
+                                      polled_at: i64)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "UPDATE device_codes SET last_polled_at=$1 WHERE device_code=$2",
+            &[&polled_at, &hash_token(device_code)])
+    }
+
+    /// Removes a device authorization request.
+    pub fn delete_device_authorization(&self, device_code: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM device_codes WHERE device_code=$1",
+            &[&hash_token(device_code)])
+    }
+
+    /// Stores a new, unconfirmed, TOTP secret for the user identified by
+    /// `user_id`, replacing the previous one.
+    pub fn create_totp_secret(&self, user_id: &str, secret: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("INSERT OR REPLACE INTO totp_secrets
+            (user_id, secret, confirmed, last_step) VALUES ($1, $2, 0, NULL)",
+            &[&user_id, &secret])
+    }
+
+    /// Retrieves the TOTP secret of the user identified by `user_id`, if
+    /// any.
+    pub fn read_totp_secret(&self, user_id: &str)
+        -> rusqlite::Result<Option<TotpSecret>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT user_id, secret, confirmed, last_step FROM totp_secrets
+             WHERE user_id=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&user_id]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(TotpSecret {
+                    user_id: row.get(0),
+                    secret: row.get(1),
+                    confirmed: row.get(2),
+                    last_step: row.get(3)
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Records that the code of the time step `step` was used by the user
+    /// identified by `user_id`, confirming the TOTP secret if it was not.
+    /// Nothing is updated if a code of the same or a later step was
+    /// already used, so codes cannot be replayed.
+    pub fn use_totp_step(&self, user_id: &str, step: i64)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("UPDATE totp_secrets
+            SET confirmed=1, last_step=$1
+            WHERE user_id=$2 AND (last_step IS NULL OR last_step<$1)",
+            &[&step, &user_id])
+    }
+
+    /// Removes the TOTP secret of the user identified by `user_id`.
+    pub fn delete_totp_secret(&self, user_id: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("DELETE FROM totp_secrets WHERE user_id=$1",
+                                &[&user_id])
+    }
+
+    /// Stores a new login challenge. Expired challenges are removed as
+    /// well.
+    pub fn create_login_challenge(&self, challenge: &str,
+                                  login: &LoginChallenge)
+        -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM login_challenges WHERE expires_at<=$1", &[&now()]
+        ));
+        self.connection.execute("INSERT INTO login_challenges
+            (challenge, user_id, attempts, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&hash_token(challenge), &login.user_id, &login.attempts,
+              &login.expires_at])
+    }
+
+    /// Retrieves the login challenge matching `challenge`, if any.
+    pub fn read_login_challenge(&self, challenge: &str)
+        -> rusqlite::Result<Option<LoginChallenge>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT user_id, attempts, expires_at FROM login_challenges
+             WHERE challenge=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&hash_token(challenge)]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(LoginChallenge {
+                    user_id: row.get(0),
+                    attempts: row.get(1),
+                    expires_at: row.get(2)
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Counts a failed attempt to complete the login of `challenge`.
+    pub fn fail_login_challenge(&self, challenge: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("UPDATE login_challenges
+            SET attempts=attempts+1 WHERE challenge=$1",
+            &[&hash_token(challenge)])
+    }
+
+    /// Removes a login challenge.
+    pub fn delete_login_challenge(&self, challenge: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM login_challenges WHERE challenge=$1",
+            &[&hash_token(challenge)])
+    }
+
+    /// Replaces the recovery codes of the user identified by `user_id`.
+    /// Only a hash of them is stored.
+    pub fn replace_recovery_codes(&self, user_id: &str, codes: &[String])
+        -> rusqlite::Result<c_int> {
+        try!(self.delete_recovery_codes(user_id));
+        for code in codes {
+            try!(self.connection.execute(
+                "INSERT INTO recovery_codes (code, user_id) VALUES ($1, $2)",
+                &[&hash_token(code), &user_id]));
+        }
+        Ok(codes.len() as c_int)
+    }
+
+    /// Number of recovery codes the user identified by `user_id` has left.
+    pub fn count_recovery_codes(&self, user_id: &str)
+        -> rusqlite::Result<i64> {
+        self.connection.query_row(
+            "SELECT COUNT(*) FROM recovery_codes WHERE user_id=$1",
+            &[&user_id], |row| row.get(0))
+    }
+
+    /// Consumes a recovery code of the user identified by `user_id`,
+    /// returning the number of codes removed: 0 if the code is not valid.
+    pub fn use_recovery_code(&self, user_id: &str, code: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM recovery_codes WHERE user_id=$1 AND code=$2",
+            &[&user_id, &hash_token(code)])
+    }
+
+    /// Removes the recovery codes of the user identified by `user_id`.
+    pub fn delete_recovery_codes(&self, user_id: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM recovery_codes WHERE user_id=$1", &[&user_id])
+    }
+
+    /// Stores a new WebAuthn credential.
+    pub fn create_webauthn_credential(&self, credential: &WebAuthnCredential)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("INSERT INTO webauthn_credentials
+            (id, user_id, name, public_key, sign_count, created_at, last_used)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[&credential.id, &credential.user_id, &credential.name,
+              &credential.public_key, &credential.sign_count,
+              &credential.created_at, &credential.last_used])
+    }
+
+    /// Retrieves the WebAuthn credential identified by `id`, if any.
+    pub fn read_webauthn_credential(&self, id: &str)
+        -> rusqlite::Result<Option<WebAuthnCredential>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT id, user_id, name, public_key, sign_count, created_at,
+             last_used FROM webauthn_credentials WHERE id=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&id]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                Ok(Some(UsersDb::webauthn_credential_from_row(&row)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Retrieves the WebAuthn credentials of the user identified by
+    /// `user_id`, oldest first.
+    pub fn read_webauthn_credentials(&self, user_id: &str)
+        -> rusqlite::Result<Vec<WebAuthnCredential>> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT id, user_id, name, public_key, sign_count, created_at,
+             last_used FROM webauthn_credentials WHERE user_id=$1
+             ORDER BY created_at, rowid"
+        ));
+        let rows = try!(stmt.query(&[&user_id]));
+        let mut credentials = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            credentials.push(UsersDb::webauthn_credential_from_row(&row));
+        }
+        Ok(credentials)
+    }
+
+    fn webauthn_credential_from_row(row: &rusqlite::Row)
+        -> WebAuthnCredential {
+        WebAuthnCredential {
+            id: row.get(0),
+            user_id: row.get(1),
+            name: row.get(2),
+            public_key: row.get(3),
+            sign_count: row.get(4),
+            created_at: row.get(5),
+            last_used: row.get(6)
+        }
+    }
+
+    /// Records an assertion of the WebAuthn credential identified by `id`
+    /// with the signature counter `sign_count`. Nothing is updated if the
+    /// counter did not increase, which hints at a cloned authenticator,
+    /// unless the authenticator never counts its signatures.
+    pub fn use_webauthn_credential(&self, id: &str, sign_count: i64)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("UPDATE webauthn_credentials
+            SET sign_count=$1, last_used=$2
+            WHERE id=$3 AND (sign_count<$1 OR (sign_count=0 AND $1=0))",
+            &[&sign_count, &now(), &id])
+    }
+
+    /// Removes a WebAuthn credential of the user identified by `user_id`.
+    pub fn delete_webauthn_credential(&self, user_id: &str, id: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute(
+            "DELETE FROM webauthn_credentials WHERE id=$1 AND user_id=$2",
+            &[&id, &user_id])
+    }
+
+    /// Stores a new WebAuthn ceremony challenge. Expired challenges are
+    /// removed as well.
+    pub fn create_webauthn_challenge(&self, challenge: &str,
+                                     ceremony: &WebAuthnChallenge)
+        -> rusqlite::Result<c_int> {
+        try!(self.connection.execute(
+            "DELETE FROM webauthn_challenges WHERE expires_at<=$1", &[&now()]
+        ));
+        self.connection.execute("INSERT INTO webauthn_challenges
+            (challenge, user_id, kind, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&hash_token(challenge), &ceremony.user_id, &ceremony.kind,
+              &ceremony.expires_at])
+    }
+
+    /// Consumes the WebAuthn ceremony challenge `challenge`, returning it
+    /// if it existed: challenges are single use.
+    pub fn take_webauthn_challenge(&self, challenge: &str)
+        -> rusqlite::Result<Option<WebAuthnChallenge>> {
+        let ceremony = {
+            let mut stmt = try!(self.connection.prepare(
+                "SELECT user_id, kind, expires_at FROM webauthn_challenges
+                 WHERE challenge=$1"
+            ));
+            let mut rows = try!(stmt.query(&[&hash_token(challenge)]));
+            match rows.next() {
+                Some(result_row) => {
+                    let row = try!(result_row);
+                    Some(WebAuthnChallenge {
+                        user_id: row.get(0),
+                        kind: row.get(1),
+                        expires_at: row.get(2)
+                    })
+                },
+                None => None
+            }
+        };
+        try!(self.connection.execute(
+            "DELETE FROM webauthn_challenges WHERE challenge=$1",
+            &[&hash_token(challenge)]));
+        Ok(ceremony)
+    }
+
+    /// Records that the user identified by `user_id` proved to own `email`.
+    pub fn set_email_verified(&self, user_id: &str, email: &str)
+        -> rusqlite::Result<c_int> {
+        self.connection.execute("INSERT OR REPLACE INTO verified_emails
+            (user_id, email) VALUES ($1, $2)", &[&user_id, &email])
+    }
+
+    /// Whether the current email address of `user` has been verified.
+    /// Changing the address drops the verification.
+    pub fn is_email_verified(&self, user: &User) -> rusqlite::Result<bool> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT user_id FROM verified_emails WHERE user_id=$1 AND email=$2"
+        ));
+        let mut rows = try!(stmt.query(&[&user.id, &user.email]));
+        Ok(rows.next().is_some())
+    }
+
+    /// Whether the session token identified by `jti` has been revoked.
+    pub fn is_token_revoked(&self, jti: &str) -> rusqlite::Result<bool> {
+        let mut stmt = try!(self.connection.prepare(
+            "SELECT jti FROM revoked_tokens WHERE jti=$1"
+        ));
+        let mut rows = try!(stmt.query(&[&jti]));
+        Ok(rows.next().is_some())
     }
 }
 
@@ -681,6 +2484,469 @@ describe! user_db_tests {
         assert_eq!(admins.len(), 0);
     }
 
+    it "should create, read and delete refresh tokens" {
+        usersDb.create_refresh_token("token1", "1", "s1", 42).unwrap();
+        usersDb.create_refresh_token("token2", "1", "s1", 43).unwrap();
+
+        let token = usersDb.read_refresh_token("token1").unwrap().unwrap();
+        assert_eq!(token, RefreshToken { user_id: "1".to_owned(),
+                                         session_id: "s1".to_owned(),
+                                         expires_at: 42 });
+
+        usersDb.delete_refresh_token("token1").unwrap();
+        assert!(usersDb.read_refresh_token("token1").unwrap().is_none());
+        assert!(usersDb.read_refresh_token("token2").unwrap().is_some());
+
+        assert_eq!(usersDb.delete_user_refresh_token("token2", "2").unwrap(),
+                   0);
+        assert!(usersDb.read_refresh_token("token2").unwrap().is_some());
+        assert_eq!(usersDb.delete_user_refresh_token("token2", "1").unwrap(),
+                   1);
+    }
+
+    it "should delete the refresh tokens of a deleted user" {
+        usersDb.create_refresh_token("token", "1", "s1", 42).unwrap();
+        usersDb.delete("1").unwrap();
+        assert!(usersDb.read_refresh_token("token").unwrap().is_none());
+    }
+
+    it "should keep a single invitation per user" {
+        use auth_middleware::now;
+
+        let expires_at = now() + 60;
+        usersDb.create_invitation("token1", "1", expires_at).unwrap();
+        assert_eq!(usersDb.read_invitation("token1").unwrap(),
+                   Some(Invitation { user_id: "1".to_owned(),
+                                     expires_at: expires_at }));
+
+        usersDb.create_invitation("token2", "1", expires_at).unwrap();
+        assert!(usersDb.read_invitation("token1").unwrap().is_none());
+        assert!(usersDb.read_invitation("token2").unwrap().is_some());
+
+        usersDb.delete_invitations("1").unwrap();
+        assert!(usersDb.read_invitation("token2").unwrap().is_none());
+    }
+
+    it "should take invitations once and only before they expire" {
+        use auth_middleware::now;
+
+        let expires_at = now() + 60;
+        usersDb.create_invitation("token1", "1", expires_at).unwrap();
+        assert_eq!(usersDb.take_invitation("token1", now()).unwrap(),
+                   Some(Invitation { user_id: "1".to_owned(),
+                                     expires_at: expires_at }));
+        assert!(usersDb.take_invitation("token1", now()).unwrap().is_none());
+
+        usersDb.create_invitation("token2", "1", now() - 1).unwrap();
+        assert!(usersDb.take_invitation("token2", now()).unwrap().is_none());
+    }
+
+    it "should keep a single password reset per user" {
+        use auth_middleware::now;
+
+        let expires_at = now() + 60;
+        usersDb.create_password_reset("token1", "1", expires_at).unwrap();
+        assert_eq!(usersDb.read_password_reset("token1").unwrap(),
+                   Some(PasswordReset { user_id: "1".to_owned(),
+                                        expires_at: expires_at }));
+
+        usersDb.create_password_reset("token2", "1", expires_at).unwrap();
+        assert!(usersDb.read_password_reset("token1").unwrap().is_none());
+        assert!(usersDb.read_password_reset("token2").unwrap().is_some());
+
+        usersDb.delete_password_resets("1").unwrap();
+        assert!(usersDb.read_password_reset("token2").unwrap().is_none());
+    }
+
+    it "should take password resets once and only before they expire" {
+        use auth_middleware::now;
+
+        usersDb.create_password_reset("token1", "1", now() + 60).unwrap();
+        assert_eq!(usersDb.take_password_reset("token1", now()).unwrap(),
+                   Some("1".to_owned()));
+        assert!(usersDb.take_password_reset("token1", now()).unwrap()
+                       .is_none());
+
+        usersDb.create_password_reset("token2", "1", now() - 1).unwrap();
+        assert!(usersDb.take_password_reset("token2", now()).unwrap()
+                       .is_none());
+    }
+
+    it "should limit the password reset requests per address" {
+        assert!(usersDb.record_password_reset_request("a@example.com", 100,
+                                                      60).unwrap());
+        assert!(!usersDb.record_password_reset_request("A@example.com", 159,
+                                                       60).unwrap());
+        assert!(usersDb.record_password_reset_request("b@example.com", 159,
+                                                      60).unwrap());
+        assert!(usersDb.record_password_reset_request("a@example.com", 160,
+                                                      60).unwrap());
+    }
+
+    it "should rotate and retire signing keys" {
+        let first = usersDb.current_signing_key().unwrap();
+        assert_eq!(usersDb.current_signing_key().unwrap(), first);
+
+        let second = usersDb.rotate_signing_keys(60, SigningAlgorithm::EdDSA)
+                            .unwrap();
+        assert_eq!(usersDb.current_signing_key().unwrap(), second);
+        assert_eq!(second.algorithm, SigningAlgorithm::EdDSA);
+        assert!(second.ed25519_keypair().is_some());
+        assert!(first.ed25519_keypair().is_none());
+        let first = usersDb.read_signing_key(&first.kid).unwrap().unwrap();
+        assert!(first.retire_at.is_some());
+        assert!(!first.is_retired());
+
+        usersDb.retire_signing_key(&first.kid, 0).unwrap();
+        let first = usersDb.read_signing_key(&first.kid).unwrap().unwrap();
+        assert!(first.is_retired());
+
+        // Retired keys are pruned on the next rotation.
+        usersDb.rotate_signing_keys(60, SigningAlgorithm::HS256).unwrap();
+        assert!(usersDb.read_signing_key(&first.kid).unwrap().is_none());
+        assert_eq!(usersDb.read_signing_keys().unwrap().len(), 2);
+    }
+
+    it "should keep ID token keys apart from session keys" {
+        let id_token_key = usersDb.current_id_token_key().unwrap();
+        assert_eq!(id_token_key.purpose, KeyPurpose::IdToken);
+        assert_eq!(id_token_key.algorithm, SigningAlgorithm::EdDSA);
+
+        let session_key = usersDb.current_signing_key().unwrap();
+        assert!(session_key != id_token_key);
+        assert_eq!(session_key.purpose, KeyPurpose::Session);
+        assert_eq!(session_key.algorithm, SigningAlgorithm::HS256);
+        assert_eq!(usersDb.current_id_token_key().unwrap(), id_token_key);
+        assert_eq!(usersDb.read_signing_key(&id_token_key.kid).unwrap(),
+                   Some(id_token_key.clone()));
+
+        // Rotating and retiring session keys leaves ID token keys alone.
+        usersDb.rotate_signing_keys(0, SigningAlgorithm::EdDSA).unwrap();
+        usersDb.rotate_signing_keys(0, SigningAlgorithm::HS256).unwrap();
+        assert_eq!(usersDb.retire_signing_key(&id_token_key.kid, 0).unwrap(),
+                   0);
+        assert_eq!(usersDb.current_id_token_key().unwrap(), id_token_key);
+    }
+
+    it "should create, read, touch and delete sessions" {
+        let session1 = Session::new("1", "Firefox", "127.0.0.1");
+        let session2 = Session::new("1", "Fennec", "192.168.1.2");
+        usersDb.create_session(&session1).unwrap();
+        usersDb.create_session(&session2).unwrap();
+        usersDb.create_session(&Session::new("2", "", "")).unwrap();
+        usersDb.create_refresh_token("token", "1", &session1.id, 42).unwrap();
+
+        assert_eq!(usersDb.read_session(&session1.id).unwrap(),
+                   Some(session1.clone()));
+        assert_eq!(usersDb.read_sessions("1").unwrap().len(), 2);
+
+        usersDb.touch_session(&session1.id, 42).unwrap();
+        let session = usersDb.read_session(&session1.id).unwrap().unwrap();
+        assert_eq!(session.last_seen, 42);
+
+        usersDb.delete_session(&session1.id).unwrap();
+        assert!(usersDb.read_session(&session1.id).unwrap().is_none());
+        assert!(usersDb.read_refresh_token("token").unwrap().is_none());
+        assert_eq!(usersDb.read_sessions("1").unwrap(), vec![session2]);
+
+        usersDb.delete_sessions("1").unwrap();
+        assert!(usersDb.read_sessions("1").unwrap().is_empty());
+        assert_eq!(usersDb.read_sessions("2").unwrap().len(), 1);
+    }
+
+    it "should revoke tokens and prune expired revocations" {
+        use auth_middleware::now;
+
+        usersDb.revoke_token("expired", now() - 1).unwrap();
+        assert!(usersDb.is_token_revoked("expired").unwrap());
+        assert!(!usersDb.is_token_revoked("valid").unwrap());
+
+        usersDb.revoke_token("valid", now() + 60).unwrap();
+        assert!(usersDb.is_token_revoked("valid").unwrap());
+        assert!(!usersDb.is_token_revoked("expired").unwrap());
+    }
+
+    it "should create, read, update and delete roles" {
+        let role = Role {
+            name: "household".to_owned(),
+            permissions: vec!["lights:control".to_owned(),
+                              "music:play".to_owned()]
+        };
+        usersDb.create_role(&role).unwrap();
+        assert_eq!(usersDb.read_role("household").unwrap(), Some(role.clone()));
+        assert_eq!(usersDb.read_roles().unwrap(), vec![role]);
+
+        let role = Role {
+            name: "household".to_owned(),
+            permissions: vec!["doors:open".to_owned()]
+        };
+        usersDb.update_role(&role).unwrap();
+        assert_eq!(usersDb.read_role("household").unwrap(), Some(role));
+
+        usersDb.delete_role("household").unwrap();
+        assert!(usersDb.read_role("household").unwrap().is_none());
+        assert!(usersDb.read_roles().unwrap().is_empty());
+    }
+
+    it "should assign roles and gather user permissions" {
+        usersDb.create_role(&Role {
+            name: "household".to_owned(),
+            permissions: vec!["lights:control".to_owned(),
+                              "music:play".to_owned()]
+        }).unwrap();
+        usersDb.create_role(&Role {
+            name: "guest".to_owned(),
+            permissions: vec!["music:play".to_owned()]
+        }).unwrap();
+
+        usersDb.add_user_role("1", "household").unwrap();
+        usersDb.add_user_role("1", "guest").unwrap();
+        usersDb.add_user_role("2", "guest").unwrap();
+
+        assert_eq!(usersDb.read_user_roles("1").unwrap(),
+                   vec!["guest".to_owned(), "household".to_owned()]);
+        assert_eq!(usersDb.read_user_permissions("1").unwrap(),
+                   vec!["lights:control".to_owned(), "music:play".to_owned()]);
+        assert_eq!(usersDb.read_user_permissions("2").unwrap(),
+                   vec!["music:play".to_owned()]);
+
+        usersDb.remove_user_role("1", "household").unwrap();
+        assert_eq!(usersDb.read_user_permissions("1").unwrap(),
+                   vec!["music:play".to_owned()]);
+
+        usersDb.delete_role("guest").unwrap();
+        assert!(usersDb.read_user_roles("2").unwrap().is_empty());
+    }
+
+    it "should create, read, touch and revoke access tokens" {
+        let access_token = AccessToken::new("1", "lights script",
+                                            vec!["lights:control".to_owned()],
+                                            None);
+        usersDb.create_access_token("secret", &access_token).unwrap();
+        assert_eq!(usersDb.read_access_token("secret").unwrap(),
+                   Some(access_token.clone()));
+        assert!(usersDb.read_access_token(&access_token.id).unwrap()
+                       .is_none());
+
+        usersDb.touch_access_token(&access_token.id, 42).unwrap();
+        let tokens = usersDb.read_access_tokens("1").unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].last_used, Some(42));
+
+        assert_eq!(usersDb.delete_access_token("2", &access_token.id)
+                          .unwrap(), 0);
+        assert_eq!(usersDb.delete_access_token("1", &access_token.id)
+                          .unwrap(), 1);
+        assert!(usersDb.read_access_token("secret").unwrap().is_none());
+    }
+
+    it "should store OAuth clients, codes and refresh tokens" {
+        use auth_middleware::now;
+
+        let client = OAuthClient::new("dashboard",
+                                      vec!["https://dashboard/cb".to_owned()],
+                                      true);
+        usersDb.create_oauth_client(&client, Some("secret")).unwrap();
+        assert_eq!(usersDb.read_oauth_client(&client.id).unwrap(),
+                   Some(client.clone()));
+        assert!(usersDb.check_oauth_client_secret(&client.id, "secret")
+                       .unwrap());
+        assert!(!usersDb.check_oauth_client_secret(&client.id, "wrong")
+                        .unwrap());
+
+        let code = AuthorizationCode {
+            client_id: client.id.clone(),
+            user_id: "1".to_owned(),
+            redirect_uri: "https://dashboard/cb".to_owned(),
+            scopes: vec!["users:read".to_owned()],
+            code_challenge: "challenge".to_owned(),
+            nonce: Some("nonce".to_owned()),
+            expires_at: now() + 60
+        };
+        usersDb.create_authorization_code("code", &code).unwrap();
+        assert_eq!(usersDb.take_authorization_code("code").unwrap(),
+                   Some(code));
+        // Codes can only be used once.
+        assert!(usersDb.take_authorization_code("code").unwrap().is_none());
+
+        let session = Session::new("1", "dashboard", "");
+        usersDb.create_session(&session).unwrap();
+        let refresh_token = OAuthRefreshToken {
+            client_id: client.id.clone(),
+            user_id: "1".to_owned(),
+            session_id: session.id.clone(),
+            scopes: vec!["users:read".to_owned()],
+            expires_at: now() + 60
+        };
+        usersDb.create_oauth_refresh_token("refresh", &refresh_token)
+               .unwrap();
+        assert_eq!(usersDb.read_oauth_refresh_token("refresh").unwrap(),
+                   Some(refresh_token));
+
+        // Removing the client closes the sessions opened for it.
+        assert_eq!(usersDb.delete_oauth_client(&client.id).unwrap(), 1);
+        assert!(usersDb.read_oauth_client(&client.id).unwrap().is_none());
+        assert!(usersDb.read_oauth_refresh_token("refresh").unwrap()
+                       .is_none());
+        assert!(usersDb.read_session(&session.id).unwrap().is_none());
+    }
+
+    it "should store device authorization requests" {
+        use auth_middleware::now;
+
+        let authorization = DeviceAuthorization {
+            user_code: "BCDFGHJK".to_owned(),
+            client_id: "speaker".to_owned(),
+            scopes: vec!["users:read".to_owned()],
+            user_id: None,
+            approved: None,
+            last_polled_at: None,
+            expires_at: now() + 60
+        };
+        usersDb.create_device_authorization("device", &authorization)
+               .unwrap();
+        assert_eq!(usersDb.read_device_authorization("device").unwrap(),
+                   Some(authorization.clone()));
+
+        // Only pending requests can be decided.
+        assert_eq!(usersDb.decide_device_authorization("BCDFGHJK", "1", true)
+                          .unwrap(), 1);
+        assert_eq!(usersDb.decide_device_authorization("BCDFGHJK", "1", false)
+                          .unwrap(), 0);
+        usersDb.touch_device_authorization("device", 42).unwrap();
+        let decided = usersDb.read_device_authorization_by_user_code(
+            "BCDFGHJK").unwrap().unwrap();
+        assert_eq!(decided.user_id, Some("1".to_owned()));
+        assert_eq!(decided.approved, Some(true));
+        assert_eq!(decided.last_polled_at, Some(42));
+
+        // Of two polls deleting the request, only one removes it.
+        assert_eq!(usersDb.delete_device_authorization("device").unwrap(), 1);
+        assert_eq!(usersDb.delete_device_authorization("device").unwrap(), 0);
+        assert!(usersDb.read_device_authorization("device").unwrap()
+                       .is_none());
+    }
+
+    it "should not let TOTP codes be used twice" {
+        usersDb.create_totp_secret("1",
+            "3132333435363738393031323334353637383930").unwrap();
+        let secret = usersDb.read_totp_secret("1").unwrap().unwrap();
+        assert!(!secret.confirmed);
+        assert_eq!(secret.last_step, None);
+
+        assert_eq!(usersDb.use_totp_step("1", 42).unwrap(), 1);
+        assert_eq!(usersDb.use_totp_step("1", 42).unwrap(), 0);
+        assert_eq!(usersDb.use_totp_step("1", 41).unwrap(), 0);
+        let secret = usersDb.read_totp_secret("1").unwrap().unwrap();
+        assert!(secret.confirmed);
+        assert_eq!(secret.last_step, Some(42));
+
+        usersDb.delete("1").unwrap();
+        assert!(usersDb.read_totp_secret("1").unwrap().is_none());
+    }
+
+    it "should count failed login challenges" {
+        use auth_middleware::now;
+
+        let login = LoginChallenge {
+            user_id: "1".to_owned(),
+            attempts: 0,
+            expires_at: now() + 60
+        };
+        usersDb.create_login_challenge("challenge", &login).unwrap();
+        usersDb.fail_login_challenge("challenge").unwrap();
+        assert_eq!(usersDb.read_login_challenge("challenge").unwrap(),
+                   Some(LoginChallenge { attempts: 1, .. login }));
+        assert_eq!(usersDb.delete_login_challenge("challenge").unwrap(), 1);
+        assert_eq!(usersDb.delete_login_challenge("challenge").unwrap(), 0);
+        assert!(usersDb.read_login_challenge("challenge").unwrap().is_none());
+    }
+
+    it "should only accept recovery codes once" {
+        let codes = vec!["aaaaabbbbb".to_owned(), "cccccddddd".to_owned()];
+        usersDb.replace_recovery_codes("1", &codes).unwrap();
+        assert_eq!(usersDb.count_recovery_codes("1").unwrap(), 2);
+        assert_eq!(usersDb.use_recovery_code("1", "aaaaabbbbb").unwrap(), 1);
+        assert_eq!(usersDb.use_recovery_code("1", "aaaaabbbbb").unwrap(), 0);
+        assert_eq!(usersDb.use_recovery_code("2", "cccccddddd").unwrap(), 0);
+        assert_eq!(usersDb.count_recovery_codes("1").unwrap(), 1);
+
+        // Regenerating the codes invalidates the previous ones.
+        usersDb.replace_recovery_codes("1", &["eeeeefffff".to_owned()])
+               .unwrap();
+        assert_eq!(usersDb.use_recovery_code("1", "cccccddddd").unwrap(), 0);
+        assert_eq!(usersDb.count_recovery_codes("1").unwrap(), 1);
+
+        usersDb.delete("1").unwrap();
+        assert_eq!(usersDb.count_recovery_codes("1").unwrap(), 0);
+    }
+
+    it "should reject WebAuthn sign counts that do not increase" {
+        use auth_middleware::now;
+
+        let credential = WebAuthnCredential {
+            id: "Y3JlZGVudGlhbA".to_owned(),
+            user_id: "1".to_owned(),
+            name: "Phone".to_owned(),
+            public_key: "00".to_owned(),
+            sign_count: 0,
+            created_at: now(),
+            last_used: None
+        };
+        usersDb.create_webauthn_credential(&credential).unwrap();
+        assert!(usersDb.create_webauthn_credential(&credential).is_err());
+        assert_eq!(usersDb.read_webauthn_credentials("1").unwrap(),
+                   vec![credential.clone()]);
+
+        // Authenticators not counting their signatures always send 0.
+        assert_eq!(usersDb.use_webauthn_credential(&credential.id, 0)
+                          .unwrap(), 1);
+        assert_eq!(usersDb.use_webauthn_credential(&credential.id, 5)
+                          .unwrap(), 1);
+        assert_eq!(usersDb.use_webauthn_credential(&credential.id, 5)
+                          .unwrap(), 0);
+        assert_eq!(usersDb.use_webauthn_credential(&credential.id, 0)
+                          .unwrap(), 0);
+        let used = usersDb.read_webauthn_credential(&credential.id).unwrap()
+                          .unwrap();
+        assert_eq!(used.sign_count, 5);
+        assert!(used.last_used.is_some());
+
+        assert_eq!(usersDb.delete_webauthn_credential("2", &credential.id)
+                          .unwrap(), 0);
+        usersDb.delete("1").unwrap();
+        assert!(usersDb.read_webauthn_credential(&credential.id).unwrap()
+                       .is_none());
+    }
+
+    it "should only let WebAuthn challenges be used once" {
+        use auth_middleware::now;
+
+        let ceremony = WebAuthnChallenge {
+            user_id: None,
+            kind: "webauthn.get".to_owned(),
+            expires_at: now() + 60
+        };
+        usersDb.create_webauthn_challenge("challenge", &ceremony).unwrap();
+        assert_eq!(usersDb.take_webauthn_challenge("challenge").unwrap(),
+                   Some(ceremony));
+        assert!(usersDb.take_webauthn_challenge("challenge").unwrap()
+                       .is_none());
+    }
+
+    it "should only consider the verified email address" {
+        let mut user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("Fox"))
+                       .email(String::from("fox@example.com"))
+                       .password(String::from("password!"))
+                       .finalize().unwrap()).unwrap();
+        assert!(!usersDb.is_email_verified(&user).unwrap());
+        usersDb.set_email_verified(&user.id, &user.email).unwrap();
+        assert!(usersDb.is_email_verified(&user).unwrap());
+        user.email = String::from("fox@example.org");
+        assert!(!usersDb.is_email_verified(&user).unwrap());
+    }
+
     it "should create admin user when requested" {
         let admin = UserBuilder::new(None).name(String::from("Admin"))
                 .email(String::from("admin@mozilla.org"))
@@ -11,21 +11,38 @@
 //! [REST documentation](https://github.com/fxbox/users/blob/master/doc/API.md)
 //! can be found in the GitHub repository.
 
-use super::auth_middleware::{ AuthEndpoint, AuthMiddleware, SessionToken };
+use super::auth_cache::{ AuthCache, users_db };
+use super::auth_middleware::{ AuthEndpoint, AuthenticatedUser, AuthMiddleware,
+                              SessionClaims, SessionToken, TokenError, TokenKind,
+                              ACCESS_TOKEN_PREFIX, get_cookie, session_cookies,
+                              REFRESH_COOKIE,
+                              SCOPE_TOKENS_INTROSPECT, SCOPE_USERS_ADMIN,
+                              SCOPE_USERS_READ, now };
 use super::errors::*;
 use super::invitation_middleware::InvitationMiddleware;
-use super::users_db::{ User, UserBuilder, UsersDb, ReadFilter };
+use super::oauth_router::{ OAuthRouter, OpenIdOptions };
+use super::two_factor_router::TwoFactorRouter;
+use super::webauthn::RelyingParty;
+use super::webauthn_router::WebAuthnRouter;
+use super::users_db::{ AccessToken, Role, Session, SigningAlgorithm, SigningKey, User,
+                       UserBuilder, UsersDb, ReadFilter };
 
 use iron::status;
-use iron::headers::{ Authorization, Basic };
+use iron::headers::{ Authorization, Basic, UserAgent };
 use iron::method::Method;
 use iron::prelude::*;
 use iron_cors::CORS;
 use router::Router;
-use rustc_serialize::json;
+use urlencoded::UrlEncodedBody;
+use rustc_serialize::base64::{ ToBase64, URL_SAFE };
+use rustc_serialize::json::{ self, Json };
+use uuid::Uuid;
 
+use std::collections::BTreeMap;
 use std::io::Read;
+use std::i64;
 use std::sync::{ Arc, RwLock };
+use std::thread;
 
 type Credentials = (String, String);
 
@@ -34,22 +51,86 @@ pub fn endpoint(path: &str) -> String {
     format!("/{}{}", API_VERSION, path)
 }
 
-/// Body response for POST /setup and POST /login
+/// Body response for POST /setup, POST /login and POST /refresh
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 struct SessionTokenResponse {
-    session_token: String
+    session_token: String,
+    refresh_token: String
+}
+
+/// How the router issues session tokens.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionOptions {
+    /// Lifetime of the session tokens, in seconds.
+    ttl: i64,
+    /// Whether session and refresh tokens are set in HttpOnly cookies
+    /// instead of the response body.
+    cookies: bool,
+    /// Whether the cookies are only sent over HTTPS.
+    secure_cookies: bool
 }
 
 impl SessionTokenResponse {
-    fn with_user(user: &User) -> IronResult<Response> {
-        let session_token = match SessionToken::from_user(&user) {
+    /// Open a new session for `user` from the device sending `req` and
+    /// respond with its tokens.
+    fn with_user(req: &Request, user: &User, db: &UsersDb,
+                 options: SessionOptions) -> IronResult<Response> {
+        let session = new_session(req, &user.id);
+        if let Err(error) = db.create_session(&session) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+        SessionTokenResponse::with_session(user, &session.id, db, options)
+    }
+
+    /// Respond with a new pair of tokens for an already open session.
+    fn with_session(user: &User, session_id: &str, db: &UsersDb,
+                    options: SessionOptions) -> IronResult<Response> {
+        let scopes = match SessionClaims::granted_scopes(user, db) {
+            Ok(scopes) => scopes,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        let claims = SessionClaims {
+            scopes: scopes,
+            .. SessionClaims::new(user, session_id, options.ttl)
+        };
+        let key = match db.current_signing_key() {
+            Ok(key) => key,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        let session_token = match SessionToken::from_claims(user, claims,
+                                                            &key) {
             Ok(token) => token,
             Err(_) => return EndpointError::with(
                 status::InternalServerError, 501, None
             )
         };
+        let refresh_token = Uuid::new_v4().simple().to_string();
+        if let Err(error) = db.create_refresh_token(
+            &refresh_token, &user.id, session_id,
+            now() + SessionToken::REFRESH_TTL) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+        // Keep the tokens of cookie sessions out of the reach of scripts.
+        if options.cookies {
+            let csrf_token = Uuid::new_v4().simple().to_string();
+            let mut response = Response::with((status::Created, "{}"));
+            response.headers.set_raw("Set-Cookie", session_cookies(
+                &session_token, &refresh_token, &csrf_token, options.ttl,
+                &endpoint("/refresh"), options.secure_cookies
+            ));
+            return Ok(response);
+        }
         let body_obj = SessionTokenResponse{
-           session_token: session_token
+           session_token: session_token,
+           refresh_token: refresh_token
         };
         let body = match json::encode(&body_obj) {
             Ok(body) => body,
@@ -61,13 +142,54 @@ impl SessionTokenResponse {
     }
 }
 
-/// Body response for POST /users
+/// Create a session for `user_id` recording the user agent and remote
+/// address of `req`.
+fn new_session(req: &Request, user_id: &str) -> Session {
+    let user_agent = match req.headers.get::<UserAgent>() {
+        Some(&UserAgent(ref user_agent)) => user_agent.to_owned(),
+        None => String::new()
+    };
+    Session::new(user_id, &user_agent, &req.remote_addr.ip().to_string())
+}
+
+/// Body response for POST /users and POST /users/:id/invitation
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub struct CreateUserResponse {
     pub email: String,
     pub activation_url: String
 }
 
+/// Invite `user` to activate the account with a new single-use activation
+/// token, invalidating any previous invitation, and respond with the
+/// activation url.
+fn invite(user: &User, db: &UsersDb) -> IronResult<Response> {
+    let activation_token = Uuid::new_v4().simple().to_string();
+    if let Err(error) = db.create_invitation(
+        &activation_token, &user.id, now() + SessionToken::ACTIVATION_TTL) {
+        println!("{:?}", error);
+        return from_sqlite_error(error);
+    }
+
+    let activation_url = endpoint(
+        &format!("/users/{}/activate?auth={}", user.id, activation_token)
+    );
+
+    // To help testing, we print the url here.
+    println!("New user: activation url {}", activation_url);
+
+    let body = match json::encode(&CreateUserResponse {
+        email: user.email.clone(),
+        activation_url: activation_url
+    }) {
+        Ok(body) => body,
+        Err(_) => return EndpointError::with(
+            status::InternalServerError, 501,
+            Some("CreateUserBody encoding error".to_owned())
+        )
+    };
+    Ok(Response::with((status::Created, body)))
+}
+
 /// Body response for GET /users/:id
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 struct GetUserResponse {
@@ -80,6 +202,84 @@ struct GetAllUsersResponse {
     users: Vec<User>
 }
 
+/// Body response for GET /users/:id/sessions
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct GetSessionsResponse {
+    sessions: Vec<Session>
+}
+
+/// Body response for POST /users/:id/tokens. The token itself is only
+/// returned on creation.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct CreateAccessTokenResponse {
+    token: String,
+    access_token: AccessToken
+}
+
+/// Body response for GET /users/:id/tokens
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct GetAccessTokensResponse {
+    tokens: Vec<AccessToken>
+}
+
+/// Body response for GET /roles
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct GetRolesResponse {
+    roles: Vec<Role>
+}
+
+/// Body request for PUT /roles/:role
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct EditRoleBody {
+    permissions: Vec<String>
+}
+
+/// Body response for GET /users/:id/roles
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct GetUserRolesResponse {
+    roles: Vec<String>
+}
+
+/// Public information of a signing key, without its secret.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct KeyResponse {
+    kid: String,
+    alg: String,
+    purpose: String,
+    created_at: i64,
+    retire_at: Option<i64>
+}
+
+impl KeyResponse {
+    fn from_key(key: &SigningKey) -> KeyResponse {
+        KeyResponse {
+            kid: key.kid.clone(),
+            alg: key.algorithm.as_str().to_owned(),
+            purpose: key.purpose.as_str().to_owned(),
+            created_at: key.created_at,
+            retire_at: key.retire_at
+        }
+    }
+}
+
+/// Body response for GET /keys
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct GetKeysResponse {
+    keys: Vec<KeyResponse>
+}
+
+/// Role names and permissions cannot be empty nor contain whitespaces or
+/// slashes, so they can be used in urls.
+fn is_valid_role_token(token: &str) -> bool {
+    !token.is_empty() &&
+    !token.chars().any(|c| c.is_whitespace() || c == '/')
+}
+
+fn is_valid_role(role: &Role) -> bool {
+    is_valid_role_token(&role.name) &&
+    role.permissions.iter().all(|permission| is_valid_role_token(permission))
+}
+
 macro_rules! get_user_id_from_request {
     ($req:ident, $user_id:ident) => {
         let user_id = $req.extensions.get::<Router>().unwrap()
@@ -138,16 +338,22 @@ macro_rules! parse_request_body {
 /// ```
 pub struct UsersRouter {
     db_path: String,
-    invitation_middleware: Arc<RwLock<InvitationMiddleware>>
+    session_options: SessionOptions,
+    openid: Option<OpenIdOptions>,
+    device_verification_uri: Option<String>,
+    webauthn: Option<RelyingParty>,
+    invitation_middleware: Arc<RwLock<InvitationMiddleware>>,
+    auth_cache: AuthCache
 }
 
 impl UsersRouter {
     /// POST /setup handler.
     /// Allow to initiate the box by registering an admin user.
-    fn setup(req: &mut Request, db_path: &str) -> IronResult<Response> {
+    fn setup(req: &mut Request, db_path: &str, options: SessionOptions)
+        -> IronResult<Response> {
         // This endpoint should be disabled and return error 410 (Gone)
         // if there is any admin user already configured.
-        let db = UsersDb::new(db_path);
+        let db = users_db(req, db_path);
         let admins = db.read(ReadFilter::IsAdmin(true)).unwrap();
         if !admins.is_empty() {
             return EndpointError::with(status::Gone, 410,
@@ -179,7 +385,7 @@ impl UsersRouter {
 
         match db.create(&admin) {
             Ok(admin) => {
-                SessionTokenResponse::with_user(&admin)
+                SessionTokenResponse::with_user(req, &admin, &db, options)
             },
             Err(error) => {
                 println!("{:?}", error);
@@ -190,7 +396,8 @@ impl UsersRouter {
 
     /// POST /login handler.
     /// Allow users to authenticate with the box.
-    fn login(req: &mut Request, db_path: &str) -> IronResult<Response> {
+    fn login(req: &mut Request, db_path: &str, options: SessionOptions,
+             rp: Option<&RelyingParty>) -> IronResult<Response> {
         // Return Some pair of valid credentials if both email and password
         // are provided or None elsewhere.
         fn credentials_from_header(auth: &Authorization<Basic>)
@@ -219,7 +426,7 @@ impl UsersRouter {
         let header: Option<&Authorization<Basic>> = req.headers.get();
         if let Some(auth) = header {
             if let Some((email, password)) = credentials_from_header(auth) {
-                let users_db = UsersDb::new(db_path);
+                let users_db = users_db(req, db_path);
                 let users = match users_db.read(
                     ReadFilter::Credentials(email, password)) {
                     Ok(users) => users,
@@ -230,7 +437,18 @@ impl UsersRouter {
                 if users.len() != 1 || !users[0].is_active {
                     return EndpointError::with(status::Unauthorized, 401, None);
                 }
-                SessionTokenResponse::with_user(&users[0])
+                // Users with a second factor get a challenge to complete
+                // with POST /login/2fa instead of a session token.
+                match TwoFactorRouter::second_factors(&users[0].id,
+                                                      &users_db, rp) {
+                    Ok(ref methods) if methods.is_empty() => {},
+                    Ok(methods) => return TwoFactorRouter::challenge(
+                        &users[0], methods, &users_db, rp
+                    ),
+                    Err(response) => return response
+                }
+                SessionTokenResponse::with_user(req, &users[0], &users_db,
+                                                options)
             } else {
                 error103
             }
@@ -239,12 +457,186 @@ impl UsersRouter {
         }
     }
 
+    /// POST /login/2fa handler.
+    /// Complete a login challenge with a second factor.
+    fn login_2fa(req: &mut Request, db_path: &str, options: SessionOptions,
+                 rp: Option<&RelyingParty>) -> IronResult<Response> {
+        let db = users_db(req, db_path);
+        match TwoFactorRouter::complete_login(req, &db, rp) {
+            Ok(user) => SessionTokenResponse::with_user(req, &user, &db,
+                                                        options),
+            Err(response) => response
+        }
+    }
+
+    /// POST /login/webauthn handler.
+    /// Log in without a password with a WebAuthn assertion of a credential
+    /// verifying the user, like a passkey.
+    fn login_webauthn(req: &mut Request, db_path: &str,
+                      options: SessionOptions, rp: &RelyingParty)
+        -> IronResult<Response> {
+        let db = users_db(req, db_path);
+        match WebAuthnRouter::complete_login(req, &db, rp) {
+            Ok(user) => SessionTokenResponse::with_user(req, &user, &db,
+                                                        options),
+            Err(response) => response
+        }
+    }
+
+    /// POST /refresh handler.
+    /// Trade a refresh token for a new session token. Refresh tokens are
+    /// single use, so a new one is returned as well. Cookie sessions send
+    /// it in the refresh token cookie instead of the body.
+    fn refresh(req: &mut Request, db_path: &str, options: SessionOptions)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct RefreshBody {
+            refresh_token: String
+        }
+
+        // Cookie sessions keep the refresh token in a cookie, which requests
+        // started by other sites must not be able to use.
+        let token = if options.cookies {
+            if !AuthMiddleware::has_csrf_token(req) {
+                return EndpointError::with(status::Forbidden, 114,
+                    Some("Missing or invalid CSRF token".to_owned()));
+            }
+            match get_cookie(req, REFRESH_COOKIE) {
+                Some(token) => token,
+                None => return EndpointError::with(status::Unauthorized, 107,
+                    Some("Invalid refresh token".to_owned()))
+            }
+        } else {
+            let body: RefreshBody = parse_request_body!(req);
+            body.refresh_token
+        };
+
+        let db = users_db(req, db_path);
+        let refresh_token = match db.read_refresh_token(&token) {
+            Ok(Some(refresh_token)) => refresh_token,
+            Ok(None) => return EndpointError::with(status::Unauthorized, 107,
+                Some("Invalid refresh token".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        // The token is only used by the request that deletes it, so it
+        // cannot be traded twice by concurrent requests.
+        match db.delete_refresh_token(&token) {
+            Ok(1) => {},
+            Ok(_) => return EndpointError::with(status::Unauthorized, 107,
+                Some("Invalid refresh token".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+
+        if refresh_token.expires_at <= now() {
+            return EndpointError::with(status::Unauthorized, 107,
+                Some("Invalid refresh token".to_owned()));
+        }
+
+        // Closing a session revokes its refresh tokens, but one read before
+        // that must not reopen it.
+        match db.read_session(&refresh_token.session_id) {
+            Ok(Some(_)) => {},
+            Ok(None) => return EndpointError::with(status::Unauthorized, 107,
+                Some("Invalid refresh token".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+
+        match db.read(ReadFilter::Id(refresh_token.user_id)) {
+            Ok(users) => {
+                if users.len() != 1 || !users[0].is_active {
+                    return EndpointError::with(status::Unauthorized, 401, None);
+                }
+                if let Err(error) = db.touch_session(&refresh_token.session_id,
+                                                     now()) {
+                    println!("{:?}", error);
+                    return from_sqlite_error(error);
+                }
+                SessionTokenResponse::with_session(&users[0],
+                                                   &refresh_token.session_id,
+                                                   &db, options)
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// POST /logout handler.
+    /// Revoke the session token sent with the request and close its session,
+    /// which revokes the refresh tokens of the session as well.
+    fn logout(req: &mut Request, db_path: &str, options: SessionOptions)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct LogoutBody {
+            refresh_token: Option<String>
+        }
+
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let body: LogoutBody = if payload.trim().is_empty() {
+            LogoutBody { refresh_token: None }
+        } else {
+            match json::decode(&payload) {
+                Ok(body) => body,
+                Err(error) => {
+                    println!("{:?}", error);
+                    return from_decoder_error(error);
+                }
+            }
+        };
+
+        // The auth middleware already verified the token.
+        let claims = match req.extensions.get::<AuthenticatedUser>() {
+            Some(&AuthenticatedUser {
+                kind: TokenKind::AccessToken { .. }, ..
+            }) => return EndpointError::with(status::BadRequest, 400,
+                Some("Access tokens are revoked with DELETE \
+                      /users/:id/tokens/:tid".to_owned())),
+            Some(caller) => caller.claims.clone(),
+            None => return EndpointError::with(status::Unauthorized, 401, None)
+        };
+
+        let db = users_db(req, db_path);
+        if let Err(error) = db.revoke_token(&claims.jti, claims.exp) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+        if let Err(error) = db.delete_session(&claims.sid) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+        // Only refresh tokens of the caller can be revoked this way.
+        if let Some(refresh_token) = body.refresh_token {
+            if let Err(error) = db.delete_user_refresh_token(&refresh_token,
+                                                             &claims.id) {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+        let mut response = Response::with(status::NoContent);
+        if options.cookies {
+            response.headers.set_raw("Set-Cookie", session_cookies(
+                "", "", "", 0, &endpoint("/refresh"), options.secure_cookies
+            ));
+        }
+        Ok(response)
+    }
+
     /// POST /users handler.
     /// Create a new user registration. By default the user is added to the DB
     /// but it remains inactive until the owner sets a user name and a password.
-    ///
-    /// XXX Once we have a permissions system, this functionality will require
-    /// admin permissions.
+    /// Requires a session token with the `users:admin` scope.
     pub fn create_user(req: &mut Request,
                        db_path: &str)
         -> IronResult<Response> {
@@ -265,37 +657,38 @@ impl UsersRouter {
                 }
             };
 
-        let db = UsersDb::new(db_path);
+        let db = users_db(req, db_path);
         match db.create(&user) {
-            Ok(user) => {
-                // XXX This token will have a short ttl.
-                //     https://github.com/fxbox/users/issues/84
-                let session_token = match SessionToken::from_user(&user) {
-                    Ok(token) => token,
-                    Err(_) => return EndpointError::with(
-                        status::InternalServerError, 501,
-                        Some("Could not generate activation token".to_owned())
-                    )
-                };
+            Ok(user) => invite(&user, &db),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
 
-                let activation_url = endpoint(
-                    &format!("/users/{}/activate?auth={}",user.id, session_token)
-                );
+    /// POST /users/:id/invitation handler.
+    /// Send a new invitation to the inactive user matching the given id.
+    /// Activation tokens sent with previous invitations are invalidated.
+    pub fn invite_user(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
+
+        let db = users_db(req, db_path);
+        match db.read(ReadFilter::Id(user_id)) {
+            Ok(users) => {
+                if users.is_empty() {
+                    return EndpointError::with(status::NotFound,
+                        404, Some("User not found".to_owned()))
+                }
 
-                // To help testing, we print the url here.
-                println!("New user: activation url {}", activation_url);
+                if users[0].is_active {
+                    return EndpointError::with(status::Conflict,
+                        409, Some("User is already active".to_owned()))
+                }
 
-                let body = match json::encode(&CreateUserResponse {
-                    email: body.email,
-                    activation_url: activation_url
-                }) {
-                    Ok(body) => body,
-                    Err(_) => return EndpointError::with(
-                        status::InternalServerError, 501,
-                        Some("CreateUserBody encoding error".to_owned())
-                    )
-                };
-                Ok(Response::with((status::Created, body)))
+                invite(&users[0], &db)
             },
             Err(error) => {
                 println!("{:?}", error);
@@ -306,14 +699,23 @@ impl UsersRouter {
 
     /// GET /user/:id handler.
     /// Get the information of the user matching the given id.
-    /// XXX Only the owner or users with admin privileges should be able to
-    ///     access this method. Pending permissions and token scopes system.
+    /// Owners can read their own information with any token granted the
+    /// `users:read` scope, other users' require an admin session token.
     pub fn get_user(req: &mut Request, db_path: &str)
         -> IronResult<Response> {
         let user_id: String;
         get_user_id_from_request!(req, user_id);
 
-        let db = UsersDb::new(db_path);
+        let allowed = match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) => caller.user.id == user_id ||
+                            UsersRouter::is_admin(caller),
+            None => false
+        };
+        if !allowed {
+            return EndpointError::with(status::Forbidden, 403, None);
+        }
+
+        let db = users_db(req, db_path);
         match db.read(ReadFilter::Id(user_id)) {
             Ok(users) => {
                 if users.is_empty() {
@@ -344,11 +746,10 @@ impl UsersRouter {
 
     /// GET /users handler.
     /// Get the list of all registered users.
-    /// XXX Once we have a permissions system, this method will require a
-    ///     session token with admin scope.
-    pub fn get_all_users(_: &mut Request, db_path: &str)
+    /// Requires a session token with the `users:admin` scope.
+    pub fn get_all_users(req: &mut Request, db_path: &str)
         -> IronResult<Response> {
-        let db = UsersDb::new(db_path);
+        let db = users_db(req, db_path);
         match db.read(ReadFilter::All) {
             Ok(users) => {
                 let users = users.iter().map(
@@ -381,16 +782,12 @@ impl UsersRouter {
 
     /// PUT /users/:id handler.
     /// Edit the information of the user matching the given id.
-    /// XXX Once we have a permission system this method should
-    ///     request a admin scope or check that the user is the
-    ///     one editing its own information.
     pub fn edit_user(req: &mut Request, db_path: &str)
         -> IronResult<Response> {
         #[derive(RustcDecodable, Debug)]
         struct EditUserBody {
             name: Option<String>,
             password: Option<String>,
-            // XXX only admin users should be able to change this value.
             is_admin: Option<bool>
         }
 
@@ -399,7 +796,23 @@ impl UsersRouter {
         let user_id: String;
         get_user_id_from_request!(req, user_id);
 
-        let db = UsersDb::new(db_path);
+        // Names and passwords are changed by their owner or an admin, with
+        // a session token of their own. Only callers granted the
+        // `users:admin` scope can grant or revoke admin privileges.
+        if !UsersRouter::is_owner_or_admin(req, &user_id) {
+            return EndpointError::with(status::Forbidden, 403, None);
+        }
+        let admin_scope = match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) => caller.claims.has_scopes(
+                &[SCOPE_USERS_ADMIN.to_owned()]),
+            None => return EndpointError::with(status::Unauthorized, 401,
+                                               None)
+        };
+        if body.is_admin.is_some() && !admin_scope {
+            return EndpointError::with(status::Forbidden, 403, None);
+        }
+
+        let db = users_db(req, db_path);
         match db.read(ReadFilter::Id(user_id)) {
             Ok(users) => {
                 if users.len() > 1 {
@@ -420,8 +833,8 @@ impl UsersRouter {
 
                 // We build a user from the one obtained from the db and
                 // add the given name, password and is_admin values.
-                // UserBuilder takes care of the validation of these two
-                // fields.
+                // UserBuilder takes care of the validation of the name and
+                // the password.
                 let mut user = UserBuilder::new(Some(users[0].clone()));
                 if let Some(name) = body.name {
                     user = user.name(name);
@@ -455,8 +868,10 @@ impl UsersRouter {
     }
 
     /// PUT /users/:id/activate handler.
-    /// Activate a user by providing a name and a password.
-    pub fn activate_user(req: &mut Request, db_path: &str)
+    /// Activate a user by providing a name and a password. Requires the
+    /// activation token sent with the user invitation, which is consumed.
+    pub fn activate_user(req: &mut Request, db_path: &str,
+                         options: SessionOptions)
         -> IronResult<Response> {
         #[derive(RustcDecodable, Debug)]
         struct ActivateUserBody {
@@ -469,7 +884,7 @@ impl UsersRouter {
         let user_id: String;
         get_user_id_from_request!(req, user_id);
 
-        let db = UsersDb::new(db_path);
+        let db = users_db(req, db_path);
         match db.read(ReadFilter::Id(user_id)) {
             Ok(users) => {
                 if users.len() > 1 {
@@ -488,6 +903,25 @@ impl UsersRouter {
                         409, Some("User is already active".to_owned()))
                 }
 
+                // Only the activation token sent with the last invitation
+                // of the user is accepted here. Session tokens are not.
+                let token = AuthMiddleware::get_session_token(req)
+                    .unwrap_or(String::new());
+                let invalid_token = || EndpointError::with(
+                    status::Unauthorized, 112,
+                    Some("Invalid or expired activation token".to_owned())
+                );
+                match db.read_invitation(&token) {
+                    Ok(Some(ref invitation))
+                        if invitation.user_id == users[0].id &&
+                           invitation.expires_at > now() => {},
+                    Ok(_) => return invalid_token(),
+                    Err(error) => {
+                        println!("{:?}", error);
+                        return from_sqlite_error(error);
+                    }
+                };
+
                 // We build a user from the one obtained from the db and
                 // add the given name and password. UserBuilder takes care
                 // of the validation of these two fields.
@@ -502,8 +936,33 @@ impl UsersRouter {
                         return from_user_builder_error(user_with_error.error);
                     }
                 };
-                match db.update(&user) {
-                    Ok(_) => SessionTokenResponse::with_user(&user),
+                // The activation token is single-use: it is consumed once
+                // the name and password are known to be valid, and only
+                // the request that consumes it activates the user.
+                match db.take_invitation(&token, now()) {
+                    Ok(Some(ref invitation))
+                        if invitation.user_id == user.id => {},
+                    Ok(_) => return invalid_token(),
+                    Err(error) => {
+                        println!("{:?}", error);
+                        return from_sqlite_error(error);
+                    }
+                }
+                if let Err(error) = db.update(&user) {
+                    println!("{:?}", error);
+                    return from_sqlite_error(error);
+                }
+                // The activation token was sent to the email address of
+                // the user, who proves to own it by using the token.
+                if let Err(error) = db.set_email_verified(&user.id,
+                                                          &user.email) {
+                    println!("{:?}", error);
+                    return from_sqlite_error(error);
+                }
+                // Other invitations sent to the user are dropped.
+                match db.delete_invitations(&user.id) {
+                    Ok(_) => SessionTokenResponse::with_user(req, &user, &db,
+                                                             options),
                     Err(error) => {
                         println!("{:?}", error);
                         from_sqlite_error(error)
@@ -517,17 +976,163 @@ impl UsersRouter {
         }
     }
 
+    /// POST /password/forgot handler.
+    /// Email a single-use link to reset the password to the user matching
+    /// the given email address. The response does not tell whether the
+    /// address belongs to a user: the email is sent from another thread,
+    /// so it takes as long either way, and requests are limited per
+    /// address whether it does or not.
+    fn forgot_password(req: &mut Request, db_path: &str,
+                       mailer: &RwLock<InvitationMiddleware>)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct ForgotPasswordBody {
+            email: String
+        }
+
+        let body: ForgotPasswordBody = parse_request_body!(req);
+
+        let mailer = mailer.read().unwrap().clone();
+        if !mailer.is_setup() {
+            return EndpointError::with(status::InternalServerError, 501,
+                Some("No email server is set up".to_owned()));
+        }
+
+        let db = users_db(req, db_path);
+        match db.record_password_reset_request(
+            &body.email, now(), SessionToken::PASSWORD_RESET_INTERVAL) {
+            Ok(true) => {},
+            Ok(false) => return EndpointError::with(
+                status::TooManyRequests, 124,
+                Some("Too many password reset requests".to_owned())
+            ),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+        let users = match db.read(ReadFilter::Email(body.email)) {
+            Ok(users) => users,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        // Inactive users have no password yet, they get invitations
+        // instead.
+        if let Some(user) = users.into_iter().find(|user| user.is_active) {
+            let reset_token = Uuid::new_v4().simple().to_string();
+            if let Err(error) = db.create_password_reset(
+                &reset_token, &user.id,
+                now() + SessionToken::PASSWORD_RESET_TTL) {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+            let reset_url = endpoint(
+                &format!("/password/reset?token={}", reset_token)
+            );
+            thread::spawn(move || {
+                mailer.send_password_reset(&user.email, &reset_url);
+            });
+        }
+        Ok(Response::with(status::NoContent))
+    }
+
+    /// POST /password/reset handler.
+    /// Set a new password with the token of a password reset link, which is
+    /// consumed. The secret of the user is rotated and all their sessions
+    /// are closed, so no token issued before the reset is valid anymore.
+    fn reset_password(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct ResetPasswordBody {
+            token: String,
+            password: String
+        }
+
+        let body: ResetPasswordBody = parse_request_body!(req);
+
+        let invalid_token = || EndpointError::with(status::Unauthorized, 123,
+            Some("Invalid or expired password reset token".to_owned()));
+        let db = users_db(req, db_path);
+        let user_id = match db.read_password_reset(&body.token) {
+            Ok(Some(reset)) => {
+                if reset.expires_at <= now() {
+                    return invalid_token();
+                }
+                reset.user_id
+            },
+            Ok(None) => return invalid_token(),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        let user = match db.read(ReadFilter::Id(user_id)) {
+            Ok(mut users) => {
+                if users.len() != 1 || !users[0].is_active {
+                    return invalid_token();
+                }
+                users.remove(0)
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        // UserBuilder takes care of the validation of the password.
+        let user = match UserBuilder::new(Some(user))
+            .password(body.password)
+            .secret(Uuid::new_v4().simple().to_string())
+            .finalize() {
+            Ok(user) => user,
+            Err(user_with_error) => {
+                println!("{:?}", user_with_error);
+                return from_user_builder_error(user_with_error.error);
+            }
+        };
+        // The reset token is single-use: it is only consumed once the new
+        // password is known to be valid, and whoever consumes it first is
+        // the one setting the password.
+        match db.take_password_reset(&body.token, now()) {
+            Ok(Some(ref user_id)) if *user_id == user.id => {},
+            Ok(_) => return invalid_token(),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+        if let Err(error) = db.update(&user) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+        // Other reset links are dropped, and sessions opened with the
+        // previous password are closed.
+        let result = db.delete_password_resets(&user.id)
+            .and_then(|_| db.delete_sessions(&user.id))
+            .and_then(|_| db.set_email_verified(&user.id, &user.email));
+        match result {
+            // Users log in with their new password, and their second
+            // factor if any.
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
     /// DELETE /users/:id handler.
     /// Delete the user matching the given id.
-    /// XXX Once we have a permission system this method should
-    ///     request a admin scope.
+    /// Requires a session token with the `users:admin` scope.
     pub fn delete_user(req: &mut Request, db_path: &str)
         -> IronResult<Response> {
         let user_id: String;
         get_user_id_from_request!(req, user_id);
 
-        let requester_id = match AuthMiddleware::get_user_id(req) {
-            Some(id) => id,
+        let requester_id = match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) => caller.user.id.clone(),
             None => return EndpointError::with(
                 status::InternalServerError, 501,
                 Some("Could not get requester id".to_owned())
@@ -539,7 +1144,7 @@ impl UsersRouter {
                 Some("You cannot delete yourself".to_owned()));
         }
 
-        let db = UsersDb::new(db_path);
+        let db = users_db(req, db_path);
         match db.read(ReadFilter::Id(user_id)) {
             Ok(users) => {
                 if users.len() > 1 {
@@ -591,336 +1196,2571 @@ impl UsersRouter {
         }
     }
 
-    pub fn new(db_path: &str) -> Self {
-        UsersRouter {
-            db_path: db_path.to_owned(),
-            invitation_middleware: Arc::new(
-                RwLock::new(InvitationMiddleware::new(API_VERSION))
-            )
-        }
-    }
-
-    pub fn init(&self) -> super::iron::middleware::Chain {
-        let mut router = Router::new();
+    /// GET /users/:id/sessions handler.
+    /// Get the list of open sessions of the user matching the given id.
+    /// Only the owner or an admin can list them.
+    pub fn get_sessions(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
 
-        // Setup.
-        let data = self.db_path.clone();
-        router.post(endpoint("/setup"),
-                    move |req: &mut Request| -> IronResult<Response> {
-            UsersRouter::setup(req, &data)
-        });
+        let db = users_db(req, db_path);
+        if !UsersRouter::is_owner_or_admin(req, &user_id) {
+            return EndpointError::with(status::Forbidden, 403, None);
+        }
 
-        // Login.
-        let data = self.db_path.clone();
-        router.post(endpoint("/login"),
-                    move |req: &mut Request| -> IronResult<Response> {
-            UsersRouter::login(req, &data)
-        });
+        match db.read_sessions(&user_id) {
+            Ok(sessions) => {
+                let body = match json::encode(&GetSessionsResponse {
+                    sessions: sessions
+                }) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Ok, body)))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
 
-        // User management.
-        let data = self.db_path.clone();
-        router.post(endpoint("/users"),
-                    move |req: &mut Request| -> IronResult<Response> {
-            UsersRouter::create_user(req, &data)
-        });
+    /// DELETE /users/:id/sessions/:sid handler.
+    /// Close a session of the user matching the given id, signing the
+    /// device that owns it out. Only the owner or an admin can do it.
+    pub fn delete_session(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
 
-        let data = self.db_path.clone();
-        router.get(endpoint("/users/:id"),
-                   move |req: &mut Request| -> IronResult<Response> {
-            UsersRouter::get_user(req, &data)
-        });
+        let session_id = req.extensions.get::<Router>().unwrap()
+            .find("sid").unwrap_or("").to_owned();
 
-        let data = self.db_path.clone();
-        router.get(endpoint("/users"),
-                   move |req: &mut Request| -> IronResult<Response> {
-            UsersRouter::get_all_users(req, &data)
-        });
+        let db = users_db(req, db_path);
+        if !UsersRouter::is_owner_or_admin(req, &user_id) {
+            return EndpointError::with(status::Forbidden, 403, None);
+        }
 
-        let data = self.db_path.clone();
-        router.put(endpoint("/users/:id"),
-                   move |req: &mut Request| -> IronResult<Response> {
-            UsersRouter::edit_user(req, &data)
-        });
+        match db.read_session(&session_id) {
+            Ok(Some(ref session)) if session.user_id == user_id => {
+                match db.delete_session(&session.id) {
+                    Ok(_) => Ok(Response::with((status::NoContent))),
+                    Err(error) => {
+                        println!("{:?}", error);
+                        from_sqlite_error(error)
+                    }
+                }
+            },
+            Ok(_) => EndpointError::with(status::NotFound, 404,
+                Some("Session not found".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
 
-        let data = self.db_path.clone();
-        router.put(endpoint("/users/:id/activate"),
-                   move |req: &mut Request| -> IronResult<Response> {
-            UsersRouter::activate_user(req, &data)
-        });
+    /// POST /users/:id/tokens handler.
+    /// Create a named personal access token for the user matching the given
+    /// id, with the given scopes or else all the scopes granted to the
+    /// user, and optionally expiring in `expires_in` seconds. Only the owner
+    /// can create them, and only with a session token of its own.
+    pub fn create_access_token(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct CreateAccessTokenBody {
+            name: String,
+            scopes: Option<Vec<String>>,
+            expires_in: Option<i64>
+        }
 
-        let data = self.db_path.clone();
-        router.delete(endpoint("/users/:id"),
-                      move |req: &mut Request| -> IronResult<Response> {
-            UsersRouter::delete_user(req, &data)
-        });
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
 
-        let cors = CORS::new(vec![
-            (vec![Method::Post], endpoint("/login")),
-            (vec![Method::Put], endpoint("/users/:id/activate"))
-        ]);
+        let caller = match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) => caller.clone(),
+            None => return EndpointError::with(status::Unauthorized, 401, None)
+        };
+        if caller.user.id != user_id {
+            return EndpointError::with(status::Forbidden, 403, None);
+        }
+        // Neither access tokens nor OAuth clients can create access tokens,
+        // which could be granted more scopes than they were.
+        if !caller.is_first_party() {
+            return EndpointError::with(status::Forbidden, 403,
+                Some("Access tokens cannot create access tokens".to_owned()));
+        }
 
-        let data = self.db_path.clone();
-        let auth_middleware = AuthMiddleware::new(vec![
-            AuthEndpoint(vec![Method::Post, Method::Get],
-                         endpoint("/users")),
-            AuthEndpoint(vec![Method::Get, Method::Put, Method::Delete],
-                         endpoint("/users/:id")),
-            AuthEndpoint(vec![Method::Put],
-                         endpoint("/users/:id/activate")),
-        ], data);
+        let body: CreateAccessTokenBody = parse_request_body!(req);
 
-        let guard = self.invitation_middleware.write().unwrap();
-        let mut chain = Chain::new(router);
-        chain.link_around(auth_middleware);
-        chain.link_after(cors);
-        chain.link_after(guard.clone());
+        let name = body.name.trim().to_owned();
+        if name.is_empty() {
+            return EndpointError::with(status::BadRequest, 100,
+                                       Some("Invalid name".to_owned()));
+        }
+        let expires_at = match body.expires_in {
+            Some(expires_in) if expires_in <= 0 => {
+                return EndpointError::with(status::BadRequest, 400,
+                    Some("Invalid expiration".to_owned()));
+            },
+            Some(expires_in) => Some(now() + expires_in),
+            None => None
+        };
 
-        chain
+        let db = users_db(req, db_path);
+        let granted = match SessionClaims::granted_scopes(&caller.user, &db) {
+            Ok(granted) => granted,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        // Access tokens cannot grant more than what the user is granted.
+        let scopes = match body.scopes {
+            Some(scopes) => {
+                if !scopes.iter().all(|scope| granted.contains(scope)) {
+                    return EndpointError::with(status::Forbidden, 110,
+                        Some("Insufficient scope".to_owned()));
+                }
+                scopes
+            },
+            None => granted
+        };
+
+        let token = format!("{}{}{}", ACCESS_TOKEN_PREFIX,
+                            Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let access_token = AccessToken::new(&user_id, &name, scopes,
+                                            expires_at);
+        if let Err(error) = db.create_access_token(&token, &access_token) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        let body = match json::encode(&CreateAccessTokenResponse {
+            token: token,
+            access_token: access_token
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Created, body)))
     }
 
-    pub fn setup_invitation_middleware(&mut self,
-                                       email_server: &str,
-                                       invitation_url_prepath: &str) {
-        let mut guard = self.invitation_middleware.write().unwrap();
-        guard.setup(email_server, invitation_url_prepath);
+    /// GET /users/:id/tokens handler.
+    /// Get the list of personal access tokens of the user matching the
+    /// given id. Only the owner or an admin can list them.
+    pub fn get_access_tokens(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
+
+        if !UsersRouter::is_owner_or_admin(req, &user_id) {
+            return EndpointError::with(status::Forbidden, 403, None);
+        }
+
+        let db = users_db(req, db_path);
+        match db.read_access_tokens(&user_id) {
+            Ok(tokens) => {
+                let body = match json::encode(&GetAccessTokensResponse {
+                    tokens: tokens
+                }) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Ok, body)))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
     }
-}
 
-#[cfg(test)]
-describe! users_router_tests {
-    before_each {
-        #[allow(unused_imports)]
-        use super::super::{ CreateUserResponse, GetUserResponse,
-                            GetAllUsersResponse, SessionTokenResponse };
+    /// DELETE /users/:id/tokens/:tid handler.
+    /// Revoke a personal access token of the user matching the given id.
+    /// Only the owner or an admin can do it.
+    pub fn delete_access_token(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
 
-        #[allow(unused_imports)]
-        use auth_middleware::SessionClaims;
-        #[allow(unused_imports)]
-        use crypto::sha2::Sha256;
-        #[allow(unused_imports)]
-        use errors::ErrorBody;
-        #[allow(unused_imports)]
-        use iron::{ headers, Headers };
-        #[allow(unused_imports)]
-        use iron::headers::{ Authorization, Basic, Bearer };
-        #[allow(unused_imports)]
-        use iron::prelude::Response;
-        #[allow(unused_imports)]
-        use iron::status::Status;
-        use iron_test::request;
-        use iron_test::response::extract_body_to_string;
-        #[allow(unused_imports)]
-        use jwt;
-        use rustc_serialize::Decodable;
-        use rustc_serialize::json::{ self, DecodeResult };
-        #[allow(unused_imports)]
-        use users_db::{ UserBuilder, remove_test_db, get_db_environment,
-                        ReadFilter };
-        use UsersManager;
+        let token_id = req.extensions.get::<Router>().unwrap()
+            .find("tid").unwrap_or("").to_owned();
 
-        #[allow(dead_code)]
-        fn extract_body_to<T: Decodable>(response: Response)
-            -> DecodeResult<T> {
-            json::decode(&extract_body_to_string(response))
+        if !UsersRouter::is_owner_or_admin(req, &user_id) {
+            return EndpointError::with(status::Forbidden, 403, None);
         }
 
-        let manager = UsersManager::new(&get_db_environment());
-        let chain = manager.get_router_chain();
+        let db = users_db(req, db_path);
+        match db.delete_access_token(&user_id, &token_id) {
+            Ok(0) => EndpointError::with(status::NotFound, 404,
+                Some("Access token not found".to_owned())),
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
     }
 
-    describe! cors_tests {
-        it "should get the appropriate CORS headers" {
-            use super::API_VERSION;
-            use iron::method::Method;
+    /// POST /introspect handler.
+    /// Tell whether the token given in the `token` form parameter is active
+    /// and, if so, who it belongs to, its scopes and its expiration, as
+    /// described by RFC 7662. Tokens rejected for any reason are just not
+    /// active.
+    pub fn introspect(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let token = match req.get_ref::<UrlEncodedBody>() {
+            Ok(params) => params.get("token")
+                                .and_then(|token| token.first().cloned()),
+            Err(_) => None
+        };
+        let token = match token {
+            Some(token) => token,
+            None => return EndpointError::with(status::BadRequest, 400,
+                Some("Missing token".to_owned()))
+        };
 
-            let endpoints = vec![
-                (vec![Method::Post], format!("{}/login", API_VERSION)),
-                (vec![Method::Put], format!("{}/users/:id/activate", API_VERSION))
-            ];
-            for endpoint in endpoints.clone() {
-                let (_, path) = endpoint;
-                let path = format!("http://localhost:3000/{}",
-                                   &(path.replace(":", "foo")));
-                match request::options(&path, Headers::new(), &chain) {
-                    Ok(res) => {
-                        let headers = &res.headers;
-                        assert!(headers.has::<headers::AccessControlAllowOrigin>());
-                        assert!(headers.has::<headers::AccessControlAllowHeaders>());
-                        assert!(headers.has::<headers::AccessControlAllowMethods>());
-                    },
-                    _ => {
-                        assert!(false)
-                    }
+        let mut body = BTreeMap::new();
+        let cache = AuthCache::from_request(req, db_path);
+        match AuthMiddleware::verify_cached(&token, &cache) {
+            Ok(session) => {
+                let claims = session.claims;
+                body.insert("active".to_owned(), Json::Boolean(true));
+                body.insert("scope".to_owned(),
+                            Json::String(claims.scopes.join(" ")));
+                body.insert("username".to_owned(),
+                            Json::String(session.user.name));
+                body.insert("email".to_owned(), Json::String(claims.email));
+                body.insert("token_type".to_owned(),
+                            Json::String("Bearer".to_owned()));
+                body.insert("sub".to_owned(), Json::String(claims.id));
+                if let Some(client_id) = claims.client_id {
+                    body.insert("client_id".to_owned(),
+                                Json::String(client_id));
                 }
+                body.insert("jti".to_owned(), Json::String(claims.jti));
+                body.insert("iat".to_owned(), Json::I64(claims.iat));
+                body.insert("nbf".to_owned(), Json::I64(claims.nbf));
+                // Access tokens without expiration have no `exp`.
+                if claims.exp != i64::MAX {
+                    body.insert("exp".to_owned(), Json::I64(claims.exp));
+                }
+            },
+            Err(TokenError::Internal) => {
+                return EndpointError::with(status::InternalServerError, 501,
+                                           None);
+            },
+            Err(_) => {
+                body.insert("active".to_owned(), Json::Boolean(false));
             }
         }
+        Ok(Response::with((status::Ok, Json::Object(body).to_string())))
+    }
 
-        it "should get the appropriate CORS headers even in case of error" {
-            match request::post(&format!("http://localhost:3000{}",
-                                         &endpoint("/login")),
-                                Headers::new(),
-                                "{}",
-                                &chain) {
-                Ok(_) => {
-                    assert!(false)
-                },
-                Err(err) => {
-                    let headers = &err.response.headers;
-                    assert!(headers.has::<headers::AccessControlAllowOrigin>());
-                    assert!(headers.has::<headers::AccessControlAllowHeaders>());
-                    assert!(headers.has::<headers::AccessControlAllowMethods>());
-                }
-
+    /// GET /keys handler.
+    /// Get the list of server signing keys, newest first. Secrets are never
+    /// exposed.
+    pub fn get_keys(req: &mut Request, db_path: &str) -> IronResult<Response> {
+        let db = users_db(req, db_path);
+        match db.read_signing_keys() {
+            Ok(keys) => {
+                let body = match json::encode(&GetKeysResponse {
+                    keys: keys.iter().map(KeyResponse::from_key).collect()
+                }) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Ok, body)))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
             }
         }
+    }
 
-        it "should not get CORS headers" {
-            match request::options(&format!("http://localhost:3000{}",
-                                            &endpoint("/setup")),
-                                   Headers::new(),
-                                   &chain) {
-                Ok(res) => {
-                    let headers = &res.headers;
-                    assert!(!headers.has::<headers::AccessControlAllowOrigin>());
-                    assert!(!headers.has::<headers::AccessControlAllowHeaders>());
-                    assert!(!headers.has::<headers::AccessControlAllowMethods>());
-                },
-                _ => {
-                    assert!(false)
+    /// POST /keys handler.
+    /// Rotate the server signing keys: new tokens are signed with a new key
+    /// and the previous keys are retired after a grace period.
+    pub fn rotate_keys(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct RotateKeysBody {
+            grace_period: Option<i64>,
+            algorithm: Option<String>
+        }
+
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let body: RotateKeysBody = if payload.trim().is_empty() {
+            RotateKeysBody { grace_period: None, algorithm: None }
+        } else {
+            match json::decode(&payload) {
+                Ok(body) => body,
+                Err(error) => {
+                    println!("{:?}", error);
+                    return from_decoder_error(error);
                 }
             }
+        };
+        let grace_period = body.grace_period
+            .unwrap_or(SessionToken::KEY_GRACE_PERIOD);
+        if grace_period < 0 {
+            return EndpointError::with(status::BadRequest, 400,
+                Some("Invalid grace period".to_owned()));
         }
-    } // cors_tests
-
-    describe! setup_tests {
-        before_each {
-            let usersDb = manager.get_db();
-            usersDb.clear().ok();
 
-            let endpoint = &format!("http://localhost:3000{}",
-                                    &endpoint("/setup"));
-        }
+        let db = users_db(req, db_path);
 
-        it "should respond 201 Created for a proper POST /setup" {
-            match request::post(endpoint, Headers::new(),
-                                "{\"name\": \"name\",
+        // Keep the algorithm of the current key unless told otherwise.
+        let algorithm = match body.algorithm {
+            Some(ref alg) => match SigningAlgorithm::from_str(alg) {
+                Some(algorithm) => algorithm,
+                None => return EndpointError::with(status::BadRequest, 400,
+                    Some("Invalid algorithm".to_owned()))
+            },
+            None => match db.current_signing_key() {
+                Ok(key) => key.algorithm,
+                Err(error) => {
+                    println!("{:?}", error);
+                    return from_sqlite_error(error);
+                }
+            }
+        };
+
+        match db.rotate_signing_keys(grace_period, algorithm) {
+            Ok(key) => {
+                let body = match json::encode(&KeyResponse::from_key(&key)) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Created, body)))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// GET /.well-known/jwks.json handler.
+    /// Publish the public keys of the `EdDSA` signing keys that are not
+    /// retired as a JWK set, so other services can verify session tokens
+    /// without access to the users database.
+    pub fn get_jwks(req: &mut Request, db_path: &str) -> IronResult<Response> {
+        let db = users_db(req, db_path);
+        let keys = match db.read_signing_keys() {
+            Ok(keys) => keys,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        let mut jwks = Vec::new();
+        for key in keys.iter().filter(|key| !key.is_retired()) {
+            if let Some((_, public_key)) = key.ed25519_keypair() {
+                let mut jwk = BTreeMap::new();
+                jwk.insert("kty".to_owned(), Json::String("OKP".to_owned()));
+                jwk.insert("crv".to_owned(),
+                           Json::String("Ed25519".to_owned()));
+                jwk.insert("x".to_owned(),
+                           Json::String(public_key.to_base64(URL_SAFE)));
+                jwk.insert("kid".to_owned(), Json::String(key.kid.clone()));
+                jwk.insert("alg".to_owned(), Json::String(
+                    key.algorithm.as_str().to_owned()));
+                jwk.insert("use".to_owned(), Json::String("sig".to_owned()));
+                jwks.push(Json::Object(jwk));
+            }
+        }
+
+        let mut body = BTreeMap::new();
+        body.insert("keys".to_owned(), Json::Array(jwks));
+        Ok(Response::with((status::Ok, Json::Object(body).to_string())))
+    }
+
+    /// DELETE /keys/:kid handler.
+    /// Retire a signing key right away. Tokens signed with it are rejected
+    /// from now on.
+    pub fn retire_key(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let kid = req.extensions.get::<Router>().unwrap()
+            .find("kid").unwrap_or("").to_owned();
+
+        let db = users_db(req, db_path);
+        match db.retire_signing_key(&kid, now()) {
+            Ok(0) => EndpointError::with(status::NotFound, 404,
+                Some("Key not found".to_owned())),
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// GET /roles handler.
+    /// Get the list of roles with their permissions.
+    pub fn get_roles(req: &mut Request, db_path: &str) -> IronResult<Response> {
+        let db = users_db(req, db_path);
+        match db.read_roles() {
+            Ok(roles) => {
+                let body = match json::encode(&GetRolesResponse {
+                    roles: roles
+                }) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Ok, body)))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// POST /roles handler.
+    /// Create a role with the given permissions.
+    pub fn create_role(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let role: Role = parse_request_body!(req);
+        if !is_valid_role(&role) {
+            return EndpointError::with(status::BadRequest, 111,
+                Some("Invalid role name or permission".to_owned()));
+        }
+
+        let db = users_db(req, db_path);
+        match db.create_role(&role) {
+            Ok(_) => {
+                let body = match json::encode(&role) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Created, body)))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// GET /roles/:role handler.
+    /// Get the role matching the given name.
+    pub fn get_role(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let name = req.extensions.get::<Router>().unwrap()
+            .find("role").unwrap_or("").to_owned();
+
+        let db = users_db(req, db_path);
+        match db.read_role(&name) {
+            Ok(Some(role)) => {
+                let body = match json::encode(&role) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Ok, body)))
+            },
+            Ok(None) => EndpointError::with(status::NotFound, 404,
+                Some("Role not found".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// PUT /roles/:role handler.
+    /// Replace the permissions of the role matching the given name.
+    pub fn edit_role(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let name = req.extensions.get::<Router>().unwrap()
+            .find("role").unwrap_or("").to_owned();
+
+        let body: EditRoleBody = parse_request_body!(req);
+        let role = Role {
+            name: name,
+            permissions: body.permissions
+        };
+        if !is_valid_role(&role) {
+            return EndpointError::with(status::BadRequest, 111,
+                Some("Invalid role name or permission".to_owned()));
+        }
+
+        let db = users_db(req, db_path);
+        match db.read_role(&role.name) {
+            Ok(Some(_)) => {},
+            Ok(None) => return EndpointError::with(status::NotFound, 404,
+                Some("Role not found".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        match db.update_role(&role) {
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// DELETE /roles/:role handler.
+    /// Delete the role matching the given name, taking it away from the
+    /// users it was assigned to.
+    pub fn delete_role(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let name = req.extensions.get::<Router>().unwrap()
+            .find("role").unwrap_or("").to_owned();
+
+        let db = users_db(req, db_path);
+        match db.delete_role(&name) {
+            Ok(0) => EndpointError::with(status::NotFound, 404,
+                Some("Role not found".to_owned())),
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// GET /users/:id/roles handler.
+    /// Get the names of the roles assigned to the user matching the given
+    /// id. Only the owner or an admin can list them.
+    pub fn get_user_roles(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
+
+        let db = users_db(req, db_path);
+        if !UsersRouter::is_owner_or_admin(req, &user_id) {
+            return EndpointError::with(status::Forbidden, 403, None);
+        }
+
+        match db.read_user_roles(&user_id) {
+            Ok(roles) => {
+                let body = match json::encode(&GetUserRolesResponse {
+                    roles: roles
+                }) {
+                    Ok(body) => body,
+                    Err(_) => return EndpointError::with(
+                        status::InternalServerError, 501, None
+                    )
+                };
+                Ok(Response::with((status::Ok, body)))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// PUT /users/:id/roles/:role handler.
+    /// Assign a role to the user matching the given id.
+    pub fn add_user_role(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
+
+        let name = req.extensions.get::<Router>().unwrap()
+            .find("role").unwrap_or("").to_owned();
+
+        let db = users_db(req, db_path);
+        match db.read(ReadFilter::Id(user_id.clone())) {
+            Ok(ref users) if users.len() == 1 => {},
+            Ok(_) => return EndpointError::with(status::NotFound, 404,
+                Some("User not found".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        match db.read_role(&name) {
+            Ok(Some(_)) => {},
+            Ok(None) => return EndpointError::with(status::NotFound, 404,
+                Some("Role not found".to_owned())),
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+
+        match db.add_user_role(&user_id, &name) {
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// DELETE /users/:id/roles/:role handler.
+    /// Take a role away from the user matching the given id.
+    pub fn remove_user_role(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id: String;
+        get_user_id_from_request!(req, user_id);
+
+        let name = req.extensions.get::<Router>().unwrap()
+            .find("role").unwrap_or("").to_owned();
+
+        let db = users_db(req, db_path);
+        match db.remove_user_role(&user_id, &name) {
+            Ok(0) => EndpointError::with(status::NotFound, 404,
+                Some("Role not assigned".to_owned())),
+            Ok(_) => Ok(Response::with(status::NoContent)),
+            Err(error) => {
+                println!("{:?}", error);
+                from_sqlite_error(error)
+            }
+        }
+    }
+
+    /// Whether the caller is an admin with a session token of their own
+    /// granted the `users:admin` scope. Personal access tokens and tokens
+    /// of OAuth clients are not enough, whatever their scopes.
+    fn is_admin(caller: &AuthenticatedUser) -> bool {
+        caller.is_first_party() && caller.user.is_admin &&
+        caller.claims.has_scopes(&[SCOPE_USERS_ADMIN.to_owned()])
+    }
+
+    /// Whether the requester is the user matching `user_id` or an admin,
+    /// with a session token of their own.
+    fn is_owner_or_admin(req: &Request, user_id: &str) -> bool {
+        match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) => (caller.user.id == user_id &&
+                             caller.is_first_party()) ||
+                            UsersRouter::is_admin(caller),
+            None => false
+        }
+    }
+
+    pub fn new(db_path: &str) -> Self {
+        UsersRouter {
+            db_path: db_path.to_owned(),
+            session_options: SessionOptions {
+                ttl: SessionToken::DEFAULT_TTL,
+                cookies: false,
+                secure_cookies: false
+            },
+            openid: None,
+            device_verification_uri: None,
+            webauthn: None,
+            invitation_middleware: Arc::new(
+                RwLock::new(InvitationMiddleware::new(API_VERSION))
+            ),
+            auth_cache: AuthCache::new(db_path)
+        }
+    }
+
+    /// The cache shared by the auth middlewares and database connections
+    /// of the router.
+    pub fn auth_cache(&self) -> AuthCache {
+        self.auth_cache.clone()
+    }
+
+    pub fn init(&self) -> super::iron::middleware::Chain {
+        let mut router = Router::new();
+
+        let session_options = self.session_options;
+
+        // Setup.
+        let data = self.db_path.clone();
+        router.post(endpoint("/setup"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::setup(req, &data, session_options)
+        });
+
+        // Login.
+        let data = self.db_path.clone();
+        let rp = self.webauthn.clone();
+        router.post(endpoint("/login"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::login(req, &data, session_options, rp.as_ref())
+        });
+
+        let data = self.db_path.clone();
+        let rp = self.webauthn.clone();
+        router.post(endpoint("/login/2fa"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::login_2fa(req, &data, session_options, rp.as_ref())
+        });
+
+        let data = self.db_path.clone();
+        router.post(endpoint("/refresh"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::refresh(req, &data, session_options)
+        });
+
+        // Logout.
+        let data = self.db_path.clone();
+        router.post(endpoint("/logout"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::logout(req, &data, session_options)
+        });
+
+        // User management.
+        let data = self.db_path.clone();
+        router.post(endpoint("/users"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::create_user(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.get(endpoint("/users/:id"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_user(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.get(endpoint("/users"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_all_users(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.put(endpoint("/users/:id"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::edit_user(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.post(endpoint("/users/:id/invitation"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::invite_user(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.put(endpoint("/users/:id/activate"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::activate_user(req, &data, session_options)
+        });
+
+        // Password reset.
+        let data = self.db_path.clone();
+        let mailer = self.invitation_middleware.clone();
+        router.post(endpoint("/password/forgot"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::forgot_password(req, &data, &mailer)
+        });
+
+        let data = self.db_path.clone();
+        router.post(endpoint("/password/reset"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::reset_password(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.delete(endpoint("/users/:id"),
+                      move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::delete_user(req, &data)
+        });
+
+        // Session management.
+        let data = self.db_path.clone();
+        router.get(endpoint("/users/:id/sessions"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_sessions(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.delete(endpoint("/users/:id/sessions/:sid"),
+                      move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::delete_session(req, &data)
+        });
+
+        // Two-factor authentication.
+        let data = self.db_path.clone();
+        router.post(endpoint("/users/:id/2fa/totp"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            TwoFactorRouter::enroll_totp(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.post(endpoint("/users/:id/2fa/totp/confirm"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            TwoFactorRouter::confirm_totp(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        let rp = self.webauthn.clone();
+        router.delete(endpoint("/users/:id/2fa/totp"),
+                      move |req: &mut Request| -> IronResult<Response> {
+            TwoFactorRouter::disable_totp(req, &data, rp.as_ref())
+        });
+
+        let data = self.db_path.clone();
+        let rp = self.webauthn.clone();
+        router.post(endpoint("/users/:id/2fa/recovery_codes"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            TwoFactorRouter::regenerate_recovery_codes(req, &data, rp.as_ref())
+        });
+
+        // WebAuthn.
+        if let Some(ref webauthn) = self.webauthn {
+            let data = self.db_path.clone();
+            let rp = webauthn.clone();
+            router.post(endpoint("/users/:id/webauthn/registration"),
+                        move |req: &mut Request| -> IronResult<Response> {
+                WebAuthnRouter::start_registration(req, &data, &rp)
+            });
+
+            let data = self.db_path.clone();
+            let rp = webauthn.clone();
+            router.post(endpoint("/users/:id/webauthn/credentials"),
+                        move |req: &mut Request| -> IronResult<Response> {
+                WebAuthnRouter::register(req, &data, &rp)
+            });
+
+            let data = self.db_path.clone();
+            router.get(endpoint("/users/:id/webauthn/credentials"),
+                       move |req: &mut Request| -> IronResult<Response> {
+                WebAuthnRouter::get_credentials(req, &data)
+            });
+
+            let data = self.db_path.clone();
+            let rp = webauthn.clone();
+            router.delete(endpoint("/users/:id/webauthn/credentials/:cid"),
+                          move |req: &mut Request| -> IronResult<Response> {
+                WebAuthnRouter::delete_credential(req, &data, &rp)
+            });
+
+            let data = self.db_path.clone();
+            let rp = webauthn.clone();
+            router.post(endpoint("/login/webauthn/options"),
+                        move |req: &mut Request| -> IronResult<Response> {
+                WebAuthnRouter::start_login(req, &data, &rp)
+            });
+
+            let data = self.db_path.clone();
+            let rp = webauthn.clone();
+            router.post(endpoint("/login/webauthn"),
+                        move |req: &mut Request| -> IronResult<Response> {
+                UsersRouter::login_webauthn(req, &data, session_options, &rp)
+            });
+        }
+
+        // Personal access tokens.
+        let data = self.db_path.clone();
+        router.post(endpoint("/users/:id/tokens"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::create_access_token(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.get(endpoint("/users/:id/tokens"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_access_tokens(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.delete(endpoint("/users/:id/tokens/:tid"),
+                      move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::delete_access_token(req, &data)
+        });
+
+        // OAuth 2.0 authorization server.
+        let data = self.db_path.clone();
+        router.post(endpoint("/oauth/clients"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::register_client(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.get(endpoint("/oauth/clients"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::get_clients(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.delete(endpoint("/oauth/clients/:client_id"),
+                      move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::delete_client(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        let openid = self.openid.clone();
+        router.get(endpoint("/oauth/authorize"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::get_authorization_request(req, &data, &openid)
+        });
+
+        let data = self.db_path.clone();
+        let openid = self.openid.clone();
+        router.post(endpoint("/oauth/authorize"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::authorize(req, &data, &openid)
+        });
+
+        let data = self.db_path.clone();
+        let openid = self.openid.clone();
+        router.post(endpoint("/oauth/token"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::token(req, &data, session_options.ttl, &openid)
+        });
+
+        // OAuth 2.0 device authorization grant.
+        if let Some(ref verification_uri) = self.device_verification_uri {
+            let data = self.db_path.clone();
+            let verification_uri = verification_uri.clone();
+            router.post(endpoint("/device/code"),
+                        move |req: &mut Request| -> IronResult<Response> {
+                OAuthRouter::device_code(req, &data, &verification_uri)
+            });
+        }
+
+        let data = self.db_path.clone();
+        let openid = self.openid.clone();
+        router.get(endpoint("/device/verify"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::get_device_authorization(req, &data, &openid)
+        });
+
+        let data = self.db_path.clone();
+        let openid = self.openid.clone();
+        router.post(endpoint("/device/verify"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::verify_device(req, &data, &openid)
+        });
+
+        // OpenID Connect.
+        if let Some(ref openid) = self.openid {
+            let openid = openid.clone();
+            let device_flow = self.device_verification_uri.is_some();
+            router.get(endpoint("/.well-known/openid-configuration"),
+                       move |req: &mut Request| -> IronResult<Response> {
+                OAuthRouter::openid_configuration(req, &openid, device_flow)
+            });
+        }
+
+        let data = self.db_path.clone();
+        router.get(endpoint("/userinfo"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::userinfo(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.post(endpoint("/userinfo"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            OAuthRouter::userinfo(req, &data)
+        });
+
+        // Token introspection.
+        let data = self.db_path.clone();
+        router.post(endpoint("/introspect"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::introspect(req, &data)
+        });
+
+        // Signing keys management.
+        let data = self.db_path.clone();
+        router.get(endpoint("/keys"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_keys(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.post(endpoint("/keys"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::rotate_keys(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.delete(endpoint("/keys/:kid"),
+                      move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::retire_key(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.get(endpoint("/.well-known/jwks.json"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_jwks(req, &data)
+        });
+
+        // Role management.
+        let data = self.db_path.clone();
+        router.get(endpoint("/roles"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_roles(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.post(endpoint("/roles"),
+                    move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::create_role(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.get(endpoint("/roles/:role"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_role(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.put(endpoint("/roles/:role"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::edit_role(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.delete(endpoint("/roles/:role"),
+                      move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::delete_role(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.get(endpoint("/users/:id/roles"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::get_user_roles(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.put(endpoint("/users/:id/roles/:role"),
+                   move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::add_user_role(req, &data)
+        });
+
+        let data = self.db_path.clone();
+        router.delete(endpoint("/users/:id/roles/:role"),
+                      move |req: &mut Request| -> IronResult<Response> {
+            UsersRouter::remove_user_role(req, &data)
+        });
+
+        let cors = CORS::new(vec![
+            (vec![Method::Post], endpoint("/login")),
+            (vec![Method::Post], endpoint("/login/2fa")),
+            (vec![Method::Post], endpoint("/login/webauthn/options")),
+            (vec![Method::Post], endpoint("/login/webauthn")),
+            (vec![Method::Post], endpoint("/refresh")),
+            (vec![Method::Get], endpoint("/.well-known/jwks.json")),
+            (vec![Method::Post], endpoint("/oauth/token")),
+            (vec![Method::Get],
+             endpoint("/.well-known/openid-configuration")),
+            (vec![Method::Get, Method::Post], endpoint("/userinfo")),
+            (vec![Method::Put], endpoint("/users/:id/activate")),
+            (vec![Method::Post], endpoint("/password/forgot")),
+            (vec![Method::Post], endpoint("/password/reset"))
+        ]);
+
+        let admin = vec![SCOPE_USERS_ADMIN.to_owned()];
+        let read = vec![SCOPE_USERS_READ.to_owned()];
+        let auth_middleware = AuthMiddleware::with_cache(vec![
+            AuthEndpoint(vec![Method::Post], endpoint("/logout"), vec![]),
+            AuthEndpoint(vec![Method::Post, Method::Get],
+                         endpoint("/users"), admin.clone()),
+            AuthEndpoint(vec![Method::Get],
+                         endpoint("/users/:id"), read),
+            AuthEndpoint(vec![Method::Put],
+                         endpoint("/users/:id"), vec![]),
+            AuthEndpoint(vec![Method::Delete],
+                         endpoint("/users/:id"), admin.clone()),
+            AuthEndpoint(vec![Method::Post],
+                         endpoint("/users/:id/invitation"), admin.clone()),
+            AuthEndpoint(vec![Method::Get],
+                         endpoint("/users/:id/sessions"), vec![]),
+            AuthEndpoint(vec![Method::Delete],
+                         endpoint("/users/:id/sessions/:sid"), vec![]),
+            AuthEndpoint(vec![Method::Post, Method::Delete],
+                         endpoint("/users/:id/2fa/totp"), vec![]),
+            AuthEndpoint(vec![Method::Post],
+                         endpoint("/users/:id/2fa/totp/confirm"), vec![]),
+            AuthEndpoint(vec![Method::Post],
+                         endpoint("/users/:id/2fa/recovery_codes"), vec![]),
+            AuthEndpoint(vec![Method::Post],
+                         endpoint("/users/:id/webauthn/registration"), vec![]),
+            AuthEndpoint(vec![Method::Get, Method::Post],
+                         endpoint("/users/:id/webauthn/credentials"), vec![]),
+            AuthEndpoint(vec![Method::Delete],
+                         endpoint("/users/:id/webauthn/credentials/:cid"),
+                         vec![]),
+            AuthEndpoint(vec![Method::Get, Method::Post],
+                         endpoint("/users/:id/tokens"), vec![]),
+            AuthEndpoint(vec![Method::Delete],
+                         endpoint("/users/:id/tokens/:tid"), vec![]),
+            AuthEndpoint(vec![Method::Get, Method::Post],
+                         endpoint("/oauth/clients"), admin.clone()),
+            AuthEndpoint(vec![Method::Delete],
+                         endpoint("/oauth/clients/:client_id"), admin.clone()),
+            AuthEndpoint(vec![Method::Get, Method::Post],
+                         endpoint("/oauth/authorize"), vec![]),
+            AuthEndpoint(vec![Method::Get, Method::Post],
+                         endpoint("/device/verify"), vec![]),
+            AuthEndpoint(vec![Method::Get, Method::Post],
+                         endpoint("/userinfo"), vec![]),
+            AuthEndpoint(vec![Method::Post], endpoint("/introspect"),
+                         vec![SCOPE_TOKENS_INTROSPECT.to_owned()]),
+            AuthEndpoint(vec![Method::Get, Method::Post],
+                         endpoint("/keys"), admin.clone()),
+            AuthEndpoint(vec![Method::Delete],
+                         endpoint("/keys/:kid"), admin.clone()),
+            AuthEndpoint(vec![Method::Get, Method::Post],
+                         endpoint("/roles"), admin.clone()),
+            AuthEndpoint(vec![Method::Get, Method::Put, Method::Delete],
+                         endpoint("/roles/:role"), admin.clone()),
+            AuthEndpoint(vec![Method::Get],
+                         endpoint("/users/:id/roles"), vec![]),
+            AuthEndpoint(vec![Method::Put, Method::Delete],
+                         endpoint("/users/:id/roles/:role"), admin),
+        ], self.auth_cache.clone());
+
+        let guard = self.invitation_middleware.write().unwrap();
+        let mut chain = Chain::new(router);
+        chain.link_around(auth_middleware);
+        chain.link_after(cors);
+        chain.link_after(guard.clone());
+
+        chain
+    }
+
+    /// Set the lifetime, in seconds, of the session tokens issued by the
+    /// router. It only applies to chains created by later calls to `init`.
+    pub fn set_session_ttl(&mut self, session_ttl: i64) {
+        self.session_options.ttl = session_ttl;
+    }
+
+    /// Hand session and refresh tokens to clients in HttpOnly cookies,
+    /// along with a CSRF token cookie, instead of the response body when
+    /// they sign in. Cookies are marked as `Secure` if `secure` is true. It
+    /// only applies to chains created by later calls to `init`.
+    pub fn enable_cookie_sessions(&mut self, secure: bool) {
+        self.session_options.cookies = true;
+        self.session_options.secure_cookies = secure;
+    }
+
+    /// Act as an OpenID Connect provider identified by `issuer`, the URL
+    /// the API is reached at including its version, with
+    /// `authorization_endpoint` as the consent page of the host
+    /// application. It only applies to chains created by later calls to
+    /// `init`.
+    pub fn setup_openid_provider(&mut self, issuer: &str,
+                                 authorization_endpoint: &str) {
+        self.openid = Some(OpenIdOptions {
+            issuer: issuer.trim_right_matches('/').to_owned(),
+            authorization_endpoint: authorization_endpoint.to_owned()
+        });
+    }
+
+    /// Let devices without a browser or keyboard get tokens with the OAuth
+    /// 2.0 device authorization grant. Users are asked to approve their
+    /// requests at `verification_uri`, a page of the host application. It
+    /// only applies to chains created by later calls to `init`.
+    pub fn setup_device_flow(&mut self, verification_uri: &str) {
+        self.device_verification_uri = Some(verification_uri.to_owned());
+    }
+
+    /// Let users register WebAuthn credentials, like passkeys, to log in
+    /// without a password or as a second factor. `rp_id` is the domain of
+    /// the host application, `rp_name` the name authenticators show and
+    /// `origin` the origin of its web pages. It only applies to chains
+    /// created by later calls to `init`.
+    pub fn setup_webauthn(&mut self, rp_id: &str, rp_name: &str,
+                          origin: &str) {
+        self.webauthn = Some(RelyingParty {
+            id: rp_id.to_owned(),
+            name: rp_name.to_owned(),
+            origin: origin.trim_right_matches('/').to_owned()
+        });
+    }
+
+    pub fn setup_invitation_middleware(&mut self,
+                                       email_server: &str,
+                                       invitation_url_prepath: &str) {
+        let mut guard = self.invitation_middleware.write().unwrap();
+        guard.setup(email_server, invitation_url_prepath);
+    }
+}
+
+#[cfg(test)]
+describe! users_router_tests {
+    before_each {
+        #[allow(unused_imports)]
+        use super::super::{ CreateAccessTokenResponse, CreateUserResponse,
+                            GetAccessTokensResponse, GetUserResponse,
+                            GetAllUsersResponse, GetSessionsResponse,
+                            GetKeysResponse, GetRolesResponse,
+                            GetUserRolesResponse, KeyResponse,
+                            SessionTokenResponse };
+
+        #[allow(unused_imports)]
+        use auth_middleware::{ SessionClaims, SessionToken };
+        #[allow(unused_imports)]
+        use errors::ErrorBody;
+        #[allow(unused_imports)]
+        use iron::{ headers, Headers };
+        #[allow(unused_imports)]
+        use iron::headers::{ Authorization, Basic, Bearer };
+        #[allow(unused_imports)]
+        use iron::prelude::Response;
+        #[allow(unused_imports)]
+        use iron::status::Status;
+        use iron_test::request;
+        #[allow(unused_imports)]
+        use iron_test::response::extract_body_to_string;
+        #[allow(unused_imports)]
+        use jwt;
+        #[allow(unused_imports)]
+        use rustc_serialize::base64::FromBase64;
+        #[allow(unused_imports)]
+        use rustc_serialize::json::{ self, Json };
+        #[allow(unused_imports)]
+        use test_helpers::extract_body_to;
+        #[allow(unused_imports)]
+        use users_db::{ UserBuilder, remove_test_db, get_db_environment,
+                        ReadFilter, Role, Session };
+        use UsersManager;
+
+        let manager = UsersManager::new(&get_db_environment());
+        let chain = manager.get_router_chain();
+    }
+
+    describe! cors_tests {
+        it "should get the appropriate CORS headers" {
+            use super::API_VERSION;
+            use iron::method::Method;
+
+            let endpoints = vec![
+                (vec![Method::Post], format!("{}/login", API_VERSION)),
+                (vec![Method::Post], format!("{}/login/2fa", API_VERSION)),
+                (vec![Method::Post], format!("{}/refresh", API_VERSION)),
+                (vec![Method::Post], format!("{}/oauth/token", API_VERSION)),
+                (vec![Method::Get, Method::Post],
+                 format!("{}/userinfo", API_VERSION)),
+                (vec![Method::Put], format!("{}/users/:id/activate", API_VERSION)),
+                (vec![Method::Post], format!("{}/password/forgot", API_VERSION)),
+                (vec![Method::Post], format!("{}/password/reset", API_VERSION))
+            ];
+            for endpoint in endpoints.clone() {
+                let (_, path) = endpoint;
+                let path = format!("http://localhost:3000/{}",
+                                   &(path.replace(":", "foo")));
+                match request::options(&path, Headers::new(), &chain) {
+                    Ok(res) => {
+                        let headers = &res.headers;
+                        assert!(headers.has::<headers::AccessControlAllowOrigin>());
+                        assert!(headers.has::<headers::AccessControlAllowHeaders>());
+                        assert!(headers.has::<headers::AccessControlAllowMethods>());
+                    },
+                    _ => {
+                        assert!(false)
+                    }
+                }
+            }
+        }
+
+        it "should get the appropriate CORS headers even in case of error" {
+            match request::post(&format!("http://localhost:3000{}",
+                                         &endpoint("/login")),
+                                Headers::new(),
+                                "{}",
+                                &chain) {
+                Ok(_) => {
+                    assert!(false)
+                },
+                Err(err) => {
+                    let headers = &err.response.headers;
+                    assert!(headers.has::<headers::AccessControlAllowOrigin>());
+                    assert!(headers.has::<headers::AccessControlAllowHeaders>());
+                    assert!(headers.has::<headers::AccessControlAllowMethods>());
+                }
+
+            }
+        }
+
+        it "should not get CORS headers" {
+            match request::options(&format!("http://localhost:3000{}",
+                                            &endpoint("/setup")),
+                                   Headers::new(),
+                                   &chain) {
+                Ok(res) => {
+                    let headers = &res.headers;
+                    assert!(!headers.has::<headers::AccessControlAllowOrigin>());
+                    assert!(!headers.has::<headers::AccessControlAllowHeaders>());
+                    assert!(!headers.has::<headers::AccessControlAllowMethods>());
+                },
+                _ => {
+                    assert!(false)
+                }
+            }
+        }
+    } // cors_tests
+
+    describe! setup_tests {
+        before_each {
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+
+            let endpoint = &format!("http://localhost:3000{}",
+                                    &endpoint("/setup"));
+        }
+
+        it "should respond 201 Created for a proper POST /setup" {
+            match request::post(endpoint, Headers::new(),
+                                "{\"name\": \"name\",
                                   \"email\": \"username@domain.com\",
                                   \"password\": \"password\"}",
                                 &chain) {
-                Ok(res) => {
-                    assert_eq!(res.status.unwrap(), Status::Created);
-                    let body_obj = extract_body_to::<SessionTokenResponse>(res).unwrap();
-                    let token = body_obj.session_token;
-                    let claims = jwt::Token::<jwt::Header, SessionClaims>::parse(&token)
-                        .ok().unwrap().claims;
-                    assert_eq!(claims.email, "username@domain.com");
+                Ok(res) => {
+                    assert_eq!(res.status.unwrap(), Status::Created);
+                    let body_obj = extract_body_to::<SessionTokenResponse>(res).unwrap();
+                    let token = body_obj.session_token;
+                    let claims = jwt::Token::<jwt::Header, SessionClaims>::parse(&token)
+                        .ok().unwrap().claims;
+                    assert_eq!(claims.email, "username@domain.com");
+                },
+                Err(err) => {
+                    println!("{:?}", err);
+                    assert!(false);
+                }
+            };
+        }
+
+        it "should create one admin user" {
+            let body = "{\"name\": \"name\",\
+                         \"email\": \"username@domain.com\",\
+                         \"password\": \"password\"}";
+
+            if let Ok(res) = request::post(endpoint, Headers::new(), body,
+                                           &chain) {
+                assert_eq!(res.status.unwrap(), Status::Created);
+                let admins = usersDb.read(ReadFilter::IsAdmin(true)).unwrap();
+                assert_eq!(admins.len(), 1);
+                assert_eq!(admins[0].email, "username@domain.com");
+            } else {
+                assert!(false);
+            };
+        }
+
+        it "should respond 410 Gone if an admin account exists" {
+            // Be sure we have an admin
+            usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("admin"))
+                       .password(String::from("password!!"))
+                       .email(String::from("admin@example.com"))
+                       .admin(true)
+                       .finalize().unwrap()).ok();
+            match request::post(endpoint, Headers::new(),
+                                "{\"name\": \"u\",
+                                  \"email\": \"u@d\",
+                                  \"password\": \"12345678\"}",
+                                &chain) {
+                Ok(_) => {
+                    assert!(false);
+                },
+                Err(error) => {
+                    let response = error.response;
+                    assert!(response.status.is_some());
+                    assert_eq!(response.status.unwrap(), Status::Gone);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 410);
+                    assert_eq!(json.message,
+                               Some("There is already an admin account".to_owned()));
+                }
+            };
+        }
+
+        it "should respond 400 BadRequest, errno 100 if name is missing" {
+            match request::post(endpoint, Headers::new(),
+                                "{\"email\": \"u@d\",
+                                  \"password\": \"12345678\"}",
+                                &chain) {
+                Ok(_) => {
+                    assert!(false);
+                },
+                Err(error) => {
+                    let response = error.response;
+                    assert!(response.status.is_some());
+                    assert_eq!(response.status.unwrap(), Status::BadRequest);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 100);
+                    assert_eq!(json.message, Some("Invalid user name".to_owned()));
+                }
+            };
+        }
+
+        it "should respond 400 BadRequest, errno 101 if email is missing" {
+           match request::post(endpoint, Headers::new(),
+                                "{\"name\": \"u\",
+                                  \"password\": \"12345678\"}",
+                                &chain) {
+                Ok(_) => {
+                    assert!(false);
+                },
+                Err(error) => {
+                    let response = error.response;
+                    assert!(response.status.is_some());
+                    assert_eq!(response.status.unwrap(), Status::BadRequest);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 101);
+                    assert_eq!(json.message, Some("Invalid email".to_owned()));
+                }
+            };
+        }
+
+        it "should respond 400 BadRequest, errno 102 if password is missing" {
+            match request::post(endpoint, Headers::new(),
+                                "{\"name\": \"u\",
+                                  \"email\": \"u@d\"}",
+                                &chain) {
+                Ok(_) => {
+                    assert!(false);
+                },
+                Err(error) => {
+                    let response = error.response;
+                    assert!(response.status.is_some());
+                    assert_eq!(response.status.unwrap(), Status::BadRequest);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 102);
+                    assert_eq!(json.message,
+                        Some("Invalid password. Passwords must have a minimum of 8 chars"
+                             .to_owned()));
+                }
+            };
+        }
+
+        after_each {
+            remove_test_db();
+        }
+    } // setup_tests
+
+    describe! login_tests {
+        before_each {
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            // Create inactive user.
+            usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("inactive_user"))
+                       .password(String::from("password"))
+                       .email(String::from("inactive_user@example.com"))
+                       .secret(String::from("secret"))
+                       .active(false)
+                       .finalize().unwrap()).ok();
+            let endpoint = &format!("http://localhost:3000{}",
+                                    endpoint("/login"));
+        }
+
+        it "should respond with a generic 400 Bad Request for requests missing
+            username" {
+            let invalid_credentials = Authorization(Basic {
+                username: "".to_owned(),
+                password: Some("password".to_owned())
+            });
+            let mut headers = Headers::new();
+            headers.set(invalid_credentials);
+
+            if let Err(error) = request::post(endpoint, headers, "", &chain) {
+                let response = error.response;
+                assert!(response.status.is_some());
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 103);
+            } else {
+                assert!(false);
+            };
+        }
+
+        it "should respond with a generic 400 Bad Request for requests missing
+            password" {
+            let invalid_credentials = Authorization(Basic {
+                username: "username".to_owned(),
+                password: Some("".to_owned())
+            });
+            let mut headers = Headers::new();
+            headers.set(invalid_credentials);
+
+            if let Err(error) = request::post(endpoint, headers, "", &chain) {
+                let response = error.response;
+                assert!(response.status.is_some());
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 103);
+            } else {
+                assert!(false);
+            };
+        }
+
+        it "should respond with a 400 Bad Request for requests missing the
+            authorization password" {
+            let headers = Headers::new();
+
+            if let Err(error) = request::post(endpoint, headers, "", &chain) {
+                let response = error.response;
+                assert!(response.status.is_some());
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 103);
+            } else {
+                assert!(false);
+            };
+        }
+
+        it "should respond with a 401 Unauthorized for invalid credentials" {
+            let invalid_credentials = Authorization(Basic {
+                username: "johndoe@example.com".to_owned(),
+                password: Some("password".to_owned())
+            });
+            let mut headers = Headers::new();
+            headers.set(invalid_credentials);
+
+            if let Err(error) = request::post(endpoint, headers, "", &chain) {
+                let response = error.response;
+                assert!(response.status.is_some());
+                assert_eq!(response.status.unwrap(), Status::Unauthorized);
+            } else {
+                assert!(false);
+            };
+        }
+
+        it "should respond with a 401 Unauthorized for inactive user" {
+            let invalid_credentials = Authorization(Basic {
+                username: "inactive_user@example.com".to_owned(),
+                password: Some("password".to_owned())
+            });
+            let mut headers = Headers::new();
+            headers.set(invalid_credentials);
+
+            if let Err(error) = request::post(endpoint, headers, "", &chain) {
+                let response = error.response;
+                assert!(response.status.is_some());
+                assert_eq!(response.status.unwrap(), Status::Unauthorized);
+            } else {
+                assert!(false);
+            };
+        }
+
+        it "should respond with a 201 Created and a valid JWT token in body for
+            valid credentials" {
+            // Create active user.
+            let user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .secret(String::from("secret"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+            let valid_credentials = Authorization(Basic {
+                username: "username@example.com".to_owned(),
+                password: Some("password".to_owned())
+            });
+            let mut headers = Headers::new();
+            headers.set(valid_credentials);
+            match request::post(endpoint, headers, "", &chain) {
+                Ok(response) => {
+                    assert!(response.status.is_some());
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                    let body_obj = extract_body_to::<SessionTokenResponse>(response).unwrap();
+                    let token = body_obj.session_token;
+                    let claims = jwt::Token::<jwt::Header, SessionClaims>::parse(&token)
+                                .ok().unwrap().claims;
+                    assert_eq!(claims.id, user.id);
+                    assert_eq!(claims.email, user.email);
+                },
+                Err(_) => assert!(false)
+            };
+        }
+
+        after_each {
+            remove_test_db();
+        }
+    } // login_tests
+
+    describe! refresh_tests {
+        before_each {
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).ok();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: "username@example.com".to_owned(),
+                password: Some("password".to_owned())
+            }));
+            let login = request::post(&format!("http://localhost:3000{}",
+                                               endpoint("/login")),
+                                      headers, "", &chain).unwrap();
+            let login = extract_body_to::<SessionTokenResponse>(login).unwrap();
+
+            let endpoint = &format!("http://localhost:3000{}",
+                                    endpoint("/refresh"));
+        }
+
+        it "should respond 201 Created with new tokens for a valid refresh
+            token" {
+            let body = format!("{{\"refresh_token\": \"{}\"}}",
+                               login.refresh_token);
+            match request::post(endpoint, Headers::new(), &body, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                    let body_obj =
+                        extract_body_to::<SessionTokenResponse>(response).unwrap();
+                    assert!(!body_obj.session_token.is_empty());
+                    assert!(body_obj.refresh_token != login.refresh_token);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false);
+                }
+            };
+        }
+
+        it "should respond 401 Unauthorized errno 107 for a reused refresh
+            token" {
+            let body = format!("{{\"refresh_token\": \"{}\"}}",
+                               login.refresh_token);
+            request::post(endpoint, Headers::new(), &body, &chain).unwrap();
+            match request::post(endpoint, Headers::new(), &body, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 107);
+                }
+            };
+        }
+
+        it "should respond 401 Unauthorized errno 107 for an unknown refresh
+            token" {
+            match request::post(endpoint, Headers::new(),
+                                "{\"refresh_token\": \"unknown\"}", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 107);
+                }
+            };
+        }
+
+        it "should respond 401 Unauthorized errno 107 for a refresh token of
+            a closed session" {
+            use auth_middleware::now;
+
+            let user_id = usersDb.read(ReadFilter::Email(
+                "username@example.com".to_owned())).unwrap()[0].id.clone();
+            usersDb.create_refresh_token("orphan", &user_id, "closed",
+                                         now() + 60).unwrap();
+            match request::post(endpoint, Headers::new(),
+                                "{\"refresh_token\": \"orphan\"}", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 107);
+                }
+            };
+        }
+
+        after_each {
+            remove_test_db();
+        }
+    } // refresh_tests
+
+    describe! logout_tests {
+        before_each {
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .admin(true)
+                       .active(true)
+                       .finalize().unwrap()).ok();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: "username@example.com".to_owned(),
+                password: Some("password".to_owned())
+            }));
+            let login = request::post(&format!("http://localhost:3000{}",
+                                               endpoint("/login")),
+                                      headers, "", &chain).unwrap();
+            let login = extract_body_to::<SessionTokenResponse>(login).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: login.session_token.to_owned()
+            }));
+
+            let logout_endpoint = &format!("http://localhost:3000{}",
+                                           endpoint("/logout"));
+            let users_endpoint = &format!("http://localhost:3000{}",
+                                          endpoint("/users"));
+        }
+
+        it "should respond 401 Unauthorized to non authenticated requests" {
+            match request::post(logout_endpoint, Headers::new(), "", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                }
+            };
+        }
+
+        it "should revoke the session token" {
+            match request::post(logout_endpoint, headers.clone(), "", &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false);
+                }
+            };
+            match request::get(users_endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 108);
+                }
+            };
+        }
+
+        it "should revoke the refresh token if provided" {
+            let body = format!("{{\"refresh_token\": \"{}\"}}",
+                               login.refresh_token);
+            match request::post(logout_endpoint, headers, &body, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false);
+                }
+            };
+            assert!(usersDb.read_refresh_token(&login.refresh_token)
+                           .unwrap().is_none());
+        }
+
+        it "should not revoke the refresh tokens of other users" {
+            use auth_middleware::now;
+
+            usersDb.create_refresh_token("other", "otheruser", "othersession",
+                                         now() + 60).unwrap();
+            let body = "{\"refresh_token\": \"other\"}";
+            match request::post(logout_endpoint, headers, body, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false);
+                }
+            };
+            assert!(usersDb.read_refresh_token("other").unwrap().is_some());
+        }
+
+        after_each {
+            remove_test_db();
+        }
+    } // logout_tests
+
+    describe! cookie_sessions_tests {
+        before_each {
+            use std::str;
+
+            let mut manager = UsersManager::new(&get_db_environment());
+            manager.enable_cookie_sessions(false);
+            let chain = manager.get_router_chain();
+
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            let user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .admin(true)
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: "username@example.com".to_owned(),
+                password: Some("password".to_owned())
+            }));
+            let login = request::post(&format!("http://localhost:3000{}",
+                                               endpoint("/login")),
+                                      headers, "", &chain).unwrap();
+            let set_cookies: Vec<String> = login.headers.get_raw("Set-Cookie")
+                .unwrap().iter()
+                .map(|cookie| str::from_utf8(cookie).unwrap().to_owned())
+                .collect();
+            let login_body = extract_body_to_string(login);
+
+            // Keep the name=value part of each cookie, as a browser would
+            // send it back.
+            let cookies: Vec<&str> = set_cookies.iter()
+                .map(|cookie| cookie.split(';').next().unwrap())
+                .collect();
+            let csrf_token = cookies[1].splitn(2, '=').nth(1).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set_raw("Cookie", vec![cookies.join("; ").into_bytes()]);
+
+            let user_endpoint = &format!("http://localhost:3000{}",
+                                         endpoint(&format!("/users/{}",
+                                                           user.id)));
+            let logout_endpoint = &format!("http://localhost:3000{}",
+                                           endpoint("/logout"));
+            let refresh_endpoint = &format!("http://localhost:3000{}",
+                                            endpoint("/refresh"));
+        }
+
+        it "should set HttpOnly and SameSite cookies on login" {
+            assert_eq!(set_cookies.len(), 3);
+            assert!(set_cookies[0].starts_with("session_token="));
+            assert!(set_cookies[0].contains("HttpOnly"));
+            assert!(set_cookies[0].contains("SameSite=Strict"));
+            assert!(set_cookies[1].starts_with("csrf_token="));
+            assert!(!set_cookies[1].contains("HttpOnly"));
+            assert!(set_cookies[1].contains("SameSite=Strict"));
+            assert!(set_cookies[2].starts_with("refresh_token="));
+            assert!(set_cookies[2].contains("HttpOnly"));
+            assert!(set_cookies[2].contains(
+                &format!("Path={};", endpoint("/refresh"))));
+        }
+
+        it "should leave the tokens out of the login body" {
+            assert_eq!(login_body, "{}");
+            assert!(!login_body.contains("session_token"));
+            assert!(!login_body.contains("refresh_token"));
+        }
+
+        it "should refresh with the refresh token cookie and the CSRF token" {
+            match request::post(refresh_endpoint, headers.clone(), "", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 114);
+                }
+            };
+
+            headers.set_raw("X-CSRF-Token",
+                            vec![csrf_token.as_bytes().to_vec()]);
+            match request::post(refresh_endpoint, headers, "", &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                    assert_eq!(response.headers.get_raw("Set-Cookie")
+                                   .unwrap().len(), 3);
+                    assert_eq!(extract_body_to_string(response), "{}");
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false);
+                }
+            };
+        }
+
+        it "should authenticate safe requests with the session cookie" {
+            match request::get(user_endpoint, headers, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false);
+                }
+            };
+        }
+
+        it "should respond 403 errno 114 to state-changing requests without
+            the CSRF token" {
+            match request::post(logout_endpoint, headers.clone(), "", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 114);
+                }
+            };
+
+            headers.set_raw("X-CSRF-Token", vec![b"forged".to_vec()]);
+            match request::post(logout_endpoint, headers, "", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 114);
+                }
+            };
+        }
+
+        it "should logout with the CSRF token and clear the cookies" {
+            headers.set_raw("X-CSRF-Token",
+                            vec![csrf_token.as_bytes().to_vec()]);
+            match request::post(logout_endpoint, headers, "", &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
+                    let cleared = response.headers.get_raw("Set-Cookie")
+                        .unwrap();
+                    assert_eq!(cleared.len(), 3);
+                    for cookie in cleared {
+                        assert!(str::from_utf8(cookie).unwrap()
+                                    .contains("Max-Age=0"));
+                    }
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false);
+                }
+            };
+        }
+
+        after_each {
+            remove_test_db();
+        }
+    } // cookie_sessions_tests
+
+    describe! sessions_tests {
+        before_each {
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            let user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+            let other = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("other"))
+                       .password(String::from("password"))
+                       .email(String::from("other@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: "username@example.com".to_owned(),
+                password: Some("password".to_owned())
+            }));
+            headers.set(headers::UserAgent("Fennec".to_owned()));
+            let login = request::post(&format!("http://localhost:3000{}",
+                                               endpoint("/login")),
+                                      headers, "", &chain).unwrap();
+            let login = extract_body_to::<SessionTokenResponse>(login).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: login.session_token.to_owned()
+            }));
+
+            let sessions_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/sessions", user.id)));
+        }
+
+        it "should return 200 OK with the list of sessions" {
+            match request::get(sessions_endpoint, headers, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                    let body_obj = extract_body_to::<GetSessionsResponse>
+                                   (response).unwrap();
+                    assert_eq!(body_obj.sessions.len(), 1);
+                    assert_eq!(body_obj.sessions[0].user_id, user.id);
+                    assert_eq!(body_obj.sessions[0].user_agent, "Fennec");
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+        }
+
+        it "should return 403 Forbidden for sessions of another user" {
+            let endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/sessions", other.id)));
+            match request::get(endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                }
+            };
+        }
+
+        it "should close a session and reject its tokens" {
+            let sessions = usersDb.read_sessions(&user.id).unwrap();
+            let endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/sessions/{}",
+                                  user.id, sessions[0].id)));
+            match request::delete(endpoint, headers.clone(), &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+            assert!(usersDb.read_sessions(&user.id).unwrap().is_empty());
+            assert!(usersDb.read_refresh_token(&login.refresh_token)
+                           .unwrap().is_none());
+            match request::get(sessions_endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 109);
+                }
+            };
+        }
+
+        it "should return 404 NotFound for an unknown session" {
+            let endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/sessions/unknown", user.id)));
+            match request::delete(endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::NotFound);
+                }
+            };
+        }
+
+        it "should return 403 Forbidden to admin access tokens" {
+            use users_db::AccessToken;
+
+            let admin = usersDb.create(&UserBuilder::new(None)
+                        .name(String::from("admin"))
+                        .password(String::from("password"))
+                        .email(String::from("admin@example.com"))
+                        .admin(true)
+                        .active(true)
+                        .finalize().unwrap()).unwrap();
+            usersDb.create_access_token("pat_admin", &AccessToken::new(
+                &admin.id, "sessions", vec!["users:read".to_owned()], None))
+                   .unwrap();
+            let mut pat_headers = Headers::new();
+            pat_headers.set(Authorization(Bearer {
+                token: "pat_admin".to_owned()
+            }));
+
+            let session_id = usersDb.read_sessions(&user.id).unwrap()[0]
+                                    .id.clone();
+            let endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/sessions/{}", user.id,
+                                  session_id)));
+            match request::delete(endpoint, pat_headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                }
+            };
+            assert!(usersDb.read_session(&session_id).unwrap().is_some());
+        }
+
+        after_each {
+            remove_test_db();
+        }
+    } // sessions_tests
+
+    describe! access_tokens_tests {
+        before_each {
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            let user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+            let other = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("other"))
+                       .password(String::from("password"))
+                       .email(String::from("other@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: "username@example.com".to_owned(),
+                password: Some("password".to_owned())
+            }));
+            let login = request::post(&format!("http://localhost:3000{}",
+                                               endpoint("/login")),
+                                      headers, "", &chain).unwrap();
+            let login = extract_body_to::<SessionTokenResponse>(login).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: login.session_token.to_owned()
+            }));
+
+            let tokens_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/tokens", user.id)));
+        }
+
+        it "should create an access token usable as a bearer token" {
+            let created = match request::post(tokens_endpoint,
+                                              headers.clone(),
+                                              "{\"name\": \"script\"}",
+                                              &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                    extract_body_to::<CreateAccessTokenResponse>(response)
+                        .unwrap()
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    panic!()
+                }
+            };
+            assert!(created.token.starts_with("pat_"));
+            assert_eq!(created.access_token.name, "script");
+            assert_eq!(created.access_token.expires_at, None);
+
+            let mut pat_headers = Headers::new();
+            pat_headers.set(Authorization(Bearer {
+                token: created.token.to_owned()
+            }));
+            let user_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}", user.id)));
+            match request::get(user_endpoint, pat_headers, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+            match request::get(tokens_endpoint, headers, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                    let body_obj = extract_body_to::<GetAccessTokensResponse>
+                                   (response).unwrap();
+                    assert_eq!(body_obj.tokens.len(), 1);
+                    assert_eq!(body_obj.tokens[0].id, created.access_token.id);
+                    // The request made with the token is recorded.
+                    assert!(body_obj.tokens[0].last_used.is_some());
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+        }
+
+        it "should not create access tokens with an access token" {
+            let created = request::post(tokens_endpoint, headers.clone(),
+                                        "{\"name\": \"script\"}", &chain)
+                          .unwrap();
+            let created = extract_body_to::<CreateAccessTokenResponse>
+                          (created).unwrap();
+            let mut pat_headers = Headers::new();
+            pat_headers.set(Authorization(Bearer {
+                token: created.token.to_owned()
+            }));
+            match request::post(tokens_endpoint, pat_headers,
+                                "{\"name\": \"another\"}", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                }
+            };
+        }
+
+        it "should return 403 Forbidden for scopes not granted to the user" {
+            match request::post(tokens_endpoint, headers,
+                                "{\"name\": \"script\", \
+                                  \"scopes\": [\"users:admin\"]}", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 110);
+                }
+            };
+        }
+
+        it "should return 403 Forbidden for tokens of another user" {
+            let endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/tokens", other.id)));
+            match request::post(endpoint, headers, "{\"name\": \"script\"}",
+                                &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                }
+            };
+        }
+
+        it "should revoke an access token and reject it" {
+            let created = request::post(tokens_endpoint, headers.clone(),
+                                        "{\"name\": \"script\"}", &chain)
+                          .unwrap();
+            let created = extract_body_to::<CreateAccessTokenResponse>
+                          (created).unwrap();
+            let endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/tokens/{}",
+                                  user.id, created.access_token.id)));
+            match request::delete(endpoint, headers.clone(), &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
                 },
-                Err(err) => {
-                    println!("{:?}", err);
-                    assert!(false);
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+
+            let mut pat_headers = Headers::new();
+            pat_headers.set(Authorization(Bearer {
+                token: created.token.to_owned()
+            }));
+            match request::get(tokens_endpoint, pat_headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                }
+            };
+
+            match request::delete(endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::NotFound);
+                }
+            };
+        }
+
+        it "should return 403 Forbidden to admin access tokens" {
+            use users_db::AccessToken;
+
+            let admin = usersDb.create(&UserBuilder::new(None)
+                        .name(String::from("admin"))
+                        .password(String::from("password"))
+                        .email(String::from("admin@example.com"))
+                        .admin(true)
+                        .active(true)
+                        .finalize().unwrap()).unwrap();
+            usersDb.create_access_token("pat_admin", &AccessToken::new(
+                &admin.id, "tokens", vec!["users:read".to_owned()], None))
+                   .unwrap();
+            let mut pat_headers = Headers::new();
+            pat_headers.set(Authorization(Bearer {
+                token: "pat_admin".to_owned()
+            }));
+
+            match request::get(tokens_endpoint, pat_headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                }
+            };
+        }
+
+        after_each {
+            remove_test_db();
+        }
+    } // access_tokens_tests
+
+    describe! keys_tests {
+        before_each {
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            usersDb.create(&UserBuilder::new(None)
+                   .name(String::from("admin"))
+                   .password(String::from("password"))
+                   .email(String::from("admin@example.com"))
+                   .admin(true)
+                   .active(true)
+                   .finalize().unwrap()).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: "admin@example.com".to_owned(),
+                password: Some("password".to_owned())
+            }));
+            let login = request::post(&format!("http://localhost:3000{}",
+                                               endpoint("/login")),
+                                      headers, "", &chain).unwrap();
+            let login = extract_body_to::<SessionTokenResponse>(login).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: login.session_token.to_owned()
+            }));
+
+            let keys_endpoint = &format!("http://localhost:3000{}",
+                                         endpoint("/keys"));
+            let first = usersDb.current_signing_key().unwrap();
+        }
+
+        it "should list the signing keys without secrets" {
+            match request::get(keys_endpoint, headers, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                    let body = extract_body_to_string(response);
+                    assert!(!body.contains(&first.secret));
+                    let body_obj: GetKeysResponse =
+                        json::decode(&body).unwrap();
+                    assert_eq!(body_obj.keys.len(), 1);
+                    assert_eq!(body_obj.keys[0].kid, first.kid);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+        }
+
+        it "should rotate keys keeping old tokens valid until retired" {
+            match request::post(keys_endpoint, headers.clone(),
+                                "{\"grace_period\": 60}", &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                    let key = extract_body_to::<KeyResponse>(response).unwrap();
+                    assert!(key.kid != first.kid);
+                    assert_eq!(usersDb.current_signing_key().unwrap().kid,
+                               key.kid);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+
+            // The token signed with the previous key still works...
+            let key_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/keys/{}", first.kid)));
+            match request::delete(key_endpoint, headers.clone(), &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+
+            // ... until the key is retired.
+            match request::get(keys_endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                }
+            };
+        }
+
+        it "should publish the public keys of EdDSA keys" {
+            use jws;
+
+            match request::post(keys_endpoint, headers.clone(),
+                                "{\"algorithm\": \"EdDSA\"}", &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                    let key = extract_body_to::<KeyResponse>(response).unwrap();
+                    assert_eq!(key.alg, "EdDSA");
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
                 }
             };
+
+            let jwks_endpoint = &format!("http://localhost:3000{}",
+                                         endpoint("/.well-known/jwks.json"));
+            let response = request::get(jwks_endpoint, Headers::new(),
+                                        &chain).unwrap();
+            assert_eq!(response.status.unwrap(), Status::Ok);
+            let jwks = Json::from_str(&extract_body_to_string(response))
+                       .unwrap();
+            let jwks = jwks.find("keys").unwrap().as_array().unwrap();
+            // The previous HS256 key is not published.
+            assert_eq!(jwks.len(), 1);
+            assert_eq!(jwks[0].find("kty").unwrap().as_string(), Some("OKP"));
+            let public_key = jwks[0].find("x").unwrap().as_string().unwrap()
+                             .from_base64().unwrap();
+
+            // Tokens issued from now on can be verified with the public key.
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: "admin@example.com".to_owned(),
+                password: Some("password".to_owned())
+            }));
+            let login = request::post(&format!("http://localhost:3000{}",
+                                               endpoint("/login")),
+                                      headers, "", &chain).unwrap();
+            let login = extract_body_to::<SessionTokenResponse>(login).unwrap();
+            let token = jws::decode::<SessionClaims>(&login.session_token)
+                        .unwrap();
+            assert_eq!(token.header.alg, "EdDSA");
+            assert!(token.verify(&public_key));
+        }
+
+        it "should reject algorithms other than HS256 and EdDSA" {
+            for alg in vec!["RS256", "ES256", "none"] {
+                match request::post(keys_endpoint, headers.clone(),
+                                    &format!("{{\"algorithm\": \"{}\"}}",
+                                             alg),
+                                    &chain) {
+                    Ok(_) => assert!(false),
+                    Err(error) => {
+                        let response = error.response;
+                        assert_eq!(response.status.unwrap(),
+                                   Status::BadRequest);
+                    }
+                };
+            }
+            assert_eq!(usersDb.current_signing_key().unwrap().kid, first.kid);
+        }
+
+        after_each {
+            remove_test_db();
         }
+    } // keys_tests
 
-        it "should create one admin user" {
-            let body = "{\"name\": \"name\",\
-                         \"email\": \"username@domain.com\",\
-                         \"password\": \"password\"}";
+    describe! introspection_tests {
+        before_each {
+            use auth_middleware::{ SCOPE_TOKENS_INTROSPECT, SCOPE_USERS_READ };
 
-            if let Ok(res) = request::post(endpoint, Headers::new(), body,
-                                           &chain) {
-                assert_eq!(res.status.unwrap(), Status::Created);
-                let admins = usersDb.read(ReadFilter::IsAdmin(true)).unwrap();
-                assert_eq!(admins.len(), 1);
-                assert_eq!(admins[0].email, "username@domain.com");
-            } else {
-                assert!(false);
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            let user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+            let service = usersDb.create(&UserBuilder::new(None)
+                          .name(String::from("service"))
+                          .password(String::from("password"))
+                          .email(String::from("service@example.com"))
+                          .active(true)
+                          .finalize().unwrap()).unwrap();
+            usersDb.create_role(&Role {
+                name: "service".to_owned(),
+                permissions: vec![SCOPE_TOKENS_INTROSPECT.to_owned()]
+            }).unwrap();
+            usersDb.add_user_role(&service.id, "service").unwrap();
+
+            let login = |email: &str| -> SessionTokenResponse {
+                let mut headers = Headers::new();
+                headers.set(Authorization(Basic {
+                    username: email.to_owned(),
+                    password: Some("password".to_owned())
+                }));
+                let response = request::post(
+                    &format!("http://localhost:3000{}", endpoint("/login")),
+                    headers, "", &chain).unwrap();
+                extract_body_to::<SessionTokenResponse>(response).unwrap()
             };
+
+            let user_token = login("username@example.com").session_token;
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: login("service@example.com").session_token
+            }));
+
+            let introspect_endpoint = &format!("http://localhost:3000{}",
+                                               endpoint("/introspect"));
         }
 
-        it "should respond 410 Gone if an admin account exists" {
-            // Be sure we have an admin
-            usersDb.create(&UserBuilder::new(None)
-                       .name(String::from("admin"))
-                       .password(String::from("password!!"))
-                       .email(String::from("admin@example.com"))
-                       .admin(true)
-                       .finalize().unwrap()).ok();
-            match request::post(endpoint, Headers::new(),
-                                "{\"name\": \"u\",
-                                  \"email\": \"u@d\",
-                                  \"password\": \"12345678\"}",
-                                &chain) {
-                Ok(_) => {
-                    assert!(false);
+        it "should describe active tokens" {
+            match request::post(introspect_endpoint, headers,
+                                &format!("token={}", user_token), &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                    let body = Json::from_str(&extract_body_to_string(response))
+                               .unwrap();
+                    assert_eq!(body.find("active").unwrap().as_boolean(),
+                               Some(true));
+                    assert_eq!(body.find("sub").unwrap().as_string(),
+                               Some(user.id.as_str()));
+                    assert_eq!(body.find("username").unwrap().as_string(),
+                               Some("username"));
+                    assert_eq!(body.find("scope").unwrap().as_string(),
+                               Some(SCOPE_USERS_READ));
+                    assert!(body.find("exp").unwrap().as_i64().is_some());
                 },
                 Err(error) => {
-                    let response = error.response;
-                    assert!(response.status.is_some());
-                    assert_eq!(response.status.unwrap(), Status::Gone);
-                    let json = extract_body_to::<ErrorBody>(response).unwrap();
-                    assert_eq!(json.errno, 410);
-                    assert_eq!(json.message,
-                               Some("There is already an admin account".to_owned()));
+                    println!("{:?}", error);
+                    assert!(false)
                 }
             };
         }
 
-        it "should respond 400 BadRequest, errno 100 if name is missing" {
-            match request::post(endpoint, Headers::new(),
-                                "{\"email\": \"u@d\",
-                                  \"password\": \"12345678\"}",
-                                &chain) {
-                Ok(_) => {
-                    assert!(false);
+        it "should tell invalid tokens are not active" {
+            match request::post(introspect_endpoint, headers,
+                                "token=foo.bar.baz", &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                    assert_eq!(extract_body_to_string(response),
+                               "{\"active\":false}");
                 },
                 Err(error) => {
-                    let response = error.response;
-                    assert!(response.status.is_some());
-                    assert_eq!(response.status.unwrap(), Status::BadRequest);
-                    let json = extract_body_to::<ErrorBody>(response).unwrap();
-                    assert_eq!(json.errno, 100);
-                    assert_eq!(json.message, Some("Invalid user name".to_owned()));
+                    println!("{:?}", error);
+                    assert!(false)
                 }
             };
         }
 
-        it "should respond 400 BadRequest, errno 101 if email is missing" {
-           match request::post(endpoint, Headers::new(),
-                                "{\"name\": \"u\",
-                                  \"password\": \"12345678\"}",
-                                &chain) {
-                Ok(_) => {
-                    assert!(false);
-                },
+        it "should return 400 BadRequest without a token" {
+            match request::post(introspect_endpoint, headers, "", &chain) {
+                Ok(_) => assert!(false),
                 Err(error) => {
                     let response = error.response;
-                    assert!(response.status.is_some());
                     assert_eq!(response.status.unwrap(), Status::BadRequest);
-                    let json = extract_body_to::<ErrorBody>(response).unwrap();
-                    assert_eq!(json.errno, 101);
-                    assert_eq!(json.message, Some("Invalid email".to_owned()));
                 }
             };
         }
 
-        it "should respond 400 BadRequest, errno 102 if password is missing" {
-            match request::post(endpoint, Headers::new(),
-                                "{\"name\": \"u\",
-                                  \"email\": \"u@d\"}",
-                                &chain) {
-                Ok(_) => {
-                    assert!(false);
-                },
+        it "should return 403 Forbidden to clients lacking the scope" {
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer { token: user_token.clone() }));
+            match request::post(introspect_endpoint, headers,
+                                &format!("token={}", user_token), &chain) {
+                Ok(_) => assert!(false),
                 Err(error) => {
                     let response = error.response;
-                    assert!(response.status.is_some());
-                    assert_eq!(response.status.unwrap(), Status::BadRequest);
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
                     let json = extract_body_to::<ErrorBody>(response).unwrap();
-                    assert_eq!(json.errno, 102);
-                    assert_eq!(json.message,
-                        Some("Invalid password. Passwords must have a minimum of 8 chars"
-                             .to_owned()));
+                    assert_eq!(json.errno, 110);
                 }
             };
         }
@@ -928,148 +3768,175 @@ describe! users_router_tests {
         after_each {
             remove_test_db();
         }
-    } // setup_tests
+    } // introspection_tests
 
-    describe! login_tests {
+    describe! roles_tests {
         before_each {
             let usersDb = manager.get_db();
             usersDb.clear().ok();
-            // Create inactive user.
             usersDb.create(&UserBuilder::new(None)
-                       .name(String::from("inactive_user"))
-                       .password(String::from("password"))
-                       .email(String::from("inactive_user@example.com"))
-                       .secret(String::from("secret"))
-                       .active(false)
-                       .finalize().unwrap()).ok();
-            let endpoint = &format!("http://localhost:3000{}",
-                                    endpoint("/login"));
-        }
+                   .name(String::from("admin"))
+                   .password(String::from("password"))
+                   .email(String::from("admin@example.com"))
+                   .admin(true)
+                   .active(true)
+                   .finalize().unwrap()).unwrap();
+            let member = usersDb.create(&UserBuilder::new(None)
+                         .name(String::from("member"))
+                         .password(String::from("password"))
+                         .email(String::from("member@example.com"))
+                         .active(true)
+                         .finalize().unwrap()).unwrap();
+
+            let login = |email: &str| -> SessionTokenResponse {
+                let mut headers = Headers::new();
+                headers.set(Authorization(Basic {
+                    username: email.to_owned(),
+                    password: Some("password".to_owned())
+                }));
+                let response = request::post(
+                    &format!("http://localhost:3000{}", endpoint("/login")),
+                    headers, "", &chain).unwrap();
+                extract_body_to::<SessionTokenResponse>(response).unwrap()
+            };
 
-        it "should respond with a generic 400 Bad Request for requests missing
-            username" {
-            let invalid_credentials = Authorization(Basic {
-                username: "".to_owned(),
-                password: Some("password".to_owned())
-            });
             let mut headers = Headers::new();
-            headers.set(invalid_credentials);
+            headers.set(Authorization(Bearer {
+                token: login("admin@example.com").session_token
+            }));
+
+            let roles_endpoint = &format!("http://localhost:3000{}",
+                                          endpoint("/roles"));
+            let household = "{\"name\": \"household\",
+                              \"permissions\": [\"lights:control\"]}";
+        }
 
-            if let Err(error) = request::post(endpoint, headers, "", &chain) {
-                let response = error.response;
-                assert!(response.status.is_some());
-                assert_eq!(response.status.unwrap(), Status::BadRequest);
-                let json = extract_body_to::<ErrorBody>(response).unwrap();
-                assert_eq!(json.errno, 103);
-            } else {
-                assert!(false);
+        it "should create a role and list it" {
+            match request::post(roles_endpoint, headers.clone(), household,
+                                &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+            match request::get(roles_endpoint, headers, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                    let body_obj = extract_body_to::<GetRolesResponse>
+                                   (response).unwrap();
+                    assert_eq!(body_obj.roles, vec![Role {
+                        name: "household".to_owned(),
+                        permissions: vec!["lights:control".to_owned()]
+                    }]);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
             };
         }
 
-        it "should respond with a generic 400 Bad Request for requests missing
-            password" {
-            let invalid_credentials = Authorization(Basic {
-                username: "username".to_owned(),
-                password: Some("".to_owned())
-            });
-            let mut headers = Headers::new();
-            headers.set(invalid_credentials);
-
-            if let Err(error) = request::post(endpoint, headers, "", &chain) {
-                let response = error.response;
-                assert!(response.status.is_some());
-                assert_eq!(response.status.unwrap(), Status::BadRequest);
-                let json = extract_body_to::<ErrorBody>(response).unwrap();
-                assert_eq!(json.errno, 103);
-            } else {
-                assert!(false);
+        it "should respond 400 BadRequest, errno 111 for invalid role" {
+            match request::post(roles_endpoint, headers,
+                                "{\"name\": \"house hold\",
+                                  \"permissions\": []}", &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::BadRequest);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 111);
+                }
             };
         }
 
-        it "should respond with a 400 Bad Request for requests missing the
-            authorization password" {
-            let headers = Headers::new();
-
-            if let Err(error) = request::post(endpoint, headers, "", &chain) {
-                let response = error.response;
-                assert!(response.status.is_some());
-                assert_eq!(response.status.unwrap(), Status::BadRequest);
-                let json = extract_body_to::<ErrorBody>(response).unwrap();
-                assert_eq!(json.errno, 103);
-            } else {
-                assert!(false);
+        it "should respond 409 Conflict for an existing role" {
+            request::post(roles_endpoint, headers.clone(), household,
+                          &chain).unwrap();
+            match request::post(roles_endpoint, headers, household, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Conflict);
+                }
             };
         }
 
-        it "should respond with a 401 Unauthorized for invalid credentials" {
-            let invalid_credentials = Authorization(Basic {
-                username: "johndoe@example.com".to_owned(),
-                password: Some("password".to_owned())
-            });
+        it "should respond 403 Forbidden to non admin users" {
             let mut headers = Headers::new();
-            headers.set(invalid_credentials);
-
-            if let Err(error) = request::post(endpoint, headers, "", &chain) {
-                let response = error.response;
-                assert!(response.status.is_some());
-                assert_eq!(response.status.unwrap(), Status::Unauthorized);
-            } else {
-                assert!(false);
+            headers.set(Authorization(Bearer {
+                token: login("member@example.com").session_token
+            }));
+            match request::post(roles_endpoint, headers, household, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 110);
+                }
             };
         }
 
-        it "should respond with a 401 Unauthorized for inactive user" {
-            let invalid_credentials = Authorization(Basic {
-                username: "inactive_user@example.com".to_owned(),
-                password: Some("password".to_owned())
-            });
-            let mut headers = Headers::new();
-            headers.set(invalid_credentials);
-
-            if let Err(error) = request::post(endpoint, headers, "", &chain) {
-                let response = error.response;
-                assert!(response.status.is_some());
-                assert_eq!(response.status.unwrap(), Status::Unauthorized);
-            } else {
-                assert!(false);
+        it "should grant role permissions to the tokens of assigned users" {
+            request::post(roles_endpoint, headers.clone(), household,
+                          &chain).unwrap();
+            let user_role_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/roles/household", member.id)));
+            match request::put(user_role_endpoint, headers.clone(), "",
+                               &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
             };
+
+            let user_roles_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/roles", member.id)));
+            let response = request::get(user_roles_endpoint, headers,
+                                        &chain).unwrap();
+            let body_obj = extract_body_to::<GetUserRolesResponse>
+                           (response).unwrap();
+            assert_eq!(body_obj.roles, vec!["household".to_owned()]);
+
+            let token = login("member@example.com").session_token;
+            let claims = SessionToken::from_string(&token).unwrap().claims;
+            assert!(claims.has_scopes(&["lights:control".to_owned()]));
         }
 
-        it "should respond with a 201 Created and a valid JWT token in body for
-            valid credentials" {
-            // Create active user.
-            let user = usersDb.create(&UserBuilder::new(None)
-                       .name(String::from("username"))
-                       .password(String::from("password"))
-                       .email(String::from("username@example.com"))
-                       .secret(String::from("secret"))
-                       .active(true)
-                       .finalize().unwrap()).unwrap();
-            let valid_credentials = Authorization(Basic {
-                username: "username@example.com".to_owned(),
-                password: Some("password".to_owned())
-            });
-            let mut headers = Headers::new();
-            headers.set(valid_credentials);
-            match request::post(endpoint, headers, "", &chain) {
+        it "should delete a role" {
+            request::post(roles_endpoint, headers.clone(), household,
+                          &chain).unwrap();
+            let role_endpoint = &format!("http://localhost:3000{}",
+                                         endpoint("/roles/household"));
+            match request::delete(role_endpoint, headers.clone(), &chain) {
                 Ok(response) => {
-                    assert!(response.status.is_some());
-                    assert_eq!(response.status.unwrap(), Status::Created);
-                    let body_obj = extract_body_to::<SessionTokenResponse>(response).unwrap();
-                    let token = body_obj.session_token;
-                    let claims = jwt::Token::<jwt::Header, SessionClaims>::parse(&token)
-                                .ok().unwrap().claims;
-                    assert_eq!(claims.id, user.id);
-                    assert_eq!(claims.email, user.email);
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
                 },
-                Err(_) => assert!(false)
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+            match request::get(role_endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::NotFound);
+                }
             };
         }
 
         after_each {
             remove_test_db();
         }
-    } // login_tests
+    } // roles_tests
 
     describe! create_user_tests {
         before_each {
@@ -1087,16 +3954,12 @@ describe! users_router_tests {
             let create_user_endpoint = &format!("http://localhost:3000{}",
                                                 endpoint("/users"));
 
-            let jwt_header: jwt::Header = Default::default();
-            let claims = SessionClaims {
-                id: user.id.to_owned(),
-                email: user.email.to_owned()
-            };
-            let token = jwt::Token::new(jwt_header, claims);
-            let signed = token.signed(
-                user.secret.to_owned().as_bytes(),
-                Sha256::new()
-            ).ok().unwrap();
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let key = usersDb.current_signing_key().unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let signed = SessionToken::from_claims(&user, claims, &key).unwrap();
 
             // With Authorization header.
             let mut headers = Headers::new();
@@ -1172,6 +4035,62 @@ describe! users_router_tests {
             };
         }
 
+        it "should invalidate previous activation urls on new invitations" {
+            let response = request::post(create_user_endpoint, headers.clone(),
+                                         "{\"email\": \"user@domain.org\"}",
+                                         &chain).unwrap();
+            let first = extract_body_to::<CreateUserResponse>(response)
+                        .unwrap().activation_url;
+            let user_id = usersDb.read(
+                ReadFilter::Email("user@domain.org".to_owned())
+            ).unwrap()[0].id.clone();
+
+            let invitation_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/invitation", user_id)));
+            let second = match request::post(invitation_endpoint, headers,
+                                             "", &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                    extract_body_to::<CreateUserResponse>(response)
+                        .unwrap().activation_url
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    panic!()
+                }
+            };
+            assert!(first != second);
+
+            let body = "{\"name\": \"name\", \"password\": \"12345678\"}";
+            match request::put(&format!("http://localhost:3000{}", first),
+                               Headers::new(), body, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                }
+            };
+            match request::put(&format!("http://localhost:3000{}", second),
+                               Headers::new(), body, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Created);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+            // The activation token was consumed with the activation.
+            match request::put(&format!("http://localhost:3000{}", second),
+                               Headers::new(), body, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Gone);
+                }
+            };
+        }
+
         after_each {
             remove_test_db();
         }
@@ -1191,16 +4110,12 @@ describe! users_router_tests {
                        .finalize().unwrap();
             let user = usersDb.create(&user).unwrap();
 
-            let jwt_header: jwt::Header = Default::default();
-            let claims = SessionClaims {
-                id: user.id.to_owned(),
-                email: user.email.to_owned()
-            };
-            let token = jwt::Token::new(jwt_header, claims);
-            let signed = token.signed(
-                user.secret.to_owned().as_bytes(),
-                Sha256::new()
-            ).ok().unwrap();
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let key = usersDb.current_signing_key().unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let signed = SessionToken::from_claims(&user, claims, &key).unwrap();
 
             // With Authorization header.
             let mut headers = Headers::new();
@@ -1267,6 +4182,49 @@ describe! users_router_tests {
             };
         }
 
+        it "should return 403 Forbidden to non admin users reading other
+            users" {
+            let other = UserBuilder::new(None)
+                .name(String::from("other"))
+                .password(String::from("password"))
+                .email(String::from("other@example.com"))
+                .active(true)
+                .finalize().unwrap();
+            let other = usersDb.create(&other).unwrap();
+            let session = Session::new(&other.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let claims = SessionClaims::new(&other, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let signed = SessionToken::from_claims(&other, claims, &key)
+                                      .unwrap();
+            let mut other_headers = Headers::new();
+            other_headers.set(Authorization(Bearer {
+                token: signed.to_owned()
+            }));
+
+            let admin_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}", user.id)));
+            match request::get(admin_endpoint, other_headers.clone(), &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                }
+            };
+
+            let own_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}", other.id)));
+            match request::get(own_endpoint, other_headers, &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::Ok);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false)
+                }
+            };
+        }
+
         after_each {
             remove_test_db();
         }
@@ -1286,16 +4244,12 @@ describe! users_router_tests {
                        .finalize().unwrap();
             let user = usersDb.create(&user).unwrap();
 
-            let jwt_header: jwt::Header = Default::default();
-            let claims = SessionClaims {
-                id: user.id.to_owned(),
-                email: user.email.to_owned()
-            };
-            let token = jwt::Token::new(jwt_header, claims);
-            let signed = token.signed(
-                user.secret.to_owned().as_bytes(),
-                Sha256::new()
-            ).ok().unwrap();
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let key = usersDb.current_signing_key().unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let signed = SessionToken::from_claims(&user, claims, &key).unwrap();
 
             // With Authorization header.
             let mut headers = Headers::new();
@@ -1316,6 +4270,33 @@ describe! users_router_tests {
             };
         }
 
+        it "should return 403 Forbidden errno 110 without admin scope" {
+            let user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("not_admin"))
+                       .password(String::from("password"))
+                       .email(String::from("not_admin@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let key = usersDb.current_signing_key().unwrap();
+            let signed = SessionToken::from_claims(&user, claims, &key).unwrap();
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer { token: signed }));
+
+            match request::get(get_users_endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 110);
+                }
+            };
+        }
+
         it "should return 200 OK with a list of one user" {
             match request::get(get_users_endpoint, headers, &chain) {
                 Ok(response) => {
@@ -1377,7 +4358,7 @@ describe! users_router_tests {
 
     describe! activate_user_tests {
         before_each {
-            use url::percent_encoding::{ utf8_percent_encode, QUERY_ENCODE_SET };
+            use auth_middleware::now;
 
             let usersDb = manager.get_db();
             usersDb.clear().ok();
@@ -1391,21 +4372,14 @@ describe! users_router_tests {
                        .finalize().unwrap();
             let user = usersDb.create(&user).unwrap();
 
-            let jwt_header: jwt::Header = Default::default();
-            let claims = SessionClaims {
-                id: user.id.to_owned(),
-                email: user.email.to_owned()
-            };
-            let token = jwt::Token::new(jwt_header, claims);
-            let signed = token.signed(
-                user.secret.to_owned().as_bytes(),
-                Sha256::new()
-            ).ok().unwrap();
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let key = usersDb.current_signing_key().unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let session_token = SessionToken::from_claims(&user, claims, &key).unwrap();
 
-            define_encode_set! {
-                pub CUSTOM_ENCODE_SET = [QUERY_ENCODE_SET] | {'+'}
-            }
-            let signed = utf8_percent_encode(&signed, CUSTOM_ENCODE_SET);
+            let signed = "activationtoken";
         }
 
         it "should return 400 BadRequest errno 100 if name is missing" {
@@ -1470,6 +4444,7 @@ describe! users_router_tests {
                        .active(false)
                        .finalize().unwrap();
             usersDb.create(&user).ok();
+            usersDb.create_invitation(signed, &user.id, now() + 60).unwrap();
 
             let endpoint = &format!(
                 "http://localhost:3000{}",
@@ -1514,12 +4489,94 @@ describe! users_router_tests {
             };
         }
 
+        it "should return 401 Unauthorized errno 112 without an invitation" {
+            let user = UserBuilder::new(None)
+                       .email(String::from("username@example.com"))
+                       .active(false)
+                       .finalize().unwrap();
+            let user = usersDb.create(&user).unwrap();
+
+            let endpoint = &format!(
+                "http://localhost:3000{}",
+                endpoint(&format!("/users/{}/activate?auth={}", user.id, signed))
+            );
+            match request::put(endpoint, Headers::new(),
+                               "{\"name\": \"name\",
+                                 \"password\": \"12345678\"}",
+                               &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 112);
+                }
+            };
+        }
+
+        it "should not accept session tokens as activation tokens" {
+            let user = UserBuilder::new(None)
+                       .email(String::from("username@example.com"))
+                       .active(false)
+                       .finalize().unwrap();
+            let user = usersDb.create(&user).unwrap();
+            usersDb.create_invitation(signed, &user.id, now() + 60).unwrap();
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: session_token.to_owned()
+            }));
+            let endpoint = &format!(
+                "http://localhost:3000{}",
+                endpoint(&format!("/users/{}/activate", user.id))
+            );
+            match request::put(endpoint, headers,
+                               "{\"name\": \"name\",
+                                 \"password\": \"12345678\"}",
+                               &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 112);
+                }
+            };
+        }
+
+        it "should reject expired activation tokens" {
+            let user = UserBuilder::new(None)
+                       .email(String::from("username@example.com"))
+                       .active(false)
+                       .finalize().unwrap();
+            let user = usersDb.create(&user).unwrap();
+            usersDb.create_invitation(signed, &user.id, now() - 1).unwrap();
+
+            let endpoint = &format!(
+                "http://localhost:3000{}",
+                endpoint(&format!("/users/{}/activate?auth={}", user.id, signed))
+            );
+            match request::put(endpoint, Headers::new(),
+                               "{\"name\": \"name\",
+                                 \"password\": \"12345678\"}",
+                               &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 112);
+                }
+            };
+        }
+
         it "should return 201 Created activating a inactive user" {
             let user = UserBuilder::new(None)
                        .email(String::from("username@example.com"))
                        .active(false)
                        .finalize().unwrap();
             let user = usersDb.create(&user).unwrap();
+            usersDb.create_invitation(signed, &user.id, now() + 60).unwrap();
 
             let endpoint = &format!(
                 "http://localhost:3000{}",
@@ -1538,6 +4595,7 @@ describe! users_router_tests {
                         },
                         Err(_) => assert!(false)
                     };
+                    assert!(usersDb.read_invitation(signed).unwrap().is_none());
                 },
                 Err(error) => {
                     println!("{:?}", error);
@@ -1551,6 +4609,181 @@ describe! users_router_tests {
         }
     } // activate_user_tests
 
+    describe! password_reset_tests {
+        before_each {
+            use auth_middleware::now;
+
+            let usersDb = manager.get_db();
+            usersDb.clear().ok();
+            let user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let key = usersDb.current_signing_key().unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let session_token = SessionToken::from_claims(&user, claims, &key)
+                                .unwrap();
+
+            let forgot_endpoint = &format!("http://localhost:3000{}",
+                                           endpoint("/password/forgot"));
+            let reset_endpoint = &format!("http://localhost:3000{}",
+                                          endpoint("/password/reset"));
+            let token = "resettoken";
+        }
+
+        it "should return 501 without an email server" {
+            match request::post(forgot_endpoint, Headers::new(),
+                                "{\"email\": \"username@example.com\"}",
+                                &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(),
+                               Status::InternalServerError);
+                }
+            };
+        }
+
+        it "should not tell whether an email address is registered" {
+            let mut manager = UsersManager::new(&get_db_environment());
+            // Nothing listens there, so no email is actually sent.
+            manager.setup_invitation_middleware("http://localhost:1",
+                                                "http://localhost:3001");
+            let chain = manager.get_router_chain();
+            for email in &["username@example.com", "unknown@example.com"] {
+                let body = format!("{{\"email\": \"{}\"}}", email);
+                match request::post(forgot_endpoint, Headers::new(), &body,
+                                    &chain) {
+                    Ok(response) => {
+                        assert_eq!(response.status.unwrap(),
+                                   Status::NoContent);
+                    },
+                    Err(error) => {
+                        println!("{:?}", error);
+                        assert!(false);
+                    }
+                };
+            }
+        }
+
+        it "should return 429 TooManyRequests errno 124 when sent again" {
+            let mut manager = UsersManager::new(&get_db_environment());
+            manager.setup_invitation_middleware("http://localhost:1",
+                                                "http://localhost:3001");
+            let chain = manager.get_router_chain();
+            for email in &["username@example.com", "unknown@example.com"] {
+                let body = format!("{{\"email\": \"{}\"}}", email);
+                assert!(request::post(forgot_endpoint, Headers::new(), &body,
+                                      &chain).is_ok());
+                match request::post(forgot_endpoint, Headers::new(), &body,
+                                    &chain) {
+                    Ok(_) => assert!(false),
+                    Err(error) => {
+                        let response = error.response;
+                        assert_eq!(response.status.unwrap(),
+                                   Status::TooManyRequests);
+                        let json = extract_body_to::<ErrorBody>(response)
+                                   .unwrap();
+                        assert_eq!(json.errno, 124);
+                    }
+                };
+            }
+        }
+
+        it "should return 401 Unauthorized errno 123 for invalid tokens" {
+            usersDb.create_password_reset("expired", &user.id, now() - 1)
+                   .unwrap();
+            for token in &["unknown", "expired"] {
+                let body = format!("{{\"token\": \"{}\",
+                                      \"password\": \"newpassword\"}}",
+                                   token);
+                match request::post(reset_endpoint, Headers::new(), &body,
+                                    &chain) {
+                    Ok(_) => assert!(false),
+                    Err(error) => {
+                        let response = error.response;
+                        assert_eq!(response.status.unwrap(),
+                                   Status::Unauthorized);
+                        let json = extract_body_to::<ErrorBody>(response)
+                                   .unwrap();
+                        assert_eq!(json.errno, 123);
+                    }
+                };
+            }
+        }
+
+        it "should return 400 BadRequest errno 102 if password is too short" {
+            usersDb.create_password_reset(token, &user.id, now() + 60)
+                   .unwrap();
+            match request::post(reset_endpoint, Headers::new(),
+                                "{\"token\": \"resettoken\",
+                                  \"password\": \"123\"}",
+                                &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::BadRequest);
+                    let json = extract_body_to::<ErrorBody>(response).unwrap();
+                    assert_eq!(json.errno, 102);
+                }
+            };
+            // The token can still be used with a valid password.
+            assert!(usersDb.read_password_reset(token).unwrap().is_some());
+        }
+
+        it "should reset the password and close the sessions" {
+            usersDb.create_password_reset(token, &user.id, now() + 60)
+                   .unwrap();
+            let body = "{\"token\": \"resettoken\",
+                         \"password\": \"newpassword\"}";
+            match request::post(reset_endpoint, Headers::new(), body,
+                                &chain) {
+                Ok(response) => {
+                    assert_eq!(response.status.unwrap(), Status::NoContent);
+                },
+                Err(error) => {
+                    println!("{:?}", error);
+                    assert!(false);
+                }
+            };
+
+            let users = usersDb.read(ReadFilter::Credentials(
+                "username@example.com".to_owned(),
+                "newpassword".to_owned())).unwrap();
+            assert_eq!(users.len(), 1);
+            assert!(users[0].secret != user.secret);
+            assert!(usersDb.read_sessions(&user.id).unwrap().is_empty());
+
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: session_token
+            }));
+            let sessions_endpoint = &format!("http://localhost:3000{}",
+                endpoint(&format!("/users/{}/sessions", user.id)));
+            match request::get(sessions_endpoint, headers, &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    assert_eq!(error.response.status.unwrap(),
+                               Status::Unauthorized);
+                }
+            };
+
+            // Reset tokens are single-use.
+            assert!(request::post(reset_endpoint, Headers::new(), body,
+                                  &chain).is_err());
+        }
+
+        after_each {
+            remove_test_db();
+        }
+    } // password_reset_tests
+
     describe! edit_user_tests {
         before_each {
             let usersDb = manager.get_db();
@@ -1565,16 +4798,12 @@ describe! users_router_tests {
                        .finalize().unwrap();
             let user = usersDb.create(&user).unwrap();
 
-            let jwt_header: jwt::Header = Default::default();
-            let claims = SessionClaims {
-                id: user.id,
-                email: user.email.to_owned()
-            };
-            let token = jwt::Token::new(jwt_header, claims);
-            let signed = token.signed(
-                user.secret.to_owned().as_bytes(),
-                Sha256::new()
-            ).ok().unwrap();
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let key = usersDb.current_signing_key().unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let signed = SessionToken::from_claims(&user, claims, &key).unwrap();
 
             // With Authorization header.
             let mut headers = Headers::new();
@@ -1668,6 +4897,63 @@ describe! users_router_tests {
             };
         }
 
+        it "should return 403 Forbidden changing passwords with access tokens" {
+            use users_db::AccessToken;
+
+            usersDb.create_access_token("pat_token", &AccessToken::new(
+                &user.id, "script",
+                vec!["users:read".to_owned(), "users:admin".to_owned()],
+                None)).unwrap();
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: "pat_token".to_owned()
+            }));
+
+            let endpoint = &format!("http://localhost:3000{}",
+                            endpoint(&format!("/users/{}", user.id)));
+            match request::put(endpoint, headers,
+                               "{\"password\": \"12345678\"}",
+                               &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                }
+            };
+        }
+
+        it "should return 403 Forbidden setting is_admin without the
+            users:admin scope" {
+            let user = usersDb.create(&UserBuilder::new(None)
+                       .name(String::from("username"))
+                       .password(String::from("password"))
+                       .email(String::from("username@example.com"))
+                       .active(true)
+                       .finalize().unwrap()).unwrap();
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let signed = SessionToken::from_claims(&user, claims, &key)
+                         .unwrap();
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer { token: signed }));
+
+            let endpoint = &format!("http://localhost:3000{}",
+                            endpoint(&format!("/users/{}", user.id)));
+            match request::put(endpoint, headers,
+                               "{\"is_admin\": true}",
+                               &chain) {
+                Ok(_) => assert!(false),
+                Err(error) => {
+                    let response = error.response;
+                    assert_eq!(response.status.unwrap(), Status::Forbidden);
+                }
+            };
+            let users = usersDb.read(ReadFilter::Id(user.id)).unwrap();
+            assert!(!users[0].is_admin);
+        }
+
         it "should return 204 NoContent when editting the user succeeds" {
             let user = UserBuilder::new(None)
                        .name(String::from("username"))
@@ -1719,16 +5005,12 @@ describe! users_router_tests {
                        .finalize().unwrap();
             let user = usersDb.create(&user).unwrap();
 
-            let jwt_header: jwt::Header = Default::default();
-            let claims = SessionClaims {
-                id: user.id.to_owned(),
-                email: user.email.to_owned()
-            };
-            let token = jwt::Token::new(jwt_header, claims);
-            let signed = token.signed(
-                user.secret.to_owned().as_bytes(),
-                Sha256::new()
-            ).ok().unwrap();
+            let session = Session::new(&user.id, "", "");
+            usersDb.create_session(&session).unwrap();
+            let key = usersDb.current_signing_key().unwrap();
+            let claims = SessionClaims::new(&user, &session.id,
+                                            SessionToken::DEFAULT_TTL);
+            let signed = SessionToken::from_claims(&user, claims, &key).unwrap();
 
             // With Authorization header.
             let mut headers = Headers::new();
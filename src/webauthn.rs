@@ -0,0 +1,717 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Relying party side of [WebAuthn](https://www.w3.org/TR/webauthn-2/)
+//! registration and assertion ceremonies.
+//!
+//! P-256 (COSE algorithm -7, `ES256`) and Ed25519 (COSE algorithm -8)
+//! credentials are supported. Most authenticators only offer the former.
+//! Attestation statements are not verified: we ask for no attestation and
+//! trust the authenticator the user chose.
+
+use crypto::digest::Digest;
+use crypto::ed25519;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use ring::signature;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+use untrusted;
+
+/// COSE identifier of the ECDSA with P-256 and SHA-256 algorithm.
+pub static COSE_ALG_ES256: i64 = -7;
+
+/// COSE identifier of the EdDSA algorithm.
+pub static COSE_ALG_EDDSA: i64 = -8;
+
+/// Client data type of registration ceremonies.
+pub static TYPE_CREATE: &'static str = "webauthn.create";
+
+/// Client data type of assertion ceremonies.
+pub static TYPE_GET: &'static str = "webauthn.get";
+
+/// Authenticator data flags.
+static FLAG_USER_PRESENT: u8 = 0x01;
+static FLAG_USER_VERIFIED: u8 = 0x04;
+static FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// COSE key parameters.
+static COSE_KEY_KTY: i64 = 1;
+static COSE_KEY_ALG: i64 = 3;
+static COSE_KEY_CRV: i64 = -1;
+static COSE_KEY_X: i64 = -2;
+static COSE_KEY_Y: i64 = -3;
+static COSE_KTY_OKP: i64 = 1;
+static COSE_KTY_EC2: i64 = 2;
+static COSE_CRV_P256: i64 = 1;
+static COSE_CRV_ED25519: i64 = 6;
+
+/// Maximum nesting of the CBOR items we decode.
+static MAX_CBOR_DEPTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebAuthnError {
+    /// The data sent by the client cannot be decoded.
+    Malformed,
+    /// The client data is not for this ceremony, challenge or origin.
+    InvalidClientData,
+    /// The authenticator data is not for this relying party.
+    InvalidRpId,
+    /// The user did not interact with the authenticator.
+    UserNotPresent,
+    /// The authenticator did not verify the user, with a PIN or a
+    /// biometric, although it was required.
+    UserNotVerified,
+    /// The credential uses neither a P-256 nor an Ed25519 key.
+    UnsupportedAlgorithm,
+    InvalidSignature
+}
+
+/// A decoded CBOR data item, restricted to the types WebAuthn uses.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cbor {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Bool(bool),
+    Null
+}
+
+impl Cbor {
+    /// Decode the data item at the start of `data`, returning it along with
+    /// the number of bytes it takes.
+    pub fn decode(data: &[u8]) -> Result<(Cbor, usize), WebAuthnError> {
+        Cbor::decode_item(data, 0)
+    }
+
+    fn decode_item(data: &[u8], depth: usize)
+        -> Result<(Cbor, usize), WebAuthnError> {
+        if depth > MAX_CBOR_DEPTH || data.is_empty() {
+            return Err(WebAuthnError::Malformed);
+        }
+        let major = data[0] >> 5;
+        let (argument, mut offset) = match data[0] & 0x1f {
+            info @ 0...23 => (info as u64, 1),
+            info @ 24...27 => {
+                let size = 1usize << (info - 24);
+                if data.len() < 1 + size {
+                    return Err(WebAuthnError::Malformed);
+                }
+                let argument = data[1..1 + size].iter()
+                    .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+                (argument, 1 + size)
+            },
+            // Indefinite lengths are not used by authenticators.
+            _ => return Err(WebAuthnError::Malformed)
+        };
+        // Every item takes at least one byte, so lengths greater than the
+        // data left are necessarily wrong.
+        let length = if argument > (data.len() - offset) as u64 {
+            None
+        } else {
+            Some(argument as usize)
+        };
+
+        let item = match (major, length) {
+            (0, _) if argument <= i64::max_value() as u64 => {
+                Cbor::Integer(argument as i64)
+            },
+            (1, _) if argument <= i64::max_value() as u64 => {
+                Cbor::Integer(-1 - argument as i64)
+            },
+            (2, Some(length)) => {
+                offset += length;
+                Cbor::Bytes(data[offset - length..offset].to_vec())
+            },
+            (3, Some(length)) => {
+                offset += length;
+                let text = data[offset - length..offset].to_vec();
+                match String::from_utf8(text) {
+                    Ok(text) => Cbor::Text(text),
+                    Err(_) => return Err(WebAuthnError::Malformed)
+                }
+            },
+            (4, Some(length)) => {
+                let mut items = Vec::with_capacity(length);
+                for _ in 0..length {
+                    let (item, size) = try!(
+                        Cbor::decode_item(&data[offset..], depth + 1));
+                    items.push(item);
+                    offset += size;
+                }
+                Cbor::Array(items)
+            },
+            (5, Some(length)) => {
+                let mut entries = Vec::with_capacity(length);
+                for _ in 0..length {
+                    let (key, size) = try!(
+                        Cbor::decode_item(&data[offset..], depth + 1));
+                    offset += size;
+                    let (value, size) = try!(
+                        Cbor::decode_item(&data[offset..], depth + 1));
+                    offset += size;
+                    entries.push((key, value));
+                }
+                Cbor::Map(entries)
+            },
+            (7, _) if argument == 20 => Cbor::Bool(false),
+            (7, _) if argument == 21 => Cbor::Bool(true),
+            (7, _) if argument == 22 => Cbor::Null,
+            _ => return Err(WebAuthnError::Malformed)
+        };
+        Ok((item, offset))
+    }
+
+    /// The value of the `key` entry of a map.
+    pub fn get(&self, key: &Cbor) -> Option<&Cbor> {
+        match *self {
+            Cbor::Map(ref entries) => entries.iter()
+                .find(|entry| entry.0 == *key).map(|entry| &entry.1),
+            _ => None
+        }
+    }
+
+    #[cfg(test)]
+    pub fn encode(&self) -> Vec<u8> {
+        fn head(major: u8, argument: u64) -> Vec<u8> {
+            let mut bytes = vec![];
+            let size = match argument {
+                0...23 => {
+                    bytes.push(major << 5 | argument as u8);
+                    0
+                },
+                24...0xff => { bytes.push(major << 5 | 24); 1 },
+                0x100...0xffff => { bytes.push(major << 5 | 25); 2 },
+                0x10000...0xffffffff => { bytes.push(major << 5 | 26); 4 },
+                _ => { bytes.push(major << 5 | 27); 8 }
+            };
+            for i in (0..size).rev() {
+                bytes.push((argument >> (8 * i)) as u8);
+            }
+            bytes
+        }
+
+        match *self {
+            Cbor::Integer(value) if value >= 0 => head(0, value as u64),
+            Cbor::Integer(value) => head(1, (-1 - value) as u64),
+            Cbor::Bytes(ref bytes) => {
+                let mut encoded = head(2, bytes.len() as u64);
+                encoded.extend_from_slice(bytes);
+                encoded
+            },
+            Cbor::Text(ref text) => {
+                let mut encoded = head(3, text.len() as u64);
+                encoded.extend_from_slice(text.as_bytes());
+                encoded
+            },
+            Cbor::Array(ref items) => {
+                let mut encoded = head(4, items.len() as u64);
+                for item in items {
+                    encoded.extend(item.encode());
+                }
+                encoded
+            },
+            Cbor::Map(ref entries) => {
+                let mut encoded = head(5, entries.len() as u64);
+                for &(ref key, ref value) in entries {
+                    encoded.extend(key.encode());
+                    encoded.extend(value.encode());
+                }
+                encoded
+            },
+            Cbor::Bool(false) => vec![0xf4],
+            Cbor::Bool(true) => vec![0xf5],
+            Cbor::Null => vec![0xf6]
+        }
+    }
+}
+
+/// Data of the authenticator signed in assertions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthenticatorData {
+    pub rp_id_hash: Vec<u8>,
+    pub flags: u8,
+    pub sign_count: u32,
+    /// The new credential, in registration ceremonies.
+    pub credential: Option<AttestedCredential>
+}
+
+/// A credential created by an authenticator.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttestedCredential {
+    pub id: Vec<u8>,
+    /// Public key of the credential: an uncompressed P-256 point or an
+    /// Ed25519 key.
+    pub public_key: Vec<u8>
+}
+
+impl AuthenticatorData {
+    pub fn parse(data: &[u8]) -> Result<AuthenticatorData, WebAuthnError> {
+        if data.len() < 37 {
+            return Err(WebAuthnError::Malformed);
+        }
+        let flags = data[32];
+        let sign_count = data[33..37].iter()
+            .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+        let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+            // AAGUID (16 bytes), credential id length (2 bytes) and id.
+            if data.len() < 55 {
+                return Err(WebAuthnError::Malformed);
+            }
+            let id_length = (data[53] as usize) << 8 | data[54] as usize;
+            if data.len() < 55 + id_length {
+                return Err(WebAuthnError::Malformed);
+            }
+            let (key, _) = try!(Cbor::decode(&data[55 + id_length..]));
+            Some(AttestedCredential {
+                id: data[55..55 + id_length].to_vec(),
+                public_key: try!(credential_public_key(&key))
+            })
+        } else {
+            None
+        };
+        Ok(AuthenticatorData {
+            rp_id_hash: data[..32].to_vec(),
+            flags: flags,
+            sign_count: sign_count,
+            credential: credential
+        })
+    }
+}
+
+/// The public key of a COSE key: the uncompressed point, 0x04 followed by
+/// its coordinates, of P-256 keys and the 32 bytes of Ed25519 keys.
+fn credential_public_key(key: &Cbor) -> Result<Vec<u8>, WebAuthnError> {
+    let param = |label: i64| key.get(&Cbor::Integer(label));
+    let has = |label: i64, value: i64| {
+        param(label) == Some(&Cbor::Integer(value))
+    };
+    if has(COSE_KEY_KTY, COSE_KTY_EC2) &&
+       has(COSE_KEY_ALG, COSE_ALG_ES256) &&
+       has(COSE_KEY_CRV, COSE_CRV_P256) {
+        return match (param(COSE_KEY_X), param(COSE_KEY_Y)) {
+            (Some(&Cbor::Bytes(ref x)), Some(&Cbor::Bytes(ref y)))
+                if x.len() == 32 && y.len() == 32 => {
+                let mut point = vec![0x04];
+                point.extend_from_slice(x);
+                point.extend_from_slice(y);
+                Ok(point)
+            },
+            _ => Err(WebAuthnError::Malformed)
+        };
+    }
+    if has(COSE_KEY_KTY, COSE_KTY_OKP) &&
+       has(COSE_KEY_ALG, COSE_ALG_EDDSA) &&
+       has(COSE_KEY_CRV, COSE_CRV_ED25519) {
+        return match param(COSE_KEY_X) {
+            Some(&Cbor::Bytes(ref x)) if x.len() == 32 => Ok(x.clone()),
+            _ => Err(WebAuthnError::Malformed)
+        };
+    }
+    Err(WebAuthnError::UnsupportedAlgorithm)
+}
+
+/// Whether `signature` of `signed` verifies with `public_key`, as returned
+/// by `credential_public_key`. P-256 signatures are DER encoded.
+fn verify_signature(public_key: &[u8], signed: &[u8], signature: &[u8])
+    -> bool {
+    match public_key.len() {
+        65 if public_key[0] == 0x04 => signature::verify(
+            &signature::ECDSA_P256_SHA256_ASN1,
+            untrusted::Input::from(public_key),
+            untrusted::Input::from(signed),
+            untrusted::Input::from(signature)
+        ).is_ok(),
+        32 => signature.len() == 64 &&
+              ed25519::verify(signed, public_key, signature),
+        _ => false
+    }
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut digest = vec![0; 32];
+    hasher.result(&mut digest);
+    digest
+}
+
+/// The challenge of the client data, which identifies the ceremony.
+pub fn client_data_challenge(client_data_json: &[u8])
+    -> Result<String, WebAuthnError> {
+    let client_data = match String::from_utf8(client_data_json.to_vec()) {
+        Ok(client_data) => client_data,
+        Err(_) => return Err(WebAuthnError::Malformed)
+    };
+    match Json::from_str(&client_data) {
+        Ok(json) => match json.find("challenge").and_then(|c| c.as_string()) {
+            Some(challenge) => Ok(challenge.to_owned()),
+            None => Err(WebAuthnError::Malformed)
+        },
+        Err(_) => Err(WebAuthnError::Malformed)
+    }
+}
+
+/// The relying party we act as: the host application users sign in to.
+#[derive(Clone, Debug)]
+pub struct RelyingParty {
+    /// Domain of the host application, like `box.local`.
+    pub id: String,
+    /// Name shown by authenticators.
+    pub name: String,
+    /// Origin of the web pages running the ceremonies, like
+    /// `https://box.local`.
+    pub origin: String
+}
+
+impl RelyingParty {
+    fn check_client_data(&self, client_data_json: &[u8], kind: &str,
+                         challenge: &str) -> Result<(), WebAuthnError> {
+        let client_data = match String::from_utf8(client_data_json.to_vec()) {
+            Ok(client_data) => client_data,
+            Err(_) => return Err(WebAuthnError::Malformed)
+        };
+        let json = match Json::from_str(&client_data) {
+            Ok(json) => json,
+            Err(_) => return Err(WebAuthnError::Malformed)
+        };
+        let field = |name: &str| json.find(name).and_then(|v| v.as_string());
+        // Challenges are compared as bytes, whatever their padding.
+        let expected = challenge.from_base64();
+        let challenges_match = match (field("challenge"), expected) {
+            (Some(received), Ok(expected)) => match received.from_base64() {
+                Ok(received) => fixed_time_eq(&received, &expected),
+                Err(_) => false
+            },
+            _ => false
+        };
+        if field("type") != Some(kind) || !challenges_match ||
+           field("origin") != Some(self.origin.as_str()) {
+            return Err(WebAuthnError::InvalidClientData);
+        }
+        Ok(())
+    }
+
+    fn check_authenticator_data(&self, data: &AuthenticatorData,
+                                user_verification: bool)
+        -> Result<(), WebAuthnError> {
+        if !fixed_time_eq(&data.rp_id_hash, &sha256(self.id.as_bytes())) {
+            return Err(WebAuthnError::InvalidRpId);
+        }
+        if data.flags & FLAG_USER_PRESENT == 0 {
+            return Err(WebAuthnError::UserNotPresent);
+        }
+        if user_verification && data.flags & FLAG_USER_VERIFIED == 0 {
+            return Err(WebAuthnError::UserNotVerified);
+        }
+        Ok(())
+    }
+
+    /// Verify the response of an authenticator to a registration ceremony
+    /// started with `challenge`, returning the new credential and its
+    /// signature counter.
+    pub fn verify_registration(&self, challenge: &str,
+                               client_data_json: &[u8],
+                               attestation_object: &[u8],
+                               user_verification: bool)
+        -> Result<(AttestedCredential, u32), WebAuthnError> {
+        try!(self.check_client_data(client_data_json, TYPE_CREATE,
+                                    challenge));
+        let (attestation, _) = try!(Cbor::decode(attestation_object));
+        let auth_data = attestation.get(&Cbor::Text("authData".to_owned()));
+        let auth_data = match auth_data {
+            Some(&Cbor::Bytes(ref auth_data)) => auth_data.clone(),
+            _ => return Err(WebAuthnError::Malformed)
+        };
+        let data = try!(AuthenticatorData::parse(&auth_data));
+        try!(self.check_authenticator_data(&data, user_verification));
+        match data.credential {
+            Some(credential) => Ok((credential, data.sign_count)),
+            None => Err(WebAuthnError::Malformed)
+        }
+    }
+
+    /// Verify an assertion signed with `public_key` in a ceremony started
+    /// with `challenge`, returning the signature counter of the
+    /// authenticator.
+    pub fn verify_assertion(&self, challenge: &str, public_key: &[u8],
+                            client_data_json: &[u8],
+                            authenticator_data: &[u8], signature: &[u8],
+                            user_verification: bool)
+        -> Result<u32, WebAuthnError> {
+        try!(self.check_client_data(client_data_json, TYPE_GET, challenge));
+        let data = try!(AuthenticatorData::parse(authenticator_data));
+        try!(self.check_authenticator_data(&data, user_verification));
+
+        let mut signed = authenticator_data.to_vec();
+        signed.extend(sha256(client_data_json));
+        if !verify_signature(public_key, &signed, signature) {
+            return Err(WebAuthnError::InvalidSignature);
+        }
+        Ok(data.sign_count)
+    }
+}
+
+/// A software authenticator holding a single Ed25519 credential, for the
+/// tests.
+#[cfg(test)]
+pub struct SoftAuthenticator {
+    pub credential_id: Vec<u8>,
+    secret_key: [u8; 64],
+    public_key: [u8; 32],
+    pub sign_count: u32,
+    /// Whether the authenticator verifies the user.
+    pub user_verification: bool
+}
+
+#[cfg(test)]
+impl SoftAuthenticator {
+    pub fn new(seed: u8) -> SoftAuthenticator {
+        let (secret_key, public_key) = ed25519::keypair(&[seed; 32]);
+        SoftAuthenticator {
+            credential_id: vec![seed; 16],
+            secret_key: secret_key,
+            public_key: public_key,
+            sign_count: 0,
+            user_verification: true
+        }
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        format!("{{\"type\":\"{}\",\"challenge\":\"{}\",\"origin\":\"{}\",\
+                 \"crossOrigin\":false}}", kind, challenge, origin).into_bytes()
+    }
+
+    fn authenticator_data(&self, rp_id: &str, attested: bool) -> Vec<u8> {
+        let mut data = sha256(rp_id.as_bytes());
+        let mut flags = FLAG_USER_PRESENT;
+        if self.user_verification {
+            flags |= FLAG_USER_VERIFIED;
+        }
+        if attested {
+            flags |= FLAG_ATTESTED_CREDENTIAL;
+        }
+        data.push(flags);
+        for i in (0..4).rev() {
+            data.push((self.sign_count >> (8 * i)) as u8);
+        }
+        if attested {
+            data.extend_from_slice(&[0; 16]);
+            data.push((self.credential_id.len() >> 8) as u8);
+            data.push(self.credential_id.len() as u8);
+            data.extend_from_slice(&self.credential_id);
+            data.extend(Cbor::Map(vec![
+                (Cbor::Integer(COSE_KEY_KTY), Cbor::Integer(COSE_KTY_OKP)),
+                (Cbor::Integer(COSE_KEY_ALG), Cbor::Integer(COSE_ALG_EDDSA)),
+                (Cbor::Integer(COSE_KEY_CRV), Cbor::Integer(COSE_CRV_ED25519)),
+                (Cbor::Integer(COSE_KEY_X),
+                 Cbor::Bytes(self.public_key.to_vec()))
+            ]).encode());
+        }
+        data
+    }
+
+    /// Create the credential, returning the client data and the
+    /// attestation object.
+    pub fn register(&self, rp_id: &str, origin: &str, challenge: &str)
+        -> (Vec<u8>, Vec<u8>) {
+        let attestation = Cbor::Map(vec![
+            (Cbor::Text("fmt".to_owned()), Cbor::Text("none".to_owned())),
+            (Cbor::Text("attStmt".to_owned()), Cbor::Map(vec![])),
+            (Cbor::Text("authData".to_owned()),
+             Cbor::Bytes(self.authenticator_data(rp_id, true)))
+        ]);
+        (SoftAuthenticator::client_data(TYPE_CREATE, challenge, origin),
+         attestation.encode())
+    }
+
+    /// Sign an assertion, returning the client data, the authenticator
+    /// data and the signature.
+    pub fn sign(&mut self, rp_id: &str, origin: &str, challenge: &str)
+        -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        self.sign_count += 1;
+        let client_data = SoftAuthenticator::client_data(TYPE_GET, challenge,
+                                                         origin);
+        let authenticator_data = self.authenticator_data(rp_id, false);
+        let mut signed = authenticator_data.clone();
+        signed.extend(sha256(&client_data));
+        let signature = ed25519::signature(&signed, &self.secret_key);
+        (client_data, authenticator_data, signature.to_vec())
+    }
+}
+
+#[cfg(test)]
+describe! webauthn_tests {
+    before_each {
+        let rp = RelyingParty {
+            id: "box.local".to_owned(),
+            name: "FoxBox".to_owned(),
+            origin: "https://box.local".to_owned()
+        };
+        let challenge = "c2VydmVyIGNoYWxsZW5nZQ";
+        let mut authenticator = SoftAuthenticator::new(1);
+    }
+
+    it "should decode CBOR items" {
+        // Examples of RFC 7049, appendix A.
+        assert_eq!(Cbor::decode(&[0x17]).unwrap(), (Cbor::Integer(23), 1));
+        assert_eq!(Cbor::decode(&[0x19, 0x03, 0xe8]).unwrap(),
+                   (Cbor::Integer(1000), 3));
+        assert_eq!(Cbor::decode(&[0x38, 0x63]).unwrap(),
+                   (Cbor::Integer(-100), 2));
+        assert_eq!(Cbor::decode(&[0x64, 0x49, 0x45, 0x54, 0x46]).unwrap(),
+                   (Cbor::Text("IETF".to_owned()), 5));
+        assert_eq!(Cbor::decode(&[0xa2, 0x01, 0x02, 0x03, 0x82, 0x04, 0xf5])
+                   .unwrap(),
+                   (Cbor::Map(vec![
+                       (Cbor::Integer(1), Cbor::Integer(2)),
+                       (Cbor::Integer(3), Cbor::Array(vec![
+                           Cbor::Integer(4), Cbor::Bool(true)
+                       ]))
+                   ]), 7));
+
+        // Truncated, indefinite length and overly long items.
+        assert!(Cbor::decode(&[0x19, 0x03]).is_err());
+        assert!(Cbor::decode(&[0x5f, 0x41, 0x00, 0xff]).is_err());
+        assert!(Cbor::decode(&[0x5a, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(Cbor::decode(&[0x81; 16]).is_err());
+
+        let item = Cbor::Map(vec![
+            (Cbor::Integer(-2), Cbor::Bytes(vec![0; 300])),
+            (Cbor::Text("fmt".to_owned()), Cbor::Null)
+        ]);
+        let encoded = item.encode();
+        assert_eq!(Cbor::decode(&encoded).unwrap(), (item, encoded.len()));
+    }
+
+    it "should verify registrations" {
+        let (client_data, attestation) =
+            authenticator.register("box.local", "https://box.local",
+                                   challenge);
+        let (credential, sign_count) = rp.verify_registration(
+            challenge, &client_data, &attestation, true).unwrap();
+        assert_eq!(credential.id, authenticator.credential_id);
+        assert_eq!(sign_count, 0);
+        assert_eq!(client_data_challenge(&client_data).unwrap(), challenge);
+
+        assert_eq!(rp.verify_registration("b3RoZXI", &client_data,
+                                          &attestation, true),
+                   Err(WebAuthnError::InvalidClientData));
+        let (client_data, attestation) =
+            authenticator.register("box.local", "https://evil.local",
+                                   challenge);
+        assert_eq!(rp.verify_registration(challenge, &client_data,
+                                          &attestation, true),
+                   Err(WebAuthnError::InvalidClientData));
+        let (client_data, attestation) =
+            authenticator.register("evil.local", "https://box.local",
+                                   challenge);
+        assert_eq!(rp.verify_registration(challenge, &client_data,
+                                          &attestation, true),
+                   Err(WebAuthnError::InvalidRpId));
+
+        authenticator.user_verification = false;
+        let (client_data, attestation) =
+            authenticator.register("box.local", "https://box.local",
+                                   challenge);
+        assert_eq!(rp.verify_registration(challenge, &client_data,
+                                          &attestation, true),
+                   Err(WebAuthnError::UserNotVerified));
+        assert!(rp.verify_registration(challenge, &client_data,
+                                       &attestation, false).is_ok());
+    }
+
+    it "should verify assertions" {
+        let (client_data, attestation) =
+            authenticator.register("box.local", "https://box.local",
+                                   challenge);
+        let (credential, _) = rp.verify_registration(
+            challenge, &client_data, &attestation, true).unwrap();
+
+        let (client_data, authenticator_data, signature) =
+            authenticator.sign("box.local", "https://box.local", challenge);
+        assert_eq!(rp.verify_assertion(challenge, &credential.public_key,
+                                       &client_data, &authenticator_data,
+                                       &signature, true),
+                   Ok(1));
+
+        // Registration responses are not assertions.
+        let (client_data, _) =
+            authenticator.register("box.local", "https://box.local",
+                                   challenge);
+        assert_eq!(rp.verify_assertion(challenge, &credential.public_key,
+                                       &client_data, &authenticator_data,
+                                       &signature, true),
+                   Err(WebAuthnError::InvalidClientData));
+
+        let (client_data, mut authenticator_data, signature) =
+            authenticator.sign("box.local", "https://box.local", challenge);
+        authenticator_data[36] += 1;
+        assert_eq!(rp.verify_assertion(challenge, &credential.public_key,
+                                       &client_data, &authenticator_data,
+                                       &signature, true),
+                   Err(WebAuthnError::InvalidSignature));
+        let other = SoftAuthenticator::new(2);
+        let (client_data, authenticator_data, signature) =
+            authenticator.sign("box.local", "https://box.local", challenge);
+        assert_eq!(rp.verify_assertion(challenge, &other.public_key,
+                                       &client_data, &authenticator_data,
+                                       &signature, true),
+                   Err(WebAuthnError::InvalidSignature));
+    }
+
+    it "should read P-256 credential keys" {
+        let key = Cbor::Map(vec![
+            (Cbor::Integer(COSE_KEY_KTY), Cbor::Integer(COSE_KTY_EC2)),
+            (Cbor::Integer(COSE_KEY_ALG), Cbor::Integer(COSE_ALG_ES256)),
+            (Cbor::Integer(COSE_KEY_CRV), Cbor::Integer(COSE_CRV_P256)),
+            (Cbor::Integer(COSE_KEY_X), Cbor::Bytes(vec![1; 32])),
+            (Cbor::Integer(COSE_KEY_Y), Cbor::Bytes(vec![2; 32]))
+        ]);
+        let public_key = credential_public_key(&key).unwrap();
+        assert_eq!(public_key.len(), 65);
+        assert_eq!(public_key[0], 0x04);
+        assert_eq!(&public_key[1..33], &[1; 32][..]);
+        assert_eq!(&public_key[33..], &[2; 32][..]);
+
+        let key = Cbor::Map(vec![
+            (Cbor::Integer(COSE_KEY_KTY), Cbor::Integer(COSE_KTY_EC2)),
+            (Cbor::Integer(COSE_KEY_ALG), Cbor::Integer(-35)),
+            (Cbor::Integer(COSE_KEY_CRV), Cbor::Integer(2))
+        ]);
+        assert_eq!(credential_public_key(&key),
+                   Err(WebAuthnError::UnsupportedAlgorithm));
+    }
+
+    it "should verify ES256 assertions" {
+        use rustc_serialize::hex::FromHex;
+
+        // Signed with OpenSSL by a P-256 key, over authenticator data for
+        // box.local flagging user presence and verification, with a
+        // signature counter of 1.
+        let public_key = "04bd7c73b88b2e9b4ceda62022b2da8be13193a5b56edc26e7\
+                          df7842e24cd0b5eb0605ada7bda83ac6a2b80d7e314040fa47\
+                          ff16b83bac85cedb014451bb7ce71a".from_hex().unwrap();
+        let signature = "3045022100b9d2c6fe17acfd790ae8c57282dd24831916ff82\
+                         75483ed4ea8cf3c046f3c7e402200a2602e40dc3b4faf46d8d\
+                         15d96cd80c9187b3f82a6038e8cfeb6c672843b9ae"
+                        .from_hex().unwrap();
+        let client_data = SoftAuthenticator::client_data(
+            TYPE_GET, challenge, "https://box.local");
+        let mut authenticator_data = sha256(b"box.local");
+        authenticator_data.extend_from_slice(&[0x05, 0, 0, 0, 1]);
+
+        assert_eq!(rp.verify_assertion(challenge, &public_key, &client_data,
+                                       &authenticator_data, &signature,
+                                       true),
+                   Ok(1));
+        authenticator_data[36] += 1;
+        assert_eq!(rp.verify_assertion(challenge, &public_key, &client_data,
+                                       &authenticator_data, &signature,
+                                       true),
+                   Err(WebAuthnError::InvalidSignature));
+    }
+}
@@ -0,0 +1,732 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! WebAuthn credentials, like the passkeys of phones.
+//!
+//! Users register credentials with POST /users/:id/webauthn/registration
+//! and POST /users/:id/webauthn/credentials. They can then log in without
+//! a password with POST /login/webauthn/options and POST /login/webauthn,
+//! as long as the authenticator verifies them, or use the credentials as
+//! a second factor after their password.
+
+use super::auth_cache::users_db;
+use super::auth_middleware::{ AuthenticatedUser, now };
+use super::errors::*;
+use super::oauth_router::random_token;
+use super::two_factor_router::TwoFactorRouter;
+use super::users_db::{ ReadFilter, User, UsersDb, WebAuthnChallenge,
+                       WebAuthnCredential };
+use super::webauthn::{ self, COSE_ALG_EDDSA, COSE_ALG_ES256, RelyingParty,
+                       TYPE_CREATE, TYPE_GET, WebAuthnError };
+
+use iron::prelude::*;
+use iron::status;
+use router::Router;
+use rustc_serialize::base64::{ FromBase64, ToBase64, URL_SAFE };
+use rustc_serialize::hex::{ FromHex, ToHex };
+use rustc_serialize::json::{ self, Json };
+
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// Lifetime of the challenges of WebAuthn ceremonies, in seconds.
+static CEREMONY_TTL: i64 = 300;
+
+/// Name of the credentials registered without one.
+static DEFAULT_CREDENTIAL_NAME: &'static str = "Security key";
+
+/// Body response for POST /users/:id/webauthn/credentials
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct CredentialResponse {
+    id: String,
+    name: String,
+    created_at: i64,
+    last_used: Option<i64>
+}
+
+impl CredentialResponse {
+    fn from_credential(credential: &WebAuthnCredential)
+        -> CredentialResponse {
+        CredentialResponse {
+            id: credential.id.clone(),
+            name: credential.name.clone(),
+            created_at: credential.created_at,
+            last_used: credential.last_used
+        }
+    }
+}
+
+/// Body response for GET /users/:id/webauthn/credentials
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct CredentialsResponse {
+    credentials: Vec<CredentialResponse>
+}
+
+/// A signed assertion of an authenticator, with all its binary fields
+/// base64url encoded.
+#[derive(Debug, RustcDecodable)]
+pub struct AssertionBody {
+    /// Id of the credential.
+    id: String,
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String
+}
+
+fn public_key_credential(id: &str) -> Json {
+    let mut credential = BTreeMap::new();
+    credential.insert("type".to_owned(), Json::String("public-key".to_owned()));
+    credential.insert("id".to_owned(), Json::String(id.to_owned()));
+    Json::Object(credential)
+}
+
+/// Manages the WebAuthn credentials of users. The routes are installed by
+/// `UsersRouter` when set up with a relying party.
+pub struct WebAuthnRouter;
+
+impl WebAuthnRouter {
+    /// POST /users/:id/webauthn/registration handler.
+    /// Start the registration of a new credential for the user, returning
+    /// the options to create it with `navigator.credentials.create`.
+    pub fn start_registration(req: &mut Request, db_path: &str,
+                              rp: &RelyingParty) -> IronResult<Response> {
+        let user = match TwoFactorRouter::get_enrolling_user(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+
+        let db = users_db(req, db_path);
+        let credentials = match db.read_webauthn_credentials(&user.id) {
+            Ok(credentials) => credentials,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        let challenge = random_token();
+        if let Err(error) = db.create_webauthn_challenge(
+            &challenge, &WebAuthnChallenge {
+                user_id: Some(user.id.clone()),
+                kind: TYPE_CREATE.to_owned(),
+                expires_at: now() + CEREMONY_TTL
+            }) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        let mut rp_entity = BTreeMap::new();
+        rp_entity.insert("id".to_owned(), Json::String(rp.id.clone()));
+        rp_entity.insert("name".to_owned(), Json::String(rp.name.clone()));
+        let mut user_entity = BTreeMap::new();
+        let user_handle = user.id.as_bytes().to_base64(URL_SAFE);
+        user_entity.insert("id".to_owned(), Json::String(user_handle));
+        user_entity.insert("name".to_owned(), Json::String(user.email.clone()));
+        user_entity.insert("displayName".to_owned(),
+                           Json::String(user.name.clone()));
+        // Most authenticators only support ES256, so it comes first.
+        let algorithms = [COSE_ALG_ES256, COSE_ALG_EDDSA].iter().map(|alg| {
+            let mut algorithm = BTreeMap::new();
+            algorithm.insert("type".to_owned(),
+                             Json::String("public-key".to_owned()));
+            algorithm.insert("alg".to_owned(), Json::I64(*alg));
+            Json::Object(algorithm)
+        }).collect();
+        let mut selection = BTreeMap::new();
+        selection.insert("residentKey".to_owned(),
+                         Json::String("preferred".to_owned()));
+        selection.insert("userVerification".to_owned(),
+                         Json::String("preferred".to_owned()));
+
+        let mut body = BTreeMap::new();
+        body.insert("challenge".to_owned(), Json::String(challenge));
+        body.insert("rp".to_owned(), Json::Object(rp_entity));
+        body.insert("user".to_owned(), Json::Object(user_entity));
+        body.insert("pubKeyCredParams".to_owned(), Json::Array(algorithms));
+        body.insert("timeout".to_owned(), Json::I64(CEREMONY_TTL * 1000));
+        body.insert("attestation".to_owned(), Json::String("none".to_owned()));
+        body.insert("authenticatorSelection".to_owned(),
+                    Json::Object(selection));
+        body.insert("excludeCredentials".to_owned(), Json::Array(
+            credentials.iter()
+                       .map(|credential| public_key_credential(&credential.id))
+                       .collect()));
+        Ok(Response::with((status::Ok, Json::Object(body).to_string())))
+    }
+
+    /// POST /users/:id/webauthn/credentials handler.
+    /// Finish the registration of a credential with the response of the
+    /// authenticator.
+    pub fn register(req: &mut Request, db_path: &str, rp: &RelyingParty)
+        -> IronResult<Response> {
+        #[derive(RustcDecodable, Debug)]
+        struct RegisterBody {
+            name: Option<String>,
+            client_data_json: String,
+            attestation_object: String
+        }
+
+        let user = match TwoFactorRouter::get_enrolling_user(req) {
+            Ok(user) => user,
+            Err(response) => return response
+        };
+
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let body: RegisterBody = match json::decode(&payload) {
+            Ok(body) => body,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_decoder_error(error);
+            }
+        };
+        let (client_data, attestation) =
+            match (body.client_data_json.from_base64(),
+                   body.attestation_object.from_base64()) {
+            (Ok(client_data), Ok(attestation)) => (client_data, attestation),
+            _ => return from_webauthn_error(WebAuthnError::Malformed)
+        };
+
+        // The challenge of the client data tells the ceremony, which must
+        // be a registration started by the same user.
+        let challenge = match webauthn::client_data_challenge(&client_data) {
+            Ok(challenge) => challenge,
+            Err(error) => return from_webauthn_error(error)
+        };
+        let db = users_db(req, db_path);
+        match WebAuthnRouter::take_ceremony(&challenge, &db) {
+            Ok(Some(ref ceremony))
+                if ceremony.kind == TYPE_CREATE &&
+                   ceremony.user_id.as_ref() == Some(&user.id) => {},
+            Ok(_) => return from_webauthn_error(
+                WebAuthnError::InvalidClientData
+            ),
+            Err(response) => return response
+        }
+
+        let (credential, sign_count) = match rp.verify_registration(
+            &challenge, &client_data, &attestation, false) {
+            Ok(result) => result,
+            Err(error) => return from_webauthn_error(error)
+        };
+        let credential = WebAuthnCredential {
+            id: credential.id.to_base64(URL_SAFE),
+            user_id: user.id.clone(),
+            name: body.name.unwrap_or_else(
+                || DEFAULT_CREDENTIAL_NAME.to_owned()),
+            public_key: credential.public_key.to_hex(),
+            sign_count: sign_count as i64,
+            created_at: now(),
+            last_used: None
+        };
+        if let Err(error) = db.create_webauthn_credential(&credential) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+
+        let body = match json::encode(
+            &CredentialResponse::from_credential(&credential)) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Created, body)))
+    }
+
+    /// GET /users/:id/webauthn/credentials handler.
+    /// Get the list of WebAuthn credentials of a user. Only the owner or an
+    /// admin can list them.
+    pub fn get_credentials(req: &mut Request, db_path: &str)
+        -> IronResult<Response> {
+        let user_id = req.extensions.get::<Router>().unwrap()
+            .find("id").unwrap_or("").to_owned();
+        match req.extensions.get::<AuthenticatedUser>() {
+            Some(caller) if caller.user.id == user_id ||
+                            caller.user.is_admin => {},
+            Some(_) => return EndpointError::with(status::Forbidden, 403,
+                                                  None),
+            None => return EndpointError::with(status::Unauthorized, 401,
+                                               None)
+        }
+
+        let db = users_db(req, db_path);
+        let credentials = match db.read_webauthn_credentials(&user_id) {
+            Ok(credentials) => credentials,
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        };
+        let body = match json::encode(&CredentialsResponse {
+            credentials: credentials.iter()
+                .map(CredentialResponse::from_credential).collect()
+        }) {
+            Ok(body) => body,
+            Err(_) => return EndpointError::with(
+                status::InternalServerError, 501, None
+            )
+        };
+        Ok(Response::with((status::Ok, body)))
+    }
+
+    /// DELETE /users/:id/webauthn/credentials/:cid handler.
+    /// Remove a WebAuthn credential of a user, like the passkey of a lost
+    /// phone. Only the owner or an admin can do it, as checked by
+    /// `TwoFactorRouter::authorize_removal`.
+    pub fn delete_credential(req: &mut Request, db_path: &str,
+                             rp: &RelyingParty) -> IronResult<Response> {
+        let id = req.extensions.get::<Router>().unwrap()
+            .find("cid").unwrap_or("").to_owned();
+        let db = users_db(req, db_path);
+        let user_id = match TwoFactorRouter::authorize_removal(req, &db) {
+            Ok(user_id) => user_id,
+            Err(response) => return response
+        };
+        match db.delete_webauthn_credential(&user_id, &id) {
+            Ok(0) => return EndpointError::with(status::NotFound, 404,
+                Some("Credential not found".to_owned())),
+            Ok(_) => {},
+            Err(error) => {
+                println!("{:?}", error);
+                return from_sqlite_error(error);
+            }
+        }
+        if let Err(response) =
+            TwoFactorRouter::drop_unused_recovery_codes(&user_id, &db,
+                                                        Some(rp)) {
+            return response;
+        }
+        Ok(Response::with(status::NoContent))
+    }
+
+    /// The options to get an assertion with `navigator.credentials.get` in
+    /// the ceremony started with `challenge`, from one of `credentials` or
+    /// else from any credential of the relying party.
+    pub fn request_options(challenge: &str, rp: &RelyingParty,
+                           credentials: &[WebAuthnCredential],
+                           user_verification: &str) -> Json {
+        let mut options = BTreeMap::new();
+        options.insert("challenge".to_owned(),
+                       Json::String(challenge.to_owned()));
+        options.insert("rpId".to_owned(), Json::String(rp.id.clone()));
+        options.insert("timeout".to_owned(), Json::I64(CEREMONY_TTL * 1000));
+        options.insert("userVerification".to_owned(),
+                       Json::String(user_verification.to_owned()));
+        options.insert("allowCredentials".to_owned(), Json::Array(
+            credentials.iter()
+                       .map(|credential| public_key_credential(&credential.id))
+                       .collect()));
+        Json::Object(options)
+    }
+
+    /// POST /login/webauthn/options handler.
+    /// Start a passwordless login, returning the options to get an
+    /// assertion from any credential registered with the relying party.
+    pub fn start_login(req: &mut Request, db_path: &str, rp: &RelyingParty)
+        -> IronResult<Response> {
+        let challenge = random_token();
+        let db = users_db(req, db_path);
+        if let Err(error) = db.create_webauthn_challenge(
+            &challenge, &WebAuthnChallenge {
+                user_id: None,
+                kind: TYPE_GET.to_owned(),
+                expires_at: now() + CEREMONY_TTL
+            }) {
+            println!("{:?}", error);
+            return from_sqlite_error(error);
+        }
+        let options = WebAuthnRouter::request_options(&challenge, rp, &[],
+                                                      "required");
+        Ok(Response::with((status::Ok, options.to_string())))
+    }
+
+    /// Verify `assertion`, signed in the ceremony started with `challenge`
+    /// by a credential of the user identified by `user_id` or, when `None`,
+    /// of any user, and record its signature counter. Returns the
+    /// credential if the assertion is valid.
+    pub fn check_assertion(assertion: &AssertionBody, challenge: &str,
+                           user_id: Option<&str>, user_verification: bool,
+                           rp: &RelyingParty, db: &UsersDb)
+        -> Result<Option<WebAuthnCredential>, IronResult<Response>> {
+        let credential = match db.read_webauthn_credential(&assertion.id) {
+            Ok(Some(credential)) => credential,
+            Ok(None) => return Ok(None),
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_sqlite_error(error));
+            }
+        };
+        if user_id.map_or(false, |user_id| user_id != credential.user_id) {
+            return Ok(None);
+        }
+
+        let decoded = (credential.public_key.from_hex(),
+                       assertion.client_data_json.from_base64(),
+                       assertion.authenticator_data.from_base64(),
+                       assertion.signature.from_base64());
+        let sign_count = match decoded {
+            (Ok(public_key), Ok(client_data), Ok(authenticator_data),
+             Ok(signature)) => {
+                match rp.verify_assertion(challenge, &public_key,
+                                          &client_data, &authenticator_data,
+                                          &signature, user_verification) {
+                    Ok(sign_count) => sign_count,
+                    Err(error) => {
+                        println!("{:?}", error);
+                        return Ok(None);
+                    }
+                }
+            },
+            _ => return Ok(None)
+        };
+
+        // A signature counter that did not increase hints at a cloned
+        // authenticator.
+        match db.use_webauthn_credential(&credential.id, sign_count as i64) {
+            Ok(1) => Ok(Some(credential)),
+            Ok(_) => {
+                println!("Signature counter of credential {} did not increase",
+                         credential.id);
+                Ok(None)
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+
+    /// Complete the passwordless login in the body of `req`, returning the
+    /// user logging in.
+    pub fn complete_login(req: &mut Request, db: &UsersDb, rp: &RelyingParty)
+        -> Result<User, IronResult<Response>> {
+        let mut payload = String::new();
+        req.body.read_to_string(&mut payload).unwrap();
+        let assertion: AssertionBody = match json::decode(&payload) {
+            Ok(body) => body,
+            Err(error) => {
+                println!("{:?}", error);
+                return Err(from_decoder_error(error));
+            }
+        };
+        let invalid_assertion = || EndpointError::with(
+            status::Unauthorized, 122,
+            Some("Invalid WebAuthn assertion".to_owned()));
+
+        let challenge = match assertion.client_data_json.from_base64() {
+            Ok(client_data) => match webauthn::client_data_challenge(
+                &client_data) {
+                Ok(challenge) => challenge,
+                Err(_) => return Err(invalid_assertion())
+            },
+            Err(_) => return Err(invalid_assertion())
+        };
+        match try!(WebAuthnRouter::take_ceremony(&challenge, db)) {
+            Some(ref ceremony)
+                if ceremony.kind == TYPE_GET &&
+                   ceremony.user_id.is_none() => {},
+            _ => return Err(invalid_assertion())
+        }
+
+        let credential = match try!(WebAuthnRouter::check_assertion(
+            &assertion, &challenge, None, true, rp, db)) {
+            Some(credential) => credential,
+            None => return Err(invalid_assertion())
+        };
+        match db.read(ReadFilter::Id(credential.user_id)) {
+            Ok(mut users) => {
+                if users.len() != 1 || !users[0].is_active {
+                    return Err(EndpointError::with(status::Unauthorized, 401,
+                                                   None));
+                }
+                Ok(users.remove(0))
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+
+    /// The pending ceremony started with `challenge`, if it has not
+    /// expired. Ceremonies are consumed by their first response.
+    fn take_ceremony(challenge: &str, db: &UsersDb)
+        -> Result<Option<WebAuthnChallenge>, IronResult<Response>> {
+        match db.take_webauthn_challenge(challenge) {
+            Ok(Some(ref ceremony)) if ceremony.expires_at <= now() => Ok(None),
+            Ok(ceremony) => Ok(ceremony),
+            Err(error) => {
+                println!("{:?}", error);
+                Err(from_sqlite_error(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+describe! webauthn_router_tests {
+    before_each {
+        use super::{ CredentialResponse, CredentialsResponse };
+
+        use errors::ErrorBody;
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::request;
+        use iron_test::response::extract_body_to_string;
+        use rustc_serialize::base64::{ ToBase64, URL_SAFE };
+        use rustc_serialize::json::Json;
+        use test_helpers::{ bearer, create_user, extract_body_to, login, url };
+        use users_db::{ get_db_environment, remove_test_db };
+        use webauthn::SoftAuthenticator;
+        use UsersManager;
+
+        let mut manager = UsersManager::new(&get_db_environment());
+        manager.setup_webauthn("box.local", "FoxBox", "https://box.local/");
+        let chain = manager.get_router_chain();
+
+        let usersDb = manager.get_db();
+        usersDb.clear().ok();
+        create_user(&usersDb, "other", false);
+        let user = create_user(&usersDb, "username", false);
+
+        let headers = bearer(&login(&chain, "username@example.com"));
+        let credentials_endpoint =
+            &url(&format!("/users/{}/webauthn/credentials", user.id));
+
+        // Register the credential of `authenticator` for the user.
+        let register = |authenticator: &SoftAuthenticator|
+            -> CredentialResponse {
+            let response = request::post(
+                &url(&format!("/users/{}/webauthn/registration", user.id)),
+                headers.clone(), "", &chain).unwrap();
+            let options = Json::from_str(&extract_body_to_string(response))
+                          .unwrap();
+            let challenge = options.find("challenge").unwrap().as_string()
+                                   .unwrap();
+            let (client_data, attestation) = authenticator.register(
+                "box.local", "https://box.local", challenge);
+            let response = request::post(credentials_endpoint,
+                headers.clone(),
+                &format!("{{\"name\": \"Phone\",
+                            \"client_data_json\": \"{}\",
+                            \"attestation_object\": \"{}\"}}",
+                         client_data.to_base64(URL_SAFE),
+                         attestation.to_base64(URL_SAFE)),
+                &chain).unwrap();
+            extract_body_to::<CredentialResponse>(response).unwrap()
+        };
+        // Body of an assertion of `authenticator` for `challenge`.
+        let assertion = |authenticator: &mut SoftAuthenticator,
+                         challenge: &str| -> String {
+            let (client_data, authenticator_data, signature) =
+                authenticator.sign("box.local", "https://box.local",
+                                   challenge);
+            format!("{{\"id\": \"{}\", \"client_data_json\": \"{}\",
+                       \"authenticator_data\": \"{}\",
+                       \"signature\": \"{}\"}}",
+                    authenticator.credential_id.to_base64(URL_SAFE),
+                    client_data.to_base64(URL_SAFE),
+                    authenticator_data.to_base64(URL_SAFE),
+                    signature.to_base64(URL_SAFE))
+        };
+        // Log in without a password with `authenticator`.
+        let passwordless_login = |authenticator: &mut SoftAuthenticator| {
+            let response = request::post(&url("/login/webauthn/options"),
+                                         Headers::new(), "", &chain).unwrap();
+            let options = Json::from_str(&extract_body_to_string(response))
+                          .unwrap();
+            let challenge = options.find("challenge").unwrap().as_string()
+                                   .unwrap().to_owned();
+            request::post(&url("/login/webauthn"), Headers::new(),
+                          &assertion(authenticator, &challenge), &chain)
+        };
+        let mut authenticator = SoftAuthenticator::new(1);
+    }
+
+    it "should register credentials" {
+        let response = request::post(
+            &url(&format!("/users/{}/webauthn/registration", user.id)),
+            headers.clone(), "", &chain).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+        let options = Json::from_str(&extract_body_to_string(response))
+                      .unwrap();
+        assert_eq!(options.find_path(&["rp", "id"]).unwrap().as_string(),
+                   Some("box.local"));
+        assert_eq!(options.find_path(&["user", "name"]).unwrap().as_string(),
+                   Some("username@example.com"));
+        let algorithms: Vec<Option<i64>> =
+            options.find("pubKeyCredParams").unwrap().as_array().unwrap()
+                   .iter().map(|param| param.find("alg").unwrap().as_i64())
+                   .collect();
+        assert_eq!(algorithms, vec![Some(-7), Some(-8)]);
+
+        let challenge = options.find("challenge").unwrap().as_string()
+                               .unwrap();
+        let (client_data, attestation) = authenticator.register(
+            "box.local", "https://box.local", challenge);
+        let body = format!("{{\"name\": \"Phone\",
+                              \"client_data_json\": \"{}\",
+                              \"attestation_object\": \"{}\"}}",
+                           client_data.to_base64(URL_SAFE),
+                           attestation.to_base64(URL_SAFE));
+        match request::post(credentials_endpoint, headers.clone(), &body,
+                            &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Created);
+                let credential = extract_body_to::<CredentialResponse>(
+                    response).unwrap();
+                assert_eq!(credential.id, authenticator.credential_id
+                                                       .to_base64(URL_SAFE));
+                assert_eq!(credential.name, "Phone");
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+
+        // Registration challenges are single use.
+        match request::post(credentials_endpoint, headers.clone(), &body,
+                            &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::BadRequest);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 122);
+            }
+        };
+
+        let response = request::get(credentials_endpoint, headers.clone(),
+                                    &chain).unwrap();
+        let credentials = extract_body_to::<CredentialsResponse>(response)
+                          .unwrap().credentials;
+        assert_eq!(credentials.len(), 1);
+
+        // Others cannot register credentials for the user.
+        match request::post(
+            &url(&format!("/users/{}/webauthn/registration", user.id)),
+            bearer(&login(&chain, "other@example.com")), "", &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                assert_eq!(error.response.status.unwrap(), Status::Forbidden);
+            }
+        };
+    }
+
+    it "should log in without a password" {
+        register(&authenticator);
+        match passwordless_login(&mut authenticator) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Created);
+                let body = Json::from_str(&extract_body_to_string(response))
+                           .unwrap();
+                assert!(body.find("session_token").is_some());
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+
+        // The authenticator must verify the user.
+        authenticator.user_verification = false;
+        match passwordless_login(&mut authenticator) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let response = error.response;
+                assert_eq!(response.status.unwrap(), Status::Unauthorized);
+                let json = extract_body_to::<ErrorBody>(response).unwrap();
+                assert_eq!(json.errno, 122);
+            }
+        };
+
+        // Unknown credentials are rejected.
+        let mut unknown = SoftAuthenticator::new(2);
+        assert!(passwordless_login(&mut unknown).is_err());
+    }
+
+    it "should reject cloned authenticators" {
+        register(&authenticator);
+        let mut clone = SoftAuthenticator::new(1);
+        assert!(passwordless_login(&mut authenticator).is_ok());
+        assert!(passwordless_login(&mut clone).is_err());
+        assert!(passwordless_login(&mut authenticator).is_ok());
+    }
+
+    it "should be a second factor" {
+        register(&authenticator);
+        let challenge = login(&chain, "username@example.com");
+        assert_eq!(challenge.find("methods").unwrap(),
+                   &Json::from_str("[\"webauthn\"]").unwrap());
+        let options = challenge.find("webauthn").unwrap();
+        let challenge = challenge.find("challenge").unwrap().as_string()
+                                 .unwrap();
+        assert_eq!(options.find("challenge").unwrap().as_string(),
+                   Some(challenge));
+        assert_eq!(options.find("allowCredentials").unwrap().as_array()
+                          .unwrap().len(), 1);
+
+        // Authenticators not verifying the user are fine after a password.
+        authenticator.user_verification = false;
+        let body = assertion(&mut authenticator, challenge);
+        let body = format!("{{\"challenge\": \"{}\", \"webauthn\": {}}}",
+                           challenge, body);
+        match request::post(&url("/login/2fa"), Headers::new(), &body,
+                            &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::Created);
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+
+        let challenge = login(&chain, "username@example.com");
+        let challenge = challenge.find("challenge").unwrap().as_string()
+                                 .unwrap();
+        let body = assertion(&mut SoftAuthenticator::new(2), challenge);
+        let body = format!("{{\"challenge\": \"{}\", \"webauthn\": {}}}",
+                           challenge, body);
+        match request::post(&url("/login/2fa"), Headers::new(), &body,
+                            &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                let json = extract_body_to::<ErrorBody>(error.response)
+                           .unwrap();
+                assert_eq!(json.errno, 122);
+            }
+        };
+    }
+
+    it "should let users and admins delete credentials" {
+        let credential = register(&authenticator);
+        let credential_endpoint = &format!("{}/{}", credentials_endpoint,
+                                           credential.id);
+        let other = bearer(&login(&chain, "other@example.com"));
+        match request::delete(credential_endpoint, other, &chain) {
+            Ok(_) => assert!(false),
+            Err(error) => {
+                assert_eq!(error.response.status.unwrap(), Status::Forbidden);
+            }
+        };
+        match request::delete(credential_endpoint, headers.clone(), &chain) {
+            Ok(response) => {
+                assert_eq!(response.status.unwrap(), Status::NoContent);
+            },
+            Err(error) => {
+                println!("{:?}", error);
+                assert!(false)
+            }
+        };
+        assert!(passwordless_login(&mut authenticator).is_err());
+        assert!(login(&chain, "username@example.com")
+                    .find("session_token").is_some());
+    }
+
+    after_each {
+        remove_test_db();
+    }
+}